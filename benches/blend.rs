@@ -0,0 +1,28 @@
+//! Times `overlay_image` on a 2000px image, the size the parallel blend was written for.
+//!
+//! `cargo bench --bench blend` times the OpenCV matrix blend, and `cargo bench --bench blend --features scalar-blend`
+//! the per-pixel loop it replaced, so the two can be compared on the same machine.
+
+use criterion::{criterion_group, criterion_main, Criterion};
+use degenbot::utils::image_utils::overlay_image;
+use opencv::core::{self, Mat};
+use opencv::prelude::*;
+
+/// Creates an image of uniformly random pixels, seeded so every run blends the same pixels.
+fn random_image(rows: i32, cols: i32, typ: i32, seed: u64) -> Mat {
+    let mut image = Mat::new_rows_cols_with_default(rows, cols, typ, core::Scalar::all(0.0)).unwrap();
+    core::set_rng_seed(seed as i32).unwrap();
+    core::randu(&mut image, &core::Scalar::all(0.0), &core::Scalar::all(256.0)).unwrap();
+    image
+}
+
+fn blend(c: &mut Criterion) {
+    // A 2000x1500 photo under a landscape overlay covering its bottom half, as in a typical request
+    let base = random_image(1500, 2000, core::CV_8UC3, 1);
+    let overlay = random_image(400, 1000, core::CV_8UC4, 2);
+    let name = if cfg!(feature = "scalar-blend") { "overlay_image_2000px_scalar" } else { "overlay_image_2000px_matrix" };
+    c.bench_function(name, |b| b.iter(|| overlay_image(&base, &overlay, None, 1.0, 0).unwrap()));
+}
+
+criterion_group!(benches, blend);
+criterion_main!(benches);
@@ -0,0 +1,23 @@
+//! Times getting an overlay ready for a request, before and after overlays were decoded once at startup.
+//!
+//! `imread` is what every request used to do; `decoded` is the copy of the cached overlay requests use now. Run it with
+//! `cargo bench --bench overlay_assets` from the repository root, so `img` is found.
+
+use std::path::Path;
+use criterion::{criterion_group, criterion_main, Criterion};
+use degenbot::utils::overlay_assets::OverlayAssets;
+use opencv::imgcodecs;
+
+fn overlay_loading(c: &mut Criterion) {
+    let overlay_assets = OverlayAssets::load(Path::new("img"));
+    let path = overlay_assets.pick("portrait").to_path_buf();
+    let path_str = path.to_str().expect("the overlay path is valid UTF-8").to_string();
+
+    let mut group = c.benchmark_group("overlay_loading");
+    group.bench_function("imread", |b| b.iter(|| imgcodecs::imread(&path_str, imgcodecs::IMREAD_UNCHANGED).unwrap()));
+    group.bench_function("decoded", |b| b.iter(|| overlay_assets.decoded(&path).unwrap()));
+    group.finish();
+}
+
+criterion_group!(benches, overlay_loading);
+criterion_main!(benches);
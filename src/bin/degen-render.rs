@@ -0,0 +1,174 @@
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+
+use degenbot::commands::overlay::{parse_styles, render_overlay, OverlayOutcome, OverlayTiming};
+use degenbot::config;
+use degenbot::utils::image_utils::OutputFormat;
+use degenbot::utils::overlay_assets::OverlayAssets;
+
+/// The help text printed for `--help` or when the arguments are wrong.
+const USAGE: &str = "\
+Renders a DegenBot overlay onto a local image, without Telegram.
+
+Usage: degen-render --input <FILE> --output <FILE> [--overlay <STYLE>] [--img-dir <DIR>]
+
+Options:
+  --input <FILE>     The image to overlay.
+  --output <FILE>    Where to write the result. A .png, .webp or .jpg extension picks the format.
+  --overlay <STYLE>  The overlay style to apply, such as hands. Repeat it or separate styles with commas to stack
+                     overlays in order. A random overlay is used if it is left out.
+  --img-dir <DIR>    The directory the overlays are read from. [default: img]
+  -h, --help         Print this help.
+
+The portrait or landscape overlay is picked from the image's shape, and the rest of the [processing] settings, such as
+the opacity and the watermark, are read from config.toml (or the file named by DEGENBOT_CONFIG), just like the bot.
+
+Example:
+  degen-render --input photo.jpg --overlay hands --output out.png
+";
+
+/// The parsed command line arguments.
+struct Args {
+    input: PathBuf,
+    output: PathBuf,
+    styles: Vec<String>,
+    img_dir: PathBuf,
+}
+
+/// Parses the command line arguments, not including the program name.
+///
+/// # Arguments
+/// * `args` - The command line arguments.
+///
+/// # Returns
+/// The parsed arguments, `Ok(None)` if help was requested, or a message describing what is wrong with them.
+fn parse_args(mut args: impl Iterator<Item = String>) -> Result<Option<Args>, String> {
+    let mut input = None;
+    let mut output = None;
+    let mut styles = Vec::new();
+    let mut img_dir = PathBuf::from("img");
+
+    while let Some(arg) = args.next() {
+        let mut value = || args.next().ok_or_else(|| format!("{} needs a value", arg));
+        match arg.as_str() {
+            "-h" | "--help" => return Ok(None),
+            "--input" => input = Some(PathBuf::from(value()?)),
+            "--output" => output = Some(PathBuf::from(value()?)),
+            "--overlay" => styles.extend(parse_styles(&value()?)),
+            "--img-dir" => img_dir = PathBuf::from(value()?),
+            _ => return Err(format!("Unknown argument: {}", arg)),
+        }
+    }
+
+    Ok(Some(Args {
+        input: input.ok_or("--input is required")?,
+        output: output.ok_or("--output is required")?,
+        styles,
+        img_dir,
+    }))
+}
+
+/// Returns the output format picked by the extension of `path`.
+///
+/// # Arguments
+/// * `path` - The path the result is written to.
+///
+/// # Returns
+/// The output format, or `None` if the extension isn't one of `.png`, `.webp`, `.jpg` or `.jpeg`.
+fn output_format_for(path: &Path) -> Option<OutputFormat> {
+    let extension = path.extension()?.to_str()?.to_lowercase();
+    match extension.as_str() {
+        "png" => Some(OutputFormat::Png),
+        "webp" => Some(OutputFormat::Webp),
+        "jpg" | "jpeg" => Some(OutputFormat::Jpeg),
+        _ => None,
+    }
+}
+
+/// Renders an overlay onto a local image with the same pipeline the bot uses, for previewing overlay assets.
+///
+/// Errors are printed to stderr and reported through the exit code.
+fn main() -> ExitCode {
+    let _ = pretty_env_logger::try_init();
+
+    let args = match parse_args(env::args().skip(1)) {
+        Ok(Some(args)) => args,
+        Ok(None) => {
+            print!("{}", USAGE);
+            return ExitCode::SUCCESS;
+        }
+        Err(e) => {
+            eprintln!("{}\n\n{}", e, USAGE);
+            return ExitCode::FAILURE;
+        }
+    };
+
+    let Some(output_format) = output_format_for(&args.output) else {
+        eprintln!("Unsupported output file {:?}, use a .png, .webp or .jpg extension", args.output);
+        return ExitCode::FAILURE;
+    };
+
+    let config = match config::load_config().and_then(|config| config.validate().map(|_| config)) {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("{}", e);
+            return ExitCode::FAILURE;
+        }
+    };
+    if !config::config_path().exists() {
+        eprintln!("Config file {:?} not found, rendering with the default [processing] settings", config::config_path());
+    }
+
+    let image_data = match fs::read(&args.input) {
+        Ok(image_data) => image_data,
+        Err(e) => {
+            eprintln!("Could not read {:?}: {}", args.input, e);
+            return ExitCode::FAILURE;
+        }
+    };
+
+    let overlay_assets = OverlayAssets::from_config(&args.img_dir, &config.processing)
+        .with_output_format(output_format, config.processing.quality);
+    if let Err(e) = overlay_assets.check() {
+        eprintln!("{}", e);
+        return ExitCode::FAILURE;
+    }
+
+    let available = overlay_assets.styles();
+    if let Some(style) = args.styles.iter().find(|style| !available.contains(style)) {
+        eprintln!("There's no \"{}\" overlay in {:?}. Available overlays: {}", style, args.img_dir, available.join(", "));
+        return ExitCode::FAILURE;
+    }
+
+    let mut timing = OverlayTiming::new();
+    match render_overlay(&overlay_assets, &image_data, &args.styles, &mut timing) {
+        OverlayOutcome::Success(buffer) => {
+            if let Err(e) = fs::write(&args.output, buffer) {
+                eprintln!("Could not write {:?}: {}", args.output, e);
+                return ExitCode::FAILURE;
+            }
+            println!(
+                "Wrote {:?} (decode {}ms, composite {}ms, encode {}ms)",
+                args.output,
+                timing.decode.as_millis(),
+                timing.composite.as_millis(),
+                timing.encode.as_millis(),
+            );
+            ExitCode::SUCCESS
+        }
+        OverlayOutcome::DecodeFailed(format) => {
+            eprintln!("Could not decode {:?} (detected format: {})", args.input, format.unwrap_or("unknown"));
+            ExitCode::FAILURE
+        }
+        OverlayOutcome::Corrupted => {
+            eprintln!("{:?} seems to be truncated, it is missing its end-of-file marker", args.input);
+            ExitCode::FAILURE
+        }
+        outcome => {
+            eprintln!("Failed to render the overlay ({})", outcome.label());
+            ExitCode::FAILURE
+        }
+    }
+}
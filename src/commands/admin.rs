@@ -0,0 +1,268 @@
+use teloxide::prelude::*;
+use teloxide::types::InputFile;
+use log::{info, warn};
+
+use crate::commands::overlay::parse_styles;
+use crate::utils::banned_users::BannedUsers;
+use crate::utils::live_config::LiveConfig;
+use crate::utils::messages::Messages;
+use crate::utils::overlay_assets::OverlayAssets;
+use crate::utils::pause::PauseSwitch;
+use crate::utils::rate_limiter::RateLimiter;
+
+/// The largest width or height `/preview` accepts, well beyond any photo Telegram will send.
+const MAX_PREVIEW_DIMENSION: u32 = 20_000;
+
+/// The most requests `/setlimit` allows per window.
+const MAX_LIMIT_REQUESTS: u32 = 1000;
+
+/// The longest window, in seconds, `/setlimit` accepts: one day.
+const MAX_LIMIT_WINDOW_SECS: u64 = 24 * 60 * 60;
+
+/// Pauses or resumes overlay processing for every chat.
+///
+/// This function is called when the `/pause` or `/resume` command is received by the bot. Only users listed in
+/// `admin_user_ids` may use it; commands from anyone else are ignored. While paused, new overlay requests are turned
+/// away and images already in the queue are held until processing is resumed.
+///
+/// # Arguments
+/// * `bot` - The Teloxide bot instance.
+/// * `msg` - The message that triggered the command.
+/// * `paused` - `true` for `/pause`, `false` for `/resume`.
+/// * `pause_switch` - The switch shared with the message handler and the queue processor.
+/// * `admin_user_ids` - The users allowed to pause and resume the bot.
+/// * `messages` - The messages in the admin's language.
+///
+/// # Returns
+/// A `ResponseResult` indicating the success or failure of the operation.
+pub async fn set_paused(bot: Bot, msg: Message, paused: bool, pause_switch: &PauseSwitch, admin_user_ids: &[UserId], messages: &Messages) -> ResponseResult<()> {
+    let user_id = msg.from().map(|user| user.id).unwrap_or(UserId(0));
+    if !admin_user_ids.contains(&user_id) {
+        warn!("Ignoring {} from non-admin user {}", if paused { "/pause" } else { "/resume" }, user_id);
+        return Ok(());
+    }
+
+    let changed = pause_switch.set_paused(paused);
+    if changed {
+        info!("Processing {} by admin {}", if paused { "paused" } else { "resumed" }, user_id);
+    }
+    bot.send_message(msg.chat.id, messages.pause_changed(paused, changed)).await?;
+    Ok(())
+}
+
+/// Bans or unbans a user, so the bot ignores everything they send.
+///
+/// This function is called when the `/ban <user_id>` or `/unban <user_id>` command is received by the bot. Instead of
+/// an ID, the command can be sent in reply to one of the user's messages. Only users listed in `admin_user_ids` may use
+/// it; commands from anyone else are ignored. Admins can't be banned, so nobody can lock themselves out.
+///
+/// # Arguments
+/// * `bot` - The Teloxide bot instance.
+/// * `msg` - The message that triggered the command.
+/// * `args` - The text after the command: the ID of the user to ban or unban, if not replying to them.
+/// * `banned` - `true` for `/ban`, `false` for `/unban`.
+/// * `banned_users` - The banned users, shared with the message handler.
+/// * `admin_user_ids` - The users allowed to ban and unban users.
+/// * `messages` - The messages in the admin's language.
+///
+/// # Returns
+/// A `ResponseResult` indicating the success or failure of the operation.
+pub async fn set_banned(bot: Bot, msg: Message, args: &str, banned: bool, banned_users: &BannedUsers, admin_user_ids: &[UserId], messages: &Messages) -> ResponseResult<()> {
+    let command = if banned { "ban" } else { "unban" };
+    let user_id = msg.from().map(|user| user.id).unwrap_or(UserId(0));
+    if !admin_user_ids.contains(&user_id) {
+        warn!("Ignoring /{} from non-admin user {}", command, user_id);
+        return Ok(());
+    }
+
+    let target = match args.trim() {
+        "" => msg.reply_to_message().and_then(|reply| reply.from()).map(|user| user.id),
+        args => args.parse().ok().map(UserId),
+    };
+    let Some(target) = target else {
+        bot.send_message(msg.chat.id, messages.ban_usage(command)).await?;
+        return Ok(());
+    };
+    if banned && admin_user_ids.contains(&target) {
+        bot.send_message(msg.chat.id, messages.cannot_ban_admin()).await?;
+        return Ok(());
+    }
+
+    let changed = banned_users.set_banned(target, banned).await;
+    if changed {
+        info!("User {} {} by admin {}", target, if banned { "banned" } else { "unbanned" }, user_id);
+    }
+    bot.send_message(msg.chat.id, messages.ban_changed(target, banned, changed)).await?;
+    Ok(())
+}
+
+/// Changes the overlay rate limit while the bot is running.
+///
+/// This function is called when the `/setlimit <max> <window_secs>` command is received by the bot, e.g.
+/// `/setlimit 5 60` for 5 overlays per minute. It changes the limit shared by `/degenme`, `/again`, `/random` and
+/// Discord, taking effect from the next request; see `RateLimiter::set_limit`. The change is only kept in memory, so
+/// the built-in limit is back after a restart. Only users listed in `admin_user_ids` may use it; commands from anyone
+/// else are ignored.
+///
+/// # Arguments
+/// * `bot` - The Teloxide bot instance.
+/// * `msg` - The message that triggered the command.
+/// * `args` - The text after the command: the number of requests and the window in seconds.
+/// * `rate_limiter` - The overlay rate limiter.
+/// * `admin_user_ids` - The users allowed to change the limit.
+/// * `messages` - The messages in the admin's language.
+///
+/// # Returns
+/// A `ResponseResult` indicating the success or failure of the operation.
+pub async fn set_limit(bot: Bot, msg: Message, args: &str, rate_limiter: &RateLimiter, admin_user_ids: &[UserId], messages: &Messages) -> ResponseResult<()> {
+    let user_id = msg.from().map(|user| user.id).unwrap_or(UserId(0));
+    if !admin_user_ids.contains(&user_id) {
+        warn!("Ignoring /setlimit from non-admin user {}", user_id);
+        return Ok(());
+    }
+
+    let Some((max_requests, window_secs)) = parse_limit(args) else {
+        let (current_max, current_window) = rate_limiter.limit();
+        bot.send_message(msg.chat.id, messages.setlimit_usage(MAX_LIMIT_REQUESTS, MAX_LIMIT_WINDOW_SECS, current_max, current_window.as_secs())).await?;
+        return Ok(());
+    };
+
+    rate_limiter.set_limit(max_requests, std::time::Duration::from_secs(window_secs));
+    info!("Rate limit set to {} per {} seconds by admin {}", max_requests, window_secs, user_id);
+    bot.send_message(msg.chat.id, messages.limit_set(max_requests, window_secs)).await?;
+    Ok(())
+}
+
+/// Reloads the config file without restarting the bot.
+///
+/// This function is called when the `/reload` command is received by the bot, and does the same as sending the process
+/// `SIGHUP`. Only the settings in `LiveSettings` and the overlays and messages in `LiveAssets` are reloaded, and only if
+/// the whole file and every file it names is valid; otherwise the current ones are kept and the admin is told why. Only users listed in `admin_user_ids` may use it; commands from anyone
+/// else are ignored.
+///
+/// # Arguments
+/// * `bot` - The Teloxide bot instance.
+/// * `msg` - The message that triggered the command.
+/// * `live_config` - The reloadable settings shared with the message handler.
+/// * `admin_user_ids` - The users allowed to reload the config.
+/// * `messages` - The messages in the admin's language.
+///
+/// # Returns
+/// A `ResponseResult` indicating the success or failure of the operation.
+pub async fn reload(bot: Bot, msg: Message, live_config: &LiveConfig, admin_user_ids: &[UserId], messages: &Messages) -> ResponseResult<()> {
+    let user_id = msg.from().map(|user| user.id).unwrap_or(UserId(0));
+    if !admin_user_ids.contains(&user_id) {
+        warn!("Ignoring /reload from non-admin user {}", user_id);
+        return Ok(());
+    }
+
+    info!("Config reload requested by admin {}", user_id);
+    let reply = match live_config.reload() {
+        Ok(_) => messages.config_reloaded().to_string(),
+        Err(e) => messages.reload_failed(&e.to_string()),
+    };
+    bot.send_message(msg.chat.id, reply).await?;
+    Ok(())
+}
+
+/// Tells an admin which overlays would be used for an image of a given size, without uploading one.
+///
+/// This function is called when the `/preview <width>x<height> [style]` command is received by the bot. It replies with
+/// the aspect ratio bucket the size falls into and the overlay files a random overlay is picked from, so designers can
+/// check which asset a shape of image gets. If a style is named, the overlay file used for that style is sent as well,
+/// as a document so its transparency is kept. Only users listed in `admin_user_ids` may use it; commands from anyone
+/// else are ignored.
+///
+/// # Arguments
+/// * `bot` - The Teloxide bot instance.
+/// * `msg` - The message that triggered the command.
+/// * `args` - The text after the command: the size and an optional style.
+/// * `overlay_assets` - The overlays the bot picks from.
+/// * `admin_user_ids` - The users allowed to preview overlays.
+/// * `messages` - The messages in the admin's language.
+///
+/// # Returns
+/// A `ResponseResult` indicating the success or failure of the operation.
+pub async fn preview(bot: Bot, msg: Message, args: &str, overlay_assets: &OverlayAssets, admin_user_ids: &[UserId], messages: &Messages) -> ResponseResult<()> {
+    let user_id = msg.from().map(|user| user.id).unwrap_or(UserId(0));
+    if !admin_user_ids.contains(&user_id) {
+        warn!("Ignoring /preview from non-admin user {}", user_id);
+        return Ok(());
+    }
+
+    let chat_id = msg.chat.id;
+    let mut args = args.split_whitespace();
+    let Some((width, height)) = args.next().and_then(parse_dimensions) else {
+        bot.send_message(chat_id, messages.preview_usage(MAX_PREVIEW_DIMENSION)).await?;
+        return Ok(());
+    };
+    let style = parse_styles(&args.collect::<Vec<_>>().join(" ")).into_iter().next();
+
+    let aspect_ratio = height as f32 / width as f32;
+    let asset = overlay_assets.asset_for(aspect_ratio);
+    let overlays: Vec<String> = overlay_assets.overlays(asset).iter()
+        .map(|path| path.display().to_string())
+        .collect();
+    bot.send_message(chat_id, messages.preview_asset(width, height, aspect_ratio, asset, &overlays)).await?;
+
+    if let Some(style) = style {
+        match overlay_assets.pick_style(&style, asset) {
+            Some(overlay_path) => {
+                let caption = overlay_path.display().to_string();
+                bot.send_document(chat_id, InputFile::file(overlay_path)).caption(caption).await?;
+            }
+            None => {
+                bot.send_message(chat_id, messages.unknown_style(&style, &overlay_assets.styles())).await?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Parses the arguments of `/setlimit`, such as `5 60`, into a number of requests and a window in seconds.
+///
+/// # Arguments
+/// * `args` - The text after the command.
+///
+/// # Returns
+/// The number of requests and the window, or `None` if there aren't exactly two numbers, or either is `0` or above
+/// `MAX_LIMIT_REQUESTS` or `MAX_LIMIT_WINDOW_SECS`.
+fn parse_limit(args: &str) -> Option<(u32, u64)> {
+    let mut args = args.split_whitespace();
+    let max_requests = args.next()?.parse::<u32>().ok().filter(|max| (1..=MAX_LIMIT_REQUESTS).contains(max))?;
+    let window_secs = args.next()?.parse::<u64>().ok().filter(|window| (1..=MAX_LIMIT_WINDOW_SECS).contains(window))?;
+    args.next().is_none().then_some((max_requests, window_secs))
+}
+
+/// Parses a size such as `1080x1920` into a width and height.
+///
+/// # Arguments
+/// * `text` - The size, with the width and height separated by `x`.
+///
+/// # Returns
+/// The width and height, or `None` if the text isn't a size or either side is `0` or above `MAX_PREVIEW_DIMENSION`.
+fn parse_dimensions(text: &str) -> Option<(u32, u32)> {
+    let (width, height) = text.split_once(['x', 'X', '×'])?;
+    let width: u32 = width.parse().ok()?;
+    let height: u32 = height.parse().ok()?;
+    let valid = 1..=MAX_PREVIEW_DIMENSION;
+    (valid.contains(&width) && valid.contains(&height)).then_some((width, height))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_limit_takes_a_count_and_a_window() {
+        assert_eq!(parse_limit("5 60"), Some((5, 60)));
+        assert_eq!(parse_limit("  1000   86400 "), Some((MAX_LIMIT_REQUESTS, MAX_LIMIT_WINDOW_SECS)));
+    }
+
+    #[test]
+    fn parse_limit_rejects_invalid_arguments() {
+        for args in ["", "5", "5 60 7", "0 60", "5 0", "1001 60", "5 86401", "-5 60", "five 60", "5 1.5"] {
+            assert_eq!(parse_limit(args), None, "accepted {:?}", args);
+        }
+    }
+}
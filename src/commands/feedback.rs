@@ -0,0 +1,67 @@
+use std::sync::Arc;
+use teloxide::prelude::*;
+use log::{info, warn};
+
+use crate::utils::messages::Messages;
+use crate::utils::rate_limiter::RateLimiter;
+use crate::utils::telegram::largest_photo;
+
+/// Forwards a user's feedback to the admin chat.
+///
+/// This function is called when the `/feedback <text>` command is received by the bot. It sends the feedback text, along
+/// with who sent it and where, to the admin chat. If the command is a reply to a message with a photo, such as one of the
+/// bot's results, that photo's file ID is included and the message itself is forwarded too. Feedback is rate limited
+/// separately from overlay requests so it can't be used to spam the admin chat.
+///
+/// # Arguments
+/// * `bot` - The Teloxide bot instance.
+/// * `msg` - The message that triggered the command.
+/// * `text` - The feedback text, i.e. the command's arguments.
+/// * `admin_chat_id` - The chat feedback is forwarded to, or `None` if feedback is not configured.
+/// * `rate_limiter` - The rate limiter used for feedback.
+/// * `messages` - The messages in the user's language. The report sent to the admin chat is always in English.
+///
+/// # Returns
+/// A `ResponseResult` indicating the success or failure of the operation.
+pub async fn feedback(bot: Bot, msg: Message, text: &str, admin_chat_id: Option<ChatId>, rate_limiter: Arc<RateLimiter>, messages: &Messages) -> ResponseResult<()> {
+    let chat_id = msg.chat.id;
+
+    let Some(admin_chat_id) = admin_chat_id else {
+        warn!("Received feedback but no admin chat is configured");
+        bot.send_message(chat_id, messages.feedback_disabled()).await?;
+        return Ok(());
+    };
+
+    let text = text.trim();
+    if text.is_empty() {
+        bot.send_message(chat_id, messages.feedback_missing()).await?;
+        return Ok(());
+    }
+
+    let user_id = msg.from().map(|user| user.id).unwrap_or(UserId(0));
+    if !rate_limiter.check_rate_limit(&user_id.to_string()).await {
+        bot.send_message(chat_id, messages.feedback_rate_limited()).await?;
+        return Ok(());
+    }
+
+    let username = msg.from()
+        .and_then(|user| user.username.as_ref())
+        .map(|username| format!("@{}", username))
+        .unwrap_or_else(|| "Anonymous".to_string());
+    let replied_photo = msg.reply_to_message()
+        .and_then(|reply| largest_photo(reply).map(|photo| (reply.id, photo.file.id.clone())));
+
+    let mut report = format!("Feedback from {} (User ID: {}, Chat ID: {}):\n{}", username, user_id, chat_id, text);
+    if let Some((_, file_id)) = &replied_photo {
+        report.push_str(&format!("\n\nImage file ID: {}", file_id));
+    }
+
+    info!("Forwarding feedback from User ID: {} in Chat ID: {}", user_id, chat_id);
+    bot.send_message(admin_chat_id, report).await?;
+    if let Some((reply_id, _)) = replied_photo {
+        bot.forward_message(admin_chat_id, chat_id, reply_id).await?;
+    }
+
+    bot.send_message(chat_id, messages.feedback_sent()).await?;
+    Ok(())
+}
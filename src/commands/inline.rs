@@ -0,0 +1,72 @@
+use std::path::Path;
+use std::sync::Arc;
+use teloxide::prelude::*;
+use teloxide::types::{InlineQueryResult, InlineQueryResultPhoto};
+use opencv::{core, imgcodecs};
+use opencv::prelude::*;
+use log::{info, error};
+use url::Url;
+
+use crate::utils::image_utils::overlay_image;
+use crate::utils::messages::Localization;
+use crate::utils::overlay_assets::OverlayAssets;
+
+/// The path the sample overlay is served from by the web server.
+pub const SAMPLE_ROUTE: &str = "/inline/sample.jpg";
+
+/// The size of the plain canvas used as the sample base image when no sample image is configured.
+const SAMPLE_CANVAS_SIZE: i32 = 1080;
+
+/// Renders the sample overlay returned for inline queries.
+///
+/// The sample is produced by the same `overlay_image` pipeline as regular requests, applied to the configured sample
+/// image or, if there is none, to a plain grey canvas. It is encoded as a JPEG, since Telegram only accepts JPEG
+/// photos for inline results.
+///
+/// # Arguments
+/// * `overlay_assets` - The cached overlay images to choose from.
+/// * `sample_image` - An optional path to the base image for the sample.
+///
+/// # Returns
+/// The JPEG-encoded sample overlay, or an error if rendering fails.
+pub fn render_sample(overlay_assets: &OverlayAssets, sample_image: Option<&Path>) -> Result<Vec<u8>, opencv::Error> {
+    let base = match sample_image.and_then(|path| path.to_str()) {
+        Some(path) => imgcodecs::imread(path, imgcodecs::IMREAD_COLOR)?,
+        None => Mat::new_rows_cols_with_default(SAMPLE_CANVAS_SIZE, SAMPLE_CANVAS_SIZE, core::CV_8UC3, core::Scalar::all(128.0))?,
+    };
+    if base.empty() {
+        return Err(opencv::Error::new(core::StsObjectNotFound, "Could not read sample image"));
+    }
+
+    let asset = overlay_assets.asset_for(base.rows() as f32 / base.cols() as f32);
+    let overlay = overlay_assets.decoded(overlay_assets.pick(asset))?;
+    let result = overlay_image(&base, &overlay, None, overlay_assets.opacity(), overlay_assets.bottom_padding())?;
+
+    let mut buffer = core::Vector::new();
+    imgcodecs::imencode(".jpg", &result, &mut buffer, &core::Vector::new())?;
+    Ok(buffer.to_vec())
+}
+
+/// Answers an inline query (`@DegenBot ...`) with the pre-rendered sample overlay.
+///
+/// This is a proof of concept for inline mode, which must also be enabled for the bot in BotFather.
+///
+/// # Arguments
+/// * `bot` - The Teloxide bot instance.
+/// * `query` - The inline query to answer.
+/// * `sample_url` - The public URL the sample overlay is served from.
+/// * `localization` - The messages in every language, picked by the language of the user who sent the query.
+///
+/// # Returns
+/// A `ResponseResult` indicating the success or failure of the operation.
+pub async fn handle_inline_query(bot: Bot, query: InlineQuery, sample_url: Arc<Url>, localization: &Localization) -> ResponseResult<()> {
+    info!("Answering inline query from User ID: {}", query.from.id);
+    let caption = localization.get(query.from.language_code.as_deref()).inline_caption();
+    let sample = InlineQueryResultPhoto::new("degen-sample", (*sample_url).clone(), (*sample_url).clone())
+        .caption(caption);
+
+    if let Err(e) = bot.answer_inline_query(query.id, vec![InlineQueryResult::Photo(sample)]).await {
+        error!("Failed to answer inline query: {}", e);
+    }
+    Ok(())
+}
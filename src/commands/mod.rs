@@ -6,24 +6,101 @@
 use teloxide::types::{Message, MessageId, ChatId, UserId};
 use tokio::sync::Mutex;
 use log::info;
-use tokio::time::Instant;
 use std::pin::Pin;
 use std::future::Future;
 
+pub mod admin;
+pub mod feedback;
+pub mod inline;
+pub mod my_images;
 pub mod overlay;
+pub mod queue;
+pub mod random;
 pub mod start;
 
 use crate::utils::rate_limiter::RateLimiter;
+use crate::utils::daily_quota::DailyQuota;
+use crate::utils::live_config::LiveConfig;
 
 /// A type alias for a Future that represents a command response.
 /// The Future must be pinned, boxed, and implement Send to be used
 /// as a command response.
 pub type CommandResponse<'a> = std::pin::Pin<Box<dyn std::future::Future<Output = ()> + Send + 'a>>;
-/// A shared state for tracking pending overlay operations.
-/// This type is an `Arc<Mutex<HashMap<(ChatId, UserId), (MessageId, Instant)>>>`,
-/// which allows multiple parts of the application to safely access and modify
-/// the pending overlay state concurrently.
-pub type PendingOverlays = Arc<Mutex<HashMap<(ChatId, UserId), (MessageId, Instant)>>>;
+pub use overlay::{PendingOverlay, PendingOverlays};
+
+/// The command that asks for an overlay unless `telegram.overlay_commands` says otherwise.
+pub const DEFAULT_OVERLAY_ALIAS: &str = "degenme";
+
+/// The names of the bot's other commands, which can't be used as overlay aliases.
+pub const BUILT_IN_COMMANDS: &[&str] = &["start", "feedback", "queue", "myimages", "pause", "resume", "ban", "unban", "setlimit", "reload", "preview", "again", "random"];
+
+/// A bot command parsed from the text of a message.
+///
+/// For `/degenme@DegenBot some args`, `name` is `"degenme"`, `mention` is `Some("DegenBot")`, and `args` is
+/// `"some args"`.
+pub struct ParsedCommand<'a> {
+    pub name: &'a str,
+    pub mention: Option<&'a str>,
+    pub args: &'a str,
+}
+
+impl ParsedCommand<'_> {
+    /// Checks whether the command is addressed to this bot.
+    ///
+    /// Commands without an `@username` suffix are addressed to every bot in the chat. Commands with a suffix are only
+    /// addressed to this bot if it matches `bot_username`, ignoring case. If the bot's username isn't known, every
+    /// command is assumed to be addressed to it.
+    ///
+    /// # Arguments
+    /// - `bot_username`: The username of this bot, without the leading `@`.
+    ///
+    /// # Returns
+    /// `true` if the command should be handled by this bot.
+    pub fn is_for(&self, bot_username: Option<&str>) -> bool {
+        match (self.mention, bot_username) {
+            (Some(mention), Some(bot_username)) => mention.eq_ignore_ascii_case(bot_username.trim_start_matches('@')),
+            _ => true,
+        }
+    }
+
+    /// Checks whether the command asks for an overlay, i.e. is one of the configured overlay aliases.
+    ///
+    /// # Arguments
+    /// - `aliases`: The commands that ask for an overlay, such as `degenme`, `degen` or `pov`, from
+    ///   `telegram.overlay_commands`.
+    ///
+    /// # Returns
+    /// `true` if the command's name is one of `aliases`.
+    pub fn is_overlay(&self, aliases: &[String]) -> bool {
+        aliases.iter().any(|alias| alias == self.name)
+    }
+}
+
+/// Parses a bot command from the text of a message.
+///
+/// The first whitespace-delimited token must start with `/`. It is split into the command name and an optional
+/// `@username` suffix, and the rest of the text, trimmed, becomes the command's arguments.
+///
+/// # Arguments
+/// - `text`: The text of the message.
+///
+/// # Returns
+/// The parsed command, or `None` if the text isn't a command.
+pub fn parse_command(text: &str) -> Option<ParsedCommand<'_>> {
+    let text = text.trim_start();
+    let (token, args) = text.split_once(char::is_whitespace).unwrap_or((text, ""));
+    let token = token.strip_prefix('/')?;
+    let (name, mention) = match token.split_once('@') {
+        Some((name, mention)) => (name, Some(mention)),
+        None => (token, None),
+    };
+
+    if name.is_empty() {
+        return None;
+    }
+
+    Some(ParsedCommand { name, mention, args: args.trim() })
+}
 
 #[derive(Clone)]
 /// The `CommandHandler` struct is responsible for registering and executing
@@ -33,13 +110,15 @@
 ///
 /// The `CommandHandler` struct holds the necessary dependencies for managing
 /// the bot's commands, such as the bot instance, shared state for pending
-/// overlays and message IDs, and a rate limiter.
+/// overlays and message IDs, a rate limiter, and a daily quota.
 pub struct CommandHandler {
-    commands: Arc<HashMap<String, Arc<dyn Fn(Bot, Message, Arc<Mutex<HashMap<(ChatId, UserId), (MessageId, Instant)>>>, Arc<Mutex<HashMap<(ChatId, UserId), MessageId>>>, Arc<RateLimiter>) -> Pin<Box<dyn Future<Output = ()> + Send + 'static>> + Send + Sync>>>,
+    commands: Arc<HashMap<String, Arc<dyn Fn(Bot, Message, PendingOverlays, Arc<Mutex<HashMap<(ChatId, UserId), MessageId>>>, Arc<RateLimiter>, Arc<DailyQuota>) -> Pin<Box<dyn Future<Output = ()> + Send + 'static>> + Send + Sync>>>,
     bot: Bot,
     pending_overlays: PendingOverlays,
     message_ids: Arc<Mutex<HashMap<(ChatId, UserId), MessageId>>>,
     rate_limiter: Arc<RateLimiter>,
+    daily_quota: Arc<DailyQuota>,
+    live_config: Arc<LiveConfig>,
 }
 
 /// The `CommandHandler` struct is responsible for registering and executing
@@ -49,19 +128,21 @@ pub struct CommandHandler {
 ///
 /// The `CommandHandler` struct holds the necessary dependencies for managing
 /// the bot's commands, such as the bot instance, shared state for pending
-/// overlays and message IDs, and a rate limiter.
+/// overlays and message IDs, a rate limiter, and a daily quota.
 impl CommandHandler {
     /// Constructs a new `CommandHandler` instance with the provided dependencies.
     ///
     /// The `CommandHandler` is responsible for managing the bot's commands, including
     /// registering new commands and executing them. It holds references to the bot
-    /// instance, shared state for pending overlays and message IDs, and a rate limiter.
+    /// instance, shared state for pending overlays and message IDs, a rate limiter, and a daily quota.
     ///
     /// # Arguments
     /// - `bot`: The `Bot` instance for the Telegram bot.
     /// - `pending_overlays`: A shared state for tracking pending overlay operations.
     /// - `message_ids`: A shared state for tracking message IDs.
     /// - `rate_limiter`: A rate limiter for limiting the number of requests per minute.
+    /// - `daily_quota`: A quota for limiting the number of requests per user per day.
+    /// - `live_config`: The reloadable settings, and the messages sent to users in every language.
     ///
     /// # Returns
     /// A new `CommandHandler` instance with the provided dependencies.
@@ -69,7 +150,9 @@ pub fn new(
         bot: Bot,
         pending_overlays: PendingOverlays,
         message_ids: Arc<Mutex<HashMap<(ChatId, UserId), MessageId>>>,
-        rate_limiter: Arc<RateLimiter>
+        rate_limiter: Arc<RateLimiter>,
+        daily_quota: Arc<DailyQuota>,
+        live_config: Arc<LiveConfig>
     ) -> Self {
         CommandHandler {
             commands: Arc::new(HashMap::new()),
@@ -77,18 +160,31 @@ pub fn new(
             pending_overlays,
             message_ids,
             rate_limiter,
+            daily_quota,
+            live_config,
         }
     }
 
     /// Registers the "degenme" and "start" commands with the `CommandHandler`.
     ///
-    /// The "degenme" command is registered with the `overlay::handle` function as its handler.
+    /// The "degenme" command is registered with an anonymous function that calls the `overlay::handle` function.
     /// The "start" command is registered with an anonymous function that calls the `start::start` function.
     fn register_commands(&mut self) {
-        self.register_command("degenme", Arc::new(overlay::handle));
-        self.register_command("start", Arc::new(|bot, msg, _pending_overlays, _message_ids, _rate_limiter| -> CommandResponse {
+        let live_config = Arc::clone(&self.live_config);
+        self.register_command("degenme", Arc::new(move |bot, msg, pending_overlays, _message_ids, rate_limiter, daily_quota| -> CommandResponse {
+            let context = overlay::RequestContext {
+                pending_overlays,
+                rate_limiter,
+                daily_quota,
+                live_config: Arc::clone(&live_config),
+            };
+            overlay::handle::<Bot>(bot, msg, Arc::new(context))
+        }));
+        let live_config = Arc::clone(&self.live_config);
+        self.register_command("start", Arc::new(move |bot, msg, _pending_overlays, _message_ids, _rate_limiter, _daily_quota| -> CommandResponse {
+            let assets = live_config.assets();
             Box::pin(async move {
-                if let Err(e) = start::start(bot, msg).await {
+                if let Err(e) = start::start(bot, msg.clone(), assets.localization.for_message(&msg), None).await {
                     log::error!("Error in start command: {:?}", e);
                 }
             })
@@ -102,18 +198,18 @@ fn register_commands(&mut self) {
     /// closure that will be executed when the command is invoked.
     ///
     /// The closure must have the following signature:
-    /// `Fn(Bot, Message, Arc<Mutex<HashMap<(ChatId, UserId), (MessageId, Instant)>>>, Arc<Mutex<HashMap<(ChatId, UserId), MessageId>>>, Arc<RateLimiter>) -> Pin<Box<dyn Future<Output = ()> + Send + 'static>>`
+    /// `Fn(Bot, Message, PendingOverlays, Arc<Mutex<HashMap<(ChatId, UserId), MessageId>>>, Arc<RateLimiter>, Arc<DailyQuota>) -> Pin<Box<dyn Future<Output = ()> + Send + 'static>>`
     ///
     /// This allows the command handler to pass the necessary dependencies to the command
     /// implementation, such as the bot instance, shared state for pending overlays and
-    /// message IDs, and a rate limiter.
+    /// message IDs, a rate limiter, and a daily quota.
     ///
     /// # Arguments
     /// - `name`: The name of the command to register.
     /// - `command`: The command implementation as a closure.
     fn register_command<F>(&mut self, name: &str, command: Arc<F>)
     where
-        F: Fn(Bot, Message, Arc<Mutex<HashMap<(ChatId, UserId), (MessageId, Instant)>>>, Arc<Mutex<HashMap<(ChatId, UserId), MessageId>>>, Arc<RateLimiter>) -> Pin<Box<dyn Future<Output = ()> + Send + 'static>> + Send + Sync + 'static,
+        F: Fn(Bot, Message, PendingOverlays, Arc<Mutex<HashMap<(ChatId, UserId), MessageId>>>, Arc<RateLimiter>, Arc<DailyQuota>) -> Pin<Box<dyn Future<Output = ()> + Send + 'static>> + Send + Sync + 'static,
     {
         Arc::get_mut(&mut self.commands).unwrap().insert(name.to_string(), command);
     }
@@ -134,38 +230,34 @@ fn register_command<F>(&mut self, name: &str, command: Arc<F>)
 /// # Arguments
 /// - `bot`: The bot instance to use for processing the message.
 /// - `msg`: The incoming message to be handled.
-/// - `pending_overlays`: A shared state for tracking pending overlays.
 /// - `message_ids`: A shared state for tracking message IDs.
+/// - `daily_quota`: A quota for limiting the number of requests per user per day.
+/// - `context`: The pending overlays, and the overlays, caches, settings and everything else photos are processed with.
 ///
 /// # Returns
 /// A `ResponseResult` indicating the success or failure of the message handling.
-pub async fn handle_message(bot: Bot, msg: Message, pending_overlays: PendingOverlays, message_ids: Arc<Mutex<HashMap<(ChatId, UserId), MessageId>>>) -> ResponseResult<()> {
+pub async fn handle_message(bot: Bot, msg: Message, message_ids: Arc<Mutex<HashMap<(ChatId, UserId), MessageId>>>, daily_quota: Arc<DailyQuota>, context: Arc<overlay::ProcessorContext>) -> ResponseResult<()> {
     info!("Entering handle_message function");
     let rate_limiter = Arc::new(RateLimiter::new(5, std::time::Duration::from_secs(60))); // 5 requests per minute
-    let handler = CommandHandler::new(bot.clone(), pending_overlays.clone(), message_ids.clone(), rate_limiter.clone());
+    let handler = CommandHandler::new(bot.clone(), context.pending_overlays.clone(), message_ids.clone(), rate_limiter.clone(), daily_quota.clone(), Arc::clone(&context.live_config));
     info!("CommandHandler created");
 
     if let Some(text) = msg.text() {
         info!("Received text message: {}", text);
-        let parts: Vec<&str> = text.splitn(2, ' ').collect();
-        if let Some(command_with_username) = parts.first() {
-            if command_with_username.starts_with('/') {
-                let command_parts: Vec<&str> = command_with_username.splitn(2, '@').collect();
-                let command_name = &command_parts[0][1..];
-                info!("Processing command: {}", command_name);
-                if let Some(command_handler) = handler.commands.get(command_name) {
-                    info!("Executing command handler for: {}", command_name);
-                    command_handler(bot.clone(), msg.clone(), handler.pending_overlays.clone(), handler.message_ids.clone(), handler.rate_limiter.clone()).await;
-                } else {
-                    // Do nothing,
-                    // Will cause it to respond to
-                    // Commands for other bots otherwise.
-                }
+        if let Some(command) = parse_command(text) {
+            info!("Processing command: {}", command.name);
+            if let Some(command_handler) = handler.commands.get(command.name) {
+                info!("Executing command handler for: {}", command.name);
+                command_handler(bot.clone(), msg.clone(), handler.pending_overlays.clone(), handler.message_ids.clone(), handler.rate_limiter.clone(), handler.daily_quota.clone()).await;
+            } else {
+                // Do nothing,
+                // Will cause it to respond to
+                // Commands for other bots otherwise.
             }
         }
     } else if msg.photo().is_some() {
         info!("Received photo message");
-        overlay::process_image(bot.clone(), msg, handler.pending_overlays.clone()).await?;
+        overlay::process_image(bot.clone(), msg, context).await?;
     } else {
         info!("Received message without text or photo");
     }
@@ -173,3 +265,81 @@ pub async fn handle_message(bot: Bot, msg: Message, pending_overlays: PendingOve
     info!("Exiting handle_message function");
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_a_bare_command() {
+        let command = parse_command("/degenme").unwrap();
+        assert_eq!((command.name, command.mention, command.args), ("degenme", None, ""));
+    }
+
+    #[test]
+    fn strips_the_bot_mention() {
+        let command = parse_command("/degenme@Bot").unwrap();
+        assert_eq!((command.name, command.mention, command.args), ("degenme", Some("Bot"), ""));
+        assert!(command.is_for(Some("bot")));
+        assert!(command.is_for(Some("@Bot")));
+        assert!(!command.is_for(Some("OtherBot")));
+    }
+
+    #[test]
+    fn splits_off_the_arguments() {
+        let command = parse_command("/degenme arg").unwrap();
+        assert_eq!((command.name, command.mention, command.args), ("degenme", None, "arg"));
+
+        let command = parse_command("  /degenme@Bot  hat, hands  ").unwrap();
+        assert_eq!((command.name, command.mention, command.args), ("degenme", Some("Bot"), "hat, hands"));
+    }
+
+    #[test]
+    fn ignores_text_that_isnt_a_command() {
+        assert!(parse_command("degenme").is_none());
+        assert!(parse_command("/").is_none());
+        assert!(parse_command("/@Bot").is_none());
+        assert!(parse_command("").is_none());
+    }
+
+    /// Returns whether `text` is the `/start` command addressed to a bot called `DegenBot`, as `message_handler` checks.
+    fn is_start_for_us(text: &str) -> bool {
+        parse_command(text).is_some_and(|command| command.name == "start" && command.is_for(Some("DegenBot")))
+    }
+
+    #[test]
+    fn matches_the_exact_command_name_for_this_bot() {
+        assert!(is_start_for_us("/start"));
+        assert!(is_start_for_us("/start hello"));
+        assert!(is_start_for_us("/start@DegenBot"));
+        assert!(!is_start_for_us("/startfoo"));
+        assert!(!is_start_for_us("/start@OtherBot"));
+        assert_eq!(parse_command("/start hello").unwrap().args, "hello");
+    }
+
+    fn is_overlay(text: &str, aliases: &[&str]) -> bool {
+        let aliases: Vec<String> = aliases.iter().map(|alias| alias.to_string()).collect();
+        parse_command(text).is_some_and(|command| command.is_overlay(&aliases))
+    }
+
+    #[test]
+    fn matches_every_configured_overlay_alias() {
+        let aliases = ["degen", "pov"];
+        assert!(is_overlay("/degen", &aliases));
+        assert!(is_overlay("/pov@DegenBot hat", &aliases));
+        assert_eq!(parse_command("/pov@DegenBot hat").unwrap().args, "hat");
+        // Leaving degenme out of the list turns it off
+        assert!(!is_overlay("/degenme", &aliases));
+        assert!(!is_overlay("/degenerate", &aliases));
+        assert!(!is_overlay("/start", &aliases));
+    }
+
+    #[test]
+    fn matches_only_degenme_with_the_default_aliases() {
+        let aliases = [DEFAULT_OVERLAY_ALIAS];
+        assert!(is_overlay("/degenme", &aliases));
+        assert!(is_overlay("/degenme@DegenBot hat", &aliases));
+        assert!(!is_overlay("/degen", &aliases));
+        assert!(!is_overlay("/pov", &aliases));
+    }
+}
@@ -0,0 +1,87 @@
+use std::sync::Arc;
+use teloxide::prelude::*;
+use teloxide::types::{InputFile, InputMedia, InputMediaPhoto};
+use log::{error, info};
+
+use crate::utils::archive::{user_key_prefix, OverlayArchive};
+use crate::utils::messages::Messages;
+use crate::utils::rate_limiter::RateLimiter;
+
+/// Re-sends the calling user's most recent results in this chat from the archive.
+///
+/// This function is called when the `/myimages` command is received by the bot. It lists the user's archived results
+/// for the chat, newest first, fetches up to `limit` of them and sends them back as a single album, so results lost in
+/// a busy chat can be found again. Only results from the chat the command is sent in are returned, so results from a
+/// private chat never show up in a group. If archiving is disabled, the user is told history isn't available.
+///
+/// The command downloads from the archive, so it is rate limited per user.
+///
+/// # Arguments
+/// * `bot` - The Teloxide bot instance.
+/// * `msg` - The message that triggered the command.
+/// * `archive` - The archive results are uploaded to, or `None` if archiving is disabled.
+/// * `limit` - The largest number of results to send.
+/// * `file_name` - The file name results are sent with, matching the configured output format.
+/// * `rate_limiter` - The rate limiter used for `/myimages`.
+/// * `messages` - The messages in the user's language.
+///
+/// # Returns
+/// A `ResponseResult` indicating the success or failure of the operation.
+pub async fn my_images(bot: Bot, msg: Message, archive: Option<Arc<dyn OverlayArchive>>, limit: usize, file_name: &str, rate_limiter: &RateLimiter, messages: &Messages) -> ResponseResult<()> {
+    let chat_id = msg.chat.id;
+    let Some(archive) = archive else {
+        bot.send_message(chat_id, messages.history_unavailable()).await?;
+        return Ok(());
+    };
+    let Some(user) = msg.from() else {
+        bot.send_message(chat_id, messages.history_empty()).await?;
+        return Ok(());
+    };
+
+    let key = user.id.to_string();
+    if !rate_limiter.check_rate_limit(&key).await {
+        let wait = rate_limiter.time_until_allowed(&key).await;
+        bot.send_message(chat_id, messages.rate_limited(wait)).await?;
+        return Ok(());
+    }
+
+    let mut keys = match archive.list(&user_key_prefix(chat_id, Some(user.id))).await {
+        Ok(keys) => keys,
+        Err(e) => {
+            error!("Failed to list archived results for user {}: {}", user.id, e);
+            bot.send_message(chat_id, messages.history_failed()).await?;
+            return Ok(());
+        }
+    };
+    // Keys end in the unix millis the result was archived at, plus the index of album results, which sort correctly as text
+    keys.sort_unstable_by(|a, b| b.cmp(a));
+    keys.truncate(limit);
+
+    let mut photos = Vec::with_capacity(keys.len());
+    for key in &keys {
+        match archive.fetch(key).await {
+            Ok(bytes) => photos.push(InputFile::memory(bytes).file_name(file_name.to_string())),
+            Err(e) => error!("Failed to fetch archived result {}: {}", key, e),
+        }
+    }
+
+    info!("Sending {} archived results to user {} in chat {}", photos.len(), user.id, chat_id);
+    // An album needs at least two items, so a single result is sent as a plain photo
+    match photos.len() {
+        0 if keys.is_empty() => {
+            bot.send_message(chat_id, messages.history_empty()).await?;
+        }
+        0 => {
+            bot.send_message(chat_id, messages.history_failed()).await?;
+        }
+        1 => {
+            let photo = photos.pop().expect("there is exactly one photo");
+            bot.send_photo(chat_id, photo).await?;
+        }
+        _ => {
+            let album: Vec<InputMedia> = photos.into_iter().map(|photo| InputMedia::Photo(InputMediaPhoto::new(photo))).collect();
+            bot.send_media_group(chat_id, album).await?;
+        }
+    }
+    Ok(())
+}
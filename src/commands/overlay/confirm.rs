@@ -0,0 +1,122 @@
+use teloxide::prelude::*;
+use teloxide::types::{InlineKeyboardButton, InlineKeyboardMarkup, UserId};
+use std::collections::HashMap;
+use std::sync::Arc;
+use tokio::sync::Mutex;
+use tokio::time::{Duration, Instant};
+use log::{info, error};
+
+use crate::utils::messages::{Localization, Messages};
+use crate::utils::queue::{Queue, QueueItem};
+use crate::utils::telegram::topic_thread_id;
+
+/// The prefix of the callback data used by the confirmation buttons.
+const CALLBACK_PREFIX: &str = "confirm";
+
+/// A type alias for a thread-safe, shared map of large images waiting for the user to confirm processing.
+///
+/// The key is the callback data prefix identifying the request, `"confirm:<chat id>:<message id>"`, and the value is
+/// the photo message together with the time the confirmation was requested.
+pub type PendingConfirmations = Arc<Mutex<HashMap<String, (Message, Instant)>>>;
+
+/// Asks the user to confirm processing of a large image with "Yes" and "No" buttons.
+///
+/// The photo message is stored in `pending_confirmations` until the user taps a button or the request expires.
+///
+/// # Arguments
+/// * `bot` - The Telegram bot instance.
+/// * `msg` - The message containing the large image.
+/// * `pending_confirmations` - The shared map of images waiting for confirmation.
+/// * `expiration` - How long a confirmation is kept, the same as an overlay request, used to drop stale ones.
+/// * `messages` - The messages in the user's language.
+///
+/// # Returns
+/// A `ResponseResult<()>` indicating the success or failure of the operation.
+pub async fn request_confirmation(bot: Bot, msg: Message, pending_confirmations: PendingConfirmations, expiration: Duration, messages: &Messages) -> ResponseResult<()> {
+    let key = format!("{}:{}:{}", CALLBACK_PREFIX, msg.chat.id, msg.id);
+    let keyboard = InlineKeyboardMarkup::new(vec![vec![
+        InlineKeyboardButton::callback(messages.confirm_yes(), format!("{}:yes", key)),
+        InlineKeyboardButton::callback(messages.confirm_no(), format!("{}:no", key)),
+    ]]);
+
+    info!("Asking for confirmation before processing large image. Chat ID: {}, Message ID: {}", msg.chat.id, msg.id);
+    let mut prompt = bot.send_message(msg.chat.id, messages.confirm_prompt())
+        .reply_to_message_id(msg.id)
+        .reply_markup(keyboard);
+    if let Some(thread_id) = topic_thread_id(&msg) {
+        prompt = prompt.message_thread_id(thread_id);
+    }
+    prompt.await?;
+
+    let mut confirmations = pending_confirmations.lock().await;
+    confirmations.retain(|_, (_, requested_at)| requested_at.elapsed() <= expiration);
+    confirmations.insert(key, (msg, Instant::now()));
+    Ok(())
+}
+
+/// Handles a tap on one of the confirmation buttons.
+///
+/// "Yes" enqueues the stored photo for processing, "No" drops it. Only the user who sent the photo can answer. If the
+/// confirmation has expired or was already answered, the user is told so.
+///
+/// # Arguments
+/// * `bot` - The Telegram bot instance.
+/// * `query` - The callback query sent when the button was tapped.
+/// * `pending_confirmations` - The shared map of images waiting for confirmation.
+/// * `message_queue` - The queue the photo is enqueued in for processing.
+/// * `expiration` - How long a confirmation can be answered, the same as an overlay request.
+/// * `localization` - The messages in every language, picked by the language of the user who tapped the button.
+///
+/// # Returns
+/// A `ResponseResult<()>` indicating the success or failure of the operation.
+pub async fn handle_callback(bot: Bot, query: CallbackQuery, pending_confirmations: PendingConfirmations, message_queue: Arc<Queue<Message>>, expiration: Duration, localization: &Localization) -> ResponseResult<()> {
+    let Some((key, answer)) = query.data.as_deref().and_then(|data| data.rsplit_once(':')) else {
+        return Ok(());
+    };
+    if !key.starts_with(CALLBACK_PREFIX) {
+        return Ok(());
+    }
+
+    let messages = localization.get(query.from.language_code.as_deref());
+    let mut confirmations = pending_confirmations.lock().await;
+    let is_owner = confirmations.get(key)
+        .map(|(msg, _)| msg.from().map(|user| user.id) == Some(query.from.id));
+
+    let reply = match is_owner {
+        None => messages.confirm_expired(),
+        Some(false) => messages.confirm_not_yours(),
+        Some(true) => {
+            let (msg, requested_at) = confirmations.remove(key).expect("confirmation was just found");
+            if requested_at.elapsed() > expiration {
+                info!("Confirmation for large image arrived after expiration");
+                messages.confirm_expired()
+            } else if answer == "yes" {
+                info!("Large image confirmed, enqueueing. Chat ID: {}, Message ID: {}", msg.chat.id, msg.id);
+                let item = QueueItem {
+                    _chat_id: msg.chat.id,
+                    _user_id: msg.from().map(|user| user.id).unwrap_or(UserId(0)),
+                    data: msg,
+                };
+                message_queue.enqueue(item).await;
+                messages.confirm_accepted()
+            } else {
+                info!("Large image declined. Chat ID: {}, Message ID: {}", msg.chat.id, msg.id);
+                messages.confirm_declined()
+            }
+        }
+    };
+    drop(confirmations);
+
+    if let Err(e) = bot.answer_callback_query(query.id).text(reply).await {
+        error!("Failed to answer callback query: {}", e);
+    }
+    // Leave the buttons in place if someone other than the sender tapped them
+    if is_owner != Some(false) {
+        if let Some(message) = query.message {
+            if let Err(e) = bot.edit_message_text(message.chat.id, message.id, reply).await {
+                error!("Failed to update confirmation message: {}", e);
+            }
+        }
+    }
+    Ok(())
+}
@@ -2,62 +2,113 @@
 use teloxide::types::{ChatId, MessageId, UserId};
 use std::collections::HashMap;
 use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
 use tokio::sync::Mutex;
 use tokio::time::Instant;
-use log::{info, error};
-use crate::commands::CommandResponse;
-use crate::utils::rate_limiter::RateLimiter;
-use super::PendingOverlays;
+use tracing::{info, error, warn, info_span, Instrument, Span};
+use crate::commands::{parse_command, CommandResponse};
+use crate::utils::live_config::LiveConfig;
+use crate::utils::rate_limiter::{RateLimitStatus, RateLimiter};
+use crate::utils::daily_quota::DailyQuota;
+use crate::utils::messages::Messages;
+use crate::utils::sender::MessageSender;
+use crate::utils::telegram::topic_thread_id;
+use crate::utils::url_download::find_image_url;
+use super::{PendingOverlay, PendingOverlays};
+
+/// The most overlay styles that can be stacked in one request, since each one is composited separately.
+pub const MAX_STACKED_OVERLAYS: usize = 3;
+
+/// The counter overlay request correlation ids are taken from.
+static NEXT_REQUEST_ID: AtomicU64 = AtomicU64::new(1);
+
+/// Returns a new correlation id for an overlay request.
+///
+/// The id is attached as `request_id` to the tracing span of every step of the request, so its log lines can be told
+/// apart from those of other requests being processed at the same time. Ids are only unique until the bot restarts.
+pub fn next_request_id() -> u64 {
+    NEXT_REQUEST_ID.fetch_add(1, Ordering::Relaxed)
+}
+
+/// Everything a `/degenme` request shares with the rest of the bot, built once at startup and handed to every request
+/// as an `Arc`, like `ProcessorContext` is for the image sent in reply.
+///
+/// - `pending_overlays`: The pending `/degenme` requests, which the new request is added to.
+/// - `rate_limiter`: The rate limiter shared by every overlay request.
+/// - `daily_quota`: The cap on how many overlays a user can request per day.
+/// - `live_config`: The reloadable settings, holding how long the user has to reply and when they are reminded, and
+///   the messages sent to users, in every language.
+pub struct RequestContext {
+    pub pending_overlays: PendingOverlays,
+    pub rate_limiter: Arc<RateLimiter>,
+    pub daily_quota: Arc<DailyQuota>,
+    pub live_config: Arc<LiveConfig>,
+}
+
+/// A reminder to reply to a `/degenme` prompt, sent by `schedule_reminder` if the prompt is still waiting for an image.
+///
+/// - `chat_id`: The chat the prompt was sent in.
+/// - `thread_id`: The forum topic the prompt was sent in, if any.
+/// - `user_id`: The user the prompt is for.
+/// - `prompt_id`: The ID of the prompt message.
+/// - `text`: The reminder text, in the user's language.
+struct Reminder {
+    chat_id: ChatId,
+    thread_id: Option<i32>,
+    user_id: UserId,
+    prompt_id: MessageId,
+    text: String,
+}
 
 /// A struct that handles the command processing for the overlay feature.
 ///
 /// This struct contains the necessary dependencies to handle the overlay command, including the bot instance,
-/// the pending overlays, the message IDs, and the rate limiter.
+/// the pending overlays, the message IDs, the rate limiter, and the daily quota.
 pub struct CommandHandler {
     bot: Bot,
     pending_overlays: PendingOverlays,
     message_ids: Arc<Mutex<HashMap<(ChatId, UserId), MessageId>>>,
     rate_limiter: Arc<RateLimiter>,
+    daily_quota: Arc<DailyQuota>,
 }
 
 /// Handles the command processing for the overlay feature.
 ///
 /// This implementation provides the necessary functionality to handle the overlay command, including:
 /// - Checking the rate limit for the user and chat
+/// - Checking the daily quota for the user
 /// - Sending a reply message with instructions for the user
 /// - Managing the pending overlays for each user and chat
 ///
 /// The `handle` method is the main entry point for processing the overlay command.
 impl CommandHandler {
-    pub fn new(bot: Bot, pending_overlays: PendingOverlays, message_ids: Arc<Mutex<HashMap<(ChatId, UserId), MessageId>>>, rate_limiter: Arc<RateLimiter>) -> Self {
+    pub fn new(bot: Bot, pending_overlays: PendingOverlays, message_ids: Arc<Mutex<HashMap<(ChatId, UserId), MessageId>>>, rate_limiter: Arc<RateLimiter>, daily_quota: Arc<DailyQuota>) -> Self {
         CommandHandler {
             bot,
             pending_overlays,
             message_ids,
             rate_limiter,
+            daily_quota,
         }
     }
 
     /// Handles the "overlay" command, which allows users to request an image overlay.
     ///
     /// This function is responsible for processing the "overlay" command, which allows users to request an image overlay. It checks the rate limit, manages the pending overlay requests, and sends a reply message to the user with instructions on how to submit an image for the overlay.
+    /// A new correlation id is logged as `request_id` and stored with the pending request, so the processing of the
+    /// image sent in reply logs the same id. If the live settings have a `reply_reminder_after`, the user is reminded once
+    /// it has passed and the request is still waiting for an image, see `schedule_reminder`.
     ///
     /// # Arguments
-    /// * `bot` - The Telegram bot instance.
+    /// * `bot` - The Telegram bot instance, or any other `MessageSender` such as `MockSender` in tests.
     /// * `msg` - The incoming message that triggered the "overlay" command.
-    /// * `pending_overlays` - A shared mutex-protected map of pending overlay requests.
-    /// * `message_ids` - A shared mutex-protected map of message IDs for pending overlay requests.
-    /// * `rate_limiter` - A rate limiter to prevent users from sending commands too quickly.
+    /// * `context` - The pending overlays, limits, messages and settings shared by every request.
     ///
     /// # Returns
     /// A `CommandResponse` that represents the result of handling the "overlay" command.
-    pub fn handle<'a>(
-        bot: Bot,
-        msg: Message,
-        pending_overlays: PendingOverlays,
-        _message_ids: Arc<Mutex<HashMap<(ChatId, UserId), MessageId>>>,
-        rate_limiter: Arc<RateLimiter>
-    ) -> CommandResponse<'a> {
+    pub fn handle<'a, S: MessageSender>(bot: S, msg: Message, context: Arc<RequestContext>) -> CommandResponse<'a> {
+        let request_id = next_request_id();
         Box::pin(async move {
             info!("Entering overlay handle function");
             let user_id = msg.from().map(|user| user.id);
@@ -71,38 +122,45 @@ pub fn handle<'a>(
 
             info!("Username: {}", username);
 
-            // Check rate limit
-            if !rate_limiter.check_rate_limit(&format!("{}:{}", chat_id, user_id.unwrap_or(UserId(0)))).await {
-                if let Err(e) = bot.send_message(chat_id, "You're sending commands too quickly. Please wait a moment before trying again.").await {
-                    error!("Failed to send rate limit message: {}", e);
-                }
+            let assets = context.live_config.assets();
+            let messages = assets.localization.for_message(&msg);
+            if !check_limits(&bot, &msg, &context.rate_limiter, &context.daily_quota, messages).await {
                 return;
             }
 
-            let mut overlays = pending_overlays.lock().await;
-            let reply_text = if let Some(user_id) = user_id {
-                if overlays.contains_key(&(chat_id, user_id)) {
-                    format!("Previous request cancelled. Hey, {}! Please reply within 3 minutes to this message with an image to see the Degen Point of View!", username)
-                } else {
-                    format!("Hey, {}! Please reply within 3 minutes to this message with an image to see the Degen Point of View!", username)
-                }
-            } else {
-                format!("Hey, {}! Please reply within 3 minutes to this message with an image to see the Degen Point of View!", username)
-            };
+            let styles = msg.text()
+                .and_then(parse_command)
+                .map(|command| parse_styles(command.args))
+                .unwrap_or_default();
+
+            let settings = context.live_config.get();
+            let mut overlays = context.pending_overlays.lock().await;
+            let replaced = user_id.is_some_and(|user_id| overlays.contains_key(&(chat_id, user_id)));
+            let reply_text = messages.prompt(&username, replaced, settings.overlay_expiration);
 
             info!("Sending reply: {}", reply_text);
 
-            let reply = bot.send_message(msg.chat.id, reply_text).await;
+            let reply = bot.send_message(msg.chat.id, topic_thread_id(&msg), reply_text).await;
             match reply {
-                Ok(sent) => {
-                    info!("Reply sent successfully. Message ID: {}", sent.id);
+                Ok(sent_id) => {
+                    info!("Reply sent successfully. Message ID: {}", sent_id);
                     if let Some(user_id) = user_id {
                         // Remove any existing pending overlay for this user
                         overlays.remove(&(chat_id, user_id));
                         // Insert new pending overlay with current timestamp
-                        overlays.insert((chat_id, user_id), (sent.id, Instant::now()));
-                        info!("Inserted pending overlay request. Chat ID: {}, User ID: {}, Message ID: {}", chat_id, user_id, sent.id);
+                        overlays.insert((chat_id, user_id), PendingOverlay { message_id: sent_id, thread_id: topic_thread_id(&msg), created: Instant::now(), styles, request_id });
+                        info!("Inserted pending overlay request. Chat ID: {}, User ID: {}, Message ID: {}", chat_id, user_id, sent_id);
                         info!("Current pending overlays: {:?}", overlays);
+                        if let Some(remind_after) = settings.reply_reminder_after {
+                            let reminder = Reminder {
+                                chat_id,
+                                thread_id: topic_thread_id(&msg),
+                                user_id,
+                                prompt_id: sent_id,
+                                text: messages.reply_reminder(&username, settings.overlay_expiration.saturating_sub(remind_after)),
+                            };
+                            schedule_reminder(bot.clone(), Arc::clone(&context.pending_overlays), reminder, remind_after);
+                        }
                     } else {
                         error!("Failed to get user ID for pending overlay request");
                     }
@@ -112,7 +170,7 @@ pub fn handle<'a>(
                 }
             }
             info!("Exiting overlay handle function");
-        })
+        }.instrument(info_span!("degenme", request_id)))
     }
 }
 
@@ -121,20 +179,220 @@ pub fn handle<'a>(
 /// This function is responsible for processing the "overlay" command, which allows users to request an image overlay. It checks the rate limit, manages the pending overlay requests, and sends a reply message to the user with instructions on how to submit an image for the overlay.
 ///
 /// # Arguments
-/// * `bot` - The Telegram bot instance.
+/// * `bot` - The Telegram bot instance, or any other `MessageSender` such as `MockSender` in tests.
 /// * `msg` - The incoming message that triggered the "overlay" command.
-/// * `pending_overlays` - A shared mutex-protected map of pending overlay requests.
-/// * `message_ids` - A shared mutex-protected map of message IDs for pending overlay requests.
-/// * `rate_limiter` - A rate limiter to prevent users from sending commands too quickly.
+/// * `context` - The pending overlays, limits, messages and settings shared by every request.
 ///
 /// # Returns
 /// A `CommandResponse` that represents the result of handling the "overlay" command.
-pub fn handle<'a>(
-    bot: Bot,
-    msg: Message,
-    pending_overlays: PendingOverlays,
-    message_ids: Arc<Mutex<HashMap<(ChatId, UserId), MessageId>>>,
-    rate_limiter: Arc<RateLimiter>
-) -> CommandResponse<'a> {
-    CommandHandler::handle(bot, msg, pending_overlays, message_ids, rate_limiter)
+pub fn handle<'a, S: MessageSender>(bot: S, msg: Message, context: Arc<RequestContext>) -> CommandResponse<'a> {
+    CommandHandler::handle(bot, msg, context)
+}
+
+/// Reminds a user to reply to their `/degenme` prompt once `remind_after` has passed.
+///
+/// Nothing needs to cancel the reminder: when it is due, it checks the pending overlays and is only sent if the user's
+/// pending request is still the one for this prompt. Replying with an image claims the request, a newer `/degenme`
+/// replaces it and the cleanup task removes it once it expires, so in each of those cases the reminder is dropped.
+///
+/// # Arguments
+/// * `bot` - The Telegram bot instance, or any other `MessageSender`.
+/// * `pending_overlays` - The pending overlay requests, checked when the reminder is due.
+/// * `reminder` - The prompt to remind the user of, and what to tell them.
+/// * `remind_after` - How long after the prompt the reminder is sent.
+fn schedule_reminder<S: MessageSender>(bot: S, pending_overlays: PendingOverlays, reminder: Reminder, remind_after: Duration) {
+    tokio::spawn(async move {
+        tokio::time::sleep(remind_after).await;
+        let Reminder { chat_id, thread_id, user_id, prompt_id, text } = reminder;
+        let still_pending = pending_overlays.lock().await
+            .get(&(chat_id, user_id))
+            .is_some_and(|pending| pending.message_id == prompt_id);
+        if !still_pending {
+            return;
+        }
+        info!("Reminding user {} in chat {} to reply to prompt {}", user_id, chat_id, prompt_id);
+        if let Err(e) = bot.send_message(chat_id, thread_id, text).await {
+            warn!("Failed to send reply reminder: {}", e);
+        }
+    }.instrument(Span::current()));
+}
+
+/// Parses the overlay styles requested in the arguments of a `/degenme` command, e.g. `hands,hat`.
+///
+/// Styles are separated by commas or whitespace and lowercased. Image links are skipped, so `/degenme hat <url>`
+/// requests the `hat` style. The styles are not checked against the available overlays here.
+///
+/// # Arguments
+/// * `args` - The arguments of the `/degenme` command.
+///
+/// # Returns
+/// The requested styles in the order they should be applied, or an empty list if none were requested.
+pub fn parse_styles(args: &str) -> Vec<String> {
+    args.split_whitespace()
+        .filter(|word| find_image_url(word).is_none())
+        .flat_map(|word| word.split(','))
+        .map(|style| style.trim().to_lowercase())
+        .filter(|style| !style.is_empty())
+        .collect()
+}
+
+/// Checks the rate limit and daily quota for the sender of an overlay request.
+///
+/// If either limit has been hit, the user is told so. A request that is allowed but leaves the user close to the rate
+/// limit still goes ahead, with a message telling them to slow down.
+///
+/// # Arguments
+/// * `bot` - The Telegram bot instance, or any other `MessageSender`.
+/// * `msg` - The message containing the overlay request.
+/// * `rate_limiter` - A rate limiter to prevent users from sending commands too quickly.
+/// * `daily_quota` - A quota to cap how many overlays a user can request per day.
+/// * `messages` - The messages in the user's language.
+///
+/// # Returns
+/// `true` if the request is within both limits, `false` otherwise.
+pub async fn check_limits<S: MessageSender>(bot: &S, msg: &Message, rate_limiter: &RateLimiter, daily_quota: &DailyQuota, messages: &Messages) -> bool {
+    let chat_id = msg.chat.id;
+    let user_id = msg.from().map(|user| user.id).unwrap_or(UserId(0));
+
+    // Check rate limit
+    let key = format!("{}:{}", chat_id, user_id);
+    let status = rate_limiter.check(&key).await;
+    if status == RateLimitStatus::Rejected {
+        let wait = rate_limiter.time_until_allowed(&key).await;
+        if let Err(e) = bot.send_message(chat_id, topic_thread_id(msg), messages.rate_limited(wait)).await {
+            error!("Failed to send rate limit message: {}", e);
+        }
+        return false;
+    }
+
+    // Check daily quota
+    if !daily_quota.check_quota(user_id).await {
+        if let Err(e) = bot.send_message(chat_id, topic_thread_id(msg), messages.daily_limit().to_string()).await {
+            error!("Failed to send daily limit message: {}", e);
+        }
+        return false;
+    }
+
+    if status == RateLimitStatus::Warn {
+        if let Err(e) = bot.send_message(chat_id, topic_thread_id(msg), messages.rate_limit_warning().to_string()).await {
+            error!("Failed to send rate limit warning: {}", e);
+        }
+    }
+
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use crate::utils::chat_overlays::ChatOverlays;
+    use crate::utils::live_config::{LiveAssets, LiveSettings};
+    use crate::utils::messages::Localization;
+    use crate::utils::overlay_assets::{OverlayAssets, OVERLAY_DIR};
+    use std::path::Path;
+    use crate::utils::sender::{mock_message, MockSender, SentCall};
+
+    /// Builds the context `handle` shares with other requests, with messages in the default language and the default
+    /// settings, which don't send reminders.
+    fn request_context(pending_overlays: &PendingOverlays, rate_limiter: Arc<RateLimiter>, daily_quota: Arc<DailyQuota>) -> Arc<RequestContext> {
+        let assets = LiveAssets::new(ChatOverlays::new(Arc::new(OverlayAssets::load(Path::new(OVERLAY_DIR)))), Localization::default());
+        let live_config = LiveConfig::new(LiveSettings::from_config(&Config::default()), assets, Arc::clone(&daily_quota), Arc::clone(&rate_limiter));
+        Arc::new(RequestContext {
+            pending_overlays: Arc::clone(pending_overlays),
+            rate_limiter,
+            daily_quota,
+            live_config: Arc::new(live_config),
+        })
+    }
+
+    /// The overlay expiration in the default settings.
+    fn default_expiration() -> Duration {
+        LiveSettings::from_config(&Config::default()).overlay_expiration
+    }
+
+    /// Sends one overlay request through `check_limits` and returns whether it was allowed and what the user was told.
+    async fn request(bot: &MockSender, rate_limiter: &RateLimiter, daily_quota: &DailyQuota) -> (bool, Option<String>) {
+        let messages = Messages::default();
+        let before = bot.calls().len();
+        let allowed = check_limits(bot, &mock_message(10, 20, "/degenme"), rate_limiter, daily_quota, &messages).await;
+        let told = bot.calls().into_iter().skip(before).find_map(|call| match call {
+            SentCall::Message { text, .. } => Some(text),
+            _ => None,
+        });
+        (allowed, told)
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn check_limits_warns_on_the_last_two_requests_before_the_limit() {
+        let bot = MockSender::new();
+        let rate_limiter = RateLimiter::new_token_bucket(5.0 / 60.0, 5);
+        let daily_quota = DailyQuota::new(0);
+        let messages = Messages::default();
+
+        for _ in 0..3 {
+            assert_eq!(request(&bot, &rate_limiter, &daily_quota).await, (true, None));
+        }
+        for _ in 0..2 {
+            assert_eq!(request(&bot, &rate_limiter, &daily_quota).await, (true, Some(messages.rate_limit_warning().to_string())));
+        }
+        let (allowed, told) = request(&bot, &rate_limiter, &daily_quota).await;
+        assert!(!allowed);
+        assert_eq!(told, Some(messages.rate_limited(rate_limiter.time_until_allowed("10:20").await)));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn handle_charges_the_rate_limit_once() {
+        let bot = MockSender::new();
+        let rate_limiter = Arc::new(RateLimiter::new_token_bucket(5.0 / 60.0, 5));
+        let daily_quota = Arc::new(DailyQuota::new(0));
+        let pending_overlays = PendingOverlays::default();
+
+        handle(bot.clone(), mock_message(10, 20, "/degenme"), request_context(&pending_overlays, Arc::clone(&rate_limiter), Arc::clone(&daily_quota))).await;
+        assert!(pending_overlays.lock().await.contains_key(&(ChatId(10), UserId(20))));
+
+        // The prompt used one of the 5 requests, so 4 are left
+        for _ in 0..4 {
+            assert!(request(&bot, &rate_limiter, &daily_quota).await.0);
+        }
+        assert!(!request(&bot, &rate_limiter, &daily_quota).await.0);
+    }
+
+    /// Sends `/degenme` with `text` through `handle`, with limits that never get in the way.
+    async fn degenme(bot: &MockSender, pending_overlays: &PendingOverlays, text: &str) {
+        let rate_limiter = Arc::new(RateLimiter::new_token_bucket(1.0, 100));
+        handle(bot.clone(), mock_message(10, 20, text), request_context(pending_overlays, rate_limiter, Arc::new(DailyQuota::new(0)))).await;
+    }
+
+    #[tokio::test]
+    async fn handle_prompts_and_stores_the_pending_request() {
+        let bot = MockSender::new();
+        let pending_overlays = PendingOverlays::default();
+
+        degenme(&bot, &pending_overlays, "/degenme hat,hands").await;
+
+        let prompt = Messages::default().prompt("@degen", false, default_expiration());
+        assert_eq!(bot.calls(), vec![SentCall::Message { chat_id: ChatId(10), thread_id: None, text: prompt }]);
+        let overlays = pending_overlays.lock().await;
+        let pending = &overlays[&(ChatId(10), UserId(20))];
+        assert_eq!(pending.message_id, MessageId(1));
+        assert_eq!(pending.styles, ["hat", "hands"]);
+    }
+
+    #[tokio::test]
+    async fn a_second_degenme_replaces_the_pending_request() {
+        let bot = MockSender::new();
+        let pending_overlays = PendingOverlays::default();
+
+        degenme(&bot, &pending_overlays, "/degenme hat").await;
+        degenme(&bot, &pending_overlays, "/degenme").await;
+
+        let replaced = Messages::default().prompt("@degen", true, default_expiration());
+        assert_eq!(bot.calls().last(), Some(&SentCall::Message { chat_id: ChatId(10), thread_id: None, text: replaced }));
+        let overlays = pending_overlays.lock().await;
+        assert_eq!(overlays.len(), 1);
+        let pending = &overlays[&(ChatId(10), UserId(20))];
+        assert_eq!(pending.message_id, MessageId(2));
+        assert!(pending.styles.is_empty());
+    }
 }
@@ -1,8 +1,10 @@
+mod confirm;
 mod handler;
 mod processor;
 
-pub use handler::handle;
-pub use processor::process_image;
+pub use confirm::{handle_callback, request_confirmation, PendingConfirmations};
+pub use handler::{check_limits, handle, parse_styles, RequestContext, MAX_STACKED_OVERLAYS};
+pub use processor::{process_image, render_overlay, OverlayOutcome, OverlayTiming, ProcessingOptions, ProcessorContext};
 
 use teloxide::types::{ChatId, MessageId, UserId};
 use std::collections::HashMap;
@@ -10,6 +12,23 @@
 use tokio::sync::Mutex;
 use tokio::time::Instant;
 
+/// A `/degenme` request waiting for the user to reply with an image.
+///
+/// - `message_id`: The prompt the image must be sent in reply to.
+/// - `thread_id`: The forum topic the prompt was sent in, so the expiry notice is posted there too.
+/// - `created`: When the prompt was sent, used to expire the request.
+/// - `styles`: The overlay styles requested with `/degenme`, applied in order; empty picks one at random.
+/// - `request_id`: The request's correlation id, so the logs of the `/degenme` command and of the image sent in reply to
+///   it share the same `request_id`.
+#[derive(Clone, Debug)]
+pub struct PendingOverlay {
+    pub message_id: MessageId,
+    pub thread_id: Option<i32>,
+    pub created: Instant,
+    pub styles: Vec<String>,
+    pub request_id: u64,
+}
+
 /// A type alias for a thread-safe, shared map of pending overlays.
 ///
 /// This type represents a collection of pending overlay operations, where each operation
@@ -19,12 +38,10 @@
 /// # Type Parameters
 ///
 /// - The key is a tuple of `(ChatId, UserId)`, identifying a unique chat-user combination.
-/// - The value is a tuple of `(MessageId, Instant)`, where:
-///   - `MessageId` likely refers to the message associated with the overlay.
-///   - `Instant` probably represents the time when the overlay operation was initiated or last updated.
+/// - The value is the user's `PendingOverlay`.
 ///
 /// # Usage
 ///
 /// This type is typically used to track and manage ongoing overlay operations across
 /// different chats and users in a concurrent environment.
-pub type PendingOverlays = Arc<Mutex<HashMap<(ChatId, UserId), (MessageId, Instant)>>>;
+pub type PendingOverlays = Arc<Mutex<HashMap<(ChatId, UserId), PendingOverlay>>>;
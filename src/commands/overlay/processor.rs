@@ -1,39 +1,294 @@
 use teloxide::prelude::*;
-use teloxide::types::InputFile;
+use teloxide::{ApiError, RequestError};
+use teloxide::types::{FileMeta, MessageId, PhotoSize, Sticker};
 use opencv::{core, imgcodecs};
 use opencv::prelude::*;
-use reqwest;
 use std::path::Path;
-use log::{info, error, warn};
-use tokio::time::{sleep, Duration};
+use std::sync::Arc;
+use tracing::{field, info, info_span, error, warn, Instrument, Span};
+use std::time::{Duration, Instant};
+use tokio::sync::oneshot;
 
 use crate::utils::queue::{Queue, QueueItem};
-use crate::utils::image_utils::overlay_image;
+use crate::utils::image_utils::{circle_mask, crop_to_circle, detect_image_format, downscale_to_fit, encode_image, is_truncated_image, resize_to_width, overlay_image_masked, sticker_to_png, CompositeMode};
+use crate::utils::overlay_assets::OverlayAssets;
+use crate::utils::worker_pool::ImageWorkerPool;
+use crate::utils::recent_results::RecentResults;
+use crate::utils::restricted_chats::RestrictedChats;
+use crate::utils::source_cache::SourceCache;
+use crate::utils::result_cache::{CachedResult, ResultCache, ResultKey};
+use crate::utils::download_cache::DownloadCache;
+use crate::utils::media_groups::MediaGroups;
+use crate::utils::archive::{archive_key, spawn_store, OverlayArchive};
+use crate::utils::audit::AuditLogger;
+use crate::utils::metrics::OverlayMetrics;
+use crate::utils::telegram::{is_expired_file_path, is_file_too_big_error, is_permission_error, is_reaction_unavailable_error, largest_photo, topic_thread_id, SUCCESS_REACTION, TELEGRAM_MAX_DOWNLOAD_BYTES};
 use super::PendingOverlays;
-use crate::utils::cleanup::OVERLAY_EXPIRATION;
+use crate::utils::live_config::LiveConfig;
+use crate::config::ProcessingConfig;
+use crate::utils::sender::MessageSender;
+use crate::utils::url_download::{download_image_url, find_image_url, UrlDownloadError};
+use crate::commands::parse_command;
+use super::handler::{next_request_id, parse_styles};
+use url::Url;
+use reqwest::header::CONTENT_TYPE;
 
-/// The maximum number of retries allowed when processing an image overlay request.
-const MAX_RETRIES: usize = 3;
+/// The outcome of an image overlay request.
+///
+/// The overlay logic returns one of these instead of messaging the user directly, and `ImageProcessor::report_outcome`
+/// translates it into a Telegram message. This keeps the outcome of a request observable without a live bot.
+#[derive(Debug)]
+pub enum OverlayOutcome {
+    /// The overlay was applied; holds the result, encoded in the configured output format.
+    Success(Vec<u8>),
+    /// The message wasn't a reply to a pending overlay request, so it was ignored.
+    NotRequested,
+    /// The photo was sent as a reply to one of the bot's results instead of the `/degenme` prompt.
+    RepliedToResult,
+    /// `/again` replied to a result whose source image is no longer cached, or to a message that isn't a result.
+    SourceExpired,
+    /// The overlay request had expired by the time the image arrived.
+    Expired,
+    /// The reply to the overlay request didn't contain a photo or an image link.
+    NoPhoto,
+    /// The reply to the overlay request was an animated or video sticker, which can't be overlaid.
+    UnsupportedSticker,
+    /// The photo is larger than the maximum file size, so it wasn't downloaded; holds the limit in bytes.
+    FileTooLarge(u32),
+    /// The photo is over the 20 MB the Bot API lets bots download, so it can't be fetched at all.
+    TooLargeToFetch,
+    /// The photo couldn't be fetched from Telegram.
+    DownloadFailed,
+    /// Telegram answered the download with something other than an image, such as an error page; holds the content type.
+    NotAnImage(String),
+    /// The image link was rejected or couldn't be downloaded; holds the reason.
+    LinkFailed(UrlDownloadError),
+    /// The photo couldn't be decoded; holds the format detected from its magic numbers, if any.
+    DecodeFailed(Option<&'static str>),
+    /// The photo is cut short: fewer bytes arrived than announced, or a PNG or JPEG is missing its end-of-file marker.
+    Corrupted,
+    /// The image's width or height is below the configured minimum dimension.
+    TooSmall,
+    /// Rendering the overlay took longer than the maximum processing time, so its result was abandoned.
+    TimedOut,
+    /// The overlay, watermark or encoding step failed.
+    OverlayFailed,
+}
+
+impl OverlayOutcome {
+    /// Returns a short, stable name for the outcome, used in the audit log.
+    pub fn label(&self) -> &'static str {
+        match self {
+            OverlayOutcome::Success(_) => "success",
+            OverlayOutcome::NotRequested => "not_requested",
+            OverlayOutcome::RepliedToResult => "replied_to_result",
+            OverlayOutcome::SourceExpired => "source_expired",
+            OverlayOutcome::Expired => "expired",
+            OverlayOutcome::NoPhoto => "no_photo",
+            OverlayOutcome::UnsupportedSticker => "unsupported_sticker",
+            OverlayOutcome::FileTooLarge(_) => "file_too_large",
+            OverlayOutcome::TooLargeToFetch => "too_large_to_fetch",
+            OverlayOutcome::DownloadFailed => "download_failed",
+            OverlayOutcome::NotAnImage(_) => "not_an_image",
+            OverlayOutcome::LinkFailed(_) => "link_failed",
+            OverlayOutcome::DecodeFailed(_) => "decode_failed",
+            OverlayOutcome::Corrupted => "corrupted",
+            OverlayOutcome::TooSmall => "too_small",
+            OverlayOutcome::TimedOut => "timed_out",
+            OverlayOutcome::OverlayFailed => "overlay_failed",
+        }
+    }
+}
+
+/// Where the image for an overlay request comes from.
+enum ImageSource<'m> {
+    /// A photo attached to the message, downloaded from Telegram.
+    Photo(&'m PhotoSize),
+    /// A static sticker sent as the message, downloaded from Telegram and converted to a PNG.
+    Sticker(&'m Sticker),
+    /// An `http` or `https` link in the message text, downloaded directly.
+    Url(Url),
+    /// The cached source image of an earlier result, reused by `/again`.
+    Cached(Arc<Vec<u8>>),
+    /// The photos of an album, with the IDs of the messages they came in, each downloaded from Telegram.
+    Album(Vec<(MessageId, PhotoSize)>),
+}
+
+impl ImageSource<'_> {
+    /// Returns the key the result for this image and these styles is cached under, if it can be cached.
+    ///
+    /// Only photos and stickers have a Telegram file ID that identifies the same image across messages, and a request
+    /// without styles picks a random overlay, so everything else returns `None`.
+    ///
+    /// # Arguments
+    /// * `img_dir` - The directory the chat's overlays come from.
+    /// * `generation` - The `LiveAssets` generation the overlays were loaded in.
+    /// * `styles` - The requested overlay styles, in order.
+    fn cache_key(&self, img_dir: &Path, generation: u64, styles: &[String]) -> Option<ResultKey> {
+        if styles.is_empty() {
+            return None;
+        }
+        match self {
+            ImageSource::Photo(photo) => Some((img_dir.to_path_buf(), generation, photo.file.unique_id.clone(), styles.to_vec())),
+            ImageSource::Sticker(sticker) => Some((img_dir.to_path_buf(), generation, sticker.file.unique_id.clone(), styles.to_vec())),
+            ImageSource::Url(_) | ImageSource::Cached(_) | ImageSource::Album(_) => None,
+        }
+    }
+}
+
+/// How long each phase of an overlay request took, and the size the image decoded to.
+///
+/// The phases are measured around the existing steps of `ImageProcessor::process_image` and logged as a single line
+/// once the result has been sent, to help with performance tuning. The decoded width and height, before any
+/// downscaling, are recorded in `OverlayMetrics` along with the result's size.
+#[derive(Debug)]
+pub struct OverlayTiming {
+    started: Instant,
+    pub download: Duration,
+    pub decode: Duration,
+    pub composite: Duration,
+    pub encode: Duration,
+    pub upload: Duration,
+    pub decoded_width: u32,
+    pub decoded_height: u32,
+}
+
+impl OverlayTiming {
+    /// Starts timing a request from now.
+    pub fn new() -> Self {
+        OverlayTiming {
+            started: Instant::now(),
+            download: Duration::ZERO,
+            decode: Duration::ZERO,
+            composite: Duration::ZERO,
+            encode: Duration::ZERO,
+            upload: Duration::ZERO,
+            decoded_width: 0,
+            decoded_height: 0,
+        }
+    }
+
+    /// Logs the timing breakdown as a single structured line.
+    fn log(&self) {
+        info!(
+            "overlay_timing download={}ms decode={}ms composite={}ms encode={}ms upload={}ms total={}ms",
+            self.download.as_millis(),
+            self.decode.as_millis(),
+            self.composite.as_millis(),
+            self.encode.as_millis(),
+            self.upload.as_millis(),
+            self.started.elapsed().as_millis(),
+        );
+    }
+}
+
+impl Default for OverlayTiming {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// The settings that decide how an overlay request is handled, taken from `[processing]` once at startup.
+///
+/// - `delete_source_photo`: Whether the user's photo is deleted once the result has been sent.
+/// - `reply_to_source`: Whether the result is sent as a reply to the user's message.
+/// - `delete_prompt_on_success`: Whether the `/degenme` prompt is deleted once the result has been produced.
+/// - `react_on_success`: Whether the user's message gets a 🔥 reaction once the result has been sent.
+/// - `max_file_size_bytes`: The largest photo, in bytes, that will be downloaded from Telegram.
+/// - `max_url_download_bytes`: The largest image, in bytes, that will be downloaded from a link.
+/// - `processing_message_timeout`: How long the processing message may be left up before it is deleted as stuck.
+/// - `max_processing_time`: How long an image may take to render before the user is told it took too long.
+#[derive(Clone, Copy, Debug)]
+pub struct ProcessingOptions {
+    pub delete_source_photo: bool,
+    pub reply_to_source: bool,
+    pub delete_prompt_on_success: bool,
+    pub react_on_success: bool,
+    pub max_file_size_bytes: u32,
+    pub max_url_download_bytes: u64,
+    pub processing_message_timeout: Duration,
+    pub max_processing_time: Duration,
+}
+
+impl ProcessingOptions {
+    /// Takes the options from the `[processing]` section of the config.
+    ///
+    /// # Arguments
+    /// * `processing` - The validated processing config.
+    ///
+    /// # Returns
+    /// The options in `processing`.
+    pub fn from_config(processing: &ProcessingConfig) -> Self {
+        ProcessingOptions {
+            delete_source_photo: processing.delete_source_photo,
+            reply_to_source: processing.reply_to_source,
+            delete_prompt_on_success: processing.delete_prompt_on_success,
+            react_on_success: processing.react_on_success,
+            max_file_size_bytes: processing.max_file_size_bytes,
+            max_url_download_bytes: processing.max_url_download_bytes,
+            processing_message_timeout: Duration::from_secs(processing.processing_message_timeout_secs),
+            max_processing_time: Duration::from_secs(processing.max_processing_time_secs),
+        }
+    }
+}
+
+/// Everything an `ImageProcessor` shares with the rest of the bot, built once at startup and handed to every request
+/// as an `Arc`.
+///
+/// - `pending_overlays`: The pending `/degenme` requests, claimed by the image sent in reply.
+/// - `http_client`: The shared HTTP client used to download images.
+/// - `worker_pool`: The worker pool overlays are rendered on.
+/// - `recent_results`: The results recently sent by the bot, used to recognise replies to them.
+/// - `source_cache`: The source images of recent results, kept for `/again`.
+/// - `result_cache`: The recently rendered results, reused when the same photo is sent with the same styles.
+/// - `download_cache`: The recently downloaded Telegram files, reused instead of downloading them again.
+/// - `media_groups`: The photos of albums sent in reply to a prompt, collected until the album is processed.
+/// - `restricted_chats`: The chats where the bot isn't allowed to post.
+/// - `archive`: The archive every result is uploaded to, or `None` if archiving is disabled.
+/// - `audit_logger`: The audit log every result is recorded in.
+/// - `metrics`: The sizes of the images and results rendered.
+/// - `live_config`: The reloadable settings, holding how long a request waits for an image, and the overlays and
+///   messages.
+/// - `overlay_aliases`: The commands that ask for an overlay, from `telegram.overlay_commands`.
+/// - `options`: How requests are handled, from `[processing]`.
+pub struct ProcessorContext {
+    pub pending_overlays: PendingOverlays,
+    pub http_client: reqwest::Client,
+    pub worker_pool: Arc<ImageWorkerPool>,
+    pub recent_results: Arc<RecentResults>,
+    pub source_cache: Arc<SourceCache>,
+    pub result_cache: Arc<ResultCache>,
+    pub download_cache: Arc<DownloadCache>,
+    pub media_groups: Arc<MediaGroups>,
+    pub restricted_chats: Arc<RestrictedChats>,
+    pub archive: Option<Arc<dyn OverlayArchive>>,
+    pub audit_logger: Arc<AuditLogger>,
+    pub metrics: Arc<OverlayMetrics>,
+    pub live_config: Arc<LiveConfig>,
+    pub overlay_aliases: Arc<[String]>,
+    pub options: ProcessingOptions,
+}
 
 /// The ImageProcessor struct is responsible for managing the queue of image overlay requests,
 /// processing them, and interacting with the Telegram bot and the pending overlays.
-/// It has a queue to store the incoming overlay requests, a reference to the Telegram bot,
-/// and a reference to the pending overlays.
-pub struct ImageProcessor {
+/// It is generic over the `MessageSender` used to talk to Telegram, which is `Bot` outside of tests.
+/// It has a queue to store the incoming overlay requests, a reference to the Telegram bot, and the
+/// `ProcessorContext` holding everything else it needs, which is shared by every request.
+pub struct ImageProcessor<S: MessageSender = Bot> {
     queue: Queue<Message>,
-    bot: Bot,
-    pending_overlays: PendingOverlays,
+    bot: S,
+    context: Arc<ProcessorContext>,
 }
 
 /// The `process_image` function is responsible for processing an image overlay request received from a Telegram message.
 /// It creates a new `ImageProcessor` instance, enqueues the message, and then processes the queue.
 /// The function returns a `ResponseResult<()>` indicating the success or failure of the operation.
-impl ImageProcessor {
-    pub fn new(bot: Bot, pending_overlays: PendingOverlays) -> Self {
+impl<S: MessageSender> ImageProcessor<S> {
+    pub fn new(bot: S, context: Arc<ProcessorContext>) -> Self {
         ImageProcessor {
             queue: Queue::new(),
             bot,
-            pending_overlays,
+            context,
         }
     }
 
@@ -54,11 +309,12 @@ pub async fn enqueue(&self, msg: Message) {
     /// Processes the queue of image overlay requests.
     ///
     /// This method continuously dequeues items from the `queue` and processes the associated `Message` objects.
-    /// For each message, it calls the `process_image` method to handle the image overlay request.
+    /// For each message, it calls the `process_image` method to handle the image overlay request inside an `overlay`
+    /// tracing span, which gets the request's `request_id` once the request has been claimed.
     /// If an error occurs during processing, it logs the error and continues to the next item in the queue.
     pub async fn process_queue(&self) {
-        while let Some(item) = self.queue.dequeue().await {
-            self.process_image(item.data).await.unwrap_or_else(|e| {
+        while let Some(item) = self.queue.try_dequeue().await {
+            self.process_image(item.data).instrument(info_span!("overlay", request_id = field::Empty)).await.unwrap_or_else(|e| {
                 error!("Error processing image: {:?}", e);
             });
         }
@@ -66,183 +322,974 @@ pub async fn process_queue(&self) {
 
     /// Processes an image overlay request received from a Telegram message.
     ///
-    /// This function creates a new `ImageProcessor` instance, enqueues the message, and then processes the queue.
-    /// It returns a `ResponseResult<()>` indicating the success or failure of the operation.
+    /// This is the Telegram-facing wrapper around the overlay logic. It claims the user's pending request, sends a
+    /// processing message, downloads and renders the image, and then translates the resulting `OverlayOutcome` into a
+    /// message for the user. Requests in chats where the bot has repeatedly been refused permission to post are ignored.
+    /// Once a result has been produced for a reply to a `/degenme` prompt, the prompt is deleted if
+    /// `delete_prompt_on_success` is set.
     ///
     /// # Arguments
-    /// * `bot` - A reference to the Telegram bot instance.
     /// * `msg` - The Telegram message containing the image overlay request.
-    /// * `pending_overlays` - A reference to the pending overlays.
     ///
     /// # Returns
     /// A `ResponseResult<()>` indicating the success or failure of the operation.
     async fn process_image(&self, msg: Message) -> ResponseResult<()> {
         info!("Entering process_image function");
+
+        let (source, styles, request_id, prompt_msg_id) = match self.claim_request(&msg).await {
+            Ok(request) => request,
+            Err(outcome) => return self.report_outcome(&msg, None, outcome, None, None).await,
+        };
+        Span::current().record("request_id", request_id);
+
+        if self.context.restricted_chats.is_suppressed(msg.chat.id).await {
+            warn!("Ignoring overlay request in chat {}, the bot isn't allowed to post there", msg.chat.id);
+            return Ok(());
+        }
+
+        let username = display_name(&msg);
+        info!("Processing image for user: {}", username);
+        let assets = self.context.live_config.assets();
+        let processing_message = assets.localization.processing(&msg, &username);
+        let processing_msg_id = match self.bot.send_message(msg.chat.id, topic_thread_id(&msg), processing_message).await {
+            Ok(processing_msg_id) => processing_msg_id,
+            Err(e) if is_permission_error(&e) => {
+                self.record_permission_error(&msg, &e).await;
+                return Ok(());
+            }
+            Err(e) => return Err(e),
+        };
+        info!("Sent processing message");
+        let (processing_done, processing_done_receiver) = oneshot::channel();
+        self.guard_processing_message(msg.chat.id, processing_msg_id, processing_done_receiver);
+
+        let overlay_assets = Arc::clone(assets.chat_overlays.for_chat(msg.chat.id));
+        let source = match source {
+            ImageSource::Album(photos) => {
+                let reported = self.process_album(&msg, processing_msg_id, prompt_msg_id, photos, overlay_assets, assets.generation, styles).await;
+                let _ = processing_done.send(());
+                return reported;
+            }
+            source => source,
+        };
+
+        let mut timing = OverlayTiming::new();
+        let cache_key = source.cache_key(overlay_assets.img_dir(), assets.generation, &styles);
+        let rendered = match cache_key {
+            Some(key) => self.context.result_cache.get_or_try_insert_with(key, || self.download_and_render(source, overlay_assets, styles, &mut timing)).await,
+            None => self.download_and_render(source, overlay_assets, styles, &mut timing).await,
+        };
+        let (outcome, image_data) = match rendered {
+            Ok(rendered) => (OverlayOutcome::Success(rendered.result.to_vec()), Some(rendered.source)),
+            Err(outcome) => (outcome, None),
+        };
+        if matches!(outcome, OverlayOutcome::Success(_)) {
+            self.delete_prompt(&msg, prompt_msg_id).await;
+        }
+
+        let reported = self.report_outcome(&msg, Some(processing_msg_id), outcome, Some(timing), image_data).await;
+        // The receiver is gone if the guard already deleted the message, which is fine
+        let _ = processing_done.send(());
+        reported?;
+        info!("Exiting process_image function");
+        Ok(())
+    }
+
+    /// Renders the requested overlays onto every photo of an album and sends the results back as one album.
+    ///
+    /// Each photo goes through the same download, cache and render steps as a single photo. Only the first
+    /// `max_album_size` photos are processed, and the user is told if any were left out. If two or more photos render,
+    /// the results are sent together by `report_album`; if only one does, it is sent like a single result, and if none
+    /// do, the user is told why the first one failed.
+    ///
+    /// # Arguments
+    /// * `msg` - The first message of the album, which claimed the overlay request.
+    /// * `processing_msg_id` - The ID of the processing message.
+    /// * `prompt_msg_id` - The ID of the `/degenme` prompt the album replied to, deleted if any photo renders.
+    /// * `photos` - The album's photos, with the IDs of the messages they came in, in the order they were sent.
+    /// * `overlay_assets` - The overlays for the request's chat.
+    /// * `generation` - The `LiveAssets` generation `overlay_assets` came from, part of every result's cache key.
+    /// * `styles` - The overlay styles to stack on every photo, in order, or an empty list for a random overlay each.
+    ///
+    /// # Returns
+    /// A `ResponseResult<()>` indicating the success or failure of the operation.
+    async fn process_album(&self, msg: &Message, processing_msg_id: MessageId, prompt_msg_id: Option<MessageId>, mut photos: Vec<(MessageId, PhotoSize)>, overlay_assets: Arc<OverlayAssets>, generation: u64, styles: Vec<String>) -> ResponseResult<()> {
+        let max_photos = self.context.media_groups.max_photos();
+        if photos.len() > max_photos {
+            info!("Album has {} photos, only processing the first {}", photos.len(), max_photos);
+            photos.truncate(max_photos);
+            let notice = self.context.live_config.assets().localization.for_message(msg).album_truncated(max_photos);
+            if let Err(e) = self.bot.send_message(msg.chat.id, topic_thread_id(msg), notice).await {
+                warn!("Failed to tell the user their album was cut short: {}", e);
+            }
+        }
+
+        let mut rendered = Vec::with_capacity(photos.len());
+        let mut failure = None;
+        for (message_id, photo) in &photos {
+            let mut timing = OverlayTiming::new();
+            let source = ImageSource::Photo(photo);
+            let cache_key = source.cache_key(overlay_assets.img_dir(), generation, &styles);
+            let result = match cache_key {
+                Some(key) => self.context.result_cache.get_or_try_insert_with(key, || self.download_and_render(source, Arc::clone(&overlay_assets), styles.clone(), &mut timing)).await,
+                None => self.download_and_render(source, Arc::clone(&overlay_assets), styles.clone(), &mut timing).await,
+            };
+            match result {
+                Ok(result) => rendered.push(result),
+                Err(outcome) => {
+                    warn!("Photo {} of the album failed: {}", message_id, outcome.label());
+                    if failure.is_none() {
+                        failure = Some(outcome);
+                    }
+                }
+            }
+        }
+
+        if !rendered.is_empty() {
+            self.delete_prompt(msg, prompt_msg_id).await;
+        }
+        if rendered.len() < 2 {
+            let (outcome, image_data) = match rendered.pop() {
+                Some(rendered) => (OverlayOutcome::Success(rendered.result.to_vec()), Some(rendered.source)),
+                None => (failure.unwrap_or(OverlayOutcome::OverlayFailed), None),
+            };
+            return self.report_outcome(msg, Some(processing_msg_id), outcome, None, image_data).await;
+        }
+        let source_ids: Vec<MessageId> = photos.iter().map(|(message_id, _)| *message_id).collect();
+        self.report_album(msg, processing_msg_id, rendered, &source_ids).await
+    }
+
+    /// Sends the results of an album back to the user as one album.
+    ///
+    /// Like `report_outcome` for a single result, the album is sent as a reply to the user's first photo if
+    /// `reply_to_source` is set, every result is archived if archiving is enabled, recorded as a recent result and has
+    /// its source cached for `/again`, the processing message is deleted, and the user's photos are deleted if
+    /// `delete_source_photo` is set. If the bot isn't allowed to post in the chat, the results are sent to the user
+    /// privately one by one instead, when possible.
+    ///
+    /// # Arguments
+    /// * `msg` - The first message of the album, which claimed the overlay request.
+    /// * `processing_msg_id` - The ID of the processing message.
+    /// * `rendered` - The results that rendered, with their source images, in the order the photos were sent.
+    /// * `source_ids` - The IDs of the messages the album's photos came in.
+    ///
+    /// # Returns
+    /// A `ResponseResult<()>` indicating the success or failure of the operation.
+    async fn report_album(&self, msg: &Message, processing_msg_id: MessageId, rendered: Vec<CachedResult>, source_ids: &[MessageId]) -> ResponseResult<()> {
+        self.context.audit_logger.record(msg, "result", "success");
+        let assets = self.context.live_config.assets();
+        let messages = assets.localization.for_message(msg);
+        let overlays = assets.chat_overlays.for_chat(msg.chat.id);
+        let output_format = overlays.output_format();
+        let photos: Vec<Vec<u8>> = rendered.iter().map(|result| result.result.to_vec()).collect();
+        if let Some(archive) = &self.context.archive {
+            for (index, photo) in photos.iter().enumerate() {
+                let key = archive_key(msg.chat.id, msg.from().map(|user| user.id), output_format, Some(index));
+                spawn_store(Arc::clone(archive), key, photo.clone());
+            }
+        }
+
+        info!("Sending album of {} processed images", photos.len());
+        let caption = messages.result_caption(&display_name(msg));
+        let file_name = overlays.output_file_name(file_name_username(msg));
+        let reply_to = self.context.options.reply_to_source.then_some(msg.id);
+        let sent_album = match self.bot.send_media_group(msg.chat.id, topic_thread_id(msg), reply_to, photos.clone(), &file_name, caption.clone()).await {
+            // The user's photo was deleted while the album was rendered
+            Err(RequestError::Api(ApiError::MessageToReplyNotFound)) if reply_to.is_some() => {
+                warn!("Source message {} is gone, sending the album without replying to it", msg.id);
+                self.bot.send_media_group(msg.chat.id, topic_thread_id(msg), None, photos.clone(), &file_name, caption).await
+            }
+            sent_album => sent_album,
+        };
+
+        if let Err(e) = self.bot.delete_message(msg.chat.id, processing_msg_id).await {
+            error!("Failed to delete processing message: {}", e);
+        }
+        let reply = match sent_album {
+            Ok(sent_ids) => {
+                info!("Album sent successfully with caption");
+                for (sent_id, result) in sent_ids.into_iter().zip(rendered) {
+                    self.context.recent_results.record(msg.chat.id, sent_id).await;
+                    self.context.source_cache.insert(msg.chat.id, sent_id, result.source).await;
+                }
+                self.context.restricted_chats.record_success(msg.chat.id).await;
+
+                // The bot may not be allowed to delete other users' messages, which shouldn't fail the request
+                if self.context.options.delete_source_photo {
+                    for source_id in source_ids {
+                        if let Err(e) = self.bot.delete_message(msg.chat.id, *source_id).await {
+                            warn!("Failed to delete source photo, the bot may lack delete rights: {}", e);
+                        }
+                    }
+                } else {
+                    self.react_to_source(msg).await;
+                }
+                return Ok(());
+            }
+            Err(e) if is_permission_error(&e) => {
+                self.record_permission_error(msg, &e).await;
+                for photo in photos {
+                    self.send_privately(msg, photo).await;
+                }
+                return Ok(());
+            }
+            Err(e) => {
+                error!("Failed to send processed album: {}", e);
+                messages.send_failed().to_string()
+            }
+        };
+
+        match self.bot.send_message(msg.chat.id, topic_thread_id(msg), reply).await {
+            Ok(_) => Ok(()),
+            Err(e) if is_permission_error(&e) => {
+                self.record_permission_error(msg, &e).await;
+                Ok(())
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Downloads the image for a request and renders the requested overlays onto it.
+    ///
+    /// # Arguments
+    /// * `source` - Where the image comes from.
+    /// * `overlay_assets` - The overlays for the request's chat.
+    /// * `styles` - The overlay styles to stack, in order, or an empty list for a random overlay.
+    /// * `timing` - Updated with how long the download and each rendering phase took.
+    ///
+    /// # Returns
+    /// The encoded result together with the source image, or the `OverlayOutcome` describing which step failed.
+    async fn download_and_render(&self, source: ImageSource<'_>, overlay_assets: Arc<OverlayAssets>, styles: Vec<String>, timing: &mut OverlayTiming) -> Result<CachedResult, OverlayOutcome> {
+        let download_started = Instant::now();
+        let downloaded = match source {
+            ImageSource::Photo(photo) => self.download_image(&photo.file).await,
+            ImageSource::Sticker(sticker) => match self.download_image(&sticker.file).await {
+                Ok(sticker_data) => self.convert_sticker(sticker_data.to_vec()).await.map(Arc::new),
+                Err(outcome) => Err(outcome),
+            },
+            ImageSource::Url(url) => download_image_url(&self.context.http_client, url, self.context.options.max_url_download_bytes).await.map(Arc::new).map_err(|e| {
+                error!("Failed to download image from link: {}", e);
+                OverlayOutcome::LinkFailed(e)
+            }),
+            ImageSource::Cached(image_data) => Ok(image_data),
+            // Albums are split into their photos by `process_album` before they get here
+            ImageSource::Album(_) => Err(OverlayOutcome::NoPhoto),
+        };
+        timing.download = download_started.elapsed();
+
+        let source = downloaded?;
+        match self.render_on_pool(overlay_assets, Arc::clone(&source), styles, timing).await {
+            OverlayOutcome::Success(result) => Ok(CachedResult { result: Arc::new(result), source }),
+            outcome => Err(outcome),
+        }
+    }
+
+    /// Checks that a message is a reply to the sender's pending overlay request and claims that request.
+    ///
+    /// The pending request is removed once it is matched, whether or not the message turns out to be usable.
+    /// A `/degenme <url>` command doesn't need a pending request, since the image link comes with the command itself,
+    /// and neither does `/again <style>` in reply to a result, which reuses that result's cached source image.
+    ///
+    /// # Arguments
+    /// * `msg` - The Telegram message containing the image overlay request.
+    ///
+    /// # Returns
+    /// The photo or image link to process together with the requested overlay styles, the request's correlation id and
+    /// the ID of the `/degenme` prompt that was replied to, if any, or the `OverlayOutcome` explaining why there is
+    /// nothing to process. A `/degenme <url>` command gets a new correlation id, while a reply reuses the one from its
+    /// `/degenme` command.
+    async fn claim_request<'m>(&self, msg: &'m Message) -> Result<(ImageSource<'m>, Vec<String>, u64, Option<MessageId>), OverlayOutcome> {
+        let command = msg.text().and_then(parse_command);
+        if let Some(command) = command.as_ref().filter(|command| command.name == "again") {
+            let Some(reply_to) = msg.reply_to_message() else {
+                return Err(OverlayOutcome::SourceExpired);
+            };
+            return match self.context.source_cache.get(msg.chat.id, reply_to.id).await {
+                Some(image_data) => {
+                    info!("Found cached source image for result {}", reply_to.id);
+                    Ok((ImageSource::Cached(image_data), parse_styles(command.args), next_request_id(), None))
+                }
+                None => {
+                    info!("No cached source image for result {}", reply_to.id);
+                    Err(OverlayOutcome::SourceExpired)
+                }
+            };
+        }
+        if let Some(command) = command.filter(|command| command.is_overlay(&self.context.overlay_aliases)) {
+            if let Some(url) = find_image_url(command.args) {
+                info!("Found image link in /degenme command");
+                return Ok((ImageSource::Url(url), parse_styles(command.args), next_request_id(), None));
+            }
+        }
+
         let user_id = msg.from().map(|user| user.id);
-        let mut overlays = self.pending_overlays.lock().await;
+        let mut overlays = self.context.pending_overlays.lock().await;
         info!("Acquired lock on pending_overlays");
 
-        if let (Some(user_id), Some(reply_to)) = (user_id, msg.reply_to_message()) {
-            info!("User ID: {:?}, Reply to message ID: {}", user_id, reply_to.id);
-            if let Some(&(original_msg_id, request_time)) = overlays.get(&(msg.chat.id, user_id)) {
-                info!("Found original message ID in pending_overlays: {}", original_msg_id);
-                info!("Comparing original_msg_id: {} with reply_to.id: {}", original_msg_id, reply_to.id);
-                if original_msg_id == reply_to.id {
-                    if request_time.elapsed() > OVERLAY_EXPIRATION {
-                        info!("Overlay request has expired");
-                        overlays.remove(&(msg.chat.id, user_id));
-                        self.bot.send_message(msg.chat.id, "Your overlay request has expired. Please use the /degenme command again.").await?;
-                        return Ok(());
-                    }
-                    info!("Reply matches the original overlay request");
-                    overlays.remove(&(msg.chat.id, user_id));
-                    info!("Removed overlay request from pending_overlays");
-                    drop(overlays);
-                    info!("Released lock on pending_overlays");
-
-                    if let Some(photo) = msg.photo().and_then(|photos| photos.last()) {
-                        info!("Found photo in message");
-                        let username = msg.from()
-                            .and_then(|user| user.username.as_ref())
-                            .map(|username| format!("@{}", username))
-                            .unwrap_or_else(|| "Anonymous".to_string());
-
-                        info!("Processing image for user: {}", username);
-                        let processing_msg = self.bot.send_message(msg.chat.id, format!("Making {} a degen... Please wait...", username)).await?;
-                        info!("Sent processing message");
-
-                        info!("Fetching file from Telegram");
-                        let file = match self.bot.get_file(&photo.file.id).await {
-                            Ok(file) => file,
-                            Err(e) => {
-                                error!("Failed to get file: {}", e);
-                                self.bot.delete_message(msg.chat.id, processing_msg.id).await?;
-                                self.bot.send_message(msg.chat.id, "Failed to process your image. Please try again.").await?;
-                                return Ok(());
-                            }
-                        };
-
-                        info!("Downloading image");
-                        let url = format!("https://api.telegram.org/file/bot{}/{}", self.bot.token(), file.path);
-                        let response = match reqwest::get(&url).await {
-                            Ok(response) => response,
-                            Err(e) => {
-                                error!("Failed to download image: {}", e);
-                                self.bot.delete_message(msg.chat.id, processing_msg.id).await?;
-                                self.bot.send_message(msg.chat.id, "Failed to download your image. Please try again.").await?;
-                                return Ok(());
-                            }
-                        };
-
-                        info!("Reading image data");
-                        let image_data = match response.bytes().await {
-                            Ok(data) => data,
-                            Err(e) => {
-                                error!("Failed to read image data: {}", e);
-                                self.bot.delete_message(msg.chat.id, processing_msg.id).await?;
-                                self.bot.send_message(msg.chat.id, "Failed to read your image. Please try again.").await?;
-                                return Ok(());
-                            }
-                        };
-
-                        info!("Decoding image");
-                        let img = match imgcodecs::imdecode(&core::Vector::from_slice(&image_data), imgcodecs::IMREAD_COLOR) {
-                            Ok(img) => img,
-                            Err(e) => {
-                                error!("Failed to decode image: {}", e);
-                                self.bot.delete_message(msg.chat.id, processing_msg.id).await?;
-                                self.bot.send_message(msg.chat.id, "Failed to decode your image. Please try again.").await?;
-                                return Ok(());
-                            }
-                        };
+        let (Some(user_id), Some(reply_to)) = (user_id, msg.reply_to_message()) else {
+            info!("Message is not a reply or user ID is missing. User ID: {:?}, Is reply: {}", user_id, msg.reply_to_message().is_some());
+            return Err(OverlayOutcome::NotRequested);
+        };
 
-                        const ASPECT_RATIO_TOLERANCE: f32 = 0.05; // 5% tolerance
+        info!("User ID: {:?}, Reply to message ID: {}", user_id, reply_to.id);
+        if (msg.photo().is_some() || msg.sticker().is_some()) && self.context.recent_results.contains(msg.chat.id, reply_to.id).await {
+            info!("Image is a reply to a previous result, not to an overlay request");
+            return Err(OverlayOutcome::RepliedToResult);
+        }
 
-                        let aspect_ratio = img.rows() as f32 / img.cols() as f32;
-                        let is_portrait = aspect_ratio > (1.0 + ASPECT_RATIO_TOLERANCE);
-                        let overlay_path = if is_portrait {
-                            Path::new("img/hands_portrait.png")
-                        } else {
-                            Path::new("img/hands_landscape.png")
-                        };
-                        info!("Using overlay: {:?}", overlay_path);
-
-                        info!("Reading overlay image");
-                        let overlay = match imgcodecs::imread(overlay_path.to_str().unwrap(), imgcodecs::IMREAD_UNCHANGED) {
-                            Ok(overlay) => overlay,
-                            Err(e) => {
-                                error!("Failed to read overlay image: {}", e);
-                                self.bot.delete_message(msg.chat.id, processing_msg.id).await?;
-                                self.bot.send_message(msg.chat.id, "Failed to process overlay. Please try again later.").await?;
-                                return Ok(());
-                            }
-                        };
-
-                        info!("Starting image overlay process");
-                        let mut retry_count = 0;
-                        let mut previous_result: Option<Mat> = None;
-                        let result = loop {
-                            match overlay_image(&img, &overlay, previous_result.as_ref()) {
-                                Ok(result) => break result,
-                                Err(e) if retry_count < MAX_RETRIES => {
-                                    warn!("Error in overlay_image, retrying (attempt {}): {}", retry_count + 1, e);
-                                    retry_count += 1;
-                                    sleep(Duration::from_millis(500)).await;
-                                    if let Some(prev) = previous_result {
-                                        previous_result = Some(prev);
-                                    }
-                                },
-                                Err(e) => {
-                                    error!("Failed to overlay image after {} retries: {}", MAX_RETRIES, e);
-                                    self.bot.delete_message(msg.chat.id, processing_msg.id).await?;
-                                    self.bot.send_message(msg.chat.id, "Failed to process your image. Please try again later.").await?;
-                                    return Ok(());
-                                }
-                            }
-                        };
-
-                        info!("Encoding result image");
-                        let mut opencv_buffer = core::Vector::new();
-                        if let Err(e) = imgcodecs::imencode(".png", &result, &mut opencv_buffer, &core::Vector::new()) {
-                            error!("Failed to encode result image: {}", e);
-                            self.bot.delete_message(msg.chat.id, processing_msg.id).await?;
-                            self.bot.send_message(msg.chat.id, "Failed to process your image. Please try again.").await?;
-                            return Ok(());
+        let Some((original_msg_id, request_time)) = overlays.get(&(msg.chat.id, user_id)).map(|pending| (pending.message_id, pending.created)) else {
+            info!("No pending overlay request found for user ID: {:?} in chat ID: {}", user_id, msg.chat.id);
+            return Err(OverlayOutcome::NotRequested);
+        };
+
+        info!("Comparing original_msg_id: {} with reply_to.id: {}", original_msg_id, reply_to.id);
+        if original_msg_id != reply_to.id {
+            info!("Reply does not match the original overlay request. Expected: {}, Got: {}", original_msg_id, reply_to.id);
+            return Err(OverlayOutcome::NotRequested);
+        }
+
+        let (styles, request_id) = overlays.remove(&(msg.chat.id, user_id))
+            .map(|pending| (pending.styles, pending.request_id))
+            .unwrap_or_else(|| (Vec::new(), next_request_id()));
+        info!("Removed overlay request from pending_overlays");
+
+        if request_time.elapsed() > self.context.live_config.get().overlay_expiration {
+            info!("Overlay request has expired");
+            return Err(OverlayOutcome::Expired);
+        }
+
+        if let Some(photo) = largest_photo(msg) {
+            if let Some(media_group_id) = msg.media_group_id() {
+                let album = self.context.media_groups.take(msg.chat.id, media_group_id).await;
+                if album.len() > 1 {
+                    info!("Found album of {} photos in message", album.len());
+                    return Ok((ImageSource::Album(album), styles, request_id, Some(original_msg_id)));
+                }
+            }
+            info!("Found photo in message");
+            return Ok((ImageSource::Photo(photo), styles, request_id, Some(original_msg_id)));
+        }
+
+        if let Some(sticker) = msg.sticker() {
+            // Animated stickers are Lottie animations and video stickers are WebM, neither of which OpenCV can decode
+            if !sticker.is_raster() {
+                info!("Found animated or video sticker in message");
+                return Err(OverlayOutcome::UnsupportedSticker);
+            }
+            info!("Found static sticker in message");
+            return Ok((ImageSource::Sticker(sticker), styles, request_id, Some(original_msg_id)));
+        }
+
+        match msg.text().and_then(find_image_url) {
+            Some(url) => {
+                info!("Found image link in message");
+                Ok((ImageSource::Url(url), styles, request_id, Some(original_msg_id)))
+            }
+            None => {
+                warn!("No photo found in the message");
+                Err(OverlayOutcome::NoPhoto)
+            }
+        }
+    }
+
+    /// Downloads a photo or sticker from Telegram, or reads it from the download cache if it was downloaded recently.
+    ///
+    /// The file size Telegram reports for the file is checked first, so oversized files are never downloaded. The
+    /// response must have a success status and an image content type, so an error page is never handed to the decoder,
+    /// and must be as long as its `Content-Length` says, when it has one, so a cut-off file isn't either. If the file
+    /// path has expired by the time the photo leaves the queue, a fresh one is fetched and the download tried once more.
+    ///
+    /// # Arguments
+    /// * `file` - The file to download, e.g. a photo's `file`.
+    ///
+    /// # Returns
+    /// The raw bytes of the file, which are cached for the next request for it, `OverlayOutcome::FileTooLarge` if it is larger than `max_file_size_bytes`,
+    /// `OverlayOutcome::TooLargeToFetch` if it is over the Bot API's download limit, `OverlayOutcome::NotAnImage` if the response isn't an image, `OverlayOutcome::Corrupted` if it ended early, or
+    /// `OverlayOutcome::DownloadFailed` if any other step of the download fails.
+    async fn download_image(&self, file: &FileMeta) -> Result<Arc<Vec<u8>>, OverlayOutcome> {
+        if let Some(cached) = self.context.download_cache.get(&file.unique_id).await {
+            info!("Using cached download of file {}", file.unique_id);
+            return Ok(cached);
+        }
+        if file.size > self.context.options.max_file_size_bytes {
+            warn!("File is {} bytes, over the {} byte limit, not downloading it", file.size, self.context.options.max_file_size_bytes);
+            return Err(OverlayOutcome::FileTooLarge(self.context.options.max_file_size_bytes));
+        }
+        if file.size > TELEGRAM_MAX_DOWNLOAD_BYTES {
+            warn!("File is {} bytes, over the Bot API's {} byte download limit, not fetching it", file.size, TELEGRAM_MAX_DOWNLOAD_BYTES);
+            return Err(OverlayOutcome::TooLargeToFetch);
+        }
+
+        let mut refreshed = false;
+        let response = loop {
+            let url = self.file_url(file).await?;
+            info!("Downloading image");
+            match self.context.http_client.get(&url).send().await.and_then(|response| response.error_for_status()) {
+                Ok(response) => break response,
+                Err(e) if !refreshed && is_expired_file_path(&e) => {
+                    warn!("Telegram file path has expired, fetching a fresh one: {}", e);
+                    refreshed = true;
+                }
+                Err(e) => {
+                    error!("Failed to download image: {}", e);
+                    return Err(OverlayOutcome::DownloadFailed);
+                }
+            }
+        };
+
+        let content_type = response.headers().get(CONTENT_TYPE).map(|content_type| content_type.to_str().unwrap_or_default());
+        if !is_image_content_type(content_type) {
+            let content_type = content_type.unwrap_or_default().to_string();
+            error!("Telegram answered the download with content type {:?}, not an image, not decoding it", content_type);
+            return Err(OverlayOutcome::NotAnImage(content_type));
+        }
+
+        info!("Reading image data");
+        let expected_length = response.content_length();
+        let image_data = response.bytes().await.map_err(|e| {
+            error!("Failed to read image data: {}", e);
+            OverlayOutcome::DownloadFailed
+        })?;
+        if let Some(expected_length) = expected_length.filter(|&length| length != image_data.len() as u64) {
+            error!("Downloaded {} bytes of an image announced as {} bytes, not decoding it", image_data.len(), expected_length);
+            return Err(OverlayOutcome::Corrupted);
+        }
+
+        let image_data = Arc::new(image_data.to_vec());
+        self.context.download_cache.insert(&file.unique_id, Arc::clone(&image_data)).await;
+        Ok(image_data)
+    }
+
+    /// Asks Telegram for the URL a file can be downloaded from.
+    ///
+    /// # Arguments
+    /// * `file` - The file to download.
+    ///
+    /// # Returns
+    /// The download URL, `OverlayOutcome::TooLargeToFetch` if Telegram refuses to serve the file because of its size, or
+    /// `OverlayOutcome::DownloadFailed` if the request fails.
+    async fn file_url(&self, file: &FileMeta) -> Result<String, OverlayOutcome> {
+        info!("Fetching file from Telegram");
+        self.bot.file_url(&file.id).await.map_err(|e| {
+            // The size in the message can be missing, in which case Telegram only refuses when asked for the file
+            if is_file_too_big_error(&e) {
+                warn!("Telegram refused to serve the file, it is over the Bot API's download limit: {}", e);
+                return OverlayOutcome::TooLargeToFetch;
+            }
+            error!("Failed to get file: {}", e);
+            OverlayOutcome::DownloadFailed
+        })
+    }
+
+    /// Makes sure the processing message doesn't linger if the request never finishes.
+    ///
+    /// A background task waits for `done`. If the request finishes normally, `report_outcome` has already deleted the
+    /// processing message and the task does nothing. If `done` is dropped without being sent, e.g. because the request
+    /// panicked, or `processing_message_timeout` passes first, the task deletes the processing message itself.
+    ///
+    /// # Arguments
+    /// * `chat_id` - The chat the processing message was sent in.
+    /// * `processing_msg_id` - The ID of the processing message.
+    /// * `done` - Receives a value once the request's outcome has been reported.
+    fn guard_processing_message(&self, chat_id: ChatId, processing_msg_id: MessageId, done: oneshot::Receiver<()>) {
+        let bot = self.bot.clone();
+        let processing_message_timeout = self.context.options.processing_message_timeout;
+        tokio::spawn(async move {
+            let reason = tokio::select! {
+                finished = done => match finished {
+                    Ok(()) => return,
+                    Err(_) => "the request stopped without finishing",
+                },
+                _ = tokio::time::sleep(processing_message_timeout) => "the request timed out",
+            };
+            warn!("Deleting processing message {} in chat {}, {}", processing_msg_id, chat_id, reason);
+            if let Err(e) = bot.delete_message(chat_id, processing_msg_id).await {
+                warn!("Failed to delete stuck processing message: {}", e);
+            }
+        }.instrument(Span::current()));
+    }
+
+    /// Converts a downloaded static sticker to a PNG on the image worker pool, see `sticker_to_png`.
+    ///
+    /// # Arguments
+    /// * `sticker_data` - The raw bytes of the sticker, usually WebP.
+    ///
+    /// # Returns
+    /// The sticker as a PNG, or `OverlayOutcome::DecodeFailed` with the sticker's detected format if it couldn't be
+    /// converted.
+    async fn convert_sticker(&self, sticker_data: Vec<u8>) -> Result<Vec<u8>, OverlayOutcome> {
+        let detected_format = detect_image_format(&sticker_data);
+        info!("Converting {} sticker", detected_format.unwrap_or("unknown"));
+        match self.context.worker_pool.submit(move || sticker_to_png(&sticker_data)).await {
+            Ok(Ok(png)) => Ok(png),
+            Ok(Err(e)) => {
+                error!("Failed to convert sticker: {}", e);
+                Err(OverlayOutcome::DecodeFailed(detected_format))
+            }
+            Err(_) => {
+                error!("Image worker pool dropped the sticker conversion job, it may have panicked");
+                Err(OverlayOutcome::OverlayFailed)
+            }
+        }
+    }
+
+    /// Renders the overlay on the image worker pool, so the OpenCV work doesn't block the async runtime.
+    ///
+    /// # Arguments
+    /// * `overlay_assets` - The overlays for the request's chat.
+    /// * `image_data` - The raw bytes of the image to overlay, shared so they can be cached for `/again` afterwards.
+    /// * `styles` - The overlay styles to stack, in order, or an empty list for a random overlay.
+    /// * `timing` - The request's timing, updated with the decode, composite and encode phases.
+    ///
+    /// OpenCV work can't be cancelled, so if it takes longer than `max_processing_time` its result is abandoned and the
+    /// request finishes without it. The worker thread keeps running the render until it ends on its own, and the result
+    /// is then dropped.
+    ///
+    /// # Returns
+    /// The outcome of `render_overlay`, `OverlayOutcome::TimedOut` if it took longer than `max_processing_time`, or
+    /// `OverlayOutcome::OverlayFailed` if the pool dropped the job, such as when OpenCV panicked on a malformed image.
+    async fn render_on_pool(&self, overlay_assets: Arc<OverlayAssets>, image_data: Arc<Vec<u8>>, styles: Vec<String>, timing: &mut OverlayTiming) -> OverlayOutcome {
+        // The worker thread doesn't inherit the span, so its log lines are tied to the request by entering it there
+        let span = Span::current();
+        let input_bytes = image_data.len();
+        let rendered = self.context.worker_pool.submit(move || {
+            let _entered = span.enter();
+            let mut timing = OverlayTiming::new();
+            let outcome = render_overlay(&overlay_assets, &image_data, &styles, &mut timing);
+            (outcome, timing)
+        });
+
+        let Ok(rendered) = tokio::time::timeout(self.context.options.max_processing_time, rendered).await else {
+            warn!("Overlay took longer than {:?}, abandoning it; its worker thread stays busy until the render finishes", self.context.options.max_processing_time);
+            return OverlayOutcome::TimedOut;
+        };
+        match rendered {
+            Ok((outcome, render_timing)) => {
+                timing.decode = render_timing.decode;
+                timing.composite = render_timing.composite;
+                timing.encode = render_timing.encode;
+                timing.decoded_width = render_timing.decoded_width;
+                timing.decoded_height = render_timing.decoded_height;
+                if let OverlayOutcome::Success(buffer) = &outcome {
+                    self.context.metrics.record(input_bytes, timing.decoded_width, timing.decoded_height, buffer.len());
+                }
+                outcome
+            }
+            Err(_) => {
+                error!("Image worker pool dropped the overlay job, it may have panicked");
+                OverlayOutcome::OverlayFailed
+            }
+        }
+    }
+
+    /// Deletes the `/degenme` prompt a request replied to, so it doesn't clutter the chat once the result is in.
+    ///
+    /// Nothing is deleted unless `delete_prompt_on_success` is set. The bot may be unable to delete the prompt, e.g. if
+    /// an admin already removed it, which is logged and otherwise ignored.
+    ///
+    /// # Arguments
+    /// * `msg` - The user's reply to the prompt.
+    /// * `prompt_msg_id` - The ID of the prompt, or `None` if the request didn't come from one.
+    async fn delete_prompt(&self, msg: &Message, prompt_msg_id: Option<MessageId>) {
+        let Some(prompt_msg_id) = prompt_msg_id.filter(|_| self.context.options.delete_prompt_on_success) else {
+            return;
+        };
+        match self.bot.delete_message(msg.chat.id, prompt_msg_id).await {
+            Ok(()) => info!("Deleted /degenme prompt {}", prompt_msg_id),
+            Err(e) => warn!("Failed to delete /degenme prompt {}: {}", prompt_msg_id, e),
+        }
+    }
+
+    /// Reacts to the user's message with `SUCCESS_REACTION`, as lightweight feedback that the result has been sent.
+    ///
+    /// Nothing is sent unless `react_on_success` is set. Chats can turn reactions off, and failing to react never
+    /// affects the request.
+    ///
+    /// # Arguments
+    /// * `msg` - The user's message with the image, or the first message of their album.
+    async fn react_to_source(&self, msg: &Message) {
+        if !self.context.options.react_on_success {
+            return;
+        }
+        match self.bot.set_reaction(msg.chat.id, msg.id, SUCCESS_REACTION).await {
+            Ok(()) => {}
+            Err(e) if is_reaction_unavailable_error(&e) => info!("Reactions aren't allowed in chat {}, not reacting: {}", msg.chat.id, e),
+            Err(e) => warn!("Failed to react to message {}: {}", msg.id, e),
+        }
+    }
+
+    /// Tells the user how their overlay request went.
+    ///
+    /// On success the result is sent as a photo, as a reply to the user's message if `reply_to_source` is set (falling back
+    /// to a plain photo if that message is gone), and the user's photo is deleted if `delete_source_photo` is set, or
+    /// reacted to if `react_on_success` is set;
+    /// otherwise a message explaining the failure is sent. The processing message, if one was sent, is deleted either way;
+    /// failing to delete it is logged and never stops the result or the explanation from being sent.
+    /// If the bot isn't allowed to post the result in the chat, it is sent to the user privately instead, when possible.
+    /// The source image of a sent result is cached, so replying to the result with `/again` can reuse it.
+    /// If archiving is enabled, the result is uploaded to the archive in the background.
+    /// Every outcome except `NotRequested` is recorded in the audit log as a `result`.
+    ///
+    /// # Arguments
+    /// * `msg` - The Telegram message containing the image overlay request.
+    /// * `processing_msg_id` - The ID of the processing message, if one was sent.
+    /// * `outcome` - The outcome of the overlay request.
+    /// * `timing` - The request's timing, logged once the result has been sent, if the request got that far.
+    /// * `image_data` - The raw bytes of the source image, if it was downloaded.
+    ///
+    /// # Returns
+    /// A `ResponseResult<()>` indicating the success or failure of the operation.
+    async fn report_outcome(&self, msg: &Message, processing_msg_id: Option<MessageId>, outcome: OverlayOutcome, timing: Option<OverlayTiming>, image_data: Option<Arc<Vec<u8>>>) -> ResponseResult<()> {
+        if !matches!(outcome, OverlayOutcome::NotRequested) {
+            self.context.audit_logger.record(msg, "result", outcome.label());
+        }
+        let assets = self.context.live_config.assets();
+        let messages = assets.localization.for_message(msg);
+        let reply = match outcome {
+            OverlayOutcome::Success(buffer) => {
+                info!("Sending processed image");
+                if let Some(archive) = &self.context.archive {
+                    let key = archive_key(msg.chat.id, msg.from().map(|user| user.id), assets.chat_overlays.for_chat(msg.chat.id).output_format(), None);
+                    spawn_store(Arc::clone(archive), key, buffer.clone());
+                }
+                let caption = messages.result_caption(&display_name(msg));
+                let upload_started = Instant::now();
+                // Kept in case the chat refuses the photo and it has to be sent privately instead
+                let fallback_buffer = buffer.clone();
+                let file_name = assets.chat_overlays.for_chat(msg.chat.id).output_file_name(file_name_username(msg));
+                let sent_photo = if self.context.options.reply_to_source {
+                    match self.bot.reply_photo(msg.chat.id, topic_thread_id(msg), msg.id, buffer, &file_name, caption.clone()).await {
+                        // The user's message was deleted while the overlay was rendered
+                        Err(RequestError::Api(ApiError::MessageToReplyNotFound)) => {
+                            warn!("Source message {} is gone, sending the result without replying to it", msg.id);
+                            self.bot.send_photo(msg.chat.id, topic_thread_id(msg), fallback_buffer.clone(), &file_name, caption).await
                         }
-                        let buffer = opencv_buffer.to_vec();
-                        
-                        info!("Sending processed image");
-                        
-                        let caption = format!("Here you go {}, you degen.", username);
-                        let sent_photo = self.bot.send_photo(msg.chat.id, InputFile::memory(buffer).file_name("overlay.png"))
-                            .caption(caption)
-                            .await?;
-                    
-                        info!("Image sent successfully with caption");
+                        sent_photo => sent_photo,
+                    }
+                } else {
+                    self.bot.send_photo(msg.chat.id, topic_thread_id(msg), buffer, &file_name, caption).await
+                };
 
-                        info!("Sent photo message ID: {}", sent_photo.id);
+                match sent_photo {
+                    Ok(sent_photo_id) => {
+                        info!("Image sent successfully with caption");
+                        if let Some(mut timing) = timing {
+                            timing.upload = upload_started.elapsed();
+                            timing.log();
+                        }
+                        info!("Sent photo message ID: {}", sent_photo_id);
+                        self.context.recent_results.record(msg.chat.id, sent_photo_id).await;
+                        if let Some(image_data) = image_data {
+                            self.context.source_cache.insert(msg.chat.id, sent_photo_id, image_data).await;
+                        }
+                        self.context.restricted_chats.record_success(msg.chat.id).await;
 
                         // Now delete the processing message
-                        if let Err(e) = self.bot.delete_message(msg.chat.id, processing_msg.id).await {
-                            error!("Failed to delete processing message: {}", e);
+                        if let Some(processing_msg_id) = processing_msg_id {
+                            if let Err(e) = self.bot.delete_message(msg.chat.id, processing_msg_id).await {
+                                error!("Failed to delete processing message: {}", e);
+                            }
+                        }
+
+                        // The bot may not be allowed to delete other users' messages, which shouldn't fail the request
+                        if self.context.options.delete_source_photo && msg.photo().is_some() {
+                            if let Err(e) = self.bot.delete_message(msg.chat.id, msg.id).await {
+                                warn!("Failed to delete source photo, the bot may lack delete rights: {}", e);
+                            }
+                        } else {
+                            self.react_to_source(msg).await;
                         }
-                    } else {
-                        warn!("No photo found in the message");
-                        self.bot.send_message(msg.chat.id, "Please reply with an image to degen.").await?;
+                        return Ok(());
+                    }
+                    Err(e) if is_permission_error(&e) => {
+                        self.record_permission_error(msg, &e).await;
+                        if let Some(processing_msg_id) = processing_msg_id {
+                            if let Err(e) = self.bot.delete_message(msg.chat.id, processing_msg_id).await {
+                                error!("Failed to delete processing message: {}", e);
+                            }
+                        }
+                        self.send_privately(msg, fallback_buffer).await;
+                        return Ok(());
+                    }
+                    Err(e) => {
+                        error!("Failed to send processed image: {}", e);
+                        messages.send_failed().to_string()
                     }
-                } else {
-                    info!("Reply does not match the original overlay request. Expected: {}, Got: {}", original_msg_id, reply_to.id);
                 }
-            } else {
-                info!("No pending overlay request found for user ID: {:?} in chat ID: {}", user_id, msg.chat.id);
             }
+            OverlayOutcome::NotRequested => return Ok(()),
+            OverlayOutcome::RepliedToResult => messages.replied_to_result().to_string(),
+            OverlayOutcome::SourceExpired => messages.source_expired().to_string(),
+            OverlayOutcome::Expired => messages.request_expired().to_string(),
+            OverlayOutcome::NoPhoto => messages.no_photo().to_string(),
+            OverlayOutcome::UnsupportedSticker => messages.unsupported_sticker().to_string(),
+            OverlayOutcome::FileTooLarge(max_bytes) => messages.file_too_large(max_bytes),
+            OverlayOutcome::TooLargeToFetch => messages.too_large_to_fetch(TELEGRAM_MAX_DOWNLOAD_BYTES),
+            OverlayOutcome::DownloadFailed => messages.download_failed().to_string(),
+            OverlayOutcome::NotAnImage(_) => messages.not_an_image().to_string(),
+            OverlayOutcome::LinkFailed(e) => messages.link_rejected(&e),
+            OverlayOutcome::DecodeFailed(Some(format)) if format != "JPEG" && format != "PNG" => {
+                messages.unsupported_format(format)
+            }
+            OverlayOutcome::DecodeFailed(_) => messages.decode_failed().to_string(),
+            OverlayOutcome::Corrupted => messages.corrupted().to_string(),
+            OverlayOutcome::TooSmall => messages.too_small().to_string(),
+            OverlayOutcome::TimedOut => messages.took_too_long().to_string(),
+            OverlayOutcome::OverlayFailed => messages.overlay_failed().to_string(),
+        };
+
+        // A processing message left behind is only cosmetic, so it mustn't stop the user from hearing how it went
+        if let Some(processing_msg_id) = processing_msg_id {
+            if let Err(e) = self.bot.delete_message(msg.chat.id, processing_msg_id).await {
+                error!("Failed to delete processing message: {}", e);
+            }
+        }
+        match self.bot.send_message(msg.chat.id, topic_thread_id(msg), reply).await {
+            Ok(_) => Ok(()),
+            Err(e) if is_permission_error(&e) => {
+                self.record_permission_error(msg, &e).await;
+                Ok(())
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Logs that the bot wasn't allowed to post in a chat and counts it towards suppressing the chat.
+    ///
+    /// # Arguments
+    /// * `msg` - The Telegram message containing the image overlay request.
+    /// * `error` - The permission error returned by Telegram.
+    async fn record_permission_error(&self, msg: &Message, error: &RequestError) {
+        warn!("The bot isn't allowed to post in chat {}: {}", msg.chat.id, error);
+        if self.context.restricted_chats.record_failure(msg.chat.id).await {
+            warn!("Suppressing overlay requests in chat {} after repeated permission errors", msg.chat.id);
+        }
+    }
+
+    /// Sends the result to the user privately after the chat they asked in refused it.
+    ///
+    /// This only works if the user has started a private chat with the bot, so a failure is only logged. Nothing is sent
+    /// if the request already came from a private chat, since that means the user has blocked the bot.
+    ///
+    /// # Arguments
+    /// * `msg` - The Telegram message containing the image overlay request.
+    /// * `buffer` - The encoded result.
+    async fn send_privately(&self, msg: &Message, buffer: Vec<u8>) {
+        let Some(user) = msg.from().filter(|_| !msg.chat.is_private()) else {
+            return;
+        };
+
+        let assets = self.context.live_config.assets();
+        let caption = assets.localization.for_message(msg).result_private().to_string();
+        let file_name = assets.chat_overlays.for_chat(msg.chat.id).output_file_name(file_name_username(msg));
+        match self.bot.send_photo(ChatId::from(user.id), None, buffer, &file_name, caption).await {
+            Ok(_) => info!("Sent processed image privately to user {}", user.id),
+            Err(e) => warn!("Failed to send processed image privately to user {}: {}", user.id, e),
+        }
+    }
+}
+
+/// Returns the `@username` of the message sender, or "Anonymous" if they don't have one.
+fn display_name(msg: &Message) -> String {
+    msg.from()
+        .and_then(|user| user.username.as_ref())
+        .map(|username| format!("@{}", username))
+        .unwrap_or_else(|| "Anonymous".to_string())
+}
+
+/// Returns the sender's Telegram username, without the `@`, for the `{username}` placeholder of result file names.
+fn file_name_username(msg: &Message) -> Option<&str> {
+    msg.from().and_then(|user| user.username.as_deref())
+}
+
+/// Checks whether the content type of a Telegram file download can be an image.
+///
+/// Telegram serves photos and stickers as `image/*`, but other files, and files without a known extension, as
+/// `application/octet-stream`, so that is allowed too and left for the decoder to sort out, as is a missing header.
+/// Anything else, such as `text/html` or `application/json`, is an error page rather than the file.
+///
+/// # Arguments
+/// * `content_type` - The value of the response's `Content-Type` header, if it has one.
+///
+/// # Returns
+/// `true` if the response may be an image, `false` otherwise.
+fn is_image_content_type(content_type: Option<&str>) -> bool {
+    let Some(content_type) = content_type else {
+        return true;
+    };
+    let mime = content_type.split(';').next().unwrap_or_default().trim().to_ascii_lowercase();
+    mime.starts_with("image/") || mime == "application/octet-stream"
+}
+
+/// Decodes an image, applies the requested overlays and the watermark, and encodes the result in the configured output format.
+///
+/// If styles were requested, their overlays are stacked in order, each one composited onto the result of the previous
+/// one. Otherwise a single overlay is chosen at random. Images larger than the configured maximum dimension are downscaled before the overlay is applied,
+/// and images narrower or shorter than the configured minimum dimension are rejected. If a normalized width is configured,
+/// images are resized to it first, though very tall images are still shrunk to the maximum dimension. Truncated PNGs and JPEGs are
+/// rejected before decoding, since OpenCV would decode them into a garbled image.
+///
+/// This function blocks while OpenCV does its work, so it is run on the `ImageWorkerPool`. It doesn't talk to Telegram,
+/// so it can be exercised without a live bot.
+///
+/// # Arguments
+/// * `overlay_assets` - The cached overlay images to choose from.
+/// * `image_data` - The raw bytes of the image to overlay.
+/// * `styles` - The overlay styles to stack, in order, or an empty list for a random overlay.
+/// * `timing` - Updated with how long the decode, composite and encode phases took.
+///
+/// # Returns
+/// `OverlayOutcome::Success` with the encoded result, or the outcome describing which step failed.
+pub fn render_overlay(overlay_assets: &OverlayAssets, image_data: &[u8], styles: &[String], timing: &mut OverlayTiming) -> OverlayOutcome {
+    info!("Decoding image");
+    let decode_started = Instant::now();
+    let detected_format = detect_image_format(image_data);
+    if is_truncated_image(image_data) {
+        error!("{} image is missing its end-of-file marker, it seems to be truncated", detected_format.unwrap_or("unknown"));
+        return OverlayOutcome::Corrupted;
+    }
+    let decoded = imgcodecs::imdecode(&core::Vector::from_slice(image_data), imgcodecs::IMREAD_COLOR)
+        .and_then(|img| if img.empty() {
+            Err(opencv::Error::new(core::StsError, "Decoded image is empty"))
         } else {
-            info!("Message is not a reply or user ID is missing. User ID: {:?}, Is reply: {}", user_id, msg.reply_to_message().is_some());
+            Ok(img)
+        });
+    let img = match decoded {
+        Ok(img) => img,
+        Err(e) => {
+            error!("Failed to decode image (detected format: {}): {}", detected_format.unwrap_or("unknown"), e);
+            return OverlayOutcome::DecodeFailed(detected_format);
         }
+    };
+    timing.decode = decode_started.elapsed();
+    timing.decoded_width = u32::try_from(img.cols()).unwrap_or(0);
+    timing.decoded_height = u32::try_from(img.rows()).unwrap_or(0);
 
-        info!("Exiting process_image function");
-        Ok(())
+    let min_dimension = overlay_assets.min_dimension();
+    if img.cols() < min_dimension || img.rows() < min_dimension {
+        info!("Rejecting {}x{} image, smaller than the minimum dimension of {}px", img.cols(), img.rows(), min_dimension);
+        return OverlayOutcome::TooSmall;
     }
+
+    let composite_started = Instant::now();
+
+    // The overlay is scaled to the image's width, so normalizing the width standardizes the whole result's size
+    let img = match overlay_assets.normalize_width() {
+        Some(width) => match resize_to_width(img, width) {
+            Ok(img) => img,
+            Err(e) => {
+                error!("Failed to resize image to {}px wide: {}", width, e);
+                return OverlayOutcome::OverlayFailed;
+            }
+        },
+        None => img,
+    };
+
+    // Compositing scales with the number of pixels, so huge images are shrunk first
+    let img = match downscale_to_fit(img, overlay_assets.max_dimension()) {
+        Ok(img) => img,
+        Err(e) => {
+            error!("Failed to downscale image: {}", e);
+            return OverlayOutcome::OverlayFailed;
+        }
+    };
+
+    let aspect_ratio = img.rows() as f32 / img.cols() as f32;
+    let asset = overlay_assets.asset_for(aspect_ratio);
+    let overlay_paths = if styles.is_empty() {
+        vec![overlay_assets.pick(asset)]
+    } else {
+        let mut overlay_paths = Vec::with_capacity(styles.len());
+        for style in styles {
+            match overlay_assets.pick_style(style, asset) {
+                Some(overlay_path) => overlay_paths.push(overlay_path),
+                None => {
+                    error!("No overlay found for style: {}", style);
+                    return OverlayOutcome::OverlayFailed;
+                }
+            }
+        }
+        overlay_paths
+    };
+
+    let mask = match overlay_assets.composite_mode() {
+        CompositeMode::Rectangle => None,
+        CompositeMode::Circle => match circle_mask(core::Size::new(img.cols(), img.rows())) {
+            Ok(mask) => Some(mask),
+            Err(e) => {
+                error!("Failed to create circle mask: {}", e);
+                return OverlayOutcome::OverlayFailed;
+            }
+        },
+    };
+
+    info!("Starting image overlay process");
+    let mut previous_result: Option<Mat> = None;
+    for overlay_path in overlay_paths {
+        info!("Using overlay: {:?}", overlay_path);
+
+        info!("Loading cached overlay image");
+        let overlay = match overlay_assets.decoded(overlay_path) {
+            Ok(overlay) => overlay,
+            Err(e) => {
+                error!("Failed to read overlay image: {}", e);
+                return OverlayOutcome::OverlayFailed;
+            }
+        };
+        // Compositing an empty overlay would divide by its zero height
+        if overlay.empty() {
+            error!("Overlay image {:?} is empty, not compositing it", overlay_path);
+            return OverlayOutcome::OverlayFailed;
+        }
+
+        // Each overlay is composited onto the result of the previous one, so they stack in order. Compositing the same
+        // inputs again fails the same way, so a failure isn't retried and doesn't hold up the worker thread
+        let result = match overlay_image_masked(&img, &overlay, previous_result.as_ref(), mask.as_ref(), overlay_assets.opacity(), overlay_assets.bottom_padding()) {
+            Ok(result) => result,
+            Err(e) => {
+                error!("Failed to overlay image: {}", e);
+                return OverlayOutcome::OverlayFailed;
+            }
+        };
+        previous_result = Some(result);
+    }
+    let result = previous_result.expect("at least one overlay is always applied");
+
+    let result = if overlay_assets.crop_to_circle() {
+        match crop_to_circle(&result) {
+            Ok(result) => result,
+            Err(e) => {
+                error!("Failed to crop result to a circle: {}", e);
+                return OverlayOutcome::OverlayFailed;
+            }
+        }
+    } else {
+        result
+    };
+
+    let result = match overlay_assets.apply_watermark(result) {
+        Ok(result) => result,
+        Err(e) => {
+            error!("Failed to apply watermark: {}", e);
+            return OverlayOutcome::OverlayFailed;
+        }
+    };
+
+    timing.composite = composite_started.elapsed();
+
+    info!("Encoding result image");
+    let encode_started = Instant::now();
+    let buffer = match encode_image(&result, overlay_assets.output_format(), overlay_assets.quality()) {
+        Ok(buffer) => buffer,
+        Err(e) => {
+            error!("Failed to encode result image: {}", e);
+            return OverlayOutcome::OverlayFailed;
+        }
+    };
+
+    timing.encode = encode_started.elapsed();
+
+    OverlayOutcome::Success(buffer)
 }
 
 /// Processes an image message received by the bot.
@@ -250,15 +1297,303 @@ async fn process_image(&self, msg: Message) -> ResponseResult<()> {
 /// This function is responsible for handling the processing of an image message received by the bot. It enqueues the message for processing and then processes the queue. If the processing is successful, it sends the processed image back to the user with a caption. If there are any errors during the processing, it sends an error message to the user.
 ///
 /// # Arguments
-/// * `bot` - The Telegram bot instance.
+/// * `bot` - The Telegram bot instance, or any other `MessageSender` such as `MockSender` in tests.
 /// * `msg` - The message containing the image to be processed.
-/// * `pending_overlays` - The pending overlays for the user.
+/// * `context` - The overlays, caches, settings and everything else shared by every request.
 ///
 /// # Returns
 /// A `ResponseResult<()>` indicating the success or failure of the operation.
-pub async fn process_image(bot: Bot, msg: Message, pending_overlays: PendingOverlays) -> ResponseResult<()> {
-    let processor = ImageProcessor::new(bot, pending_overlays);
+pub async fn process_image<S: MessageSender>(bot: S, msg: Message, context: Arc<ProcessorContext>) -> ResponseResult<()> {
+    let processor = ImageProcessor::new(bot, context);
     processor.enqueue(msg).await;
     processor.process_queue().await;
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::http::{header, StatusCode};
+    use axum::routing::get;
+    use axum::Router;
+    use crate::utils::sender::{MockSender, SentCall};
+    use super::super::PendingOverlay;
+    use crate::commands::DEFAULT_OVERLAY_ALIAS;
+    use crate::config::Config;
+    use crate::utils::chat_overlays::ChatOverlays;
+    use crate::utils::daily_quota::DailyQuota;
+    use crate::utils::live_config::{LiveAssets, LiveSettings};
+    use crate::utils::messages::Localization;
+    use crate::utils::rate_limiter::RateLimiter;
+
+    const IMAGE: &[u8] = b"\x89PNG\r\n\x1a\n";
+
+    fn context() -> Arc<ProcessorContext> {
+        let overlay_assets = Arc::new(OverlayAssets::load(Path::new("img")));
+        Arc::new(ProcessorContext {
+            pending_overlays: PendingOverlays::default(),
+            http_client: reqwest::Client::new(),
+            worker_pool: Arc::new(ImageWorkerPool::new(1)),
+            recent_results: Arc::new(RecentResults::new(10)),
+            source_cache: Arc::new(SourceCache::new(10, Duration::from_secs(60))),
+            result_cache: Arc::new(ResultCache::new(0, 0)),
+            download_cache: Arc::new(DownloadCache::new(0)),
+            media_groups: Arc::new(MediaGroups::new(10)),
+            restricted_chats: Arc::new(RestrictedChats::new()),
+            archive: None,
+            audit_logger: Arc::new(AuditLogger::disabled()),
+            metrics: Arc::new(OverlayMetrics::new()),
+            live_config: Arc::new(LiveConfig::new(
+                LiveSettings::from_config(&Config::default()),
+                LiveAssets::new(ChatOverlays::new(overlay_assets), Localization::default()),
+                Arc::new(DailyQuota::new(0)),
+                Arc::new(RateLimiter::new(100, Duration::from_secs(60))),
+            )),
+            overlay_aliases: Arc::from([DEFAULT_OVERLAY_ALIAS.to_string()]),
+            options: ProcessingOptions::from_config(&ProcessingConfig::default()),
+        })
+    }
+
+    /// Serves `routes` on a free local port and returns its base URL.
+    async fn serve(routes: Router) -> String {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let base_url = format!("http://{}", listener.local_addr().unwrap());
+        tokio::spawn(async move { axum::serve(listener, routes).await.unwrap() });
+        base_url
+    }
+
+    /// A file server where `/expired` answers like an expired Telegram file path and `/fresh` serves the image.
+    async fn file_server() -> String {
+        serve(Router::new()
+            .route("/expired", get(|| async { StatusCode::NOT_FOUND }))
+            .route("/fresh", get(|| async { ([(header::CONTENT_TYPE, "image/png")], IMAGE) })))
+            .await
+    }
+
+    fn file() -> FileMeta {
+        FileMeta { id: "photo".to_string(), unique_id: "unique".to_string(), size: IMAGE.len() as u32 }
+    }
+
+    fn file_url_calls(bot: &MockSender) -> usize {
+        bot.calls().iter().filter(|call| matches!(call, SentCall::FileUrl { .. })).count()
+    }
+
+    #[tokio::test]
+    async fn an_expired_file_path_is_refreshed_once() {
+        let base_url = file_server().await;
+        let bot = MockSender::new().with_file_urls([format!("{}/expired", base_url), format!("{}/fresh", base_url)]);
+        let processor = ImageProcessor::new(bot.clone(), context());
+
+        let image_data = processor.download_image(&file()).await.unwrap();
+        assert_eq!(image_data.as_slice(), IMAGE);
+        assert_eq!(file_url_calls(&bot), 2);
+    }
+
+    #[tokio::test]
+    async fn a_path_that_is_still_missing_after_refreshing_fails() {
+        let base_url = file_server().await;
+        let bot = MockSender::new().with_file_url(format!("{}/expired", base_url));
+        let processor = ImageProcessor::new(bot.clone(), context());
+
+        let outcome = processor.download_image(&file()).await;
+        assert!(matches!(outcome, Err(OverlayOutcome::DownloadFailed)));
+        assert_eq!(file_url_calls(&bot), 2);
+    }
+
+    /// Encodes an image of one BGRA colour as a PNG.
+    fn png(rows: i32, cols: i32, bgra: [f64; 4]) -> Vec<u8> {
+        let [b, g, r, a] = bgra;
+        let image = Mat::new_rows_cols_with_default(rows, cols, core::CV_8UC4, core::Scalar::new(b, g, r, a)).unwrap();
+        let mut buffer = core::Vector::new();
+        imgcodecs::imencode(".png", &image, &mut buffer, &core::Vector::new()).unwrap();
+        buffer.to_vec()
+    }
+
+    #[test]
+    fn images_below_the_minimum_dimension_are_too_small() {
+        let overlay_assets = OverlayAssets::load(Path::new("img")).with_min_dimension(64);
+
+        for (rows, cols) in [(20, 20), (200, 63), (63, 200)] {
+            let outcome = render_overlay(&overlay_assets, &png(rows, cols, [255.0; 4]), &[], &mut OverlayTiming::new());
+            assert!(matches!(outcome, OverlayOutcome::TooSmall), "{}x{} gave {:?}", cols, rows, outcome);
+        }
+        let outcome = render_overlay(&overlay_assets, &png(64, 64, [255.0; 4]), &[], &mut OverlayTiming::new());
+        assert!(matches!(outcome, OverlayOutcome::Success(_)), "64x64 gave {:?}", outcome);
+    }
+
+    /// Decodes a rendered result, keeping its alpha channel.
+    fn decode(buffer: &[u8]) -> Mat {
+        imgcodecs::imdecode(&core::Vector::from_slice(buffer), imgcodecs::IMREAD_UNCHANGED).unwrap()
+    }
+
+    #[test]
+    fn results_are_normalized_to_the_configured_width() {
+        let overlay_assets = OverlayAssets::load(Path::new("img")).with_normalize_width(Some(512));
+
+        for (rows, cols) in [(300, 200), (1500, 2000)] {
+            let OverlayOutcome::Success(buffer) = render_overlay(&overlay_assets, &png(rows, cols, [255.0; 4]), &[], &mut OverlayTiming::new()) else {
+                panic!("{}x{} image failed to render", cols, rows);
+            };
+            let result = decode(&buffer);
+            assert_eq!((result.cols(), result.rows()), (512, rows * 512 / cols));
+        }
+    }
+
+    /// Loads two square overlays from a new image directory, with each in both the portrait and the landscape
+    /// directory so every image picks from them whatever its shape: `red` is opaque red all over, and `bottom` is
+    /// opaque blue on its bottom half and transparent above.
+    fn stacking_overlays(name: &str) -> OverlayAssets {
+        let img_dir = std::env::temp_dir().join(format!("degenbot-overlays-{}-{}", std::process::id(), name));
+        let red = Mat::new_rows_cols_with_default(100, 100, core::CV_8UC4, core::Scalar::new(0.0, 0.0, 255.0, 255.0)).unwrap();
+        let mut bottom = Mat::new_rows_cols_with_default(100, 100, core::CV_8UC4, core::Scalar::all(0.0)).unwrap();
+        Mat::roi_mut(&mut bottom, core::Rect::new(0, 50, 100, 50)).unwrap()
+            .set_to(&core::Scalar::new(255.0, 0.0, 0.0, 255.0), &core::no_array()).unwrap();
+        for asset in ["portrait", "landscape"] {
+            let dir = img_dir.join(asset);
+            std::fs::create_dir_all(&dir).unwrap();
+            for (style, overlay) in [("red", &red), ("bottom", &bottom)] {
+                let path = dir.join(format!("{}.png", style));
+                imgcodecs::imwrite(path.to_str().unwrap(), overlay, &core::Vector::new()).unwrap();
+            }
+        }
+        OverlayAssets::load(&img_dir)
+    }
+
+    /// Renders a white 100x100 image with `styles` and returns the colour of the result's top and bottom halves.
+    fn render_halves(overlay_assets: &OverlayAssets, styles: &[&str]) -> ([u8; 4], [u8; 4]) {
+        let styles: Vec<String> = styles.iter().map(|style| style.to_string()).collect();
+        let OverlayOutcome::Success(buffer) = render_overlay(overlay_assets, &png(100, 100, [255.0; 4]), &styles, &mut OverlayTiming::new()) else {
+            panic!("failed to render {:?}", styles);
+        };
+        let result = decode(&buffer);
+        let pixel = |y| result.at_2d::<core::Vec4b>(y, 50).unwrap().0;
+        (pixel(25), pixel(75))
+    }
+
+    #[test]
+    fn stacked_styles_are_composited_in_order() {
+        const RED: [u8; 4] = [0, 0, 255, 255];
+        const BLUE: [u8; 4] = [255, 0, 0, 255];
+        let overlay_assets = stacking_overlays("stacked");
+
+        // Each overlay goes onto the result of the one before, so the blue half covers the red...
+        assert_eq!(render_halves(&overlay_assets, &["red", "bottom"]), (RED, BLUE));
+        // ...and in the other order, the red covers the blue half
+        assert_eq!(render_halves(&overlay_assets, &["bottom", "red"]), (RED, RED));
+        // Without stacking, the white image shows through above the blue half
+        assert_eq!(render_halves(&overlay_assets, &["bottom"]), ([255, 255, 255, 255], BLUE));
+    }
+
+    #[test]
+    fn an_unknown_style_fails_the_whole_stack() {
+        let overlay_assets = stacking_overlays("unknown");
+
+        let outcome = render_overlay(&overlay_assets, &png(100, 100, [255.0; 4]), &["red".to_string(), "hat".to_string()], &mut OverlayTiming::new());
+        assert!(matches!(outcome, OverlayOutcome::OverlayFailed), "{:?}", outcome);
+    }
+
+    /// Builds a photo of `file_size` bytes sent by user 20 in chat 10 in reply to the prompt with ID 100, as Telegram
+    /// would deliver it.
+    fn photo_reply(file_size: u32) -> Message {
+        serde_json::from_value(serde_json::json!({
+            "message_id": 101,
+            "date": 1_700_000_000,
+            "chat": { "id": 10, "type": "private", "first_name": "Degen" },
+            "from": { "id": 20, "is_bot": false, "first_name": "Degen", "username": "degen" },
+            "photo": [{ "file_id": "photo", "file_unique_id": "unique", "file_size": file_size, "width": 200, "height": 200 }],
+            "reply_to_message": {
+                "message_id": 100,
+                "date": 1_700_000_000,
+                "chat": { "id": 10, "type": "private", "first_name": "Degen" },
+                "from": { "id": 1, "is_bot": true, "first_name": "DegenBot", "username": "DegenBot" },
+                "text": "Reply to this message with a photo",
+            },
+        })).expect("a valid message")
+    }
+
+    /// Records the pending `/degenme` request that `photo_reply` answers.
+    async fn request_overlay(context: &ProcessorContext) {
+        context.pending_overlays.lock().await
+            .insert((ChatId(10), UserId(20)), PendingOverlay { message_id: MessageId(100), thread_id: None, created: tokio::time::Instant::now(), styles: Vec::new(), request_id: 1 });
+    }
+
+    /// Returns the text of every message sent, leaving out photos, deletes and other calls.
+    fn sent_texts(bot: &MockSender) -> Vec<String> {
+        bot.calls().into_iter().filter_map(|call| match call {
+            SentCall::Message { text, .. } => Some(text),
+            _ => None,
+        }).collect()
+    }
+
+    #[tokio::test]
+    async fn the_result_is_sent_even_if_deleting_the_processing_message_fails() {
+        let base_url = serve(Router::new()
+            .route("/photo", get(|| async { ([(header::CONTENT_TYPE, "image/png")], png(200, 200, [255.0; 4])) })))
+            .await;
+        let bot = MockSender::new().with_file_url(format!("{}/photo", base_url)).with_failing_deletes();
+        let context = context();
+        request_overlay(&context).await;
+
+        process_image(bot.clone(), photo_reply(1000), Arc::clone(&context)).await.unwrap();
+
+        let calls = bot.calls();
+        assert!(calls.iter().any(|call| matches!(call, SentCall::Photo { chat_id: ChatId(10), .. })), "{:?}", calls);
+        // The processing message is the first message sent, so it has ID 1
+        assert!(calls.contains(&SentCall::DeleteMessage { chat_id: ChatId(10), message_id: MessageId(1) }), "{:?}", calls);
+        // Only the processing message was sent as text, so the user wasn't told the request failed
+        assert_eq!(sent_texts(&bot).len(), 1, "{:?}", calls);
+    }
+
+    #[tokio::test]
+    async fn a_render_that_panics_doesnt_take_down_the_worker() {
+        let base_url = serve(Router::new()
+            .route("/photo", get(|| async { ([(header::CONTENT_TYPE, "image/png")], png(200, 200, [255.0; 4])) })))
+            .await;
+        let bot = MockSender::new().with_file_url(format!("{}/photo", base_url));
+        // The test context has a single worker, so the request below runs on the thread that panicked
+        let context = context();
+        let panicked = context.worker_pool.submit(|| -> OverlayOutcome { panic!("OpenCV rejected a malformed image") });
+        assert!(panicked.await.is_err());
+
+        request_overlay(&context).await;
+        process_image(bot.clone(), photo_reply(1000), Arc::clone(&context)).await.unwrap();
+        assert!(bot.calls().iter().any(|call| matches!(call, SentCall::Photo { .. })), "{:?}", bot.calls());
+    }
+
+    #[tokio::test]
+    async fn oversized_files_are_rejected_without_downloading_them() {
+        let bot = MockSender::new().with_file_url("http://127.0.0.1:9/never-fetched");
+        let context = context();
+        request_overlay(&context).await;
+        let max_bytes = context.options.max_file_size_bytes;
+        let msg = photo_reply(max_bytes + 1);
+
+        process_image(bot.clone(), msg.clone(), Arc::clone(&context)).await.unwrap();
+
+        assert_eq!(file_url_calls(&bot), 0);
+        let told = context.live_config.assets().localization.for_message(&msg).file_too_large(max_bytes);
+        assert_eq!(sent_texts(&bot).last(), Some(&told));
+        assert!(!bot.calls().iter().any(|call| matches!(call, SentCall::Photo { .. })));
+    }
+
+    #[tokio::test]
+    async fn a_non_image_response_is_not_decoded() {
+        let base_url = serve(Router::new()
+            .route("/photo", get(|| async { ([(header::CONTENT_TYPE, "text/html; charset=utf-8")], "<html>Bad Gateway</html>") })))
+            .await;
+        let bot = MockSender::new().with_file_url(format!("{}/photo", base_url));
+        let processor = ImageProcessor::new(bot.clone(), context());
+
+        let outcome = processor.download_image(&file()).await;
+        assert!(matches!(&outcome, Err(OverlayOutcome::NotAnImage(content_type)) if content_type == "text/html; charset=utf-8"), "{:?}", outcome);
+
+        // Through the whole pipeline, the user is told and nothing is sent as a result
+        let context = context();
+        request_overlay(&context).await;
+        let msg = photo_reply(1000);
+        process_image(bot.clone(), msg.clone(), Arc::clone(&context)).await.unwrap();
+        let told = context.live_config.assets().localization.for_message(&msg).not_an_image().to_string();
+        assert_eq!(sent_texts(&bot).last(), Some(&told));
+        assert!(!bot.calls().iter().any(|call| matches!(call, SentCall::Photo { .. })));
+    }
+}
@@ -0,0 +1,37 @@
+use teloxide::prelude::*;
+use log::info;
+
+use crate::commands::PendingOverlays;
+use crate::utils::messages::Messages;
+use crate::utils::queue::Queue;
+use crate::utils::rate_limiter::RateLimiter;
+
+/// Tells the chat how busy the bot is.
+///
+/// This function is called when the `/queue` command is received by the bot. It replies with the number of images
+/// waiting to be processed and the number of `/degenme` prompts still waiting for an image, across every chat.
+/// Anyone can use it, but it is rate limited per chat; a command over the limit is ignored rather than answered, so
+/// the command can't be used to spam a group either way.
+///
+/// # Arguments
+/// * `bot` - The Teloxide bot instance.
+/// * `msg` - The message that triggered the command.
+/// * `message_queue` - The queue of images waiting to be processed.
+/// * `pending_overlays` - The `/degenme` prompts waiting for an image.
+/// * `rate_limiter` - The rate limiter used for `/queue`.
+/// * `messages` - The messages in the user's language.
+///
+/// # Returns
+/// A `ResponseResult` indicating the success or failure of the operation.
+pub async fn queue(bot: Bot, msg: Message, message_queue: &Queue<Message>, pending_overlays: &PendingOverlays, rate_limiter: &RateLimiter, messages: &Messages) -> ResponseResult<()> {
+    let chat_id = msg.chat.id;
+    if !rate_limiter.check_rate_limit(&chat_id.to_string()).await {
+        info!("Ignoring /queue in chat {}, it was used too recently", chat_id);
+        return Ok(());
+    }
+
+    let queued = message_queue.len().await;
+    let awaiting_upload = pending_overlays.lock().await.len();
+    bot.send_message(chat_id, messages.queue_status(queued, awaiting_upload)).await?;
+    Ok(())
+}
@@ -0,0 +1,97 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use teloxide::prelude::*;
+use rand::seq::SliceRandom;
+use log::{info, warn, error};
+
+use crate::commands::overlay::{render_overlay, OverlayOutcome, OverlayTiming};
+use crate::utils::messages::Messages;
+use crate::utils::overlay_assets::OverlayAssets;
+use crate::utils::sender::MessageSender;
+use crate::utils::worker_pool::ImageWorkerPool;
+
+/// Lists the sample photos `/random` picks from, sorted by name.
+///
+/// Only JPEG and PNG files directly inside `dir` are used. A missing or unreadable directory is logged and gives no
+/// samples, so `/random` replies that none are configured instead of failing at startup.
+///
+/// # Arguments
+/// * `dir` - The directory holding the sample photos.
+///
+/// # Returns
+/// The paths of the sample photos.
+pub fn scan_samples(dir: &Path) -> Vec<PathBuf> {
+    let mut paths: Vec<PathBuf> = match fs::read_dir(dir) {
+        Ok(entries) => entries
+            .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+            .filter(|path| path.extension().is_some_and(|ext| ["jpg", "jpeg", "png"].iter().any(|known| ext.eq_ignore_ascii_case(known))))
+            .collect(),
+        Err(e) => {
+            warn!("Could not read sample directory {:?}: {}", dir, e);
+            Vec::new()
+        }
+    };
+
+    paths.sort();
+    info!("Loaded {} sample photos for /random from {:?}", paths.len(), dir);
+    paths
+}
+
+/// Applies an overlay to a random sample photo and posts the result.
+///
+/// This function is called when the `/random` command is received by the bot. It runs the same `render_overlay`
+/// pipeline as `/degenme`, so it shows the bot works without anyone having to upload a photo. The caller is expected
+/// to have checked the rate limit and daily quota first.
+///
+/// # Arguments
+/// * `bot` - The Telegram bot instance, or any other `MessageSender`.
+/// * `msg` - The message that triggered the command.
+/// * `samples` - The sample photos to pick from, from `scan_samples`.
+/// * `overlay_assets` - The cached overlay images to choose from.
+/// * `worker_pool` - The worker pool the overlay is rendered on.
+/// * `messages` - The messages in the user's language.
+///
+/// # Returns
+/// A `ResponseResult` indicating the success or failure of the operation.
+pub async fn random<S: MessageSender>(bot: S, msg: Message, samples: &[PathBuf], overlay_assets: Arc<OverlayAssets>, worker_pool: &ImageWorkerPool, messages: &Messages) -> ResponseResult<()> {
+    let chat_id = msg.chat.id;
+    let thread_id = topic_thread_id(&msg);
+
+    let Some(sample) = samples.choose(&mut rand::thread_rng()) else {
+        bot.send_message(chat_id, thread_id, messages.random_no_samples().to_string()).await?;
+        return Ok(());
+    };
+
+    info!("Rendering random overlay on {:?} for Chat ID: {}", sample, chat_id);
+    let image_data = match tokio::fs::read(sample).await {
+        Ok(image_data) => image_data,
+        Err(e) => {
+            error!("Failed to read sample photo {:?}: {}", sample, e);
+            bot.send_message(chat_id, thread_id, messages.random_failed().to_string()).await?;
+            return Ok(());
+        }
+    };
+
+    let render_assets = Arc::clone(&overlay_assets);
+    let outcome = worker_pool.submit(move || render_overlay(&render_assets, &image_data, &[], &mut OverlayTiming::new()))
+        .await
+        .unwrap_or_else(|_| {
+            error!("Image worker pool dropped the random overlay job");
+            OverlayOutcome::OverlayFailed
+        });
+
+    match outcome {
+        OverlayOutcome::Success(buffer) => {
+            let caption = messages.random_caption().to_string();
+            let file_name = overlay_assets.output_file_name(msg.from().and_then(|user| user.username.as_deref()));
+            bot.send_photo(chat_id, thread_id, buffer, &file_name, caption).await?;
+        }
+        outcome => {
+            error!("Failed to render random overlay on {:?}: {:?}", sample, outcome);
+            bot.send_message(chat_id, thread_id, messages.random_failed().to_string()).await?;
+        }
+    }
+
+    Ok(())
+}
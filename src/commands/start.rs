@@ -1,18 +1,67 @@
+use std::fs;
+use std::path::Path;
+use std::sync::Arc;
 use teloxide::prelude::*;
+use teloxide::types::InputFile;
+use log::{error, info, warn};
+
+use crate::utils::image_utils::detect_image_format;
+use crate::utils::messages::Messages;
+
+/// Loads the sample image sent with the `/start` welcome message.
+///
+/// The image is read once at startup so `/start` doesn't touch the disk. A missing or unreadable file, or one that
+/// isn't a JPEG, PNG or WebP image, is logged and `/start` falls back to the text-only welcome.
+///
+/// # Arguments
+/// * `path` - The path of the sample image.
+///
+/// # Returns
+/// The image's bytes, or `None` if it could not be loaded.
+pub fn load_start_image(path: &Path) -> Option<Arc<Vec<u8>>> {
+    let data = match fs::read(path) {
+        Ok(data) => data,
+        Err(e) => {
+            warn!("Could not read start image {:?}, /start will be text-only: {}", path, e);
+            return None;
+        }
+    };
+
+    match detect_image_format(&data) {
+        Some("JPEG" | "PNG" | "WebP") => {
+            info!("Loaded start image from {:?}", path);
+            Some(Arc::new(data))
+        }
+        format => {
+            warn!("Start image {:?} is not a JPEG, PNG or WebP image ({}), /start will be text-only", path, format.unwrap_or("unknown format"));
+            None
+        }
+    }
+}
 
 /// Starts the DegenMe bot and sends a welcome message to the user.
 ///
 /// This function is called when the `/start` command is received by the bot. It sends a welcome message to the user
-/// with instructions on how to use the bot.
+/// with instructions on how to use the bot. If a start image is configured, the welcome is sent as its caption so users
+/// immediately see what the bot does; if sending the image fails, the text is sent on its own instead.
 ///
 /// # Arguments
 /// * `bot` - The Teloxide bot instance.
 /// * `msg` - The message that triggered the command.
+/// * `messages` - The messages in the user's language.
+/// * `start_image` - The sample image to send with the welcome, if one is configured.
 ///
 /// # Returns
 /// A `ResponseResult` indicating the success or failure of the operation.
-pub async fn start(bot: Bot, msg: Message) -> ResponseResult<()> {
-    let response = "Welcome to the Degen POV bot! Use /degenme to create an overlay in any channel, group, or DM I am in!";
-    bot.send_message(msg.chat.id, response).await?;
+pub async fn start(bot: Bot, msg: Message, messages: &Messages, start_image: Option<Arc<Vec<u8>>>) -> ResponseResult<()> {
+    if let Some(start_image) = start_image {
+        let photo = InputFile::memory((*start_image).clone()).file_name("degenbot.jpg");
+        match bot.send_photo(msg.chat.id, photo).caption(messages.welcome()).await {
+            Ok(_) => return Ok(()),
+            Err(e) => error!("Failed to send start image, sending the welcome as text: {}", e),
+        }
+    }
+
+    bot.send_message(msg.chat.id, messages.welcome()).await?;
     Ok(())
 }
@@ -1,30 +1,1201 @@
 use serde::Deserialize;
+use std::collections::HashMap;
+use std::env;
+use std::io;
+use std::fmt::Display;
 use std::fs;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use thiserror::Error;
+
+use crate::commands::{BUILT_IN_COMMANDS, DEFAULT_OVERLAY_ALIAS};
+use crate::utils::messages::{unknown_placeholders, PROCESSING_PLACEHOLDERS};
+use crate::utils::image_utils::{CompositeMode, ImageQualityConfig, OutputFormat, WatermarkCorner, DEFAULT_FILE_NAME_TEMPLATE, FILE_NAME_PLACEHOLDERS};
+use crate::utils::overlay_assets::{default_aspect_buckets, AspectBucket};
+use crate::utils::logging::LogFormat;
+use crate::utils::rate_limiter::RateLimiter;
 
 /// The main configuration for the application.
 ///
 /// This struct contains the configuration for various components of the application,
 /// such as the Telegram integration.
-#[derive(Deserialize)]
+///
+/// Values are layered with a clear precedence: environment variables override the config file, which overrides the
+/// defaults. The supported environment variables are listed with each section.
+///
+/// `Config::default()` is the configuration used when there is no config file: the Telegram bot is disabled and every
+/// other section has its defaults, so only the web server comes up.
+#[derive(Deserialize, Default)]
 pub struct Config {
     pub telegram: TelegramConfig,
+    #[serde(default)]
+    pub processing: ProcessingConfig,
+    #[serde(default)]
+    pub limits: LimitsConfig,
+    #[serde(default)]
+    pub inline: InlineConfig,
+    #[serde(default)]
+    pub web: WebConfig,
+    #[serde(default)]
+    pub archive: ArchiveConfig,
+    #[serde(default)]
+    pub discord: DiscordConfig,
+    #[serde(default)]
+    pub logging: LoggingConfig,
+}
+
+impl Config {
+    /// Applies overrides from `DEGENBOT_*` environment variables on top of the values from the config file.
+    ///
+    /// # Returns
+    /// `Ok(())` if every set override was applied, or `ConfigError::Override` for the first one that could not be parsed.
+    pub fn apply_env_overrides(&mut self) -> Result<(), ConfigError> {
+        env_override("DEGENBOT_TELEGRAM_ENABLED", &mut self.telegram.enabled)?;
+        env_override_opt("DEGENBOT_TELEGRAM_ADMIN_CHAT_ID", &mut self.telegram.admin_chat_id)?;
+        env_override("DEGENBOT_TELEGRAM_REQUEST_TIMEOUT_SECS", &mut self.telegram.request_timeout_secs)?;
+        env_override_opt("DEGENBOT_TELEGRAM_BOT_USERNAME", &mut self.telegram.bot_username)?;
+        env_override_opt("DEGENBOT_TELEGRAM_BANNED_USERS_PATH", &mut self.telegram.banned_users_path)?;
+        env_override("DEGENBOT_TELEGRAM_NOTIFY_ON_EXPIRY", &mut self.telegram.notify_on_expiry)?;
+        env_override("DEGENBOT_TELEGRAM_CLEANUP_INTERVAL_SECS", &mut self.telegram.cleanup_interval_secs)?;
+        env_override("DEGENBOT_TELEGRAM_CLEANUP_JITTER_SECS", &mut self.telegram.cleanup_jitter_secs)?;
+        env_override("DEGENBOT_TELEGRAM_RESTART_BACKGROUND_TASKS", &mut self.telegram.restart_background_tasks)?;
+        env_override("DEGENBOT_TELEGRAM_AUDIT_ENABLED", &mut self.telegram.audit_enabled)?;
+        env_override("DEGENBOT_TELEGRAM_AUDIT_LOG_PATH", &mut self.telegram.audit_log_path)?;
+        env_override_opt("DEGENBOT_TELEGRAM_START_IMAGE_PATH", &mut self.telegram.start_image_path)?;
+        env_override("DEGENBOT_TELEGRAM_DEFAULT_LANGUAGE", &mut self.telegram.default_language)?;
+        env_override("DEGENBOT_TELEGRAM_MESSAGES_DIR", &mut self.telegram.messages_dir)?;
+        env_override("DEGENBOT_TELEGRAM_USE_USER_LANGUAGE", &mut self.telegram.use_user_language)?;
+        env_override("DEGENBOT_TELEGRAM_DM_NUDGE", &mut self.telegram.dm_nudge)?;
+        env_override("DEGENBOT_TELEGRAM_REPLY_REMINDER", &mut self.telegram.reply_reminder)?;
+        env_override("DEGENBOT_TELEGRAM_REPLY_REMINDER_FRACTION", &mut self.telegram.reply_reminder_fraction)?;
+        env_override("DEGENBOT_TELEGRAM_OVERLAY_EXPIRATION_SECS", &mut self.telegram.overlay_expiration_secs)?;
+        env_override("DEGENBOT_PROCESSING_MAX_CONCURRENT_OVERLAYS", &mut self.processing.max_concurrent_overlays)?;
+        env_override("DEGENBOT_PROCESSING_MAX_QUEUE_DEPTH", &mut self.processing.max_queue_depth)?;
+        env_override("DEGENBOT_PROCESSING_IMAGE_WORKERS", &mut self.processing.image_workers)?;
+        env_override("DEGENBOT_PROCESSING_PROCESSING_MESSAGE_TIMEOUT_SECS", &mut self.processing.processing_message_timeout_secs)?;
+        env_override("DEGENBOT_PROCESSING_MAX_PROCESSING_TIME_SECS", &mut self.processing.max_processing_time_secs)?;
+        env_override("DEGENBOT_PROCESSING_CONFIRM_ABOVE_BYTES", &mut self.processing.confirm_above_bytes)?;
+        env_override("DEGENBOT_PROCESSING_MAX_FILE_SIZE_BYTES", &mut self.processing.max_file_size_bytes)?;
+        env_override("DEGENBOT_PROCESSING_MAX_URL_DOWNLOAD_BYTES", &mut self.processing.max_url_download_bytes)?;
+        env_override("DEGENBOT_PROCESSING_MAX_DIMENSION", &mut self.processing.max_dimension)?;
+        env_override("DEGENBOT_PROCESSING_MIN_DIMENSION", &mut self.processing.min_dimension)?;
+        env_override_opt("DEGENBOT_PROCESSING_NORMALIZE_WIDTH", &mut self.processing.normalize_width)?;
+        env_override("DEGENBOT_PROCESSING_MAX_ALBUM_SIZE", &mut self.processing.max_album_size)?;
+        env_override("DEGENBOT_PROCESSING_RESULT_CACHE_ENTRIES", &mut self.processing.result_cache_entries)?;
+        env_override("DEGENBOT_PROCESSING_RESULT_CACHE_MAX_BYTES", &mut self.processing.result_cache_max_bytes)?;
+        env_override("DEGENBOT_PROCESSING_DOWNLOAD_CACHE_MAX_BYTES", &mut self.processing.download_cache_max_bytes)?;
+        env_override("DEGENBOT_PROCESSING_DELETE_SOURCE_PHOTO", &mut self.processing.delete_source_photo)?;
+        env_override("DEGENBOT_PROCESSING_DELETE_PROMPT_ON_SUCCESS", &mut self.processing.delete_prompt_on_success)?;
+        env_override("DEGENBOT_PROCESSING_REACT_ON_SUCCESS", &mut self.processing.react_on_success)?;
+        env_override("DEGENBOT_PROCESSING_REPLY_TO_SOURCE", &mut self.processing.reply_to_source)?;
+        env_override("DEGENBOT_PROCESSING_OVERLAY_OPACITY", &mut self.processing.overlay_opacity)?;
+        env_override("DEGENBOT_PROCESSING_PREMULTIPLIED_ALPHA", &mut self.processing.premultiplied_alpha)?;
+        env_override("DEGENBOT_PROCESSING_OVERLAY_BOTTOM_PADDING_PX", &mut self.processing.overlay_bottom_padding_px)?;
+        env_override("DEGENBOT_PROCESSING_QUALITY_JPEG_QUALITY", &mut self.processing.quality.jpeg_quality)?;
+        env_override("DEGENBOT_PROCESSING_QUALITY_WEBP_QUALITY", &mut self.processing.quality.webp_quality)?;
+        env_override("DEGENBOT_PROCESSING_QUALITY_PNG_COMPRESSION", &mut self.processing.quality.png_compression)?;
+        env_override("DEGENBOT_PROCESSING_OUTPUT_FILENAME_TEMPLATE", &mut self.processing.output_filename_template)?;
+        env_override_opt("DEGENBOT_PROCESSING_WATERMARK_PATH", &mut self.processing.watermark_path)?;
+        env_override_opt("DEGENBOT_PROCESSING_RANDOM_SAMPLE_DIR", &mut self.processing.random_sample_dir)?;
+        env_override("DEGENBOT_LIMITS_MAX_OVERLAYS_PER_DAY", &mut self.limits.max_overlays_per_day)?;
+        env_override("DEGENBOT_LIMITS_RATE_LIMIT_MAX_REQUESTS", &mut self.limits.rate_limit_max_requests)?;
+        env_override("DEGENBOT_LIMITS_RATE_LIMIT_WINDOW_SECS", &mut self.limits.rate_limit_window_secs)?;
+        env_override_opt("DEGENBOT_LIMITS_RATE_LIMIT_STATE_PATH", &mut self.limits.rate_limit_state_path)?;
+        env_override("DEGENBOT_INLINE_ENABLED", &mut self.inline.enabled)?;
+        env_override_opt("DEGENBOT_INLINE_PUBLIC_URL", &mut self.inline.public_url)?;
+        env_override("DEGENBOT_WEB_REDIRECT_URL", &mut self.web.redirect_url)?;
+        env_override("DEGENBOT_WEB_BIND_ADDR", &mut self.web.bind_addr)?;
+        env_override_opt("DEGENBOT_WEB_RENDER_SECRET", &mut self.web.render_secret)?;
+        env_override("DEGENBOT_ARCHIVE_ENABLED", &mut self.archive.enabled)?;
+        env_override("DEGENBOT_ARCHIVE_BUCKET", &mut self.archive.bucket)?;
+        env_override_opt("DEGENBOT_ARCHIVE_ENDPOINT", &mut self.archive.endpoint)?;
+        env_override_opt("DEGENBOT_ARCHIVE_ACCESS_KEY_ID", &mut self.archive.access_key_id)?;
+        env_override_opt("DEGENBOT_ARCHIVE_SECRET_ACCESS_KEY", &mut self.archive.secret_access_key)?;
+        env_override("DEGENBOT_ARCHIVE_HISTORY_LIMIT", &mut self.archive.history_limit)?;
+        env_override("DEGENBOT_DISCORD_ENABLED", &mut self.discord.enabled)?;
+        env_override("DEGENBOT_LOGGING_LEVEL", &mut self.logging.level)?;
+        env_override("DEGENBOT_LOGGING_FORMAT", &mut self.logging.format)?;
+        Ok(())
+    }
+
+    /// Checks the configuration for values that parse but make no sense, such as a concurrency limit of zero.
+    ///
+    /// Every problem found is reported, not just the first, so they can all be fixed in one go.
+    ///
+    /// # Returns
+    /// `Ok(())` if the configuration is valid, or `ConfigError::Invalid` listing every problem found.
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        let mut problems = Vec::new();
+
+        // The same timeout bounds the dispatcher's long poll, which waits up to `LONG_POLL_SECS` for updates
+        if self.telegram.request_timeout_secs <= LONG_POLL_SECS {
+            problems.push(format!("telegram.request_timeout_secs must be greater than {}", LONG_POLL_SECS));
+        }
+        if self.telegram.overlay_expiration_secs == 0 {
+            problems.push("telegram.overlay_expiration_secs must be greater than 0".to_string());
+        }
+        if let Some(banned_users_path) = &self.telegram.banned_users_path {
+            if banned_users_path.trim().is_empty() {
+                problems.push("telegram.banned_users_path must not be empty; leave it out to keep bans in memory".to_string());
+            }
+        }
+        if self.telegram.overlay_commands.is_empty() {
+            problems.push("telegram.overlay_commands must list at least one command".to_string());
+        }
+        for alias in &self.telegram.overlay_commands {
+            // Telegram only recognises commands of up to 32 lowercase letters, digits and underscores
+            let valid = (1..=32).contains(&alias.len()) && alias.chars().all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '_');
+            if !valid {
+                problems.push(format!(
+                    "telegram.overlay_commands entries must be 1 to 32 lowercase letters, digits or underscores, without the /, got {:?}",
+                    alias
+                ));
+            } else if BUILT_IN_COMMANDS.contains(&alias.as_str()) {
+                problems.push(format!("telegram.overlay_commands can't include the built-in /{} command", alias));
+            }
+        }
+        for chat_id in self.telegram.chat_overlays.keys() {
+            if chat_id.trim().parse::<i64>().is_err() {
+                problems.push(format!("telegram.chat_overlays keys must be chat IDs, got {:?}", chat_id));
+            }
+        }
+        for (chat_id, template) in &self.telegram.chat_messages {
+            if chat_id.trim().parse::<i64>().is_err() {
+                problems.push(format!("telegram.chat_messages keys must be chat IDs, got {:?}", chat_id));
+            }
+            if template.trim().is_empty() {
+                problems.push(format!("telegram.chat_messages for chat {} must not be empty", chat_id));
+            }
+            for placeholder in unknown_placeholders(template, PROCESSING_PLACEHOLDERS) {
+                problems.push(format!(
+                    "telegram.chat_messages for chat {} uses unknown placeholder {{{}}}, expected one of {:?}",
+                    chat_id, placeholder, PROCESSING_PLACEHOLDERS
+                ));
+            }
+        }
+        if self.telegram.cleanup_interval_secs == 0 {
+            problems.push("telegram.cleanup_interval_secs must be greater than 0".to_string());
+        } else if self.telegram.cleanup_jitter_secs >= self.telegram.cleanup_interval_secs {
+            problems.push(format!(
+                "telegram.cleanup_jitter_secs must be less than telegram.cleanup_interval_secs ({}), got {}",
+                self.telegram.cleanup_interval_secs, self.telegram.cleanup_jitter_secs
+            ));
+        }
+        if !(self.telegram.reply_reminder_fraction > 0.0 && self.telegram.reply_reminder_fraction < 1.0) {
+            problems.push(format!(
+                "telegram.reply_reminder_fraction must be between 0 and 1, exclusive, got {}",
+                self.telegram.reply_reminder_fraction
+            ));
+        }
+        if self.processing.max_concurrent_overlays == 0 {
+            problems.push("processing.max_concurrent_overlays must be greater than 0".to_string());
+        }
+        if self.processing.processing_message_timeout_secs == 0 {
+            problems.push("processing.processing_message_timeout_secs must be greater than 0".to_string());
+        }
+        if self.processing.max_processing_time_secs == 0 {
+            problems.push("processing.max_processing_time_secs must be greater than 0".to_string());
+        }
+        if self.processing.image_workers == 0 {
+            problems.push("processing.image_workers must be greater than 0".to_string());
+        }
+        if self.processing.max_file_size_bytes == 0 {
+            problems.push("processing.max_file_size_bytes must be greater than 0".to_string());
+        }
+        if self.processing.max_url_download_bytes == 0 {
+            problems.push("processing.max_url_download_bytes must be greater than 0".to_string());
+        }
+        if self.processing.max_dimension == 0 {
+            problems.push("processing.max_dimension must be greater than 0".to_string());
+        }
+        if self.processing.min_dimension > self.processing.max_dimension {
+            problems.push(format!(
+                "processing.min_dimension must be at most processing.max_dimension ({}), got {}",
+                self.processing.max_dimension, self.processing.min_dimension
+            ));
+        }
+        if let Some(normalize_width) = self.processing.normalize_width {
+            if normalize_width == 0 || normalize_width > self.processing.max_dimension {
+                problems.push(format!(
+                    "processing.normalize_width must be between 1 and processing.max_dimension ({}), got {}",
+                    self.processing.max_dimension, normalize_width
+                ));
+            }
+        }
+        let template = &self.processing.output_filename_template;
+        if template.trim().is_empty() {
+            problems.push("processing.output_filename_template must not be empty".to_string());
+        } else if template.contains(['/', '\\']) {
+            problems.push(format!("processing.output_filename_template must be a file name, not a path, got {:?}", template));
+        }
+        for placeholder in unknown_placeholders(template, FILE_NAME_PLACEHOLDERS) {
+            problems.push(format!(
+                "processing.output_filename_template uses unknown placeholder {{{}}}, expected one of {:?}",
+                placeholder, FILE_NAME_PLACEHOLDERS
+            ));
+        }
+        // Telegram albums hold at most 10 photos, so a larger limit could never be sent back
+        if !(1..=10).contains(&self.processing.max_album_size) {
+            problems.push(format!("processing.max_album_size must be between 1 and 10, got {}", self.processing.max_album_size));
+        }
+        if !(0.0..=1.0).contains(&self.processing.overlay_opacity) {
+            problems.push(format!("processing.overlay_opacity must be between 0 and 1, got {}", self.processing.overlay_opacity));
+        }
+        for (style, hue_shift) in &self.processing.style_tints {
+            if !(-360.0..=360.0).contains(hue_shift) {
+                problems.push(format!("processing.style_tints.{} must be between -360 and 360, got {}", style, hue_shift));
+            }
+        }
+        if self.processing.quality.jpeg_quality > 100 {
+            problems.push(format!("processing.quality.jpeg_quality must be between 0 and 100, got {}", self.processing.quality.jpeg_quality));
+        }
+        if self.processing.quality.webp_quality > 100 {
+            problems.push(format!("processing.quality.webp_quality must be between 0 and 100, got {}", self.processing.quality.webp_quality));
+        }
+        if self.processing.quality.png_compression > 9 {
+            problems.push(format!("processing.quality.png_compression must be between 0 and 9, got {}", self.processing.quality.png_compression));
+        }
+        if !(0.0..=1.0).contains(&self.processing.watermark_opacity) {
+            problems.push(format!("processing.watermark_opacity must be between 0 and 1, got {}", self.processing.watermark_opacity));
+        }
+        if !(self.processing.watermark_max_width > 0.0 && self.processing.watermark_max_width <= 1.0) {
+            problems.push(format!("processing.watermark_max_width must be greater than 0 and at most 1, got {}", self.processing.watermark_max_width));
+        }
+        if let Some(watermark_path) = &self.processing.watermark_path {
+            if watermark_path.trim().is_empty() {
+                problems.push("processing.watermark_path must not be empty; leave it out to disable the watermark".to_string());
+            }
+        }
+        problems.extend(aspect_bucket_problems(&self.processing.aspect_buckets));
+        if self.limits.rate_limit_max_requests == 0 {
+            problems.push("limits.rate_limit_max_requests must be greater than 0".to_string());
+        }
+        if self.limits.rate_limit_window_secs == 0 {
+            problems.push("limits.rate_limit_window_secs must be greater than 0".to_string());
+        }
+        if let Some(rate_limit_state_path) = &self.limits.rate_limit_state_path {
+            if rate_limit_state_path.trim().is_empty() {
+                problems.push("limits.rate_limit_state_path must not be empty; leave it out to disable persistence".to_string());
+            }
+        }
+        if let Some(random_sample_dir) = &self.processing.random_sample_dir {
+            if random_sample_dir.trim().is_empty() {
+                problems.push("processing.random_sample_dir must not be empty; leave it out to disable /random".to_string());
+            }
+        }
+        if self.inline.enabled {
+            match &self.inline.public_url {
+                None => problems.push("inline.public_url must be set when inline.enabled is true".to_string()),
+                Some(public_url) => {
+                    if let Err(e) = url::Url::parse(public_url) {
+                        problems.push(format!("inline.public_url is not a valid URL ({}): {}", public_url, e));
+                    }
+                }
+            }
+        }
+        match url::Url::parse(&self.web.redirect_url) {
+            Ok(url) if matches!(url.scheme(), "http" | "https") => {}
+            Ok(_) => problems.push(format!("web.redirect_url must be an http or https URL, got {}", self.web.redirect_url)),
+            Err(e) => problems.push(format!("web.redirect_url is not a valid URL ({}): {}", self.web.redirect_url, e)),
+        }
+        if let Err(e) = self.web.bind_addr.parse::<std::net::SocketAddr>() {
+            problems.push(format!("web.bind_addr is not a valid address and port ({}): {}", self.web.bind_addr, e));
+        }
+        if let Some(render_secret) = &self.web.render_secret {
+            if render_secret.trim().is_empty() {
+                problems.push("web.render_secret must not be empty; leave it out to disable the render endpoint".to_string());
+            }
+        }
+        if self.telegram.audit_enabled && self.telegram.audit_log_path.trim().is_empty() {
+            problems.push("telegram.audit_log_path must be set when telegram.audit_enabled is true".to_string());
+        }
+        if let Some(start_image_path) = &self.telegram.start_image_path {
+            if start_image_path.trim().is_empty() {
+                problems.push("telegram.start_image_path must not be empty; leave it out for a text-only /start".to_string());
+            }
+        }
+        if self.telegram.default_language.trim().is_empty() {
+            problems.push("telegram.default_language must not be empty".to_string());
+        }
+        if self.telegram.messages_dir.trim().is_empty() {
+            problems.push("telegram.messages_dir must not be empty".to_string());
+        }
+        if self.archive.enabled {
+            if self.archive.bucket.trim().is_empty() {
+                problems.push("archive.bucket must be set when archive.enabled is true".to_string());
+            }
+            if let Some(endpoint) = &self.archive.endpoint {
+                if let Err(e) = url::Url::parse(endpoint) {
+                    problems.push(format!("archive.endpoint is not a valid URL ({}): {}", endpoint, e));
+                }
+            }
+        }
+        if !(1..=10).contains(&self.archive.history_limit) {
+            problems.push(format!("archive.history_limit must be between 1 and 10, got {}", self.archive.history_limit));
+        }
+        if self.logging.level.trim().is_empty() {
+            problems.push("logging.level must not be empty".to_string());
+        }
+        // A directive without `=` may name a module rather than a level, so only `module=level` directives are checked
+        for (module, level) in self.logging.level.split(',').filter_map(|directive| directive.split_once('=')) {
+            if level.trim().parse::<log::LevelFilter>().is_err() {
+                problems.push(format!("logging.level has an unknown level {:?} for {:?}", level.trim(), module.trim()));
+            }
+        }
+
+        if problems.is_empty() {
+            Ok(())
+        } else {
+            Err(ConfigError::Invalid(problems))
+        }
+    }
 }
 
 /// Represents the configuration for the Telegram integration.
 ///
-/// This struct contains the settings for the Telegram bot, such as whether it is enabled or not.
-
+/// Every field but `enabled` has a default. Fields that can be set from the environment name their `DEGENBOT_*`
+/// variable.
 #[derive(Deserialize)]
 pub struct TelegramConfig {
+    /// Whether the Telegram bot runs. Overridden by `DEGENBOT_TELEGRAM_ENABLED` (`true`/`false`).
+    pub enabled: bool,
+    /// The chat `/feedback` messages are forwarded to. Overridden by `DEGENBOT_TELEGRAM_ADMIN_CHAT_ID` (integer).
+    #[serde(default)]
+    pub admin_chat_id: Option<i64>,
+    /// How long to wait for a Telegram API request. Must be longer than the 10 second long poll for updates, which uses
+    /// the same timeout. Overridden by `DEGENBOT_TELEGRAM_REQUEST_TIMEOUT_SECS` (integer).
+    #[serde(default = "default_request_timeout_secs")]
+    pub request_timeout_secs: u64,
+    /// The bot's username, used to ignore commands addressed to other bots such as `/degenme@OtherBot`. Fetched from
+    /// Telegram at startup if left out. Overridden by `DEGENBOT_TELEGRAM_BOT_USERNAME`.
+    #[serde(default)]
+    pub bot_username: Option<String>,
+    /// The users allowed to use admin commands such as `/pause` and `/resume`.
+    #[serde(default)]
+    pub admin_user_ids: Vec<u64>,
+    /// The commands that ask for an overlay, without the `/`. Defaults to `degenme`, which stops working if it is left
+    /// out of the list.
+    #[serde(default = "default_overlay_commands")]
+    pub overlay_commands: Vec<String>,
+    /// Users the bot ignores entirely. Admins can ban and unban more at runtime with `/ban` and `/unban`.
+    #[serde(default)]
+    pub banned_user_ids: Vec<u64>,
+    /// Where bans made with `/ban` are saved so they survive a restart; unset keeps them in memory only. Overridden by
+    /// `DEGENBOT_TELEGRAM_BANNED_USERS_PATH`.
+    #[serde(default)]
+    pub banned_users_path: Option<String>,
+    /// Whether users are told their `/degenme` request expired; the prompt is deleted either way. Overridden by
+    /// `DEGENBOT_TELEGRAM_NOTIFY_ON_EXPIRY` (`true`/`false`).
+    #[serde(default = "default_notify_on_expiry")]
+    pub notify_on_expiry: bool,
+    /// How often expired requests are looked for. Overridden by `DEGENBOT_TELEGRAM_CLEANUP_INTERVAL_SECS` (integer).
+    #[serde(default = "default_cleanup_interval_secs")]
+    pub cleanup_interval_secs: u64,
+    /// A random amount of up to this many seconds added to or taken from `cleanup_interval_secs`, so several instances
+    /// of the bot don't all call Telegram at the same moment. Overridden by `DEGENBOT_TELEGRAM_CLEANUP_JITTER_SECS`
+    /// (integer).
+    #[serde(default = "default_cleanup_jitter_secs")]
+    pub cleanup_jitter_secs: u64,
+    /// Whether the cleanup task and the queue processor are restarted a few seconds after stopping unexpectedly. Either
+    /// way, the exit is logged and counted at `/metrics`. Overridden by `DEGENBOT_TELEGRAM_RESTART_BACKGROUND_TASKS`
+    /// (`true`/`false`).
+    #[serde(default = "default_restart_background_tasks")]
+    pub restart_background_tasks: bool,
+    /// Whether a JSON line recording the chat, the user, the command and its outcome is appended to `audit_log_path`
+    /// for every `/degenme` and every result. If the file can't be opened, auditing is disabled with a warning.
+    /// Overridden by `DEGENBOT_TELEGRAM_AUDIT_ENABLED` (`true`/`false`).
+    #[serde(default)]
+    pub audit_enabled: bool,
+    /// The file audit lines are appended to. Overridden by `DEGENBOT_TELEGRAM_AUDIT_LOG_PATH`.
+    #[serde(default = "default_audit_log_path")]
+    pub audit_log_path: String,
+    /// A JPEG, PNG or WebP sample overlay sent with the `/start` welcome as its caption. If it is unset or can't be
+    /// loaded at startup, `/start` sends the welcome text on its own. Overridden by `DEGENBOT_TELEGRAM_START_IMAGE_PATH`.
+    #[serde(default)]
+    pub start_image_path: Option<String>,
+    /// The language of messages when the user's own isn't available, and of messages to the whole chat. Missing
+    /// messages fall back to English. Overridden by `DEGENBOT_TELEGRAM_DEFAULT_LANGUAGE`.
+    #[serde(default = "default_language")]
+    pub default_language: String,
+    /// The directory of `<language>.toml` message files (see `Localization`). Overridden by
+    /// `DEGENBOT_TELEGRAM_MESSAGES_DIR`.
+    #[serde(default = "default_messages_dir")]
+    pub messages_dir: String,
+    /// Whether users get their Telegram language when there is a message file for it. Overridden by
+    /// `DEGENBOT_TELEGRAM_USE_USER_LANGUAGE` (`true`/`false`).
+    #[serde(default = "default_use_user_language")]
+    pub use_user_language: bool,
+    /// Whether text that isn't a command sent in a private chat gets a short reply pointing to `/degenme`. Groups never
+    /// get it, to avoid spam. Overridden by `DEGENBOT_TELEGRAM_DM_NUDGE` (`true`/`false`).
+    #[serde(default = "default_dm_nudge")]
+    pub dm_nudge: bool,
+    /// Whether users who haven't replied to their `/degenme` prompt are reminded once `reply_reminder_fraction` of the
+    /// time to reply has passed. Overridden by `DEGENBOT_TELEGRAM_REPLY_REMINDER` (`true`/`false`).
+    #[serde(default)]
+    pub reply_reminder: bool,
+    /// The fraction of the time to reply after which the reminder is sent, e.g. after 2 of the 3 minutes for `0.67`.
+    /// Overridden by `DEGENBOT_TELEGRAM_REPLY_REMINDER_FRACTION` (number).
+    #[serde(default = "default_reply_reminder_fraction")]
+    pub reply_reminder_fraction: f64,
+    /// How long, in seconds, users have to reply to their `/degenme` prompt with an image before the request expires.
+    /// Overridden by `DEGENBOT_TELEGRAM_OVERLAY_EXPIRATION_SECS` (integer).
+    #[serde(default = "default_overlay_expiration_secs")]
+    pub overlay_expiration_secs: u64,
+    /// Chat IDs mapped to overlay directories laid out like `img`, so those chats get their own overlays instead of the
+    /// default ones. Every directory is checked at startup.
+    #[serde(default)]
+    pub chat_overlays: HashMap<String, String>,
+    /// Chat IDs mapped to a processing message of their own, shown instead of the translated one while an image is
+    /// processed. It may use the `{username}` placeholder, and every template is checked at startup.
+    #[serde(default)]
+    pub chat_messages: HashMap<String, String>,
+}
+
+impl TelegramConfig {
+    /// Returns the overlay directory for each chat in `chat_overlays`, skipping keys that aren't chat IDs, which
+    /// `Config::validate` reports.
+    pub fn chat_overlay_dirs(&self) -> HashMap<i64, PathBuf> {
+        self.chat_overlays.iter()
+            .filter_map(|(chat_id, dir)| chat_id.trim().parse().ok().map(|chat_id| (chat_id, PathBuf::from(dir))))
+            .collect()
+    }
+
+    /// Returns the processing message template for each chat in `chat_messages`, skipping keys that aren't chat IDs,
+    /// which `Config::validate` reports.
+    pub fn chat_processing_messages(&self) -> HashMap<i64, String> {
+        self.chat_messages.iter()
+            .filter_map(|(chat_id, template)| chat_id.trim().parse().ok().map(|chat_id| (chat_id, template.clone())))
+            .collect()
+    }
+}
+
+impl Default for TelegramConfig {
+    fn default() -> Self {
+        TelegramConfig {
+            enabled: false,
+            admin_chat_id: None,
+            request_timeout_secs: default_request_timeout_secs(),
+            bot_username: None,
+            admin_user_ids: Vec::new(),
+            overlay_commands: default_overlay_commands(),
+            banned_user_ids: Vec::new(),
+            banned_users_path: None,
+            notify_on_expiry: default_notify_on_expiry(),
+            cleanup_interval_secs: default_cleanup_interval_secs(),
+            cleanup_jitter_secs: default_cleanup_jitter_secs(),
+            restart_background_tasks: default_restart_background_tasks(),
+            audit_enabled: false,
+            audit_log_path: default_audit_log_path(),
+            start_image_path: None,
+            default_language: default_language(),
+            messages_dir: default_messages_dir(),
+            use_user_language: default_use_user_language(),
+            dm_nudge: default_dm_nudge(),
+            reply_reminder: false,
+            reply_reminder_fraction: default_reply_reminder_fraction(),
+            overlay_expiration_secs: default_overlay_expiration_secs(),
+            chat_overlays: HashMap::new(),
+            chat_messages: HashMap::new(),
+        }
+    }
+}
+
+fn default_overlay_commands() -> Vec<String> {
+    vec![DEFAULT_OVERLAY_ALIAS.to_string()]
+}
+
+fn default_request_timeout_secs() -> u64 {
+    30
+}
+
+fn default_notify_on_expiry() -> bool {
+    true
+}
+
+fn default_cleanup_interval_secs() -> u64 {
+    60
+}
+
+fn default_cleanup_jitter_secs() -> u64 {
+    10
+}
+
+fn default_restart_background_tasks() -> bool {
+    true
+}
+
+fn default_audit_log_path() -> String {
+    "audit.jsonl".to_string()
+}
+
+fn default_language() -> String {
+    "en".to_string()
+}
+
+fn default_messages_dir() -> String {
+    "messages".to_string()
+}
+
+fn default_use_user_language() -> bool {
+    true
+}
+
+fn default_dm_nudge() -> bool {
+    true
+}
+
+fn default_reply_reminder_fraction() -> f64 {
+    0.67
+}
+
+fn default_overlay_expiration_secs() -> u64 {
+    180
+}
+
+/// Represents the configuration for image processing.
+///
+/// Every field has a default, so the whole `[processing]` section may be left out of the config file. Fields that can
+/// be set from the environment name their `DEGENBOT_*` variable.
+#[derive(Deserialize)]
+#[serde(default)]
+pub struct ProcessingConfig {
+    /// How many images may be processed at the same time. Overridden by
+    /// `DEGENBOT_PROCESSING_MAX_CONCURRENT_OVERLAYS` (integer).
+    pub max_concurrent_overlays: usize,
+    /// How many images may be waiting in the queue before new `/degenme` and `/again` requests are turned away; `0`
+    /// never turns them away. Overridden by `DEGENBOT_PROCESSING_MAX_QUEUE_DEPTH` (integer).
+    pub max_queue_depth: usize,
+    /// How many OS threads the OpenCV work runs on. Overridden by `DEGENBOT_PROCESSING_IMAGE_WORKERS` (integer).
+    pub image_workers: usize,
+    /// How long the "Please wait..." message may stay up before it is deleted, in case the request got stuck.
+    /// Overridden by `DEGENBOT_PROCESSING_PROCESSING_MESSAGE_TIMEOUT_SECS` (integer).
+    pub processing_message_timeout_secs: u64,
+    /// How long rendering an image may take before the user is told it took too long. OpenCV work can't be cancelled,
+    /// so the worker thread keeps running the abandoned render until it finishes. Overridden by
+    /// `DEGENBOT_PROCESSING_MAX_PROCESSING_TIME_SECS` (integer).
+    pub max_processing_time_secs: u64,
+    /// How large a photo can be before the user is asked to confirm processing it; `0` never asks. Overridden by
+    /// `DEGENBOT_PROCESSING_CONFIRM_ABOVE_BYTES` (integer).
+    pub confirm_above_bytes: u32,
+    /// The largest photo downloaded from Telegram, checked against the size Telegram reports before downloading.
+    /// Overridden by `DEGENBOT_PROCESSING_MAX_FILE_SIZE_BYTES` (integer).
+    pub max_file_size_bytes: u32,
+    /// The largest image downloaded from a link. Overridden by `DEGENBOT_PROCESSING_MAX_URL_DOWNLOAD_BYTES` (integer).
+    pub max_url_download_bytes: u64,
+    /// The largest width or height an image may have before it is downscaled for compositing. Overridden by
+    /// `DEGENBOT_PROCESSING_MAX_DIMENSION` (integer).
+    pub max_dimension: u32,
+    /// The smallest width or height an image may have to be overlaid at all; `0` accepts any size. Overridden by
+    /// `DEGENBOT_PROCESSING_MIN_DIMENSION` (integer).
+    pub min_dimension: u32,
+    /// The width every image is resized to before compositing, so results come out the same size; at most
+    /// `max_dimension`. Unset keeps each image's own width, and images too tall to fit `max_dimension` at this width
+    /// are still shrunk. Overridden by `DEGENBOT_PROCESSING_NORMALIZE_WIDTH` (integer).
+    pub normalize_width: Option<u32>,
+    /// How many photos of an album sent in reply to the prompt are processed and sent back as an album, at most 10.
+    /// Overridden by `DEGENBOT_PROCESSING_MAX_ALBUM_SIZE` (integer).
+    pub max_album_size: usize,
+    /// How many rendered results are kept to be sent again when the same photo is sent with the same styles; `0`
+    /// disables the cache. Overridden by `DEGENBOT_PROCESSING_RESULT_CACHE_ENTRIES` (integer).
+    pub result_cache_entries: usize,
+    /// How many bytes the cached results may take up, including their source images; `0` disables the cache.
+    /// Overridden by `DEGENBOT_PROCESSING_RESULT_CACHE_MAX_BYTES` (integer).
+    pub result_cache_max_bytes: u64,
+    /// How many bytes of recently downloaded Telegram files are kept so the same file isn't downloaded again, e.g. for
+    /// `/degenme` with other styles; `0` disables it. Overridden by `DEGENBOT_PROCESSING_DOWNLOAD_CACHE_MAX_BYTES`
+    /// (integer).
+    pub download_cache_max_bytes: u64,
+    /// Whether the user's photo is deleted once the result has been sent, which needs delete rights in groups.
+    /// Overridden by `DEGENBOT_PROCESSING_DELETE_SOURCE_PHOTO` (`true`/`false`).
+    pub delete_source_photo: bool,
+    /// Whether the bot's own `/degenme` prompt is deleted once a result has been produced for the reply to it, so
+    /// finished requests don't clutter the chat. Overridden by `DEGENBOT_PROCESSING_DELETE_PROMPT_ON_SUCCESS`
+    /// (`true`/`false`).
+    pub delete_prompt_on_success: bool,
+    /// Whether the user's message gets a 🔥 reaction once the result has been sent, unless it was deleted with
+    /// `delete_source_photo`. Chats without reactions are skipped. Overridden by
+    /// `DEGENBOT_PROCESSING_REACT_ON_SUCCESS` (`true`/`false`).
+    pub react_on_success: bool,
+    /// Whether the result is sent as a reply to the user's message, so busy groups can tell which submission it belongs
+    /// to. If that message has been deleted, the result is sent without replying to it. Overridden by
+    /// `DEGENBOT_PROCESSING_REPLY_TO_SOURCE` (`true`/`false`).
+    pub reply_to_source: bool,
+    /// How the overlay is composited onto the image.
+    pub composite_mode: CompositeMode,
+    /// Whether the output is cropped to a circle with transparent corners.
+    pub crop_to_circle: bool,
+    /// How opaque the overlay is, from 0 to 1, multiplying the overlay's own alpha. Overridden by
+    /// `DEGENBOT_PROCESSING_OVERLAY_OPACITY` (number).
+    pub overlay_opacity: f32,
+    /// Whether the overlay PNGs were exported with premultiplied rather than straight alpha. Overridden by
+    /// `DEGENBOT_PROCESSING_PREMULTIPLIED_ALPHA` (`true`/`false`).
+    pub premultiplied_alpha: bool,
+    /// Style names mapped to how far the hue of their overlays is shifted, in degrees from -360 to 360.
+    pub style_tints: HashMap<String, f32>,
+    /// How many pixels are left between the bottom of the overlay and the bottom of the image, measured after
+    /// downscaling. The overlay is never moved above the top of the image. Overridden by
+    /// `DEGENBOT_PROCESSING_OVERLAY_BOTTOM_PADDING_PX` (integer).
+    pub overlay_bottom_padding_px: u32,
+    /// The format results are encoded in: `png`, `webp` or `jpeg`. WebP falls back to PNG if OpenCV can't encode it.
+    pub output_format: OutputFormat,
+    /// The encoder settings, from the `[processing.quality]` table. Overridden by
+    /// `DEGENBOT_PROCESSING_QUALITY_JPEG_QUALITY`, `DEGENBOT_PROCESSING_QUALITY_WEBP_QUALITY` and
+    /// `DEGENBOT_PROCESSING_QUALITY_PNG_COMPRESSION` (integers).
+    pub quality: ImageQualityConfig,
+    /// The file name results are sent with, `overlay.{ext}` by default, with the `{username}`, `{timestamp}` and `{ext}`
+    /// placeholders. The extension always matches `output_format`. Overridden by
+    /// `DEGENBOT_PROCESSING_OUTPUT_FILENAME_TEMPLATE`.
+    pub output_filename_template: String,
+    /// The logo added to every output; unset disables the watermark. Overridden by
+    /// `DEGENBOT_PROCESSING_WATERMARK_PATH`.
+    pub watermark_path: Option<String>,
+    /// The corner the watermark is placed in.
+    pub watermark_corner: WatermarkCorner,
+    /// How opaque the watermark is, from 0 to 1.
+    pub watermark_opacity: f32,
+    /// The largest width of the watermark, as a fraction of the image's width.
+    pub watermark_max_width: f32,
+    /// The directory of JPEG or PNG sample photos `/random` applies an overlay to. If it is unset, `/random` replies
+    /// that no samples are configured. Overridden by `DEGENBOT_PROCESSING_RANDOM_SAMPLE_DIR`.
+    pub random_sample_dir: Option<String>,
+    /// Which overlay asset images of each shape use, by height divided by width. The default matches the original
+    /// portrait/landscape split.
+    pub aspect_buckets: Vec<AspectBucket>,
+}
+
+impl Default for ProcessingConfig {
+    fn default() -> Self {
+        ProcessingConfig {
+            max_concurrent_overlays: 2,
+            max_queue_depth: 0,
+            image_workers: 2,
+            processing_message_timeout_secs: 120,
+            max_processing_time_secs: 60,
+            confirm_above_bytes: 0,
+            max_file_size_bytes: 10 * 1024 * 1024,
+            max_url_download_bytes: 10 * 1024 * 1024,
+            max_dimension: 2048,
+            min_dimension: 64,
+            normalize_width: None,
+            max_album_size: 10,
+            result_cache_entries: 64,
+            result_cache_max_bytes: 64 * 1024 * 1024,
+            download_cache_max_bytes: 32 * 1024 * 1024,
+            delete_source_photo: false,
+            delete_prompt_on_success: true,
+            react_on_success: false,
+            reply_to_source: true,
+            composite_mode: CompositeMode::Rectangle,
+            crop_to_circle: false,
+            overlay_opacity: 1.0,
+            premultiplied_alpha: false,
+            style_tints: HashMap::new(),
+            overlay_bottom_padding_px: 0,
+            output_format: OutputFormat::Png,
+            quality: ImageQualityConfig::default(),
+            output_filename_template: DEFAULT_FILE_NAME_TEMPLATE.to_string(),
+            watermark_path: None,
+            watermark_corner: WatermarkCorner::BottomRight,
+            watermark_opacity: 0.8,
+            watermark_max_width: 0.1,
+            random_sample_dir: None,
+            aspect_buckets: default_aspect_buckets(),
+        }
+    }
+}
+
+/// Checks the aspect ratio buckets: there must be at least one, `ratio_max` must be positive and increasing, only the
+/// last bucket may leave it out and it must, and asset names must be plain directory names.
+fn aspect_bucket_problems(buckets: &[AspectBucket]) -> Vec<String> {
+    let mut problems = Vec::new();
+    if buckets.is_empty() {
+        problems.push("processing.aspect_buckets must contain at least one bucket".to_string());
+        return problems;
+    }
+
+    let mut previous_ratio_max = 0.0;
+    for (i, bucket) in buckets.iter().enumerate() {
+        let is_last = i == buckets.len() - 1;
+        match bucket.ratio_max {
+            Some(_) if is_last => problems.push("processing.aspect_buckets must end with a bucket without ratio_max".to_string()),
+            None if !is_last => problems.push(format!("processing.aspect_buckets[{}] must set ratio_max, only the last bucket may leave it out", i)),
+            Some(ratio_max) if ratio_max.is_nan() || ratio_max <= previous_ratio_max => {
+                problems.push(format!("processing.aspect_buckets[{}].ratio_max must be positive and greater than the previous bucket's, got {}", i, ratio_max));
+            }
+            Some(ratio_max) => previous_ratio_max = ratio_max,
+            None => {}
+        }
+        if bucket.asset.is_empty() || !bucket.asset.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-') {
+            problems.push(format!("processing.aspect_buckets[{}].asset must be a plain directory name, got {:?}", i, bucket.asset));
+        }
+    }
+    problems
+}
+
+/// Represents the configuration for per-user usage limits.
+///
+/// Fields that can be set from the environment name their `DEGENBOT_*` variable.
+#[derive(Deserialize)]
+#[serde(default)]
+pub struct LimitsConfig {
+    /// How many overlays each user may request per UTC day; `0` disables the quota. Overridden by
+    /// `DEGENBOT_LIMITS_MAX_OVERLAYS_PER_DAY` (integer).
+    pub max_overlays_per_day: u32,
+    /// How many overlays each user may request in quick succession. The `/degenme` rate limiter is a token bucket that
+    /// holds this many requests and refills at this many every `rate_limit_window_secs`. Overridden by
+    /// `DEGENBOT_LIMITS_RATE_LIMIT_MAX_REQUESTS` (integer).
+    pub rate_limit_max_requests: u32,
+    /// The time, in seconds, the rate limiter takes to refill completely. Overridden by
+    /// `DEGENBOT_LIMITS_RATE_LIMIT_WINDOW_SECS` (integer).
+    pub rate_limit_window_secs: u64,
+    /// Where the `/degenme` rate limiter's state is saved every minute and on shutdown, and restored from at startup,
+    /// so a restart doesn't reset everyone's limits; unset keeps it in memory only. Overridden by
+    /// `DEGENBOT_LIMITS_RATE_LIMIT_STATE_PATH`.
+    pub rate_limit_state_path: Option<String>,
+}
+
+impl LimitsConfig {
+    /// Creates the `/degenme` rate limiter for these limits.
+    ///
+    /// # Returns
+    /// A token-bucket `RateLimiter` with bursts of up to `rate_limit_max_requests`, refilling at that many per
+    /// `rate_limit_window_secs`.
+    pub fn overlay_rate_limiter(&self) -> RateLimiter {
+        let rate = self.rate_limit_max_requests as f64 / self.rate_limit_window_secs as f64;
+        RateLimiter::new_token_bucket(rate, self.rate_limit_max_requests)
+    }
+}
+
+impl Default for LimitsConfig {
+    fn default() -> Self {
+        LimitsConfig {
+            max_overlays_per_day: 25,
+            rate_limit_max_requests: 5,
+            rate_limit_window_secs: 60,
+            rate_limit_state_path: None,
+        }
+    }
+}
+
+/// Represents the configuration for inline mode (`@DegenBot ...` queries).
+///
+/// Inline mode must also be enabled for the bot in BotFather. Inline results point at a sample overlay served by the
+/// web server, so `public_url` must be the address the server is reachable at. If `sample_image` is not set, the
+/// sample overlay is rendered on a plain canvas.
+///
+/// Supported environment variable overrides:
+/// - `DEGENBOT_INLINE_ENABLED` (`true`/`false`) overrides `enabled`.
+/// - `DEGENBOT_INLINE_PUBLIC_URL` overrides `public_url`.
+#[derive(Deserialize, Default)]
+#[serde(default)]
+pub struct InlineConfig {
+    pub enabled: bool,
+    pub public_url: Option<String>,
+    pub sample_image: Option<String>,
+}
+
+/// Represents the configuration for the Discord bridge.
+///
+/// If `enabled` is set, the bot also joins Discord with the `DISCORD_BOT_TOKEN` secret and answers `!degenme` messages
+/// with an image attached, using the same overlays, image workers and rate limit as the Telegram bot. It works with the
+/// Telegram bot disabled too. The bot needs the Message Content intent enabled in the Discord developer portal to read commands.
+///
+/// Supported environment variable overrides:
+/// - `DEGENBOT_DISCORD_ENABLED` (`true`/`false`) overrides `enabled`.
+#[derive(Deserialize, Default)]
+#[serde(default)]
+pub struct DiscordConfig {
     pub enabled: bool,
 }
 
-/// Loads the application's configuration from a TOML file located at "config.toml".
+/// Represents the configuration for the bot's logs.
 ///
-/// This function reads the contents of the "config.toml" file, parses it using the `toml` crate,
-/// and returns the resulting `Config` struct. If there is an error reading or parsing the
-/// configuration file, the function will panic with an appropriate error message.
-pub fn load_config() -> Config {
-    let config_content = fs::read_to_string("config.toml").expect("Failed to read config file");
-    toml::from_str(&config_content).expect("Failed to parse config file")
+/// `level` is the lowest level that is logged, e.g. `info`, or a list of `env_logger`-style directives such as
+/// `warn,degenbot=debug`; it defaults to `error`, which is what the bot logged before it was configurable. The
+/// `RUST_LOG` environment variable still takes precedence over it. `format` is `pretty` for human-readable lines or
+/// `json` for one JSON object per line, for platforms that ingest structured logs (see `LogFormat`).
+///
+/// Supported environment variable overrides:
+/// - `DEGENBOT_LOGGING_LEVEL` overrides `level`.
+/// - `DEGENBOT_LOGGING_FORMAT` (`pretty`/`json`) overrides `format`.
+#[derive(Clone, Deserialize)]
+#[serde(default)]
+pub struct LoggingConfig {
+    pub level: String,
+    pub format: LogFormat,
+}
+
+impl Default for LoggingConfig {
+    fn default() -> Self {
+        LoggingConfig {
+            level: "error".to_string(),
+            format: LogFormat::Pretty,
+        }
+    }
+}
+
+/// Represents the configuration for the web server.
+///
+/// The web server's index page redirects visitors to `redirect_url`, which defaults to the Degen Studios site.
+/// `bind_addr` is the address and port the web server listens on when the bot is built with the `local` feature to
+/// run without Shuttle; on Shuttle, the platform picks the address and it is ignored. Setting `render_secret` enables
+/// the `POST /render` endpoint for smoke-testing the overlay pipeline over HTTP, for requests that send the secret in
+/// the `X-Render-Secret` header; leaving it unset disables the endpoint.
+///
+/// Supported environment variable overrides:
+/// - `DEGENBOT_WEB_REDIRECT_URL` overrides `redirect_url`.
+/// - `DEGENBOT_WEB_BIND_ADDR` overrides `bind_addr`.
+/// - `DEGENBOT_WEB_RENDER_SECRET` overrides `render_secret`.
+#[derive(Deserialize)]
+#[serde(default)]
+pub struct WebConfig {
+    pub redirect_url: String,
+    pub bind_addr: String,
+    pub render_secret: Option<String>,
+}
+
+impl Default for WebConfig {
+    fn default() -> Self {
+        WebConfig {
+            redirect_url: "https://degenstudios.media".to_string(),
+            bind_addr: "127.0.0.1:8000".to_string(),
+            render_secret: None,
+        }
+    }
+}
+
+/// Represents the configuration for archiving generated results to S3-compatible object storage.
+///
+/// When `enabled`, every result is uploaded to `bucket` in the background after it has been rendered, under
+/// `<prefix><chat id>/<user id>/<unix millis>.<extension>`, with `-<index>` before the extension for the results of an
+/// album. Setting `endpoint` targets S3-compatible storage such as
+/// MinIO or R2 instead of AWS, in which case `region` is only used for request signing. Credentials left unset are read
+/// from the usual `AWS_ACCESS_KEY_ID` and `AWS_SECRET_ACCESS_KEY` environment variables. `history_limit` is the
+/// largest number of results `/myimages` sends back, at most 10 since they are sent as a single album.
+///
+/// Supported environment variable overrides:
+/// - `DEGENBOT_ARCHIVE_ENABLED` (`true`/`false`) overrides `enabled`.
+/// - `DEGENBOT_ARCHIVE_BUCKET` overrides `bucket`.
+/// - `DEGENBOT_ARCHIVE_ENDPOINT` overrides `endpoint`.
+/// - `DEGENBOT_ARCHIVE_ACCESS_KEY_ID` overrides `access_key_id`.
+/// - `DEGENBOT_ARCHIVE_SECRET_ACCESS_KEY` overrides `secret_access_key`.
+/// - `DEGENBOT_ARCHIVE_HISTORY_LIMIT` (integer) overrides `history_limit`.
+#[derive(Deserialize)]
+#[serde(default)]
+pub struct ArchiveConfig {
+    pub enabled: bool,
+    pub bucket: String,
+    pub region: String,
+    pub endpoint: Option<String>,
+    pub access_key_id: Option<String>,
+    pub secret_access_key: Option<String>,
+    pub prefix: String,
+    pub history_limit: usize,
+}
+
+impl Default for ArchiveConfig {
+    fn default() -> Self {
+        ArchiveConfig {
+            enabled: false,
+            bucket: String::new(),
+            region: "us-east-1".to_string(),
+            endpoint: None,
+            access_key_id: None,
+            secret_access_key: None,
+            prefix: String::new(),
+            history_limit: 5,
+        }
+    }
+}
+
+/// How long the dispatcher's `getUpdates` long poll waits for updates, teloxide's default. The poll shares the bot's
+/// request timeout, so `request_timeout_secs` has to be longer.
+pub const LONG_POLL_SECS: u64 = 10;
+
+/// The environment variable that overrides the path of the config file.
+pub const CONFIG_PATH_ENV: &str = "DEGENBOT_CONFIG";
+
+/// The path of the config file used when `DEGENBOT_CONFIG` is not set.
+pub const DEFAULT_CONFIG_PATH: &str = "config.toml";
+
+/// Represents errors that can occur while loading the configuration.
+///
+/// - `Read`: The config file could not be read, e.g. because it does not exist.
+/// - `Parse`: The config file is not valid TOML or does not match the expected structure.
+/// - `ParseJson`: The `.json` config file is not valid JSON or does not match the expected structure.
+/// - `UnsupportedFormat`: The config file's extension is neither `.toml` nor `.json`.
+/// - `Invalid`: The config file parsed, but some values are out of range; holds one message per problem.
+/// - `Override`: An environment variable override could not be parsed as the type of the field it overrides.
+#[derive(Debug, Error)]
+pub enum ConfigError {
+    #[error("Failed to read config file {path}: {source}")]
+    Read { path: PathBuf, source: std::io::Error },
+    #[error("Failed to parse config file {path}: {source}")]
+    Parse { path: PathBuf, source: toml::de::Error },
+    #[error("Failed to parse config file {path}: {source}")]
+    ParseJson { path: PathBuf, source: serde_json::Error },
+    #[error("Unsupported config file {path}, use a .toml or .json extension")]
+    UnsupportedFormat { path: PathBuf },
+    #[error("Invalid config: {}", .0.join("; "))]
+    Invalid(Vec<String>),
+    #[error("Invalid value {value:?} for environment variable {var}: {reason}")]
+    Override { var: String, value: String, reason: String },
+}
+
+/// Returns the path of the config file, taken from the `DEGENBOT_CONFIG` environment variable if it is set,
+/// or "config.toml" otherwise.
+pub fn config_path() -> PathBuf {
+    env::var_os(CONFIG_PATH_ENV)
+        .map(PathBuf::from)
+        .unwrap_or_else(|| PathBuf::from(DEFAULT_CONFIG_PATH))
+}
+
+/// Loads the application's configuration from the TOML or JSON file returned by `config_path`.
+///
+/// This function reads the contents of the config file, parses it as TOML or JSON depending on its extension,
+/// applies any `DEGENBOT_*` environment variable overrides, and returns the resulting `Config` struct.
+/// If the config file doesn't exist, the overrides are applied on top of `Config::default()` instead.
+/// If there is an error reading or parsing the configuration file or an override, a `ConfigError`
+/// describing the problem is returned.
+pub fn load_config() -> Result<Config, ConfigError> {
+    let mut config = load_config_from(&config_path())?;
+    config.apply_env_overrides()?;
+    Ok(config)
+}
+
+/// Loads the application's configuration from the TOML or JSON file at `path`.
+///
+/// The format is picked by the file's extension: `.toml` files are parsed with the `toml` crate and `.json` files with
+/// `serde_json`, into the same `Config`, so either one can hold the same settings. Any other extension is an error, even
+/// if the file doesn't exist, rather than guessing the format. A missing file is not an error: `Config::default()` is
+/// returned, so a deploy without a config file still brings up the web server with the Telegram bot disabled. Callers
+/// warn about it themselves, once their logger is set up. A file that exists but can't be read or parsed is still an
+/// error, since silently ignoring it would hide a broken config.
+///
+/// # Arguments
+/// * `path` - The path of the config file.
+///
+/// # Returns
+/// The parsed `Config`, the default `Config` if the file doesn't exist, or a `ConfigError` if the file has an
+/// unsupported extension or could not be read or parsed.
+pub fn load_config_from(path: &Path) -> Result<Config, ConfigError> {
+    let format = path.extension()
+        .and_then(|extension| extension.to_str())
+        .map(str::to_lowercase)
+        .filter(|extension| extension == "toml" || extension == "json")
+        .ok_or_else(|| ConfigError::UnsupportedFormat { path: path.to_path_buf() })?;
+
+    let config_content = match fs::read_to_string(path) {
+        Ok(config_content) => config_content,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Config::default()),
+        Err(source) => return Err(ConfigError::Read { path: path.to_path_buf(), source }),
+    };
+    if format == "json" {
+        serde_json::from_str(&config_content)
+            .map_err(|source| ConfigError::ParseJson { path: path.to_path_buf(), source })
+    } else {
+        toml::from_str(&config_content)
+            .map_err(|source| ConfigError::Parse { path: path.to_path_buf(), source })
+    }
+}
+
+/// Overwrites `target` with the value of the environment variable `var`, if it is set.
+fn env_override<T>(var: &str, target: &mut T) -> Result<(), ConfigError>
+where
+    T: FromStr,
+    T::Err: Display,
+{
+    if let Ok(value) = env::var(var) {
+        *target = value.trim().parse().map_err(|e: T::Err| ConfigError::Override {
+            var: var.to_string(),
+            value: value.clone(),
+            reason: e.to_string(),
+        })?;
+    }
+    Ok(())
+}
+
+/// Sets an optional `target` to the value of the environment variable `var`, if it is set.
+fn env_override_opt<T>(var: &str, target: &mut Option<T>) -> Result<(), ConfigError>
+where
+    T: FromStr,
+    T::Err: Display,
+{
+    if let Ok(value) = env::var(var) {
+        let parsed = value.trim().parse().map_err(|e: T::Err| ConfigError::Override {
+            var: var.to_string(),
+            value: value.clone(),
+            reason: e.to_string(),
+        })?;
+        *target = Some(parsed);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Writes `contents` to a file called `name` in a directory of its own under the system temp directory, and
+    /// returns its path.
+    fn temp_config(name: &str, contents: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!("degenbot-config-{}-{}", std::process::id(), name));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join(name);
+        fs::write(&path, contents).unwrap();
+        path
+    }
+
+    #[test]
+    fn loads_a_config_from_a_custom_path() {
+        let path = temp_config("staging.toml", "[telegram]\nenabled = true\nadmin_chat_id = 42\n\n[limits]\nmax_overlays_per_day = 7\n");
+
+        let config = load_config_from(&path).unwrap();
+        assert!(config.telegram.enabled);
+        assert_eq!(config.telegram.admin_chat_id, Some(42));
+        assert_eq!(config.limits.max_overlays_per_day, 7);
+    }
+
+    #[test]
+    fn the_config_path_comes_from_the_environment() {
+        env::set_var(CONFIG_PATH_ENV, "/etc/degenbot/staging.toml");
+        assert_eq!(config_path(), PathBuf::from("/etc/degenbot/staging.toml"));
+        env::remove_var(CONFIG_PATH_ENV);
+        assert_eq!(config_path(), PathBuf::from(DEFAULT_CONFIG_PATH));
+    }
+
+    /// Returns the problems `validate` finds in `config`.
+    fn problems(config: &Config) -> Vec<String> {
+        match config.validate() {
+            Ok(()) => Vec::new(),
+            Err(ConfigError::Invalid(problems)) => problems,
+            Err(e) => panic!("unexpected error: {}", e),
+        }
+    }
+
+    #[test]
+    fn the_default_config_is_valid() {
+        assert_eq!(problems(&Config::default()), Vec::<String>::new());
+    }
+
+    #[test]
+    fn validate_reports_every_zero_limit() {
+        let mut config = Config::default();
+        config.limits.rate_limit_max_requests = 0;
+        config.telegram.overlay_expiration_secs = 0;
+        config.processing.max_concurrent_overlays = 0;
+
+        assert_eq!(problems(&config), vec![
+            "telegram.overlay_expiration_secs must be greater than 0",
+            "processing.max_concurrent_overlays must be greater than 0",
+            "limits.rate_limit_max_requests must be greater than 0",
+        ]);
+    }
+
+    #[test]
+    fn the_request_timeout_must_outlast_the_long_poll() {
+        let mut config = Config::default();
+        config.telegram.request_timeout_secs = LONG_POLL_SECS;
+        assert_eq!(problems(&config), vec!["telegram.request_timeout_secs must be greater than 10"]);
+
+        config.telegram.request_timeout_secs = LONG_POLL_SECS + 1;
+        assert!(config.validate().is_ok());
+    }
+
+    #[test]
+    fn validate_rejects_out_of_range_values() {
+        let mut config = Config::default();
+        config.processing.overlay_opacity = 1.5;
+        config.telegram.overlay_commands = vec!["start".to_string()];
+
+        let problems = problems(&config);
+        assert_eq!(problems.len(), 2, "{:?}", problems);
+        assert!(problems.iter().any(|problem| problem.starts_with("processing.overlay_opacity")));
+        assert!(problems.iter().any(|problem| problem.contains("built-in /start")));
+    }
+
+    #[test]
+    fn validate_checks_the_chat_processing_templates() {
+        let mut config = Config::default();
+        config.telegram.chat_messages.insert("-100".to_string(), "Hang on {username}".to_string());
+        assert!(config.validate().is_ok());
+
+        config.telegram.chat_messages.insert("-200".to_string(), "Hang on {user}".to_string());
+        let problems = problems(&config);
+        assert_eq!(problems.len(), 1, "{:?}", problems);
+        assert!(problems[0].contains("unknown placeholder {user}"), "{}", problems[0]);
+    }
+
+    #[test]
+    fn negative_values_fail_to_parse() {
+        let path = temp_config("negative.toml", "[telegram]\nenabled = false\n\n[limits]\nmax_overlays_per_day = -1\n");
+
+        assert!(matches!(load_config_from(&path), Err(ConfigError::Parse { .. })));
+    }
+
+    #[test]
+    fn a_missing_file_falls_back_to_the_default_config() {
+        let path = env::temp_dir().join(format!("degenbot-config-{}-missing", std::process::id())).join("config.toml");
+
+        let config = load_config_from(&path).unwrap();
+        assert!(!config.telegram.enabled);
+        assert!(config.validate().is_ok());
+    }
+
+    #[test]
+    fn a_malformed_file_is_still_an_error() {
+        let path = temp_config("malformed.toml", "[telegram\nenabled = true\n");
+
+        assert!(matches!(load_config_from(&path), Err(ConfigError::Parse { .. })));
+    }
+
+    #[test]
+    fn toml_and_json_configs_load_the_same() {
+        let toml = temp_config("config.toml", r#"
+[telegram]
+enabled = true
+admin_chat_id = -100
+overlay_commands = ["degenme", "pov"]
+overlay_expiration_secs = 300
+
+[limits]
+max_overlays_per_day = 7
+rate_limit_max_requests = 3
+
+[processing]
+max_queue_depth = 12
+overlay_opacity = 0.5
+
+[web]
+bind_addr = "127.0.0.1:8080"
+"#);
+        let json = temp_config("config.json", r#"{
+  "telegram": { "enabled": true, "admin_chat_id": -100, "overlay_commands": ["degenme", "pov"], "overlay_expiration_secs": 300 },
+  "limits": { "max_overlays_per_day": 7, "rate_limit_max_requests": 3 },
+  "processing": { "max_queue_depth": 12, "overlay_opacity": 0.5 },
+  "web": { "bind_addr": "127.0.0.1:8080" }
+}"#);
+
+        let (toml, json) = (load_config_from(&toml).unwrap(), load_config_from(&json).unwrap());
+        assert_eq!(toml.telegram.enabled, json.telegram.enabled);
+        assert_eq!(toml.telegram.admin_chat_id, json.telegram.admin_chat_id);
+        assert_eq!(toml.telegram.overlay_commands, json.telegram.overlay_commands);
+        assert_eq!(toml.telegram.overlay_expiration_secs, json.telegram.overlay_expiration_secs);
+        assert_eq!(toml.limits.max_overlays_per_day, json.limits.max_overlays_per_day);
+        assert_eq!(toml.limits.rate_limit_max_requests, json.limits.rate_limit_max_requests);
+        assert_eq!(toml.limits.rate_limit_window_secs, json.limits.rate_limit_window_secs);
+        assert_eq!(toml.processing.max_queue_depth, json.processing.max_queue_depth);
+        assert_eq!(toml.processing.overlay_opacity, json.processing.overlay_opacity);
+        assert_eq!(toml.web.bind_addr, json.web.bind_addr);
+        // And both really are the values in the files, not the defaults
+        assert_eq!(json.telegram.overlay_commands, ["degenme", "pov"]);
+        assert_eq!(json.processing.max_queue_depth, 12);
+    }
+
+    #[test]
+    fn other_extensions_are_rejected() {
+        let path = temp_config("config.yaml", "telegram:\n  enabled: true\n");
+
+        assert!(matches!(load_config_from(&path), Err(ConfigError::UnsupportedFormat { .. })));
+    }
 }
@@ -0,0 +1,203 @@
+use std::sync::Arc;
+use std::time::Duration;
+use log::{error, info, warn};
+use serenity::all::{Attachment, Client, Context, CreateAttachment, CreateMessage, EventHandler, GatewayIntents, Message, Ready};
+use serenity::async_trait;
+
+use crate::commands::overlay::{parse_styles, render_overlay, OverlayOutcome, OverlayTiming, MAX_STACKED_OVERLAYS};
+use crate::utils::live_config::LiveConfig;
+use crate::utils::messages::Messages;
+use crate::utils::overlay_assets::OverlayAssets;
+use crate::utils::rate_limiter::RateLimiter;
+use crate::utils::worker_pool::ImageWorkerPool;
+
+/// The command that asks for an overlay on Discord, sent with the image attached, e.g. `!degenme hands`.
+pub const COMMAND: &str = "!degenme";
+
+/// The Discord event handler, holding the parts of the overlay pipeline it shares with the Telegram bot.
+struct Handler {
+    live_config: Arc<LiveConfig>,
+    worker_pool: Arc<ImageWorkerPool>,
+    rate_limiter: Arc<RateLimiter>,
+    max_file_size_bytes: u32,
+    max_processing_time: Duration,
+}
+
+#[async_trait]
+impl EventHandler for Handler {
+    async fn ready(&self, _ctx: Context, ready: Ready) {
+        info!("Connected to Discord as {}", ready.user.name);
+    }
+
+    async fn message(&self, ctx: Context, msg: Message) {
+        if msg.author.bot {
+            return;
+        }
+        let Some(args) = parse_command(&msg.content) else {
+            return;
+        };
+        if let Err(e) = self.degenme(&ctx, &msg, args).await {
+            error!("Failed to answer {} in Discord channel {}: {}", COMMAND, msg.channel_id, e);
+        }
+    }
+}
+
+impl Handler {
+    /// Applies the requested overlays to the image attached to a `!degenme` message and replies with the result.
+    ///
+    /// The styles are checked and the rate limit is applied before anything is downloaded, like `/degenme` on
+    /// Telegram. Discord has no prompt to reply to, so the image must be attached to the command itself.
+    ///
+    /// # Arguments
+    /// * `ctx` - The Discord context, used to reply.
+    /// * `msg` - The message that triggered the command.
+    /// * `args` - The text after the command: the overlay styles to stack, if any.
+    ///
+    /// # Returns
+    /// A `serenity::Result` indicating whether the reply could be sent.
+    async fn degenme(&self, ctx: &Context, msg: &Message, args: &str) -> serenity::Result<()> {
+        let assets = self.live_config.assets();
+        // Discord has no chat overlays, and doesn't say which language its users speak, so the defaults are used
+        let overlay_assets = assets.chat_overlays.default_overlays();
+        let messages = assets.localization.default_messages();
+
+        let Some(attachment) = msg.attachments.iter().find(|attachment| is_image(attachment)) else {
+            msg.reply(&ctx.http, messages.discord_usage()).await?;
+            return Ok(());
+        };
+        if let Some(problem) = style_problem(overlay_assets, args, messages) {
+            msg.reply(&ctx.http, problem).await?;
+            return Ok(());
+        }
+
+        let key = format!("discord:{}:{}", msg.channel_id, msg.author.id);
+        if !self.rate_limiter.check_rate_limit(&key).await {
+            let wait = self.rate_limiter.time_until_allowed(&key).await;
+            msg.reply(&ctx.http, messages.rate_limited(wait)).await?;
+            return Ok(());
+        }
+        if attachment.size > self.max_file_size_bytes {
+            warn!("Discord attachment is {} bytes, over the {} byte limit, not downloading it", attachment.size, self.max_file_size_bytes);
+            msg.reply(&ctx.http, messages.file_too_large(self.max_file_size_bytes)).await?;
+            return Ok(());
+        }
+
+        info!("Downloading Discord attachment {}", attachment.filename);
+        let image_data = match attachment.download().await {
+            Ok(image_data) => image_data,
+            Err(e) => {
+                error!("Failed to download Discord attachment: {}", e);
+                msg.reply(&ctx.http, messages.download_failed()).await?;
+                return Ok(());
+            }
+        };
+
+        let styles = parse_styles(args);
+        let render_assets = Arc::clone(overlay_assets);
+        let rendered = self.worker_pool.submit(move || render_overlay(&render_assets, &image_data, &styles, &mut OverlayTiming::new()));
+        // The render can't be cancelled, so one that overruns is abandoned and its worker thread finishes it unseen
+        let outcome = match tokio::time::timeout(self.max_processing_time, rendered).await {
+            Ok(Ok(outcome)) => outcome,
+            Ok(Err(_)) => {
+                error!("Image worker pool dropped the Discord overlay job, it may have panicked");
+                OverlayOutcome::OverlayFailed
+            }
+            Err(_) => {
+                warn!("Discord overlay took longer than {:?}, abandoning it", self.max_processing_time);
+                OverlayOutcome::TimedOut
+            }
+        };
+
+        match outcome {
+            OverlayOutcome::Success(buffer) => {
+                let file = CreateAttachment::bytes(buffer, overlay_assets.output_file_name(Some(msg.author.name.as_str())));
+                msg.channel_id.send_message(&ctx.http, CreateMessage::new().reference_message(msg).add_file(file)).await?;
+            }
+            OverlayOutcome::TooSmall => {
+                msg.reply(&ctx.http, messages.too_small()).await?;
+            }
+            OverlayOutcome::TimedOut => {
+                msg.reply(&ctx.http, messages.took_too_long()).await?;
+            }
+            OverlayOutcome::DecodeFailed(Some(format)) if format != "JPEG" && format != "PNG" => {
+                msg.reply(&ctx.http, messages.unsupported_format(format)).await?;
+            }
+            OverlayOutcome::DecodeFailed(_) => {
+                msg.reply(&ctx.http, messages.decode_failed()).await?;
+            }
+            OverlayOutcome::Corrupted => {
+                msg.reply(&ctx.http, messages.corrupted()).await?;
+            }
+            outcome => {
+                error!("Failed to render Discord overlay: {}", outcome.label());
+                msg.reply(&ctx.http, messages.overlay_failed()).await?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Checks the styles named in a `!degenme` command, like the Telegram bot does for `/degenme`.
+///
+/// # Arguments
+/// * `overlay_assets` - The overlays the styles are looked up in.
+/// * `args` - The text after the command.
+/// * `messages` - The messages the problem is explained in.
+///
+/// # Returns
+/// The message explaining what's wrong with the styles, or `None` if they can all be used.
+fn style_problem(overlay_assets: &OverlayAssets, args: &str, messages: &Messages) -> Option<String> {
+    let styles = parse_styles(args);
+    if styles.len() > MAX_STACKED_OVERLAYS {
+        return Some(messages.too_many_styles(MAX_STACKED_OVERLAYS));
+    }
+    let available = overlay_assets.styles();
+    styles.iter()
+        .find(|style| !available.contains(style))
+        .map(|style| messages.unknown_style(style, &available))
+}
+
+/// Returns the text after `!degenme`, or `None` if the message isn't the command.
+///
+/// # Arguments
+/// * `content` - The text of the message.
+///
+/// # Returns
+/// The trimmed arguments, which may be empty.
+fn parse_command(content: &str) -> Option<&str> {
+    let rest = content.trim().strip_prefix(COMMAND)?;
+    (rest.is_empty() || rest.starts_with(char::is_whitespace)).then(|| rest.trim())
+}
+
+/// Checks whether an attachment is an image, going by the content type Discord reports for it.
+fn is_image(attachment: &Attachment) -> bool {
+    attachment.content_type.as_deref().is_some_and(|content_type| content_type.starts_with("image/"))
+}
+
+/// Connects to Discord and answers `!degenme` messages until the connection ends for good.
+///
+/// The overlays, worker pool, rate limiter and messages are the ones the Telegram bot uses, so both platforms share the
+/// same limits and image workers, and a config reload applies to both. The `!degenme` rate limit is applied per Discord channel and user. Errors are logged
+/// rather than returned, since the Telegram bot and the web server keep running without Discord.
+///
+/// # Arguments
+/// * `token` - The Discord bot token.
+/// * `live_config` - The reloadable config, holding the overlays to choose from and the messages sent to users.
+/// * `worker_pool` - The worker pool overlays are rendered on.
+/// * `rate_limiter` - The rate limiter shared with `/degenme`.
+/// * `max_file_size_bytes` - The largest attachment, in bytes, that will be downloaded.
+/// * `max_processing_time` - How long an image may take to render before the user is told it took too long.
+pub async fn run(token: String, live_config: Arc<LiveConfig>, worker_pool: Arc<ImageWorkerPool>, rate_limiter: Arc<RateLimiter>, max_file_size_bytes: u32, max_processing_time: Duration) {
+    let intents = GatewayIntents::GUILD_MESSAGES | GatewayIntents::DIRECT_MESSAGES | GatewayIntents::MESSAGE_CONTENT;
+    let handler = Handler { live_config, worker_pool, rate_limiter, max_file_size_bytes, max_processing_time };
+    let mut client = match Client::builder(&token, intents).event_handler(handler).await {
+        Ok(client) => client,
+        Err(e) => {
+            error!("Failed to create the Discord client: {}", e);
+            return;
+        }
+    };
+    if let Err(e) = client.start().await {
+        error!("Discord client stopped: {}", e);
+    }
+}
@@ -0,0 +1,10 @@
+//! The DegenBot library, shared by the bot itself and the `degen-render` command line tool.
+//!
+//! The bot's entry point lives in `main.rs`; everything it is built from, including the Telegram-free overlay pipeline
+//! in `commands::overlay::render_overlay`, is exposed here so other binaries can reuse it.
+
+pub mod commands;
+pub mod config;
+pub mod discord;
+pub mod render_api;
+pub mod utils;
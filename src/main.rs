@@ -1,32 +1,77 @@
 use log::info;
 use teloxide::prelude::*;
-use teloxide::types::{ChatId, MessageId, UserId};
+use teloxide::RequestError;
+use teloxide::dispatching::{ShutdownToken, UpdateHandler};
+use teloxide::types::{ChatId, UserId};
 use thiserror::Error;
-use axum::{routing::get, Router};
+use axum::{extract::State, routing::{get, post}, Router};
+use axum::extract::{DefaultBodyLimit, Multipart, Query};
+use axum::http::HeaderMap;
 use axum::response::Html;
+use axum::http::header;
+#[cfg(not(feature = "local"))]
 use shuttle_axum::ShuttleAxum;
 use tower_http::trace::TraceLayer;
 use std::sync::Arc;
-use tokio::sync::Mutex;
+use std::sync::atomic::{AtomicBool, Ordering};
+use tokio::sync::{Mutex, Semaphore};
 use std::collections::HashMap;
-use tokio::time::Duration;
+use tokio::time::{Duration, Instant};
+#[cfg(not(feature = "local"))]
 use shuttle_runtime::SecretStore;
+use url::Url;
+use std::path::{Path, PathBuf};
 
-mod config;
-mod commands;
-mod utils;
+use degenbot::{commands, config, utils};
+use degenbot::render_api::{RenderApi, RenderQuery};
 
 use crate::utils::queue::{Queue, QueueItem};
 use crate::utils::rate_limiter::RateLimiter;
-use crate::utils::cleanup::cleanup_expired_overlays;
+use crate::utils::daily_quota::DailyQuota;
+use crate::utils::cleanup::{cleanup_expired_overlays, jittered_interval};
+use crate::utils::overlay_assets::{OverlayAssets, OverlayAssetsError, OVERLAY_DIR};
+use crate::utils::chat_overlays::ChatOverlays;
+use crate::utils::worker_pool::ImageWorkerPool;
+use crate::utils::url_download::{find_image_url, PublicResolver};
+use crate::utils::recent_results::RecentResults;
+use crate::utils::restricted_chats::RestrictedChats;
+use crate::utils::source_cache::SourceCache;
+use crate::utils::result_cache::ResultCache;
+use crate::utils::download_cache::DownloadCache;
+use crate::utils::media_groups::{MediaGroups, MEDIA_GROUP_WAIT};
+use crate::utils::metrics::OverlayMetrics;
+use crate::utils::banned_users::BannedUsers;
+use crate::utils::archive::{OverlayArchive, S3Archive};
+use crate::utils::audit::AuditLogger;
+use crate::utils::messages::{Localization, Messages};
+use crate::utils::pause::PauseSwitch;
+use crate::utils::seen_messages::SeenMessages;
+use crate::utils::watchdog::{watch, Respawn};
+use crate::utils::live_config::{LiveAssets, LiveConfig, LiveSettings};
+use crate::utils::sender::MessageSender;
+use crate::utils::telegram::{bot_with_timeout, topic_thread_id};
+use crate::commands::overlay::{ProcessingOptions, ProcessorContext, RequestContext};
+
+/// How long to wait for a connection to the Telegram file server or an image link before giving up on a download.
+const HTTP_CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+/// How long to wait for each read from the Telegram file server or an image link before giving up on a download.
+const HTTP_READ_TIMEOUT: Duration = Duration::from_secs(30);
+/// How long to wait before restarting a dispatcher that stopped, doubled after each restart.
+const DISPATCHER_MIN_BACKOFF: Duration = Duration::from_secs(1);
+/// The longest wait before restarting a dispatcher that stopped.
+const DISPATCHER_MAX_BACKOFF: Duration = Duration::from_secs(5 * 60);
+/// How long a dispatcher has to run before its restart wait goes back to `DISPATCHER_MIN_BACKOFF`.
+const DISPATCHER_STABLE_AFTER: Duration = Duration::from_secs(10 * 60);
 
 #[derive(Debug, Error)]
 /// Represents errors that can occur in the Telegram bot application.
 ///
-/// This enum defines two types of errors that can be returned by the bot:
+/// This enum defines three types of errors that can be returned by the bot:
 ///
 /// - `TeloxideError`: Represents errors that occur when making requests to the Telegram API using the Teloxide library.
 /// - `IoError`: Represents errors that occur when performing I/O operations, such as reading or writing files.
+/// - `ConfigError`: Represents errors loading or validating the configuration, when running without Shuttle.
+/// - `OverlayAssetsError`: Represents overlay images that are missing or can't be decoded at startup.
 ///
 /// These errors are used throughout the application to handle various failure scenarios and provide meaningful error messages to the user or the application's logging system.
 enum BotError {
@@ -34,150 +79,1030 @@ enum BotError {
     TeloxideError(#[from] teloxide::RequestError),
     #[error("IO error: {0}")]
     IoError(#[from] std::io::Error),
+    #[error("Config error: {0}")]
+    ConfigError(#[from] config::ConfigError),
+    #[error("Overlay error: {0}")]
+    OverlayAssetsError(#[from] OverlayAssetsError),
+}
+
+/// The shared state handed to the message handler for every incoming message.
+///
+/// Cloning a `BotState` is cheap, since every field is either an `Arc` or a small `Copy` value.
+#[derive(Clone)]
+struct BotState {
+    pending_overlays: commands::PendingOverlays,
+    rate_limiter: Arc<RateLimiter>,
+    daily_quota: Arc<DailyQuota>,
+    message_queue: Arc<Queue<Message>>,
+    feedback_rate_limiter: Arc<RateLimiter>,
+    queue_rate_limiter: Arc<RateLimiter>,
+    history_rate_limiter: Arc<RateLimiter>,
+    archive: Option<Arc<dyn OverlayArchive>>,
+    history_limit: usize,
+    admin_chat_id: Option<ChatId>,
+    bot_username: Option<Arc<str>>,
+    pending_confirmations: commands::overlay::PendingConfirmations,
+    live_config: Arc<LiveConfig>,
+    admin_user_ids: Arc<[UserId]>,
+    banned_users: Arc<BannedUsers>,
+    pause_switch: Arc<PauseSwitch>,
+    restricted_chats: Arc<RestrictedChats>,
+    worker_pool: Arc<ImageWorkerPool>,
+    random_samples: Arc<[PathBuf]>,
+    audit_logger: Arc<AuditLogger>,
+    start_image: Option<Arc<Vec<u8>>>,
+    seen_messages: Arc<SeenMessages>,
+    media_groups: Arc<MediaGroups>,
+    overlay_requests: Arc<RequestContext>,
+    overlay_aliases: Arc<[String]>,
 }
 
+/// The parts of the overlay pipeline shared by every enabled bot, so Telegram and Discord requests use the same
+/// overlays, image workers, rate limit and messages, and a config reload applies to all of them.
+#[derive(Clone)]
+struct SharedPipeline {
+    live_config: Arc<LiveConfig>,
+    daily_quota: Arc<DailyQuota>,
+    worker_pool: Arc<ImageWorkerPool>,
+    rate_limiter: Arc<RateLimiter>,
+}
+
+#[cfg(not(feature = "local"))]
 #[shuttle_runtime::main]
-/// This is the main entry point for the Telegram bot application. It sets up the necessary components, including the Telegram bot, rate limiter, message queue, and pending overlays, and starts the bot's message handler and cleanup tasks.
+/// This is the main entry point for the Telegram bot application when it is deployed to Shuttle, which is the default.
 ///
 /// The `main` function is marked with the `#[shuttle_runtime::main]` attribute, which indicates that it is the entry point for the Shuttle runtime. It takes a `SecretStore` parameter, which is used to retrieve the Telegram bot token from the environment.
 ///
-/// The function first initializes the logger, then loads the application configuration. If the Telegram bot is enabled in the configuration, it creates the Telegram bot instance, initializes the necessary data structures (pending overlays, message IDs, rate limiter, and message queue), and sets up the message handler and cleanup tasks.
-///
-/// The message handler is responsible for processing incoming messages from the Telegram bot, including handling specific commands and enqueuing messages with photos for later processing. The cleanup task periodically checks for and removes expired overlay requests.
-///
-/// Finally, the function sets up an Axum router with a single route for the root path, which serves a simple HTML response. The router is then returned as the result of the `main` function, which is used by the Shuttle runtime to deploy the application.
+/// The function first loads the application configuration and initializes the logger with `load_validated_config`, returning an error if it can't be loaded or fails validation. It then starts the bot with `build_router` and returns the router, which is used by the Shuttle runtime to deploy the application.
 async fn main(#[shuttle_runtime::Secrets] secrets: SecretStore) -> ShuttleAxum {
-    let _ = pretty_env_logger::try_init();
+    let config = load_validated_config().map_err(shuttle_runtime::CustomError::new)?;
     info!("Starting bot...");
+    let router = build_router(config, secrets.get("TELEGRAM_BOT_TOKEN"), secrets.get("DISCORD_BOT_TOKEN")).await.map_err(shuttle_runtime::CustomError::new)?;
+    Ok(router.into())
+}
+
+#[cfg(feature = "local")]
+#[tokio::main]
+/// This is the main entry point for the Telegram bot application when it is built with the `local` feature, for running
+/// it with `cargo run --features local` without Shuttle.
+///
+/// It loads the configuration and starts the bot like the Shuttle entry point, but reads the Telegram bot token from
+/// the `TELEGRAM_BOT_TOKEN` environment variable instead of Shuttle's secrets, and serves the router itself on
+/// `web.bind_addr` with `axum::serve`.
+async fn main() -> Result<(), BotError> {
+    let config = load_validated_config()?;
+    info!("Starting bot without Shuttle...");
+    let bind_addr = config.web.bind_addr.clone();
+    let router = build_router(config, std::env::var("TELEGRAM_BOT_TOKEN").ok(), std::env::var("DISCORD_BOT_TOKEN").ok()).await?;
+
+    let listener = tokio::net::TcpListener::bind(&bind_addr).await?;
+    info!("Web server listening on http://{}", bind_addr);
+    axum::serve(listener, router).await?;
+    Ok(())
+}
 
+/// Loads the application configuration from the path in `DEGENBOT_CONFIG`, or "config.toml" by default, sets up the
+/// logger from its `[logging]` section, and validates it, logging the error if either fails.
+///
+/// If the config can't be loaded, the logger is set up with the default `[logging]` settings so the error is still
+/// logged.
+fn load_validated_config() -> Result<config::Config, config::ConfigError> {
     let config = config::load_config();
+    match &config {
+        Ok(config) => utils::logging::init(&config.logging),
+        Err(_) => utils::logging::init(&config::LoggingConfig::default()),
+    }
+    // The logger is only set up once the config is read, so a missing file is reported here
+    if config.is_ok() && !config::config_path().exists() {
+        log::warn!("Config file {:?} not found, using the default config with the Telegram bot disabled", config::config_path());
+    }
+    config
+        .and_then(|config| config.validate().map(|_| config))
+        .inspect_err(|e| log::error!("{}", e))
+}
+
+/// Starts the Telegram bot, if it is enabled, and builds the web server's router.
+///
+/// If the Telegram bot is enabled in the configuration, it creates the Telegram bot instance, initializes the necessary data structures (pending overlays, message IDs, rate limiter, and message queue), and sets up the message handler and cleanup tasks.
+///
+/// The message handler is responsible for processing incoming messages from the Telegram bot, including handling specific commands and enqueuing messages with photos for later processing. The cleanup task periodically checks for and removes expired overlay requests.
+///
+/// If inline mode is enabled, a sample overlay is rendered at startup, inline queries are answered with it, and the web server serves it.
+///
+/// While the Telegram bot is enabled, the web server also serves the sizes of the images it renders at `/metrics`.
+///
+/// Finally, the function sets up an Axum router with a route for the root path, which serves a simple HTML response redirecting to the configured `web.redirect_url`.
+///
+/// # Arguments
+/// * `config` - The validated application configuration.
+/// * `bot_token` - The Telegram bot token, which must be set if the Telegram bot is enabled.
+/// * `discord_token` - The Discord bot token, which must be set if the Discord bridge is enabled.
+///
+/// # Returns
+/// The router for the web server, or `BotError::OverlayAssetsError` if any overlay image is missing or can't be decoded.
+async fn build_router(config: config::Config, bot_token: Option<String>, discord_token: Option<String>) -> Result<Router, BotError> {
+    let mut inline_sample: Option<Arc<Vec<u8>>> = None;
+    let mut overlay_metrics: Option<Arc<OverlayMetrics>> = None;
+
+    let pipeline = if config.telegram.enabled || config.discord.enabled || config.web.render_secret.is_some() {
+        let overlay_assets = OverlayAssets::from_config(Path::new(OVERLAY_DIR), &config.processing);
+        // A broken overlay would fail every request that picks it, so refuse to start instead
+        overlay_assets.check().inspect_err(|e| log::error!("{}", e))?;
+        let chat_overlay_dirs = config.telegram.chat_overlay_dirs().into_iter()
+            .map(|(chat_id, dir)| (ChatId(chat_id), dir))
+            .collect();
+        let chat_overlays = ChatOverlays::load(Arc::new(overlay_assets), &chat_overlay_dirs, &config.processing)
+            .inspect_err(|e| log::error!("{}", e))?;
+        let localization = Localization::load(
+            Path::new(&config.telegram.messages_dir),
+            &config.telegram.default_language,
+            config.telegram.use_user_language,
+        ).with_chat_processing(config.telegram.chat_processing_messages());
+        let rate_limiter = Arc::new(config.limits.overlay_rate_limiter());
+        let daily_quota = Arc::new(DailyQuota::new(config.limits.max_overlays_per_day));
+        let live_config = Arc::new(LiveConfig::new(
+            LiveSettings::from_config(&config),
+            LiveAssets::new(chat_overlays, localization),
+            Arc::clone(&daily_quota),
+            Arc::clone(&rate_limiter),
+        ));
+        #[cfg(unix)]
+        tokio::spawn(reload_on_sighup(Arc::clone(&live_config)));
+        Some(SharedPipeline {
+            live_config,
+            daily_quota,
+            worker_pool: Arc::new(ImageWorkerPool::new(config.processing.image_workers)),
+            rate_limiter,
+        })
+    } else {
+        None
+    };
 
     if config.telegram.enabled {
-        let bot_token = secrets.get("TELEGRAM_BOT_TOKEN")
-            .expect("TELEGRAM_BOT_TOKEN secret not found");
-        let bot = Bot::new(&bot_token);
+        let SharedPipeline { live_config, daily_quota, worker_pool, rate_limiter } = pipeline.clone()
+            .expect("the pipeline is built when the Telegram bot is enabled");
+        let bot_token = bot_token.expect("TELEGRAM_BOT_TOKEN secret not found");
+        let request_timeout = Duration::from_secs(config.telegram.request_timeout_secs);
+        let bot = bot_with_timeout(&bot_token, request_timeout);
+        let overlay_aliases: Arc<[String]> = config.telegram.overlay_commands.clone().into();
 
         let pending_overlays: commands::PendingOverlays = Arc::new(Mutex::new(HashMap::new()));
-        let message_ids: Arc<Mutex<HashMap<(ChatId, UserId), MessageId>>> = Arc::new(Mutex::new(HashMap::new()));
-        let rate_limiter = Arc::new(RateLimiter::new(5, Duration::from_secs(60))); // 5 requests per minute
+        let rate_limit_state_path = config.limits.rate_limit_state_path.as_deref().map(PathBuf::from);
+        if let Some(path) = &rate_limit_state_path {
+            rate_limiter.restore(path).await;
+        }
         let message_queue = Arc::new(Queue::<Message>::new());
+        let pending_confirmations: commands::overlay::PendingConfirmations = Arc::new(Mutex::new(HashMap::new()));
+        let recent_results = Arc::new(RecentResults::new(256)); // Remember the last 256 results sent
+        let pause_switch = Arc::new(PauseSwitch::new());
+        let restricted_chats = Arc::new(RestrictedChats::new());
+        let source_cache = Arc::new(SourceCache::new(32, Duration::from_secs(10 * 60))); // Keep the last 32 sources for 10 minutes
+        let result_cache = Arc::new(ResultCache::new(
+            config.processing.result_cache_entries,
+            usize::try_from(config.processing.result_cache_max_bytes).unwrap_or(usize::MAX),
+        ));
+        let download_cache = Arc::new(DownloadCache::new(
+            usize::try_from(config.processing.download_cache_max_bytes).unwrap_or(usize::MAX),
+        ));
+        let media_groups = Arc::new(MediaGroups::new(config.processing.max_album_size));
+        // A broken archive shouldn't stop the bot, results just aren't archived
+        let archive: Option<Arc<dyn OverlayArchive>> = if config.archive.enabled {
+            match S3Archive::new(&config.archive) {
+                Ok(archive) => Some(Arc::new(archive)),
+                Err(e) => {
+                    log::error!("{}, results won't be archived", e);
+                    None
+                }
+            }
+        } else {
+            None
+        };
+        let audit_logger = Arc::new(if config.telegram.audit_enabled {
+            AuditLogger::open_jsonl(Path::new(&config.telegram.audit_log_path))
+        } else {
+            AuditLogger::disabled()
+        });
+        let metrics = Arc::new(OverlayMetrics::new());
+        overlay_metrics = Some(Arc::clone(&metrics));
+        // Redirects are followed by hand when downloading image links, so every hop can be checked, and hosts that
+        // resolve to internal addresses are refused when connecting
+        let http_client = reqwest::Client::builder()
+            .connect_timeout(HTTP_CONNECT_TIMEOUT)
+            .read_timeout(HTTP_READ_TIMEOUT)
+            .redirect(reqwest::redirect::Policy::none())
+            .dns_resolver(Arc::new(PublicResolver))
+            .build()
+            .expect("Failed to build HTTP client");
+
+        let mut inline_sample_url = None;
+        if config.inline.enabled {
+            let sample_url = config.inline.public_url.as_deref()
+                .and_then(|public_url| Url::parse(public_url).and_then(|url| url.join(commands::inline::SAMPLE_ROUTE)).ok());
+            // The sample is rendered once, so it keeps the overlays the bot started with
+            let sample_assets = Arc::clone(live_config.assets().chat_overlays.default_overlays());
+            let sample_image = config.inline.sample_image.clone();
+            let sample = worker_pool.submit(move || commands::inline::render_sample(&sample_assets, sample_image.as_deref().map(Path::new)))
+                .await
+                .unwrap_or_else(|_| Err(opencv::Error::new(opencv::core::StsError, "Image worker pool dropped the job")));
+            match (sample_url, sample) {
+                (Some(sample_url), Ok(sample)) => {
+                    info!("Inline mode enabled, serving sample overlay at {}", sample_url);
+                    inline_sample = Some(Arc::new(sample));
+                    inline_sample_url = Some(Arc::new(sample_url));
+                }
+                (None, _) => log::error!("Inline mode is enabled but inline.public_url is missing or invalid"),
+                (_, Err(e)) => log::error!("Failed to render inline sample overlay: {}", e),
+            }
+        }
 
-        let handler_pending_overlays = Arc::clone(&pending_overlays);
-        let handler_message_ids = Arc::clone(&message_ids);
-        let handler_rate_limiter = Arc::clone(&rate_limiter);
-        let handler_message_queue = Arc::clone(&message_queue);
+        let state = BotState {
+            pending_overlays: Arc::clone(&pending_overlays),
+            rate_limiter: Arc::clone(&rate_limiter),
+            daily_quota: Arc::clone(&daily_quota),
+            message_queue: Arc::clone(&message_queue),
+            feedback_rate_limiter: Arc::new(RateLimiter::new_token_bucket(3.0 / 3600.0, 3)), // 3 per hour
+            queue_rate_limiter: Arc::new(RateLimiter::new(1, Duration::from_secs(30))), // 1 per chat every 30 seconds
+            history_rate_limiter: Arc::new(RateLimiter::new(1, Duration::from_secs(60))), // 1 per user every minute
+            archive: archive.clone(),
+            history_limit: config.archive.history_limit,
+            admin_chat_id: config.telegram.admin_chat_id.map(ChatId),
+            bot_username: resolve_bot_username(&bot, config.telegram.bot_username.clone()).await.map(Arc::from),
+            pending_confirmations: Arc::clone(&pending_confirmations),
+            live_config: Arc::clone(&live_config),
+            admin_user_ids: config.telegram.admin_user_ids.iter().copied().map(UserId).collect(),
+            banned_users: Arc::new(BannedUsers::load(
+                &config.telegram.banned_user_ids,
+                config.telegram.banned_users_path.as_deref().map(PathBuf::from),
+            ).await),
+            pause_switch: Arc::clone(&pause_switch),
+            restricted_chats: Arc::clone(&restricted_chats),
+            worker_pool: Arc::clone(&worker_pool),
+            random_samples: config.processing.random_sample_dir.as_deref()
+                .map(|dir| commands::random::scan_samples(Path::new(dir)))
+                .unwrap_or_default()
+                .into(),
+            audit_logger: Arc::clone(&audit_logger),
+            start_image: config.telegram.start_image_path.as_deref()
+                .and_then(|path| commands::start::load_start_image(Path::new(path))),
+            seen_messages: Arc::new(SeenMessages::new(1024, Duration::from_secs(10 * 60))), // Ignore redeliveries for 10 minutes
+            media_groups: Arc::clone(&media_groups),
+            overlay_requests: Arc::new(RequestContext {
+                pending_overlays: Arc::clone(&pending_overlays),
+                rate_limiter: Arc::clone(&rate_limiter),
+                daily_quota: Arc::clone(&daily_quota),
+                live_config: Arc::clone(&live_config),
+            }),
+            overlay_aliases: Arc::clone(&overlay_aliases),
+        };
 
-        let handler = dptree::entry()
+        let callback_pending_confirmations = Arc::clone(&pending_confirmations);
+        let callback_message_queue = Arc::clone(&message_queue);
+        let callback_live_config = Arc::clone(&live_config);
+
+        let mut handler = dptree::entry()
             .branch(Update::filter_message().endpoint(move |bot: Bot, msg: Message| {
-                let pending_overlays = Arc::clone(&handler_pending_overlays);
-                let message_ids = Arc::clone(&handler_message_ids);
-                let rate_limiter = Arc::clone(&handler_rate_limiter);
-                let message_queue = Arc::clone(&handler_message_queue);
+                let state = state.clone();
+                async move {
+                    message_handler(bot, msg, state).await
+                }
+            }))
+            .branch(Update::filter_callback_query().endpoint(move |bot: Bot, query: CallbackQuery| {
+                let pending_confirmations = Arc::clone(&callback_pending_confirmations);
+                let message_queue = Arc::clone(&callback_message_queue);
+                let assets = callback_live_config.assets();
+                let expiration = callback_live_config.get().overlay_expiration;
+                async move {
+                    commands::overlay::handle_callback(bot, query, pending_confirmations, message_queue, expiration, &assets.localization).await
+                }
+            }));
+        if let Some(sample_url) = inline_sample_url {
+            let inline_live_config = Arc::clone(&live_config);
+            handler = handler.branch(Update::filter_inline_query().endpoint(move |bot: Bot, query: InlineQuery| {
+                let sample_url = Arc::clone(&sample_url);
+                let assets = inline_live_config.assets();
                 async move {
-                    message_handler(bot, msg, pending_overlays, message_ids, rate_limiter, message_queue).await
+                    commands::inline::handle_inline_query(bot, query, sample_url, &assets.localization).await
                 }
             }));
+        }
 
-        tokio::spawn(async move {
-            Dispatcher::builder(bot, handler)
-                .enable_ctrlc_handler()
-                .build()
-                .dispatch()
-                .await;
+        let dispatcher_message_queue = Arc::clone(&message_queue);
+        let dispatcher_rate_limiter = Arc::clone(&rate_limiter);
+        let dispatcher_rate_limit_state_path = rate_limit_state_path.clone();
+        let dispatcher_task = tokio::spawn(async move {
+            supervise_dispatcher(bot, handler).await;
+            // No more messages will arrive, let the queue processor drain what's left and stop
+            dispatcher_message_queue.close();
+            if let Some(path) = dispatcher_rate_limit_state_path {
+                save_rate_limits(&dispatcher_rate_limiter, &path).await;
+            }
         });
+        // The dispatcher restarts itself after an error and only returns on shutdown, so it is never restarted here
+        watch("dispatcher", dispatcher_task, || true, None, Arc::clone(&metrics));
+        let restart_background_tasks = config.telegram.restart_background_tasks;
+
+        // Spawn a task to save the rate limiter's state, so a restart doesn't reset it
+        if let Some(path) = rate_limit_state_path {
+            let save_rate_limiter = Arc::clone(&rate_limiter);
+            tokio::spawn(async move {
+                loop {
+                    tokio::time::sleep(Duration::from_secs(60)).await; // Save every minute
+                    save_rate_limits(&save_rate_limiter, &path).await;
+                }
+            });
+        }
 
         // Spawn a task to clean up expired overlay requests
-        let cleanup_bot = Bot::new(&bot_token);
+        let cleanup_bot = bot_with_timeout(&bot_token, request_timeout);
         let cleanup_pending_overlays = Arc::clone(&pending_overlays);
-        tokio::spawn(async move {
-            loop {
-                tokio::time::sleep(Duration::from_secs(60)).await; // Run every minute
-                cleanup_expired_overlays(cleanup_bot.clone(), cleanup_pending_overlays.clone()).await;
-            }
-        });
+        let notify_on_expiry = config.telegram.notify_on_expiry;
+        let expiry_notice_limiter = Arc::new(RateLimiter::new(1, utils::cleanup::EXPIRY_NOTICE_INTERVAL)); // 1 notice per chat per interval
+        let cleanup_live_config = Arc::clone(&live_config);
+        let cleanup_interval = Duration::from_secs(config.telegram.cleanup_interval_secs);
+        let cleanup_jitter = Duration::from_secs(config.telegram.cleanup_jitter_secs);
+        let spawn_cleanup = move || {
+            let cleanup_bot = cleanup_bot.clone();
+            let cleanup_pending_overlays = Arc::clone(&cleanup_pending_overlays);
+            let expiry_notice_limiter = Arc::clone(&expiry_notice_limiter);
+            let cleanup_live_config = Arc::clone(&cleanup_live_config);
+            tokio::spawn(async move {
+                loop {
+                    tokio::time::sleep(jittered_interval(cleanup_interval, cleanup_jitter)).await;
+                    cleanup_expired_overlays(cleanup_bot.clone(), cleanup_pending_overlays.clone(), &cleanup_live_config, notify_on_expiry, &expiry_notice_limiter).await;
+                }
+            })
+        };
+        // The cleanup loop never returns, so any exit is unexpected
+        watch("cleanup", spawn_cleanup(), || false, restart_background_tasks.then(|| Box::new(spawn_cleanup) as Respawn), Arc::clone(&metrics));
 
         // Spawn a task to process the message queue
-        let queue_bot = Bot::new(&bot_token);
-        let queue_pending_overlays = Arc::clone(&pending_overlays);
+        let queue_bot = bot_with_timeout(&bot_token, request_timeout);
         let queue_message_queue = Arc::clone(&message_queue);
-        tokio::spawn(async move {
-            process_queue(queue_bot, queue_pending_overlays, queue_message_queue).await;
+        let queue_pause_switch = Arc::clone(&pause_switch);
+        let max_concurrent_overlays = config.processing.max_concurrent_overlays;
+        let processor_context = Arc::new(ProcessorContext {
+            pending_overlays: Arc::clone(&pending_overlays),
+            http_client: http_client.clone(),
+            worker_pool: Arc::clone(&worker_pool),
+            recent_results: Arc::clone(&recent_results),
+            source_cache: Arc::clone(&source_cache),
+            result_cache: Arc::clone(&result_cache),
+            download_cache: Arc::clone(&download_cache),
+            media_groups: Arc::clone(&media_groups),
+            restricted_chats: Arc::clone(&restricted_chats),
+            archive: archive.clone(),
+            audit_logger: Arc::clone(&audit_logger),
+            metrics: Arc::clone(&metrics),
+            live_config: Arc::clone(&live_config),
+            overlay_aliases: Arc::clone(&overlay_aliases),
+            options: ProcessingOptions::from_config(&config.processing),
         });
+        let spawn_queue = move || {
+            let queue_bot = queue_bot.clone();
+            let queue_message_queue = Arc::clone(&queue_message_queue);
+            let queue_pause_switch = Arc::clone(&queue_pause_switch);
+            let processor_context = Arc::clone(&processor_context);
+            tokio::spawn(async move {
+                let worker_pool = Arc::clone(&processor_context.worker_pool);
+                process_queue(queue_bot, queue_message_queue, queue_pause_switch, max_concurrent_overlays, processor_context).await;
+                // Every queued image has been processed, so the worker threads can be stopped
+                worker_pool.shutdown();
+            })
+        };
+        // The queue processor only returns once the queue has been closed on shutdown
+        let watched_queue = Arc::clone(&message_queue);
+        watch("queue", spawn_queue(), move || watched_queue.is_closed(), restart_background_tasks.then(|| Box::new(spawn_queue) as Respawn), Arc::clone(&metrics));
     } else {
         info!("Telegram bot is disabled in config.");
     }
 
-    let router = Router::new()
-        .route("/", get(index))
+    if config.discord.enabled {
+        let pipeline = pipeline.clone().expect("the pipeline is built when the Discord bridge is enabled");
+        let discord_token = discord_token.expect("DISCORD_BOT_TOKEN secret not found");
+        tokio::spawn(degenbot::discord::run(
+            discord_token,
+            pipeline.live_config,
+            pipeline.worker_pool,
+            pipeline.rate_limiter,
+            config.processing.max_file_size_bytes,
+            Duration::from_secs(config.processing.max_processing_time_secs),
+        ));
+    }
+
+    let mut router = Router::new()
+        .route("/", get(index));
+    if let Some(sample) = inline_sample {
+        router = router.route(commands::inline::SAMPLE_ROUTE, get(move || {
+            let sample = Arc::clone(&sample);
+            async move { ([(header::CONTENT_TYPE, "image/jpeg")], sample.to_vec()) }
+        }));
+    }
+    if let Some(metrics) = overlay_metrics {
+        router = router.route("/metrics", get(move || {
+            let metrics = Arc::clone(&metrics);
+            async move { ([(header::CONTENT_TYPE, "text/plain; version=0.0.4")], metrics.render()) }
+        }));
+    }
+    if let Some(render_secret) = config.web.render_secret.clone() {
+        let pipeline = pipeline.expect("the pipeline is built when the render endpoint is enabled");
+        let render_api = Arc::new(RenderApi::new(
+            pipeline.live_config,
+            pipeline.worker_pool,
+            render_secret,
+            config.processing.max_file_size_bytes,
+            Duration::from_secs(config.processing.max_processing_time_secs),
+        ));
+        info!("Render endpoint enabled at {}", degenbot::render_api::ROUTE);
+        // Axum turns away bodies over 2 MB by default, which is smaller than most photos
+        let max_body_bytes = render_api.max_body_bytes();
+        router = router.route(degenbot::render_api::ROUTE, post(move |headers: HeaderMap, Query(query): Query<RenderQuery>, multipart: Multipart| {
+            let render_api = Arc::clone(&render_api);
+            async move { render_api.render(headers, query, multipart).await }
+        }).layer(DefaultBodyLimit::max(max_body_bytes)));
+    }
+    let router = router
+        .with_state(Arc::<str>::from(config.web.redirect_url.as_str()))
         .layer(TraceLayer::new_for_http());
 
-    Ok(router.into())
+    Ok(router)
+}
+
+/// Works out the bot's username, used to ignore commands addressed to other bots such as `/start@OtherBot`.
+///
+/// The username from the config is used if there is one; otherwise it is fetched from Telegram with `get_me`.
+/// If that fails too, `None` is returned and commands with any `@username` suffix are handled.
+async fn resolve_bot_username(bot: &Bot, configured: Option<String>) -> Option<String> {
+    if configured.is_some() {
+        return configured;
+    }
+
+    match bot.get_me().await {
+        Ok(me) => {
+            info!("Resolved bot username: {:?}", me.user.username);
+            me.user.username.clone()
+        }
+        Err(e) => {
+            log::error!("Failed to fetch bot username, commands for other bots may be handled: {}", e);
+            None
+        }
+    }
 }
 
 /// Handles incoming messages for the Telegram bot.
 ///
-/// This function is called whenever a new message is received by the bot. It checks the message text and
-/// performs the appropriate action, such as starting the bot or processing an image overlay request.
-/// If the message contains a photo, it is enqueued in the `message_queue` for later processing.
+/// This function is called whenever a new message is received by the bot. It parses the command from the message text,
+/// ignoring commands addressed to other bots with an `@username` suffix, and performs the appropriate action, such as starting the bot, forwarding feedback, or processing an image overlay request.
+/// A `/degenme <url>` command, or a reply to the overlay prompt containing an image link, is enqueued straight away so
+/// the image is downloaded from the link instead of from Telegram.
+/// If the message contains a photo, it is enqueued in the `message_queue` for later processing, and the user is told their
+/// position in line if other photos are waiting ahead of theirs. Photos larger than `confirm_above_bytes` are only
+/// enqueued once the user confirms with the inline buttons.
+/// A sticker is only enqueued if it replies to the sender's overlay prompt; static stickers are overlaid like photos,
+/// and animated or video stickers are answered with a notice that they aren't supported.
+/// `/degenme` can also be sent as any of the aliases in `overlay_commands`, such as `/degen`, with the same arguments
+/// and `@botname` handling; `/degenme` itself only works while it is one of them.
+/// `/degenme` may name overlay styles to stack, e.g. `/degenme hands,hat`; unknown styles are rejected up front.
+/// Chats with their own overlays under `[telegram.chat_overlays]` get those for `/degenme`, `/again` and `/random`.
+/// Admins can pause and resume processing with `/pause` and `/resume`; while paused, `/degenme` is answered with a
+/// notice and photos are not enqueued. Admins can also check which overlays an image of a given size would get with
+/// `/preview <width>x<height> [style]`, ban or unban users with `/ban` and `/unban`, and change the overlay rate limit with
+/// `/setlimit <max> <window_secs>`. Everything a banned user sends
+/// is ignored without a reply, before any rate limit is checked or anything is enqueued.
+/// While `max_queue_depth` images are queued, `/degenme` and `/again` are turned away with a notice.
+/// Overlay requests are ignored in chats where the bot has repeatedly been refused permission to post.
+/// `/queue` tells anyone in the chat how many images are queued and how many prompts are awaiting an image.
+/// `/random` applies an overlay to a random sample photo and is limited like `/degenme`.
+/// `/myimages` sends the sender's most recent archived results from the chat back to them, if archiving is enabled.
+/// `/again <style>` in reply to one of the bot's results re-renders that result's source image with other overlays,
+/// and is limited like `/degenme` too.
 /// The function also checks the rate limit for the user and sends a message if they are sending commands too quickly.
-async fn message_handler(
-    bot: Bot,
-    msg: Message,
-    pending_overlays: commands::PendingOverlays,
-    message_ids: Arc<Mutex<HashMap<(ChatId, UserId), MessageId>>>,
-    rate_limiter: Arc<RateLimiter>,
-    message_queue: Arc<Queue<Message>>,
-) -> ResponseResult<()> {
+/// Replies are sent in the sender's language, as picked by `Localization`.
+/// A message Telegram delivers again after it was already handled is ignored, so a photo is never overlaid twice.
+async fn message_handler(bot: Bot, msg: Message, state: BotState) -> ResponseResult<()> {
+    if !state.seen_messages.first_seen(msg.chat.id, msg.id).await {
+        info!("Ignoring message {} in chat {}, it was already handled", msg.id, msg.chat.id);
+        return Ok(());
+    }
+    if let Some(user) = msg.from() {
+        if state.banned_users.is_banned(user.id).await {
+            info!("Ignoring message {} from banned user {}", msg.id, user.id);
+            return Ok(());
+        }
+    }
+    let assets = state.live_config.assets();
+    let messages = assets.localization.for_message(&msg);
     if let Some(text) = msg.text() {
-        if text.starts_with("/start") {
-            commands::start::start(bot.clone(), msg).await?;
-        } else if text.starts_with("/degenme") {
-            let chat_id = msg.chat.id;
-            let user_id = msg.from().map(|user| user.id).unwrap_or(UserId(0));
-            
-            if rate_limiter.check_rate_limit(&format!("{}:{}", chat_id, user_id)).await {
-                commands::overlay::handle(bot.clone(), msg, pending_overlays.clone(), message_ids.clone(), rate_limiter.clone()).await;
-            } else {
-                bot.send_message(chat_id, "You're sending commands too quickly. Please wait a moment before trying again.").await?;
+        let Some(command) = commands::parse_command(text) else {
+            // A reply to the overlay prompt may carry an image link instead of a photo
+            if !state.pause_switch.is_paused() && find_image_url(text).is_some() && is_pending_reply(&msg, &state).await {
+                enqueue_overlay(&bot, msg, &state, true).await?;
+            } else if state.live_config.get().dm_nudge && msg.chat.is_private() {
+                // Newcomers often say "hi" first, and silence makes the bot look broken. Groups are left alone
+                reply_in_topic(&bot, &msg, messages.dm_nudge()).await?;
             }
+            return Ok(());
+        };
+        if !command.is_for(state.bot_username.as_deref()) {
+            return Ok(());
+        }
+
+        match command.name {
+            "start" => {
+                commands::start::start(bot.clone(), msg.clone(), messages, state.start_image.clone()).await?;
+            }
+            "feedback" => {
+                commands::feedback::feedback(bot.clone(), msg.clone(), command.args, state.admin_chat_id, state.feedback_rate_limiter.clone(), messages).await?;
+            }
+            "queue" => {
+                commands::queue::queue(bot.clone(), msg.clone(), &state.message_queue, &state.pending_overlays, &state.queue_rate_limiter, messages).await?;
+            }
+            "myimages" => {
+                let file_name = assets.chat_overlays.default_overlays().output_file_name(msg.from().and_then(|user| user.username.as_deref()));
+                commands::my_images::my_images(bot.clone(), msg.clone(), state.archive.clone(), state.history_limit, &file_name, &state.history_rate_limiter, messages).await?;
+            }
+            "pause" | "resume" => {
+                commands::admin::set_paused(bot.clone(), msg.clone(), command.name == "pause", &state.pause_switch, &state.admin_user_ids, messages).await?;
+            }
+            "ban" | "unban" => {
+                commands::admin::set_banned(bot.clone(), msg.clone(), command.args, command.name == "ban", &state.banned_users, &state.admin_user_ids, messages).await?;
+            }
+            "setlimit" => {
+                commands::admin::set_limit(bot.clone(), msg.clone(), command.args, &state.rate_limiter, &state.admin_user_ids, messages).await?;
+            }
+            "reload" => {
+                commands::admin::reload(bot.clone(), msg.clone(), &state.live_config, &state.admin_user_ids, messages).await?;
+            }
+            "preview" => {
+                commands::admin::preview(bot.clone(), msg.clone(), command.args, assets.chat_overlays.for_chat(msg.chat.id), &state.admin_user_ids, messages).await?;
+            }
+            _ if command.is_overlay(&state.overlay_aliases) => {
+                let chat_id = msg.chat.id;
+
+                if state.restricted_chats.is_suppressed(chat_id).await {
+                    info!("The bot isn't allowed to post in chat {}, ignoring /degenme", chat_id);
+                    state.audit_logger.record(&msg, "degenme", "suppressed");
+                } else if state.pause_switch.is_paused() {
+                    state.audit_logger.record(&msg, "degenme", "paused");
+                    reply_in_topic(&bot, &msg, messages.paused()).await?;
+                } else if is_swamped(&state).await {
+                    state.audit_logger.record(&msg, "degenme", "swamped");
+                    reply_in_topic(&bot, &msg, messages.swamped()).await?;
+                } else if let Some(problem) = style_problem(command.args, &assets.chat_overlays.for_chat(chat_id).styles(), messages) {
+                    state.audit_logger.record(&msg, "degenme", "invalid_style");
+                    reply_in_topic(&bot, &msg, problem).await?;
+                } else if find_image_url(command.args).is_some() {
+                    if commands::overlay::check_limits(&bot, &msg, &state.rate_limiter, &state.daily_quota, messages).await {
+                        state.audit_logger.record(&msg, "degenme", "queued");
+                        enqueue_overlay(&bot, msg.clone(), &state, true).await?;
+                    } else {
+                        state.audit_logger.record(&msg, "degenme", "limited");
+                    }
+                } else {
+                    // `handle` checks the limits itself and tells a limited user, so the request is only charged once
+                    state.audit_logger.record(&msg, "degenme", "requested");
+                    commands::overlay::handle(bot.clone(), msg.clone(), Arc::clone(&state.overlay_requests)).await;
+                }
+            }
+            "again" => {
+                let chat_id = msg.chat.id;
+                if state.restricted_chats.is_suppressed(chat_id).await {
+                    info!("The bot isn't allowed to post in chat {}, ignoring /again", chat_id);
+                } else if state.pause_switch.is_paused() {
+                    reply_in_topic(&bot, &msg, messages.paused()).await?;
+                } else if is_swamped(&state).await {
+                    reply_in_topic(&bot, &msg, messages.swamped()).await?;
+                } else if msg.reply_to_message().is_none() {
+                    reply_in_topic(&bot, &msg, messages.again_usage()).await?;
+                } else if let Some(problem) = style_problem(command.args, &assets.chat_overlays.for_chat(chat_id).styles(), messages) {
+                    reply_in_topic(&bot, &msg, problem).await?;
+                } else if commands::overlay::check_limits(&bot, &msg, &state.rate_limiter, &state.daily_quota, messages).await {
+                    enqueue_overlay(&bot, msg.clone(), &state, true).await?;
+                }
+            }
+            "random" => {
+                if state.restricted_chats.is_suppressed(msg.chat.id).await {
+                    info!("The bot isn't allowed to post in chat {}, ignoring /random", msg.chat.id);
+                } else if state.pause_switch.is_paused() {
+                    reply_in_topic(&bot, &msg, messages.paused()).await?;
+                } else if commands::overlay::check_limits(&bot, &msg, &state.rate_limiter, &state.daily_quota, messages).await {
+                    commands::random::random(bot.clone(), msg.clone(), &state.random_samples, Arc::clone(assets.chat_overlays.for_chat(msg.chat.id)), &state.worker_pool, messages).await?;
+                }
+            }
+            _ => {}
         }
     } else if msg.photo().is_some() {
-        message_queue.enqueue(QueueItem { _chat_id: msg.chat.id, _user_id: msg.from().map(|user| user.id).unwrap_or(UserId(0)), data: msg }).await;
+        if state.pause_switch.is_paused() {
+            info!("Processing is paused, ignoring photo");
+            return Ok(());
+        }
+        if state.restricted_chats.is_suppressed(msg.chat.id).await {
+            info!("The bot isn't allowed to post in chat {}, ignoring photo", msg.chat.id);
+            return Ok(());
+        }
+
+        let is_pending_reply = is_pending_reply(&msg, &state).await;
+
+        // Telegram delivers each photo of an album as a message of its own, so they're collected before it is queued
+        if let (true, Some(media_group_id)) = (is_pending_reply, msg.media_group_id()) {
+            let photo = utils::telegram::largest_photo(&msg).cloned().expect("the message has a photo");
+            if state.media_groups.add(msg.chat.id, media_group_id, msg.id, photo).await {
+                let state = state.clone();
+                tokio::spawn(async move {
+                    tokio::time::sleep(MEDIA_GROUP_WAIT).await;
+                    if let Err(e) = enqueue_overlay(&bot, msg, &state, true).await {
+                        log::error!("Failed to queue album: {}", e);
+                    }
+                });
+            }
+            return Ok(());
+        }
+
+        let photo_size = utils::telegram::largest_photo(&msg).map_or(0, |photo| photo.file.size);
+        let confirm_above_bytes = state.live_config.get().confirm_above_bytes;
+        if is_pending_reply && confirm_above_bytes > 0 && photo_size > confirm_above_bytes {
+            return commands::overlay::request_confirmation(bot, msg, state.pending_confirmations.clone(), state.live_config.get().overlay_expiration, messages).await;
+        }
+
+        enqueue_overlay(&bot, msg, &state, is_pending_reply).await?;
+    } else if msg.sticker().is_some() {
+        // Stickers are everyday chatter, so only the ones sent in reply to a /degenme prompt are queued
+        if state.pause_switch.is_paused() || state.restricted_chats.is_suppressed(msg.chat.id).await {
+            info!("Ignoring sticker while processing is paused or the chat is restricted");
+            return Ok(());
+        }
+        if is_pending_reply(&msg, &state).await {
+            enqueue_overlay(&bot, msg, &state, true).await?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Runs the dispatcher that feeds updates to `handler`, restarting it whenever it stops until Ctrl+C is pressed.
+///
+/// If the dispatcher returns or panics without a shutdown being requested, it is rebuilt and started again after a
+/// wait that starts at `DISPATCHER_MIN_BACKOFF` and doubles with each restart, up to `DISPATCHER_MAX_BACKOFF`. A
+/// dispatcher that ran for at least `DISPATCHER_STABLE_AFTER` resets the wait. The handler's closures hold the shared
+/// state, so every restarted dispatcher works with the same queue, limits and pending requests.
+///
+/// # Arguments
+/// * `bot` - The Teloxide bot instance.
+/// * `handler` - The update handler, cloned for each dispatcher.
+async fn supervise_dispatcher(bot: Bot, handler: UpdateHandler<RequestError>) {
+    let shutdown_requested = Arc::new(AtomicBool::new(false));
+    let current_token: Arc<std::sync::Mutex<Option<ShutdownToken>>> = Arc::new(std::sync::Mutex::new(None));
+    {
+        let shutdown_requested = Arc::clone(&shutdown_requested);
+        let current_token = Arc::clone(&current_token);
+        tokio::spawn(async move {
+            if let Err(e) = tokio::signal::ctrl_c().await {
+                log::error!("Failed to listen for Ctrl+C: {}", e);
+                return;
+            }
+            info!("Ctrl+C received, shutting down the dispatcher");
+            shutdown_requested.store(true, Ordering::SeqCst);
+            let token = current_token.lock().unwrap().clone();
+            // A dispatcher that isn't running, such as while waiting to restart, just isn't started again
+            if let Some(Ok(shutdown)) = token.map(|token| token.shutdown()) {
+                shutdown.await;
+            }
+        });
+    }
+
+    let mut backoff = DISPATCHER_MIN_BACKOFF;
+    loop {
+        let mut dispatcher = Dispatcher::builder(bot.clone(), handler.clone()).build();
+        *current_token.lock().unwrap() = Some(dispatcher.shutdown_token());
+        if shutdown_requested.load(Ordering::SeqCst) {
+            break;
+        }
+
+        let started = Instant::now();
+        // Dispatching in its own task turns a panic into an error here instead of ending the supervisor too
+        if let Err(e) = tokio::spawn(async move { dispatcher.dispatch().await }).await {
+            log::error!("Dispatcher panicked: {}", e);
+        }
+        if shutdown_requested.load(Ordering::SeqCst) {
+            break;
+        }
+
+        let uptime = started.elapsed();
+        if uptime >= DISPATCHER_STABLE_AFTER {
+            backoff = DISPATCHER_MIN_BACKOFF;
+        }
+        log::warn!("Dispatcher stopped after {:?}, restarting it in {:?}", uptime, backoff);
+        tokio::time::sleep(backoff).await;
+        backoff = (backoff * 2).min(DISPATCHER_MAX_BACKOFF);
+    }
+}
+
+/// Saves the rate limiter's state to `path`, logging any failure since it shouldn't stop the bot.
+async fn save_rate_limits(rate_limiter: &RateLimiter, path: &Path) {
+    if let Err(e) = rate_limiter.save(path).await {
+        log::error!("Failed to save rate limiter state to {:?}: {}", path, e);
+    }
+}
+
+/// Checks the overlay styles requested with `/degenme` or `/again`, such as `/degenme hands,hat`.
+///
+/// # Returns
+/// A message for the user, in their language, if too many styles were requested or one of them doesn't exist, or
+/// `None` if they are fine.
+fn style_problem(args: &str, available: &[String], messages: &Messages) -> Option<String> {
+    let styles = commands::overlay::parse_styles(args);
+    if styles.len() > commands::overlay::MAX_STACKED_OVERLAYS {
+        return Some(messages.too_many_styles(commands::overlay::MAX_STACKED_OVERLAYS));
+    }
+    styles.iter()
+        .find(|style| !available.contains(style))
+        .map(|style| messages.unknown_style(style, available))
+}
+
+/// Checks whether the queue is too deep to take new `/degenme` or `/again` requests, as set by `max_queue_depth`.
+///
+/// Requests are turned away with a message up front rather than queued behind a long wait. Photos sent in reply to a
+/// prompt that was already given out are still queued.
+async fn is_swamped(state: &BotState) -> bool {
+    is_too_deep(&*state.message_queue, state.live_config.get().max_queue_depth).await
+}
+
+/// Checks whether a queue holds `max_queue_depth` items or more, or never if `max_queue_depth` is `0`.
+async fn is_too_deep<T>(queue: &Queue<T>, max_queue_depth: usize) -> bool {
+    max_queue_depth > 0 && queue.len().await >= max_queue_depth
+}
+
+/// Reloads the config every time the process receives `SIGHUP`, e.g. from `kill -HUP <pid>`.
+///
+/// # Arguments
+/// * `live_config` - The reloadable settings to update.
+#[cfg(unix)]
+async fn reload_on_sighup(live_config: Arc<LiveConfig>) {
+    let mut hangups = match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::hangup()) {
+        Ok(hangups) => hangups,
+        Err(e) => {
+            log::error!("Failed to listen for SIGHUP, the config can only be reloaded with /reload: {}", e);
+            return;
+        }
+    };
+    while hangups.recv().await.is_some() {
+        info!("Received SIGHUP, reloading the config");
+        // The outcome is logged by reload, and the current settings are kept if it fails
+        let _ = live_config.reload();
+    }
+}
+
+/// Checks whether a message is a reply to the sender's pending overlay request.
+async fn is_pending_reply(msg: &Message, state: &BotState) -> bool {
+    let user_id = msg.from().map(|user| user.id).unwrap_or(UserId(0));
+    match msg.reply_to_message() {
+        Some(reply_to) => state.pending_overlays.lock().await
+            .get(&(msg.chat.id, user_id))
+            .is_some_and(|pending| pending.message_id == reply_to.id),
+        None => false,
     }
+}
+
+/// Sends a message to the chat `msg` was sent in, in the same forum topic.
+async fn reply_in_topic<S: MessageSender>(bot: &S, msg: &Message, text: impl Into<String>) -> ResponseResult<()> {
+    bot.send_message(msg.chat.id, topic_thread_id(msg), text.into()).await?;
+    Ok(())
+}
+
+/// Enqueues a message for overlay processing.
+///
+/// The processing message is only sent once the message is dequeued, so if `notify_position` is set and other images
+/// are waiting ahead of this one, the user is told their position in line now, in the topic they sent the message in.
+async fn enqueue_overlay<S: MessageSender>(bot: &S, msg: Message, state: &BotState, notify_position: bool) -> ResponseResult<()> {
+    let assets = state.live_config.assets();
+    let messages = assets.localization.for_message(&msg);
+    queue_message(bot, &state.message_queue, msg, messages, notify_position).await
+}
 
+/// Adds a message to `message_queue`, telling the user their position in line if `notify_position` is set and other
+/// images are waiting ahead of theirs.
+async fn queue_message<S: MessageSender>(bot: &S, message_queue: &Queue<Message>, msg: Message, messages: &Messages, notify_position: bool) -> ResponseResult<()> {
+    let chat_id = msg.chat.id;
+    let thread_id = topic_thread_id(&msg);
+    let user_id = msg.from().map(|user| user.id).unwrap_or(UserId(0));
+    message_queue.enqueue(QueueItem { _chat_id: chat_id, _user_id: user_id, data: msg }).await;
+
+    let position = message_queue.len().await;
+    if notify_position && position > 1 {
+        bot.send_message(chat_id, thread_id, messages.queue_position(position)).await?;
+    }
     Ok(())
 }
 
 /// Processes the message queue, handling incoming messages for the Telegram bot.
 ///
 /// This function runs in a loop, continuously dequeuing messages from the `message_queue` and processing them.
-/// For each message, it calls the `commands::overlay::process_image` function to handle the message.
-/// If an error occurs while processing a message, it is logged using `log::error`.
-/// The function also includes a short delay of 100 milliseconds between each iteration of the loop.
-async fn process_queue(bot: Bot, pending_overlays: commands::PendingOverlays, message_queue: Arc<Queue<Message>>) {
+/// Up to `max_concurrent_overlays` messages are processed at the same time: a permit is taken from a semaphore before
+/// each dequeue, and a task is spawned for the message that holds the permit until `commands::overlay::process_image`
+/// finishes. Messages are still started in the order they were queued.
+/// While an admin has paused processing with `/pause`, the dequeued message is held until `/resume`.
+/// Every message is processed with the shared `context`, so if an archive is configured every result is also uploaded
+/// to it in the background, and every result is recorded in the audit log.
+/// Messages to the user are sent in their language, from the messages in the context's `live_config`.
+/// If an error occurs while processing a message, it is logged using `log::error`. If processing panics, the panic is
+/// logged and the user is told their image couldn't be processed; the loop carries on with the next message either way.
+/// Panics in the OpenCV work itself are already caught by the `ImageWorkerPool` and reported as a failed overlay, but
+/// an OpenCV abort still takes down the whole process. An image that takes longer than `max_processing_time` to render
+/// is reported to the user as taking too long and its permit is released, although its worker thread stays busy until
+/// the render finishes.
+/// When the queue is empty, the function waits for the next message to be enqueued, and it returns once the queue has
+/// been closed and drained and every message it started has finished processing.
+async fn process_queue(bot: Bot, message_queue: Arc<Queue<Message>>, pause_switch: Arc<PauseSwitch>, max_concurrent_overlays: usize, context: Arc<ProcessorContext>) {
+    let max_concurrent_overlays = max_concurrent_overlays.max(1);
+    let semaphore = Arc::new(Semaphore::new(max_concurrent_overlays));
     loop {
-        if let Some(item) = message_queue.dequeue().await {
-            commands::overlay::process_image(bot.clone(), item.data, pending_overlays.clone()).await.unwrap_or_else(|e| {
-                log::error!("Error processing image: {:?}", e);
-            });
+        let permit = Arc::clone(&semaphore).acquire_owned().await.expect("processing semaphore is never closed");
+        let Some(item) = message_queue.dequeue().await else {
+            info!("Message queue closed, stopping queue processor");
+            break;
+        };
+        // Hold on to the message while an admin has paused processing
+        if pause_switch.is_paused() {
+            info!("Processing is paused, holding queued message until resumed");
+            pause_switch.wait_until_resumed().await;
         }
-        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        let bot = bot.clone();
+        let context = Arc::clone(&context);
+        let chat_id = item.data.chat.id;
+        let thread_id = topic_thread_id(&item.data);
+        let panic_message = context.live_config.assets().localization.for_message(&item.data).overlay_failed().to_string();
+        tokio::spawn(async move {
+            // Processing runs in a task of its own, so a panic is caught here and the user still hears back
+            let panic_bot = bot.clone();
+            let processing = tokio::spawn(commands::overlay::process_image(bot, item.data, context));
+            match processing.await {
+                Ok(Ok(())) => {}
+                Ok(Err(e)) => log::error!("Error processing image: {:?}", e),
+                Err(e) if e.is_panic() => {
+                    log::error!("Processing an image in chat {} panicked: {}", chat_id, e);
+                    if let Err(e) = MessageSender::send_message(&panic_bot, chat_id, thread_id, panic_message).await {
+                        log::error!("Failed to tell the user processing failed: {}", e);
+                    }
+                }
+                Err(e) => log::error!("Image processing task was cancelled: {}", e),
+            }
+            drop(permit);
+        });
     }
+
+    // Wait for the messages still being processed by taking back every permit
+    let _ = semaphore.acquire_many(max_concurrent_overlays as u32).await;
 }
 
-/// This function returns an HTML response that redirects the user to the "<https://degenstudios.media>" URL.
+/// This function returns an HTML response that redirects the user to the configured `web.redirect_url`, which defaults
+/// to "<https://degenstudios.media>".
 /// The response includes a meta refresh tag that automatically redirects the user, and also includes a link
 /// that the user can click if they are not automatically redirected.
-async fn index() -> Html<&'static str> {
-    Html(r#"<!DOCTYPE html>
+async fn index(State(redirect_url): State<Arc<str>>) -> Html<String> {
+    let redirect_url = escape_html_attribute(&redirect_url);
+    Html(format!(r#"<!DOCTYPE html>
 <html lang="en">
 <head>
     <meta charset="UTF-8">
-    <meta http-equiv="refresh" content="0; URL=https://degenstudios.media">
+    <meta http-equiv="refresh" content="0; URL={redirect_url}">
     <title>Redirecting...</title>
 </head>
 <body>
-    <p>If you are not redirected, <a href="https://degenstudios.media">click here</a>.</p>
+    <p>If you are not redirected, <a href="{redirect_url}">click here</a>.</p>
 </body>
-</html>"#)
+</html>"#))
+}
+
+/// Escapes a value for use inside a double-quoted HTML attribute.
+fn escape_html_attribute(value: &str) -> String {
+    value.replace('&', "&amp;")
+        .replace('"', "&quot;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use teloxide::types::MessageId;
+    use crate::utils::sender::{MockSender, SentCall};
+
+    /// Returns a queue holding `len` items.
+    async fn queue_of(len: usize) -> Queue<()> {
+        let queue = Queue::new();
+        for _ in 0..len {
+            queue.enqueue(QueueItem { _chat_id: ChatId(1), _user_id: UserId(1), data: () }).await;
+        }
+        queue
+    }
+
+    #[tokio::test]
+    async fn requests_are_turned_away_from_the_max_queue_depth() {
+        assert!(!is_too_deep(&queue_of(2), 3).await);
+        assert!(is_too_deep(&queue_of(3), 3).await);
+        assert!(is_too_deep(&queue_of(4), 3).await);
+    }
+
+    #[tokio::test]
+    async fn a_zero_max_queue_depth_never_turns_requests_away() {
+        assert!(!is_too_deep(&queue_of(100), 0).await);
+    }
+
+    /// Builds a photo message sent in topic `thread_id` of a forum supergroup.
+    fn topic_photo(message_id: i32, thread_id: i32) -> Message {
+        serde_json::from_value(serde_json::json!({
+            "message_id": message_id,
+            "message_thread_id": thread_id,
+            "is_topic_message": true,
+            "date": 1_700_000_000,
+            "chat": { "id": -100, "type": "supergroup", "title": "Degens", "is_forum": true },
+            "from": { "id": 20, "is_bot": false, "first_name": "Degen" },
+            "photo": [{ "file_id": "photo", "file_unique_id": "photo", "width": 640, "height": 480 }],
+        })).expect("a valid message")
+    }
+
+    /// Returns the state of a bot with the default config that remembers handled messages in `seen_messages`.
+    async fn bot_state(seen_messages: SeenMessages) -> BotState {
+        let config = config::Config::default();
+        let pending_overlays: commands::PendingOverlays = Arc::new(Mutex::new(HashMap::new()));
+        let rate_limiter = Arc::new(RateLimiter::new(100, Duration::from_secs(60)));
+        let daily_quota = Arc::new(DailyQuota::new(0));
+        let assets = LiveAssets::new(ChatOverlays::new(Arc::new(OverlayAssets::load(Path::new(OVERLAY_DIR)))), Localization::default());
+        let live_config = Arc::new(LiveConfig::new(LiveSettings::from_config(&config), assets, Arc::clone(&daily_quota), Arc::clone(&rate_limiter)));
+        BotState {
+            pending_overlays: Arc::clone(&pending_overlays),
+            rate_limiter: Arc::clone(&rate_limiter),
+            daily_quota: Arc::clone(&daily_quota),
+            message_queue: Arc::new(Queue::new()),
+            feedback_rate_limiter: Arc::new(RateLimiter::new(1, Duration::from_secs(60))),
+            queue_rate_limiter: Arc::new(RateLimiter::new(1, Duration::from_secs(60))),
+            history_rate_limiter: Arc::new(RateLimiter::new(1, Duration::from_secs(60))),
+            archive: None,
+            history_limit: config.archive.history_limit,
+            admin_chat_id: None,
+            bot_username: None,
+            pending_confirmations: Arc::new(Mutex::new(HashMap::new())),
+            live_config: Arc::clone(&live_config),
+            admin_user_ids: Arc::from([]),
+            banned_users: Arc::new(BannedUsers::load(&[], None).await),
+            pause_switch: Arc::new(PauseSwitch::new()),
+            restricted_chats: Arc::new(RestrictedChats::new()),
+            worker_pool: Arc::new(ImageWorkerPool::new(1)),
+            random_samples: Arc::from([]),
+            audit_logger: Arc::new(AuditLogger::disabled()),
+            start_image: None,
+            seen_messages: Arc::new(seen_messages),
+            media_groups: Arc::new(MediaGroups::new(10)),
+            overlay_requests: Arc::new(RequestContext { pending_overlays, rate_limiter, daily_quota, live_config }),
+            overlay_aliases: Arc::from([commands::DEFAULT_OVERLAY_ALIAS.to_string()]),
+        }
+    }
+
+    /// Builds a photo sent by user 20 in private chat 10, in reply to message `reply_to`.
+    fn photo_reply(message_id: i32, reply_to: i32) -> Message {
+        serde_json::from_value(serde_json::json!({
+            "message_id": message_id,
+            "date": 1_700_000_000,
+            "chat": { "id": 10, "type": "private", "first_name": "Degen" },
+            "from": { "id": 20, "is_bot": false, "first_name": "Degen" },
+            "photo": [{ "file_id": "photo", "file_unique_id": "photo", "file_size": 1000, "width": 640, "height": 480 }],
+            "reply_to_message": {
+                "message_id": reply_to,
+                "date": 1_700_000_000,
+                "chat": { "id": 10, "type": "private", "first_name": "Degen" },
+                "from": { "id": 1, "is_bot": true, "first_name": "DegenBot" },
+                "text": "Reply to this message with an image",
+            },
+        })).expect("a valid message")
+    }
+
+    #[tokio::test]
+    async fn a_redelivered_photo_only_produces_one_overlay() {
+        // A full set of seen messages must still recognize the redelivery
+        let state = bot_state(SeenMessages::new(1, Duration::from_secs(60))).await;
+        let prompt = commands::PendingOverlay { message_id: MessageId(100), thread_id: None, created: Instant::now(), styles: Vec::new(), request_id: 1 };
+        state.pending_overlays.lock().await.insert((ChatId(10), UserId(20)), prompt);
+        // Nothing is sent to Telegram for the first photo in line, so the bot never connects
+        let bot = Bot::new("0:test");
+
+        let photo = photo_reply(5, 100);
+        message_handler(bot.clone(), photo.clone(), state.clone()).await.unwrap();
+        message_handler(bot, photo, state.clone()).await.unwrap();
+
+        assert_eq!(state.message_queue.len().await, 1);
+    }
+
+    #[tokio::test]
+    async fn the_queue_position_is_posted_in_the_senders_topic() {
+        let sender = MockSender::new();
+        let message_queue = Queue::new();
+        let messages = Messages::default();
+
+        queue_message(&sender, &message_queue, topic_photo(1, 7), &messages, true).await.unwrap();
+        queue_message(&sender, &message_queue, topic_photo(2, 7), &messages, true).await.unwrap();
+
+        assert_eq!(sender.calls(), vec![
+            SentCall::Message { chat_id: ChatId(-100), thread_id: Some(7), text: messages.queue_position(2) },
+        ]);
+    }
 }
@@ -0,0 +1,144 @@
+use std::sync::Arc;
+use std::time::Duration;
+use axum::extract::{Multipart, Query};
+use axum::http::{header, HeaderMap, StatusCode};
+use axum::response::{IntoResponse, Response};
+use log::{error, info, warn};
+use serde::Deserialize;
+
+use crate::commands::overlay::{parse_styles, render_overlay, OverlayOutcome, OverlayTiming, MAX_STACKED_OVERLAYS};
+use crate::utils::live_config::LiveConfig;
+use crate::utils::worker_pool::ImageWorkerPool;
+
+/// The route the render endpoint is served at.
+pub const ROUTE: &str = "/render";
+
+/// The header requests to the render endpoint must carry `web.render_secret` in.
+pub const SECRET_HEADER: &str = "x-render-secret";
+
+/// The query parameters of a render request, e.g. `/render?style=hands,hat`.
+#[derive(Deserialize)]
+pub struct RenderQuery {
+    style: Option<String>,
+}
+
+/// An HTTP endpoint that runs an uploaded image through the overlay pipeline, for smoke-testing a deployment without
+/// Telegram.
+///
+/// It renders with the same overlays and image workers as the bots, including overlays swapped in by a config reload, so
+/// a successful render shows that the overlays load and the workers are running. Every request must carry the configured secret in the `X-Render-Secret` header,
+/// and is rejected otherwise, so the endpoint can't be used to render images for free.
+pub struct RenderApi {
+    live_config: Arc<LiveConfig>,
+    worker_pool: Arc<ImageWorkerPool>,
+    secret: String,
+    max_file_size_bytes: u32,
+    max_processing_time: Duration,
+}
+
+impl RenderApi {
+    /// Creates a new `RenderApi` instance.
+    ///
+    /// # Arguments
+    /// * `live_config` - The reloadable config, holding the overlays to choose from.
+    /// * `worker_pool` - The worker pool overlays are rendered on.
+    /// * `secret` - The secret requests must carry in the `X-Render-Secret` header.
+    /// * `max_file_size_bytes` - The largest image, in bytes, that will be rendered.
+    /// * `max_processing_time` - How long an image may take to render before the request fails.
+    ///
+    /// # Returns
+    /// A new `RenderApi` instance.
+    pub fn new(live_config: Arc<LiveConfig>, worker_pool: Arc<ImageWorkerPool>, secret: String, max_file_size_bytes: u32, max_processing_time: Duration) -> Self {
+        RenderApi { live_config, worker_pool, secret, max_file_size_bytes, max_processing_time }
+    }
+
+    /// Returns the largest request body accepted, leaving room for the multipart framing around the image.
+    pub fn max_body_bytes(&self) -> usize {
+        usize::try_from(self.max_file_size_bytes).unwrap_or(usize::MAX).saturating_add(64 * 1024)
+    }
+
+    /// Handles a `POST /render` request: checks the secret, reads the image from the multipart body and renders it.
+    ///
+    /// The image is read from the first multipart field that has data, whatever its name, and the styles from the
+    /// `style` query parameter, separated by commas as in `/degenme`. A random overlay is used if no style is given.
+    ///
+    /// # Arguments
+    /// * `headers` - The request headers, which must include `X-Render-Secret`.
+    /// * `query` - The request's query parameters.
+    /// * `multipart` - The request body, holding the image.
+    ///
+    /// # Returns
+    /// The result image with its content type, `401` if the secret is missing or wrong, `400` if there is no image, the
+    /// image can't be decoded or a style is unknown, `413` if the image is too large, `504` if rendering took too long,
+    /// or `500` if it failed.
+    pub async fn render(&self, headers: HeaderMap, query: RenderQuery, mut multipart: Multipart) -> Response {
+        let authorized = headers.get(SECRET_HEADER)
+            .is_some_and(|secret| secrets_match(secret.as_bytes(), self.secret.as_bytes()));
+        if !authorized {
+            warn!("Rejecting render request without a valid {} header", SECRET_HEADER);
+            return (StatusCode::UNAUTHORIZED, "Missing or invalid render secret").into_response();
+        }
+
+        // The endpoint has no chat, so it renders with the default overlays
+        let overlay_assets = Arc::clone(self.live_config.assets().chat_overlays.default_overlays());
+        let styles = query.style.as_deref().map(parse_styles).unwrap_or_default();
+        if styles.len() > MAX_STACKED_OVERLAYS {
+            return (StatusCode::BAD_REQUEST, format!("At most {} styles can be stacked", MAX_STACKED_OVERLAYS)).into_response();
+        }
+        let available = overlay_assets.styles();
+        if let Some(style) = styles.iter().find(|style| !available.contains(style)) {
+            return (StatusCode::BAD_REQUEST, format!("Unknown style {:?}, available styles: {}", style, available.join(", "))).into_response();
+        }
+
+        let image_data = loop {
+            match multipart.next_field().await {
+                Ok(Some(field)) => match field.bytes().await {
+                    Ok(bytes) if bytes.is_empty() => continue,
+                    Ok(bytes) => break bytes.to_vec(),
+                    Err(e) => return (StatusCode::BAD_REQUEST, format!("Could not read the image: {}", e)).into_response(),
+                },
+                Ok(None) => return (StatusCode::BAD_REQUEST, "The request has no image").into_response(),
+                Err(e) => return (StatusCode::BAD_REQUEST, format!("Could not read the multipart body: {}", e)).into_response(),
+            }
+        };
+        if image_data.len() > self.max_file_size_bytes as usize {
+            return (StatusCode::PAYLOAD_TOO_LARGE, format!("The image is over the {} byte limit", self.max_file_size_bytes)).into_response();
+        }
+
+        info!("Rendering {} byte image from the render endpoint", image_data.len());
+        let output_format = overlay_assets.output_format();
+        let rendered = self.worker_pool.submit(move || render_overlay(&overlay_assets, &image_data, &styles, &mut OverlayTiming::new()));
+        // The render can't be cancelled, so one that overruns is abandoned and its worker thread finishes it unseen
+        let outcome = match tokio::time::timeout(self.max_processing_time, rendered).await {
+            Ok(Ok(outcome)) => outcome,
+            Ok(Err(_)) => {
+                error!("Image worker pool dropped the render endpoint's job, it may have panicked");
+                OverlayOutcome::OverlayFailed
+            }
+            Err(_) => {
+                warn!("Render endpoint overlay took longer than {:?}, abandoning it", self.max_processing_time);
+                OverlayOutcome::TimedOut
+            }
+        };
+
+        match outcome {
+            OverlayOutcome::Success(buffer) => {
+                ([(header::CONTENT_TYPE, output_format.content_type())], buffer).into_response()
+            }
+            OverlayOutcome::DecodeFailed(_) | OverlayOutcome::Corrupted | OverlayOutcome::TooSmall => {
+                (StatusCode::BAD_REQUEST, format!("Invalid image: {}", outcome.label())).into_response()
+            }
+            OverlayOutcome::TimedOut => (StatusCode::GATEWAY_TIMEOUT, "Rendering took too long").into_response(),
+            outcome => {
+                error!("Failed to render overlay for the render endpoint: {}", outcome.label());
+                (StatusCode::INTERNAL_SERVER_ERROR, "Rendering failed").into_response()
+            }
+        }
+    }
+}
+
+/// Compares two secrets in time that doesn't depend on where they differ, so the secret can't be guessed byte by byte
+/// from response times.
+fn secrets_match(given: &[u8], expected: &[u8]) -> bool {
+    given.len() == expected.len() && given.iter().zip(expected).fold(0, |diff, (a, b)| diff | (a ^ b)) == 0
+}
@@ -0,0 +1,221 @@
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+use s3::creds::Credentials;
+use s3::{Bucket, Region};
+use teloxide::types::{ChatId, UserId};
+use thiserror::Error;
+use log::{info, error};
+
+use crate::config::ArchiveConfig;
+use crate::utils::image_utils::OutputFormat;
+
+/// An error that occurred while archiving a result.
+#[derive(Debug, Error)]
+pub enum ArchiveError {
+    #[error("Invalid archive configuration: {0}")]
+    Config(String),
+    #[error("Failed to upload to the archive: {0}")]
+    Upload(String),
+    #[error("Failed to list the archive: {0}")]
+    List(String),
+    #[error("Failed to fetch from the archive: {0}")]
+    Fetch(String),
+}
+
+/// A storage backend every generated result is archived to.
+///
+/// The processor holds the archive as an `Arc<dyn OverlayArchive>`, so its methods return boxed futures rather than
+/// being `async fn`s.
+pub trait OverlayArchive: Send + Sync {
+    /// Stores a result under `key`, overwriting anything already stored there.
+    fn store<'a>(&'a self, key: &'a str, bytes: &'a [u8]) -> Pin<Box<dyn Future<Output = Result<(), ArchiveError>> + Send + 'a>>;
+
+    /// Lists the keys of every stored result starting with `prefix`, such as one from `user_key_prefix`, in any order.
+    fn list<'a>(&'a self, prefix: &'a str) -> Pin<Box<dyn Future<Output = Result<Vec<String>, ArchiveError>> + Send + 'a>>;
+
+    /// Fetches the result stored under `key`.
+    fn fetch<'a>(&'a self, key: &'a str) -> Pin<Box<dyn Future<Output = Result<Vec<u8>, ArchiveError>> + Send + 'a>>;
+}
+
+/// An `OverlayArchive` that uploads results to an S3 bucket, or any S3-compatible object storage.
+pub struct S3Archive {
+    bucket: Box<Bucket>,
+    prefix: String,
+}
+
+impl S3Archive {
+    /// Creates a new `S3Archive` from the `[archive]` section of the config.
+    ///
+    /// If `endpoint` is set, the bucket is addressed by path on that endpoint, which is what most S3-compatible
+    /// storage expects. Otherwise `region` is used as an AWS region. Credentials that aren't set in the config are
+    /// read from the usual AWS environment variables. Every key is stored under the configured `prefix`, with the
+    /// content type of the format its extension names, so results encoded in a format set by a reload are labelled
+    /// correctly.
+    ///
+    /// # Arguments
+    /// * `config` - The archive configuration.
+    ///
+    /// # Returns
+    /// A new `S3Archive` instance, or an `ArchiveError` if the bucket or credentials could not be set up.
+    pub fn new(config: &ArchiveConfig) -> Result<Self, ArchiveError> {
+        let region = match &config.endpoint {
+            Some(endpoint) => Region::Custom { region: config.region.clone(), endpoint: endpoint.clone() },
+            None => config.region.parse().map_err(|e| ArchiveError::Config(format!("unknown region {}: {}", config.region, e)))?,
+        };
+        let credentials = Credentials::new(config.access_key_id.as_deref(), config.secret_access_key.as_deref(), None, None, None)
+            .map_err(|e| ArchiveError::Config(e.to_string()))?;
+        let mut bucket = Bucket::new(&config.bucket, region, credentials)
+            .map_err(|e| ArchiveError::Config(e.to_string()))?;
+        if config.endpoint.is_some() {
+            bucket = bucket.with_path_style();
+        }
+
+        info!("Archiving results to bucket {}", config.bucket);
+        Ok(S3Archive {
+            bucket,
+            prefix: config.prefix.clone(),
+        })
+    }
+}
+
+impl OverlayArchive for S3Archive {
+    fn store<'a>(&'a self, key: &'a str, bytes: &'a [u8]) -> Pin<Box<dyn Future<Output = Result<(), ArchiveError>> + Send + 'a>> {
+        Box::pin(async move {
+            let path = format!("{}{}", self.prefix, key);
+            let response = self.bucket.put_object_with_content_type(&path, bytes, content_type(key))
+                .await
+                .map_err(|e| ArchiveError::Upload(e.to_string()))?;
+            match response.status_code() {
+                200..=299 => Ok(()),
+                status => Err(ArchiveError::Upload(format!("bucket returned status {}", status))),
+            }
+        })
+    }
+
+    fn list<'a>(&'a self, prefix: &'a str) -> Pin<Box<dyn Future<Output = Result<Vec<String>, ArchiveError>> + Send + 'a>> {
+        Box::pin(async move {
+            let pages = self.bucket.list(format!("{}{}", self.prefix, prefix), None)
+                .await
+                .map_err(|e| ArchiveError::List(e.to_string()))?;
+            // Keys are handed out without the configured prefix, the same way they are passed to store
+            Ok(pages.into_iter()
+                .flat_map(|page| page.contents)
+                .filter_map(|object| object.key.strip_prefix(self.prefix.as_str()).map(str::to_string))
+                .collect())
+        })
+    }
+
+    fn fetch<'a>(&'a self, key: &'a str) -> Pin<Box<dyn Future<Output = Result<Vec<u8>, ArchiveError>> + Send + 'a>> {
+        Box::pin(async move {
+            let path = format!("{}{}", self.prefix, key);
+            let response = self.bucket.get_object(&path)
+                .await
+                .map_err(|e| ArchiveError::Fetch(e.to_string()))?;
+            match response.status_code() {
+                200..=299 => Ok(response.bytes().to_vec()),
+                status => Err(ArchiveError::Fetch(format!("bucket returned status {}", status))),
+            }
+        })
+    }
+}
+
+/// Builds the key a result is archived under, `<chat id>/<user id>/<unix millis>.<extension>`, or
+/// `<chat id>/<user id>/<unix millis>-<index>.<extension>` for a result that is part of an album.
+///
+/// # Arguments
+/// * `chat_id` - The chat the result was sent in.
+/// * `user_id` - The user who asked for the result, or `None` for anonymous senders.
+/// * `output_format` - The format the result is encoded in.
+/// * `album_index` - The result's position in its album, so the results of one album archived in the same millisecond
+///   get keys of their own, or `None` for a single result.
+///
+/// # Returns
+/// The archive key.
+pub fn archive_key(chat_id: ChatId, user_id: Option<UserId>, output_format: OutputFormat, album_index: Option<usize>) -> String {
+    let timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_millis())
+        .unwrap_or(0);
+    let index = album_index.map(|index| format!("-{}", index)).unwrap_or_default();
+    format!("{}{}{}{}", user_key_prefix(chat_id, user_id), timestamp, index, output_format.extension())
+}
+
+/// Returns the content type of a result stored under `key`, going by the extension `archive_key` gave it.
+///
+/// # Arguments
+/// * `key` - The archive key.
+///
+/// # Returns
+/// The MIME type of the format the key's extension names, or `application/octet-stream` for any other key.
+fn content_type(key: &str) -> &'static str {
+    [OutputFormat::Png, OutputFormat::Webp, OutputFormat::Jpeg].into_iter()
+        .find(|format| key.ends_with(format.extension()))
+        .map_or("application/octet-stream", OutputFormat::content_type)
+}
+
+/// Builds the prefix shared by the keys of every result a user asked for in a chat, `<chat id>/<user id>/`.
+///
+/// # Arguments
+/// * `chat_id` - The chat the results were sent in.
+/// * `user_id` - The user who asked for the results, or `None` for anonymous senders.
+///
+/// # Returns
+/// The key prefix, ending in a slash.
+pub fn user_key_prefix(chat_id: ChatId, user_id: Option<UserId>) -> String {
+    let user = user_id.map(|user_id| user_id.to_string()).unwrap_or_else(|| "anonymous".to_string());
+    format!("{}/{}/", chat_id, user)
+}
+
+/// Archives a result in the background, so the upload never delays the reply to the user.
+///
+/// Failures are logged and otherwise ignored.
+///
+/// # Arguments
+/// * `archive` - The archive to store the result in.
+/// * `key` - The key to store the result under, from `archive_key`.
+/// * `bytes` - The encoded result.
+pub fn spawn_store(archive: Arc<dyn OverlayArchive>, key: String, bytes: Vec<u8>) {
+    tokio::spawn(async move {
+        match archive.store(&key, &bytes).await {
+            Ok(()) => info!("Archived result as {}", key),
+            Err(e) => error!("Failed to archive result as {}: {}", key, e),
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn album_results_get_keys_of_their_own() {
+        let keys: Vec<String> = (0..4)
+            .map(|index| archive_key(ChatId(-100), Some(UserId(7)), OutputFormat::Png, Some(index)))
+            .collect();
+        for (index, key) in keys.iter().enumerate() {
+            assert!(key.starts_with("-100/7/"), "{}", key);
+            assert!(key.ends_with(&format!("-{}.png", index)), "{}", key);
+        }
+        let mut unique = keys.clone();
+        unique.sort();
+        unique.dedup();
+        assert_eq!(unique.len(), keys.len());
+    }
+
+    #[test]
+    fn single_results_have_no_index() {
+        let key = archive_key(ChatId(1), None, OutputFormat::Jpeg, None);
+        let name = key.strip_prefix("1/anonymous/").expect("the user prefix");
+        let millis = name.strip_suffix(".jpg").expect("the extension");
+        assert!(millis.parse::<u128>().is_ok(), "{}", key);
+    }
+
+    #[test]
+    fn the_content_type_follows_the_key() {
+        assert_eq!(content_type(&archive_key(ChatId(1), None, OutputFormat::Webp, None)), "image/webp");
+        assert_eq!(content_type(&archive_key(ChatId(1), None, OutputFormat::Jpeg, Some(2))), "image/jpeg");
+        assert_eq!(content_type("1/anonymous/notes.txt"), "application/octet-stream");
+    }
+}
@@ -0,0 +1,147 @@
+use std::fs::{File, OpenOptions};
+use std::io::{self, LineWriter, Write};
+use std::path::Path;
+use std::sync::mpsc::{self, Sender};
+use std::thread;
+use std::time::{SystemTime, UNIX_EPOCH};
+use serde::Serialize;
+use teloxide::types::Message;
+use log::{info, warn, error};
+
+/// A single line of the audit log: who ran which command, where, and how it went.
+#[derive(Debug, Serialize)]
+pub struct AuditEntry {
+    /// Seconds since the Unix epoch.
+    pub timestamp: u64,
+    pub chat_id: i64,
+    /// `None` for anonymous senders, such as channels.
+    pub user_id: Option<u64>,
+    pub command: String,
+    pub outcome: String,
+}
+
+/// Where audit entries are written to.
+///
+/// Entries are written from the audit log's own thread, so implementations may block.
+pub trait AuditSink: Send + 'static {
+    /// Writes an entry to the sink.
+    fn write(&mut self, entry: &AuditEntry) -> io::Result<()>;
+}
+
+/// An `AuditSink` that appends entries to a file as JSON lines.
+pub struct JsonlFileSink {
+    writer: LineWriter<File>,
+}
+
+impl JsonlFileSink {
+    /// Opens the file at `path` for appending, creating it if it doesn't exist.
+    ///
+    /// # Arguments
+    /// * `path` - The path of the audit log file.
+    ///
+    /// # Returns
+    /// A new `JsonlFileSink` instance, or an error if the file could not be opened.
+    pub fn open(path: &Path) -> io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(JsonlFileSink { writer: LineWriter::new(file) })
+    }
+}
+
+impl AuditSink for JsonlFileSink {
+    fn write(&mut self, entry: &AuditEntry) -> io::Result<()> {
+        serde_json::to_writer(&mut self.writer, entry)?;
+        // The line writer flushes on the newline, so every entry is on disk once written
+        self.writer.write_all(b"\n")
+    }
+}
+
+/// An AuditLogger struct that records who ran which command and how it went, for moderation and analytics.
+///
+/// Recording an entry only sends it to a background thread that owns the `AuditSink`, so it never blocks the async
+/// runtime. A write that fails is logged and the entry dropped; the bot keeps running either way. A disabled logger
+/// ignores every entry, so callers don't need to check whether auditing is enabled.
+pub struct AuditLogger {
+    sender: Option<Sender<AuditEntry>>,
+}
+
+impl AuditLogger {
+    /// Creates a new `AuditLogger` that writes entries to `sink` on a background thread.
+    ///
+    /// # Arguments
+    /// * `sink` - Where entries are written to.
+    ///
+    /// # Returns
+    /// A new `AuditLogger` instance, or a disabled one if the background thread could not be started.
+    pub fn new(sink: impl AuditSink) -> Self {
+        let (sender, receiver) = mpsc::channel::<AuditEntry>();
+        let mut sink = sink;
+        let spawned = thread::Builder::new()
+            .name("audit-log".to_string())
+            .spawn(move || {
+                // Ends once every sender has been dropped
+                for entry in receiver {
+                    if let Err(e) = sink.write(&entry) {
+                        error!("Failed to write audit log entry: {}", e);
+                    }
+                }
+            });
+        match spawned {
+            Ok(_) => AuditLogger { sender: Some(sender) },
+            Err(e) => {
+                error!("Failed to start audit log thread, auditing is disabled: {}", e);
+                Self::disabled()
+            }
+        }
+    }
+
+    /// Opens the JSON lines audit log at `path`, falling back to a disabled logger if it can't be opened.
+    ///
+    /// # Arguments
+    /// * `path` - The path of the audit log file.
+    ///
+    /// # Returns
+    /// A new `AuditLogger` writing to the file, or a disabled one if it could not be opened.
+    pub fn open_jsonl(path: &Path) -> Self {
+        match JsonlFileSink::open(path) {
+            Ok(sink) => {
+                info!("Writing audit log to {:?}", path);
+                Self::new(sink)
+            }
+            Err(e) => {
+                warn!("Could not open audit log {:?}, auditing is disabled: {}", path, e);
+                Self::disabled()
+            }
+        }
+    }
+
+    /// Creates an `AuditLogger` that ignores every entry.
+    ///
+    /// # Returns
+    /// A new, disabled `AuditLogger` instance.
+    pub fn disabled() -> Self {
+        AuditLogger { sender: None }
+    }
+
+    /// Records that a command was run, without waiting for the entry to be written.
+    ///
+    /// # Arguments
+    /// * `msg` - The message the command was run with.
+    /// * `command` - The command, e.g. `"degenme"`.
+    /// * `outcome` - How the command went, e.g. `"queued"` or `"limited"`.
+    pub fn record(&self, msg: &Message, command: &str, outcome: &str) {
+        let Some(sender) = &self.sender else {
+            return;
+        };
+
+        let entry = AuditEntry {
+            timestamp: SystemTime::now().duration_since(UNIX_EPOCH).map(|elapsed| elapsed.as_secs()).unwrap_or(0),
+            chat_id: msg.chat.id.0,
+            user_id: msg.from().map(|user| user.id.0),
+            command: command.to_string(),
+            outcome: outcome.to_string(),
+        };
+        if sender.send(entry).is_err() {
+            warn!("Audit log thread has stopped, dropping entry");
+        }
+    }
+}
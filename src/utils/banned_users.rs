@@ -0,0 +1,87 @@
+use std::collections::HashSet;
+use std::io;
+use std::path::{Path, PathBuf};
+use log::{info, warn};
+use teloxide::types::UserId;
+use tokio::sync::Mutex;
+
+/// A BannedUsers struct that keeps the users the bot ignores entirely.
+///
+/// Users start out banned if they are listed in `banned_user_ids` in the config, and admins can ban and unban users at
+/// runtime with `/ban` and `/unban`. If a state file is set, the whole list is written to it after every change and
+/// read back at startup, so runtime bans survive a restart; otherwise they are only kept in memory. Users in the config
+/// are banned again on every start, so unbanning one of them for good means removing them from the config too.
+pub struct BannedUsers {
+    users: Mutex<HashSet<UserId>>,
+    state_path: Option<PathBuf>,
+}
+
+impl BannedUsers {
+    /// Creates a new `BannedUsers` instance with the users from the config and, if there is one, the state file.
+    ///
+    /// A missing or corrupt state file is logged and ignored, so a broken ban list can never stop the bot from starting.
+    ///
+    /// # Arguments
+    /// * `configured` - The users banned in the config.
+    /// * `state_path` - The file bans are saved to, or `None` to only keep them in memory.
+    ///
+    /// # Returns
+    /// A new `BannedUsers` instance.
+    pub async fn load(configured: &[u64], state_path: Option<PathBuf>) -> Self {
+        let mut users: HashSet<UserId> = configured.iter().copied().map(UserId).collect();
+        if let Some(path) = &state_path {
+            match read_state(path).await {
+                Ok(saved) => {
+                    info!("Restored {} banned users from {:?}", saved.len(), path);
+                    users.extend(saved.into_iter().map(UserId));
+                }
+                Err(e) if e.kind() == io::ErrorKind::NotFound => info!("No banned users saved at {:?} yet", path),
+                Err(e) => warn!("Failed to read banned users from {:?}, using the config's list: {}", path, e),
+            }
+        }
+        BannedUsers { users: Mutex::new(users), state_path }
+    }
+
+    /// Returns whether a user is banned.
+    pub async fn is_banned(&self, user_id: UserId) -> bool {
+        self.users.lock().await.contains(&user_id)
+    }
+
+    /// Bans or unbans a user, saving the list if it changed and a state file is set.
+    ///
+    /// # Arguments
+    /// * `user_id` - The user to ban or unban.
+    /// * `banned` - `true` to ban the user, `false` to unban them.
+    ///
+    /// # Returns
+    /// `true` if the user's ban changed, `false` if they were already in the requested state.
+    pub async fn set_banned(&self, user_id: UserId, banned: bool) -> bool {
+        let mut users = self.users.lock().await;
+        let changed = if banned { users.insert(user_id) } else { users.remove(&user_id) };
+        if changed {
+            if let Some(path) = &self.state_path {
+                // Saving while holding the lock keeps concurrent bans from writing the file out of order
+                let mut saved: Vec<u64> = users.iter().map(|user_id| user_id.0).collect();
+                saved.sort_unstable();
+                if let Err(e) = write_state(path, &saved).await {
+                    warn!("Failed to save banned users to {:?}: {}", path, e);
+                }
+            }
+        }
+        changed
+    }
+}
+
+/// Reads the user IDs saved by `write_state`.
+async fn read_state(path: &Path) -> io::Result<Vec<u64>> {
+    let json = tokio::fs::read(path).await?;
+    serde_json::from_slice(&json).map_err(io::Error::other)
+}
+
+/// Saves the user IDs as a JSON array, replacing the file in one step so a crash never leaves half a list behind.
+async fn write_state(path: &Path, user_ids: &[u64]) -> io::Result<()> {
+    let json = serde_json::to_vec(user_ids).map_err(io::Error::other)?;
+    let temp_path = path.with_extension("tmp");
+    tokio::fs::write(&temp_path, json).await?;
+    tokio::fs::rename(&temp_path, path).await
+}
@@ -0,0 +1,115 @@
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::Arc;
+use teloxide::types::ChatId;
+use log::info;
+
+use crate::config::ProcessingConfig;
+use crate::utils::overlay_assets::{OverlayAssets, OverlayAssetsError};
+
+/// A ChatOverlays struct that picks the overlays to use in each chat.
+///
+/// Chats mapped to their own directory under `[telegram.chat_overlays]` get the overlays from that directory, laid out
+/// like `img` with `portrait` and `landscape` subdirectories, so one deployment can serve several branded groups. Every
+/// other chat uses the default overlays. The chat's overlays are loaded with the same `[processing]` settings as the
+/// default ones, so only the overlay images themselves differ.
+pub struct ChatOverlays {
+    default: Arc<OverlayAssets>,
+    chats: HashMap<ChatId, Arc<OverlayAssets>>,
+}
+
+impl ChatOverlays {
+    /// Creates a new `ChatOverlays` instance that uses the default overlays in every chat.
+    ///
+    /// # Arguments
+    /// * `default` - The overlays used in every chat.
+    ///
+    /// # Returns
+    /// A new `ChatOverlays` instance.
+    pub fn new(default: Arc<OverlayAssets>) -> Self {
+        ChatOverlays { default, chats: HashMap::new() }
+    }
+
+    /// Loads the overlays for each chat with a directory of its own, checking every one of them.
+    ///
+    /// Like the default overlays, a chat's overlays are checked up front, so a typo in a directory or a broken overlay
+    /// stops the bot from starting instead of failing every request in that chat.
+    ///
+    /// # Arguments
+    /// * `default` - The overlays used in chats without a directory of their own.
+    /// * `chat_dirs` - The overlay directory for each chat that has one.
+    /// * `processing` - The processing settings from the config, applied to every chat's overlays.
+    ///
+    /// # Returns
+    /// A new `ChatOverlays` instance, `OverlayAssetsError::MissingDirectory` if a chat's directory doesn't exist, or
+    /// `OverlayAssetsError::Invalid` if any of its overlays are missing or can't be decoded.
+    pub fn load(default: Arc<OverlayAssets>, chat_dirs: &HashMap<ChatId, PathBuf>, processing: &ProcessingConfig) -> Result<Self, OverlayAssetsError> {
+        let mut chats = HashMap::with_capacity(chat_dirs.len());
+        for (chat_id, dir) in chat_dirs {
+            if !dir.is_dir() {
+                return Err(OverlayAssetsError::MissingDirectory(dir.clone()));
+            }
+            let overlay_assets = OverlayAssets::from_config(dir, processing);
+            overlay_assets.check()?;
+            info!("Using the overlays in {:?} for chat {}", dir, chat_id);
+            chats.insert(*chat_id, Arc::new(overlay_assets));
+        }
+        Ok(ChatOverlays { default, chats })
+    }
+
+    /// Returns the default overlays, used in chats without their own and outside Telegram.
+    pub fn default_overlays(&self) -> &Arc<OverlayAssets> {
+        &self.default
+    }
+
+    /// Returns the overlays to use in a chat.
+    ///
+    /// # Arguments
+    /// * `chat_id` - The chat the overlay is for.
+    ///
+    /// # Returns
+    /// The chat's own overlays, or the default overlays if it has none.
+    pub fn for_chat(&self, chat_id: ChatId) -> &Arc<OverlayAssets> {
+        self.chats.get(&chat_id).unwrap_or(&self.default)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::path::Path;
+
+    /// Creates an overlay directory of its own under the system temp directory, with the default hands as a `brand`
+    /// overlay for both shapes, and returns its path.
+    fn brand_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("degenbot-chat-overlays-{}-{}", std::process::id(), name));
+        for asset in ["portrait", "landscape"] {
+            fs::create_dir_all(dir.join(asset)).unwrap();
+            fs::copy(format!("img/hands_{}.png", asset), dir.join(asset).join("brand.png")).unwrap();
+        }
+        dir
+    }
+
+    #[test]
+    fn mapped_chats_get_their_own_overlays_and_others_the_default() {
+        let default = Arc::new(OverlayAssets::load(Path::new("img")));
+        let dir = brand_dir("lookup");
+        let chat_dirs = HashMap::from([(ChatId(-1001234), dir.clone())]);
+
+        let chat_overlays = ChatOverlays::load(Arc::clone(&default), &chat_dirs, &ProcessingConfig::default()).unwrap();
+        assert_eq!(chat_overlays.for_chat(ChatId(-1001234)).img_dir(), dir);
+        assert_eq!(chat_overlays.for_chat(ChatId(-1001234)).styles(), ["brand"]);
+        assert!(Arc::ptr_eq(chat_overlays.for_chat(ChatId(-1005678)), &default));
+    }
+
+    #[test]
+    fn a_missing_chat_directory_stops_the_load() {
+        let default = Arc::new(OverlayAssets::load(Path::new("img")));
+        let missing = std::env::temp_dir().join(format!("degenbot-chat-overlays-{}-missing", std::process::id()));
+        let chat_dirs = HashMap::from([(ChatId(-1001234), missing)]);
+
+        let loaded = ChatOverlays::load(default, &chat_dirs, &ProcessingConfig::default());
+        assert!(matches!(loaded, Err(OverlayAssetsError::MissingDirectory(_))));
+    }
+}
@@ -1,44 +1,132 @@
+use std::collections::HashMap;
 use teloxide::prelude::*;
+use teloxide::types::MessageId;
 use log::{info, error};
+use rand::Rng;
 use tokio::time::{ Duration, Instant };
 
 use crate::commands::overlay::PendingOverlays;
+use crate::utils::live_config::LiveConfig;
+use crate::utils::rate_limiter::RateLimiter;
+use crate::utils::sender::MessageSender;
 
-/// The duration after which an overlay request is considered expired and should be removed.
-/// This is set to 3 minutes.
-pub const OVERLAY_EXPIRATION: Duration = Duration::from_secs(180); // 3 minutes
+/// How often each chat may be sent an expiry notice, so busy groups aren't flooded with them.
+pub const EXPIRY_NOTICE_INTERVAL: Duration = Duration::from_secs(300); // 5 minutes
+
+/// Returns how long to wait before the next cleanup: `interval`, moved earlier or later by a random amount of up to
+/// `jitter`.
+///
+/// Randomizing each wait keeps several instances of the bot from cleaning up, and calling Telegram, in lockstep.
+///
+/// # Arguments
+/// * `interval` - The average time between cleanups.
+/// * `jitter` - The largest amount the wait may differ from `interval` by.
+///
+/// # Returns
+/// The time to wait, between `interval - jitter` and `interval + jitter`.
+pub fn jittered_interval(interval: Duration, jitter: Duration) -> Duration {
+    let jitter_ms = u64::try_from(jitter.as_millis()).unwrap_or(u64::MAX / 2);
+    let offset = Duration::from_millis(rand::thread_rng().gen_range(0..=jitter_ms * 2));
+    (interval + offset).saturating_sub(jitter)
+}
 
 /// Cleans up expired overlay requests by removing them from the `PendingOverlays` map and sending an expiry message to the user.
 ///
 /// This function is called periodically to maintain the `PendingOverlays` map and ensure that expired overlay requests are removed.
-/// It iterates through the map, finds any requests that have been pending for longer than `OVERLAY_EXPIRATION` (3 minutes),
-/// removes them from the map, and sends an expiry message to the user.
+/// It iterates through the map, finds any requests that have been pending for longer than the overlay expiration in
+/// `live_config`, so a reload applies to requests already pending,
+/// removes them from the map, deletes their prompt messages, and tells the users their requests expired.
+///
+/// Expiry notices are batched, so every user whose request expired in the same chat is named in a single message, and
+/// the notice is posted in the forum topic the prompts were sent in. Prompts from different topics of a chat get a
+/// notice each, and `notice_limiter` decides whether a chat may be sent a notice at all. A chat that is over its limit only has the
+/// prompts deleted. If `notify_on_expiry` is `false`, no notices are sent and the users' names aren't looked up.
+/// Since a notice can name several users, it is sent in the default language rather than any one user's, from the
+/// messages in `live_config`.
 ///
 /// # Arguments
-/// * `bot` - The `Bot` instance used to interact with the Telegram API.
+/// * `bot` - The Telegram bot instance, or any other `MessageSender` such as `MockSender` in tests.
 /// * `pending_overlays` - The `PendingOverlays` map that stores the pending overlay requests.
-pub async fn cleanup_expired_overlays(bot: Bot, pending_overlays: PendingOverlays) {
-    let mut overlays = pending_overlays.lock().await;
+/// * `live_config` - The reloadable settings, holding how long requests wait for an image and the messages.
+/// * `notify_on_expiry` - Whether users are told their request expired.
+/// * `notice_limiter` - A rate limiter keyed by chat, limiting how often each chat is sent an expiry notice.
+pub async fn cleanup_expired_overlays<S: MessageSender>(bot: S, pending_overlays: PendingOverlays, live_config: &LiveConfig, notify_on_expiry: bool, notice_limiter: &RateLimiter) {
     let now = Instant::now();
-    let expired: Vec<_> = overlays
-        .iter()
-        .filter(|(_, (_, time))| now.duration_since(*time) > OVERLAY_EXPIRATION)
-        .map(|((chat_id, user_id), _)| (*chat_id, *user_id))
-        .collect();
-
-    for (chat_id, user_id) in expired {
-        if let Some((msg_id, _)) = overlays.remove(&(chat_id, user_id)) {
+    let expiration = live_config.get().overlay_expiration;
+    // The requests are taken out of the map first, so the lock isn't held while talking to Telegram
+    let mut expired: HashMap<(ChatId, Option<i32>), Vec<(UserId, MessageId)>> = HashMap::new();
+    {
+        let mut overlays = pending_overlays.lock().await;
+        overlays.retain(|(chat_id, user_id), pending| {
+            if now.duration_since(pending.created) <= expiration {
+                return true;
+            }
             info!("Removing expired overlay request for Chat ID: {}, User ID: {}", chat_id, user_id);
-            if let Ok(chat_member) = bot.get_chat_member(chat_id, user_id).await {
-                let username = chat_member.user.username.unwrap_or_else(|| "Degen".to_string());
-                let expiry_message = format!("{}, you degen, you forgot to send me a picture! Please run /degenme again to send an image.", username);
-                if let Err(e) = bot.send_message(chat_id, expiry_message).await {
+            expired.entry((*chat_id, pending.thread_id)).or_default().push((*user_id, pending.message_id));
+            false
+        });
+    }
+
+    let assets = live_config.assets();
+    let messages = assets.localization.default_messages();
+    for ((chat_id, thread_id), requests) in expired {
+        if notify_on_expiry && notice_limiter.check_rate_limit(&chat_id.to_string()).await {
+            let mut usernames = Vec::with_capacity(requests.len());
+            for (user_id, _) in &requests {
+                if let Ok(username) = bot.member_username(chat_id, *user_id).await {
+                    usernames.push(username.unwrap_or_else(|| "Degen".to_string()));
+                }
+            }
+            if !usernames.is_empty() {
+                if let Err(e) = bot.send_message(chat_id, thread_id, messages.expired(&usernames)).await {
                     error!("Failed to send expiry message: {}", e);
                 }
             }
+        } else if notify_on_expiry {
+            info!("Skipping expiry notice for Chat ID: {}, one was sent recently", chat_id);
+        }
+
+        for (_, msg_id) in requests {
             if let Err(e) = bot.delete_message(chat_id, msg_id).await {
                 error!("Failed to delete expired overlay message: {}", e);
             }
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use tokio::sync::Mutex;
+    use crate::commands::overlay::PendingOverlay;
+    use crate::config::Config;
+    use crate::utils::daily_quota::DailyQuota;
+    use crate::utils::chat_overlays::ChatOverlays;
+    use crate::utils::live_config::{LiveAssets, LiveSettings};
+    use crate::utils::messages::{Localization, Messages};
+    use crate::utils::overlay_assets::{OverlayAssets, OVERLAY_DIR};
+    use std::path::Path;
+    use crate::utils::sender::{MockSender, SentCall};
+
+    #[tokio::test(start_paused = true)]
+    async fn the_expiry_notice_is_posted_in_the_prompts_topic() {
+        let pending_overlays: PendingOverlays = Arc::new(Mutex::new(HashMap::new()));
+        let prompt = PendingOverlay { message_id: MessageId(100), thread_id: Some(7), created: Instant::now(), styles: Vec::new(), request_id: 1 };
+        pending_overlays.lock().await.insert((ChatId(10), UserId(20)), prompt);
+        let assets = LiveAssets::new(ChatOverlays::new(Arc::new(OverlayAssets::load(Path::new(OVERLAY_DIR)))), Localization::default());
+        let live_config = LiveConfig::new(LiveSettings::from_config(&Config::default()), assets, Arc::new(DailyQuota::new(10)), Arc::new(RateLimiter::new(5, Duration::from_secs(60))));
+        tokio::time::advance(live_config.get().overlay_expiration + Duration::from_secs(1)).await;
+
+        let sender = MockSender::new();
+        let messages = Messages::default();
+        let notice_limiter = RateLimiter::new(1, EXPIRY_NOTICE_INTERVAL);
+        cleanup_expired_overlays(sender.clone(), Arc::clone(&pending_overlays), &live_config, true, &notice_limiter).await;
+
+        assert!(pending_overlays.lock().await.is_empty());
+        assert_eq!(sender.calls(), vec![
+            SentCall::Message { chat_id: ChatId(10), thread_id: Some(7), text: messages.expired(&["user20".to_string()]) },
+            SentCall::DeleteMessage { chat_id: ChatId(10), message_id: MessageId(100) },
+        ]);
+    }
+}
@@ -0,0 +1,83 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+use teloxide::types::UserId;
+use tokio::sync::Mutex;
+
+/// The number of seconds in a day.
+const SECONDS_PER_DAY: u64 = 86_400;
+
+/// A DailyQuota struct that caps how many overlays each user can request per day.
+///
+/// The DailyQuota maintains a HashMap that tracks, for each user, the UTC day of their last request and how many
+/// requests they have made on that day. Counts reset at UTC midnight.
+///
+/// Counts are only kept in memory, so they also reset when the bot restarts. The limit can be changed while the bot runs
+/// with `set_max_per_day`, keeping the counts made so far today.
+pub struct DailyQuota {
+    counts: Arc<Mutex<HashMap<UserId, (u64, u32)>>>,
+    max_per_day: AtomicU32,
+}
+
+impl DailyQuota {
+    /// Creates a new `DailyQuota` instance with the specified maximum number of requests per user per day.
+    ///
+    /// # Arguments
+    /// * `max_per_day` - The maximum number of requests a user may make per UTC day. `0` disables the quota.
+    ///
+    /// # Returns
+    /// A new `DailyQuota` instance.
+    pub fn new(max_per_day: u32) -> Self {
+        DailyQuota {
+            counts: Arc::new(Mutex::new(HashMap::new())),
+            max_per_day: AtomicU32::new(max_per_day),
+        }
+    }
+
+    /// Checks the daily quota for the given user and counts the request if the quota has not been reached.
+    ///
+    /// # Arguments
+    /// * `user_id` - The user to check the quota for.
+    ///
+    /// # Returns
+    /// `true` if the user still had quota left today, `false` otherwise.
+    pub async fn check_quota(&self, user_id: UserId) -> bool {
+        let max_per_day = self.max_per_day.load(Ordering::Relaxed);
+        if max_per_day == 0 {
+            return true;
+        }
+
+        let mut counts = self.counts.lock().await;
+        let today = current_utc_day();
+        let (day, count) = counts.entry(user_id).or_insert((today, 0));
+
+        if *day != today {
+            *day = today;
+            *count = 0;
+        }
+
+        if *count >= max_per_day {
+            return false;
+        }
+
+        *count += 1;
+        true
+    }
+
+    /// Changes the maximum number of requests per user per day.
+    ///
+    /// # Arguments
+    /// * `max_per_day` - The new maximum. `0` disables the quota.
+    pub fn set_max_per_day(&self, max_per_day: u32) {
+        self.max_per_day.store(max_per_day, Ordering::Relaxed);
+    }
+}
+
+/// Returns the number of whole days since the Unix epoch, which changes at UTC midnight.
+fn current_utc_day() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_secs() / SECONDS_PER_DAY)
+        .unwrap_or(0)
+}
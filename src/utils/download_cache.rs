@@ -0,0 +1,155 @@
+use std::collections::{HashMap, VecDeque};
+use std::sync::Arc;
+use tokio::sync::Mutex;
+
+/// The cached files, with their unique IDs from least to most recently used.
+struct Entries {
+    files: HashMap<String, Arc<Vec<u8>>>,
+    order: VecDeque<String>,
+    bytes: usize,
+}
+
+/// A DownloadCache struct that keeps the raw bytes of recently downloaded Telegram files, so the same file isn't
+/// downloaded twice.
+///
+/// Files are keyed by their `file_unique_id`, which stays the same for a file however it is sent, so a photo forwarded
+/// into another chat or sent again with other styles is read from memory instead of fetched from Telegram again.
+/// Unlike `ResultCache`, it keeps the source images rather than the rendered results, so it also helps when the
+/// overlay differs.
+///
+/// The cache is bounded by the bytes the files take up, and evicts the least recently used files first. Files are only
+/// kept in memory, so they are forgotten when the bot restarts.
+pub struct DownloadCache {
+    entries: Mutex<Entries>,
+    max_bytes: usize,
+}
+
+impl DownloadCache {
+    /// Creates a new `DownloadCache` instance that keeps files taking up at most `max_bytes`.
+    ///
+    /// # Arguments
+    /// * `max_bytes` - The combined size of the cached files, or `0` to disable the cache.
+    ///
+    /// # Returns
+    /// A new `DownloadCache` instance.
+    pub fn new(max_bytes: usize) -> Self {
+        DownloadCache {
+            entries: Mutex::new(Entries {
+                files: HashMap::new(),
+                order: VecDeque::new(),
+                bytes: 0,
+            }),
+            max_bytes,
+        }
+    }
+
+    /// Returns the cached bytes of a file and marks it as the most recently used.
+    ///
+    /// # Arguments
+    /// * `unique_id` - The file's `file_unique_id`.
+    ///
+    /// # Returns
+    /// The file's bytes, or `None` if it isn't cached.
+    pub async fn get(&self, unique_id: &str) -> Option<Arc<Vec<u8>>> {
+        let mut entries = self.entries.lock().await;
+        let hit = Arc::clone(entries.files.get(unique_id)?);
+        if let Some(position) = entries.order.iter().position(|cached| cached == unique_id) {
+            let unique_id = entries.order.remove(position).expect("position is in bounds");
+            entries.order.push_back(unique_id);
+        }
+        Some(hit)
+    }
+
+    /// Caches the bytes of a file, evicting the least recently used files until it fits.
+    ///
+    /// Files larger than the whole cache aren't cached.
+    ///
+    /// # Arguments
+    /// * `unique_id` - The file's `file_unique_id`.
+    /// * `data` - The file's bytes.
+    pub async fn insert(&self, unique_id: &str, data: Arc<Vec<u8>>) {
+        let size = data.len();
+        if size > self.max_bytes {
+            return;
+        }
+
+        let mut entries = self.entries.lock().await;
+        if let Some(replaced) = entries.files.remove(unique_id) {
+            entries.bytes -= replaced.len();
+            entries.order.retain(|cached| cached != unique_id);
+        }
+        while entries.bytes + size > self.max_bytes {
+            let Some(oldest) = entries.order.pop_front() else {
+                break;
+            };
+            if let Some(evicted) = entries.files.remove(&oldest) {
+                entries.bytes -= evicted.len();
+            }
+        }
+
+        entries.bytes += size;
+        entries.files.insert(unique_id.to_string(), data);
+        entries.order.push_back(unique_id.to_string());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn file(size: usize) -> Arc<Vec<u8>> {
+        Arc::new(vec![0; size])
+    }
+
+    #[tokio::test]
+    async fn returns_cached_files_and_misses_others() {
+        let cache = DownloadCache::new(100);
+        cache.insert("a", file(10)).await;
+
+        assert_eq!(cache.get("a").await.map(|data| data.len()), Some(10));
+        assert!(cache.get("b").await.is_none());
+    }
+
+    #[tokio::test]
+    async fn evicts_the_least_recently_used_file_to_fit() {
+        let cache = DownloadCache::new(100);
+        cache.insert("a", file(40)).await;
+        cache.insert("b", file(40)).await;
+        // Reading "a" makes "b" the least recently used
+        assert!(cache.get("a").await.is_some());
+        cache.insert("c", file(40)).await;
+
+        assert!(cache.get("a").await.is_some());
+        assert!(cache.get("b").await.is_none());
+        assert!(cache.get("c").await.is_some());
+        assert_eq!(cache.entries.lock().await.bytes, 80);
+    }
+
+    #[tokio::test]
+    async fn replacing_a_file_doesnt_count_it_twice() {
+        let cache = DownloadCache::new(100);
+        cache.insert("a", file(60)).await;
+        cache.insert("a", file(60)).await;
+
+        assert!(cache.get("a").await.is_some());
+        assert_eq!(cache.entries.lock().await.bytes, 60);
+    }
+
+    #[tokio::test]
+    async fn skips_files_larger_than_the_cache() {
+        let cache = DownloadCache::new(100);
+        cache.insert("a", file(50)).await;
+        cache.insert("huge", file(101)).await;
+
+        assert!(cache.get("huge").await.is_none());
+        assert!(cache.get("a").await.is_some());
+    }
+
+    #[tokio::test]
+    async fn a_disabled_cache_keeps_nothing() {
+        let cache = DownloadCache::new(0);
+        cache.insert("a", file(1)).await;
+
+        assert!(cache.get("a").await.is_none());
+    }
+}
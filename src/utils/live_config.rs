@@ -0,0 +1,281 @@
+use std::io;
+use std::path::Path;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+use log::{error, info};
+use teloxide::types::ChatId;
+use thiserror::Error;
+
+use crate::config::{config_path, load_config, Config, ConfigError};
+use crate::utils::chat_overlays::ChatOverlays;
+use crate::utils::daily_quota::DailyQuota;
+use crate::utils::messages::{Localization, MessagesError};
+use crate::utils::overlay_assets::{OverlayAssets, OverlayAssetsError, OVERLAY_DIR};
+use crate::utils::rate_limiter::RateLimiter;
+
+/// The settings that take effect when the config is reloaded, without restarting the bot, besides the overlays and
+/// messages in `LiveAssets`.
+///
+/// - `max_overlays_per_day`: `limits.max_overlays_per_day`.
+/// - `rate_limit_max_requests` and `rate_limit_window`: `limits.rate_limit_max_requests` and
+///   `limits.rate_limit_window_secs`.
+/// - `overlay_expiration`: `telegram.overlay_expiration_secs`.
+/// - `max_queue_depth`: `processing.max_queue_depth`.
+/// - `confirm_above_bytes`: `processing.confirm_above_bytes`.
+/// - `dm_nudge`: `telegram.dm_nudge`.
+/// - `reply_reminder_after`: from `telegram.reply_reminder` and `telegram.reply_reminder_fraction`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct LiveSettings {
+    pub max_overlays_per_day: u32,
+    pub rate_limit_max_requests: u32,
+    pub rate_limit_window: Duration,
+    pub overlay_expiration: Duration,
+    pub max_queue_depth: usize,
+    pub confirm_above_bytes: u32,
+    pub dm_nudge: bool,
+    pub reply_reminder_after: Option<Duration>,
+}
+
+impl LiveSettings {
+    /// Takes the reloadable settings from a config.
+    ///
+    /// # Arguments
+    /// * `config` - The validated config.
+    ///
+    /// # Returns
+    /// The reloadable settings in `config`.
+    pub fn from_config(config: &Config) -> Self {
+        let overlay_expiration = Duration::from_secs(config.telegram.overlay_expiration_secs);
+        LiveSettings {
+            max_overlays_per_day: config.limits.max_overlays_per_day,
+            rate_limit_max_requests: config.limits.rate_limit_max_requests,
+            rate_limit_window: Duration::from_secs(config.limits.rate_limit_window_secs),
+            overlay_expiration,
+            max_queue_depth: config.processing.max_queue_depth,
+            confirm_above_bytes: config.processing.confirm_above_bytes,
+            dm_nudge: config.telegram.dm_nudge,
+            reply_reminder_after: config.telegram.reply_reminder
+                .then(|| overlay_expiration.mul_f64(config.telegram.reply_reminder_fraction)),
+        }
+    }
+}
+
+/// The overlays and messages that take effect when the config is reloaded, loaded from the files the config names.
+///
+/// - `chat_overlays`: the overlays in `img` and in each `[telegram.chat_overlays]` directory, built with the
+///   `[processing]` settings, so their styles, `style_tints`, watermark and the way they are drawn.
+/// - `localization`: the messages in `telegram.messages_dir`, including the result captions, and the processing
+///   messages in `[telegram.chat_messages]`.
+/// - `generation`: how many reloads these were loaded by, so results rendered with older overlays aren't served from
+///   the result cache.
+pub struct LiveAssets {
+    pub chat_overlays: ChatOverlays,
+    pub localization: Localization,
+    pub generation: u64,
+}
+
+impl LiveAssets {
+    /// Creates the assets the bot starts with.
+    ///
+    /// # Arguments
+    /// * `chat_overlays` - The overlays loaded at startup.
+    /// * `localization` - The messages loaded at startup.
+    ///
+    /// # Returns
+    /// A new `LiveAssets` instance of the first generation.
+    pub fn new(chat_overlays: ChatOverlays, localization: Localization) -> Self {
+        LiveAssets { chat_overlays, localization, generation: 0 }
+    }
+
+    /// Loads the overlays and messages a config names, checking every one of them, for a reload.
+    ///
+    /// Unlike at startup, a messages file that can't be loaded is an error rather than skipped, so the reload is
+    /// refused instead of quietly dropping a language.
+    ///
+    /// # Arguments
+    /// * `config` - The validated config.
+    /// * `generation` - The generation of the new assets.
+    ///
+    /// # Returns
+    /// The new assets, or the `ReloadError` for the first overlay or messages file that couldn't be loaded.
+    fn load(config: &Config, generation: u64) -> Result<Self, ReloadError> {
+        let default_overlays = OverlayAssets::from_config(Path::new(OVERLAY_DIR), &config.processing);
+        default_overlays.check()?;
+        let chat_overlay_dirs = config.telegram.chat_overlay_dirs().into_iter()
+            .map(|(chat_id, dir)| (ChatId(chat_id), dir))
+            .collect();
+        let chat_overlays = ChatOverlays::load(Arc::new(default_overlays), &chat_overlay_dirs, &config.processing)?;
+        let localization = Localization::try_load(
+            Path::new(&config.telegram.messages_dir),
+            &config.telegram.default_language,
+            config.telegram.use_user_language,
+        )?.with_chat_processing(config.telegram.chat_processing_messages());
+        Ok(LiveAssets { chat_overlays, localization, generation })
+    }
+}
+
+/// An error that kept a reload from applying, leaving the current settings, overlays and messages in place.
+#[derive(Debug, Error)]
+pub enum ReloadError {
+    #[error(transparent)]
+    Config(#[from] ConfigError),
+    #[error(transparent)]
+    Overlays(#[from] OverlayAssetsError),
+    #[error(transparent)]
+    Messages(#[from] MessagesError),
+}
+
+/// A LiveConfig struct that holds the settings, overlays and messages that can be changed by reloading the config file
+/// while the bot runs.
+///
+/// The settings and assets sit behind `RwLock`s, so the handlers read the current ones for every message while a
+/// reload swaps in new ones. A reload re-reads the config file and validates the whole of it, then loads and checks
+/// every overlay and messages file it names, and only applies them if all of that succeeds, so a typo or a broken
+/// overlay keeps the current ones instead of half-applying them. The fields in `LiveSettings` and `LiveAssets` are
+/// reloaded; everything else, such as the bot tokens, `web.bind_addr`, the overlay commands, the number of image
+/// workers, the archive settings, the `/start` image and the inline sample, is read once at startup and needs a
+/// restart to change.
+pub struct LiveConfig {
+    settings: RwLock<LiveSettings>,
+    assets: RwLock<Arc<LiveAssets>>,
+    daily_quota: Arc<DailyQuota>,
+    rate_limiter: Arc<RateLimiter>,
+}
+
+impl LiveConfig {
+    /// Creates a new `LiveConfig` instance with the settings and assets from startup.
+    ///
+    /// # Arguments
+    /// * `settings` - The reloadable settings from the config the bot started with.
+    /// * `assets` - The overlays and messages loaded at startup.
+    /// * `daily_quota` - The daily quota, whose limit is updated on reload.
+    /// * `rate_limiter` - The overlay rate limiter, whose limit is updated on reload.
+    ///
+    /// # Returns
+    /// A new `LiveConfig` instance.
+    pub fn new(settings: LiveSettings, assets: LiveAssets, daily_quota: Arc<DailyQuota>, rate_limiter: Arc<RateLimiter>) -> Self {
+        LiveConfig {
+            settings: RwLock::new(settings),
+            assets: RwLock::new(Arc::new(assets)),
+            daily_quota,
+            rate_limiter,
+        }
+    }
+
+    /// Returns a copy of the current settings.
+    pub fn get(&self) -> LiveSettings {
+        *self.settings.read().unwrap()
+    }
+
+    /// Returns the current overlays and messages.
+    ///
+    /// What this returns doesn't change when a reload swaps in new assets, so the overlays and messages read from one
+    /// call always belong together.
+    pub fn assets(&self) -> Arc<LiveAssets> {
+        Arc::clone(&self.assets.read().unwrap())
+    }
+
+    /// Re-reads the config file, including the `DEGENBOT_*` environment overrides, and applies its reloadable settings,
+    /// overlays and messages.
+    ///
+    /// The outcome is logged, so a reload triggered by `SIGHUP` is visible in the logs as well. The rate limit is only
+    /// set if it differs from the one in the config loaded before, so a limit set with `/setlimit` survives reloads
+    /// that don't change it. The overlays are decoded to check them, so a reload takes a moment with many overlays.
+    ///
+    /// # Returns
+    /// The settings now in force, or the `ReloadError` that kept the current settings in place if the file is missing,
+    /// can't be parsed or is invalid, or an overlay or messages file it names can't be loaded.
+    pub fn reload(&self) -> Result<LiveSettings, ReloadError> {
+        let generation = self.assets().generation + 1;
+        let result = load_reloaded_config().and_then(|config| {
+            let assets = LiveAssets::load(&config, generation)?;
+            let settings = LiveSettings::from_config(&config);
+            *self.assets.write().unwrap() = Arc::new(assets);
+            let previous = std::mem::replace(&mut *self.settings.write().unwrap(), settings);
+            self.daily_quota.set_max_per_day(settings.max_overlays_per_day);
+            if (settings.rate_limit_max_requests, settings.rate_limit_window) != (previous.rate_limit_max_requests, previous.rate_limit_window) {
+                self.rate_limiter.set_limit(settings.rate_limit_max_requests, settings.rate_limit_window);
+            }
+            Ok(settings)
+        });
+        match &result {
+            Ok(settings) => info!("Reloaded the config: {:?}", settings),
+            Err(e) => error!("Failed to reload the config, keeping the current settings: {}", e),
+        }
+        result
+    }
+}
+
+/// Loads and validates the config file for a reload.
+///
+/// Unlike at startup, a missing file is an error, since falling back to the defaults would quietly undo every setting.
+fn load_reloaded_config() -> Result<Config, ReloadError> {
+    let path = config_path();
+    if !path.exists() {
+        return Err(ConfigError::Read { path, source: io::Error::from(io::ErrorKind::NotFound) }.into());
+    }
+    let config = load_config()?;
+    config.validate()?;
+    Ok(config)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn from_config_takes_the_rate_limit_and_expiration() {
+        let mut config = Config::default();
+        config.limits.rate_limit_max_requests = 10;
+        config.limits.rate_limit_window_secs = 30;
+        config.telegram.overlay_expiration_secs = 300;
+        config.telegram.reply_reminder = true;
+        config.telegram.reply_reminder_fraction = 0.5;
+
+        let settings = LiveSettings::from_config(&config);
+        assert_eq!(settings.rate_limit_max_requests, 10);
+        assert_eq!(settings.rate_limit_window, Duration::from_secs(30));
+        assert_eq!(settings.overlay_expiration, Duration::from_secs(300));
+        assert_eq!(settings.reply_reminder_after, Some(Duration::from_secs(150)));
+    }
+
+    /// Creates a messages directory of its own under the system temp directory holding `files`, and returns its path.
+    fn messages_dir(name: &str, files: &[(&str, &str)]) -> String {
+        let dir = std::env::temp_dir().join(format!("degenbot-live-config-{}-{}", std::process::id(), name));
+        std::fs::create_dir_all(&dir).unwrap();
+        for (file, content) in files {
+            std::fs::write(dir.join(file), content).unwrap();
+        }
+        dir.to_string_lossy().into_owned()
+    }
+
+    #[test]
+    fn loads_the_messages_and_overlays_the_config_names() {
+        let mut config = Config::default();
+        config.telegram.messages_dir = messages_dir("valid", &[("de.toml", "result_caption = \"Bitte sehr {username}\"\n")]);
+        config.telegram.default_language = "de".to_string();
+
+        let assets = LiveAssets::load(&config, 3).unwrap();
+        assert_eq!(assets.localization.default_messages().result_caption("@degen"), "Bitte sehr @degen");
+        assert!(!assets.chat_overlays.default_overlays().styles().is_empty());
+        assert_eq!(assets.generation, 3);
+    }
+
+    #[test]
+    fn a_broken_messages_file_fails_the_load() {
+        let mut config = Config::default();
+        config.telegram.messages_dir = messages_dir("broken", &[("de.toml", "result_caption = \n")]);
+
+        assert!(matches!(LiveAssets::load(&config, 1), Err(ReloadError::Messages(_))));
+    }
+
+    #[test]
+    fn a_missing_chat_overlay_directory_fails_the_load() {
+        let mut config = Config::default();
+        let missing = std::env::temp_dir().join(format!("degenbot-live-config-{}-missing", std::process::id()));
+        config.telegram.chat_overlays.insert("-1001234".to_string(), missing.to_string_lossy().into_owned());
+
+        assert!(matches!(LiveAssets::load(&config, 1), Err(ReloadError::Overlays(OverlayAssetsError::MissingDirectory(_)))));
+    }
+
+}
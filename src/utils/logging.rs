@@ -0,0 +1,61 @@
+use std::env;
+use std::str::FromStr;
+use log::warn;
+use serde::Deserialize;
+use tracing_subscriber::EnvFilter;
+
+use crate::config::LoggingConfig;
+
+/// How log lines are written.
+///
+/// - `Pretty`: Colored, human-readable lines, as written by `pretty_env_logger`.
+/// - `Json`: One JSON object per line, for platforms that ingest structured logs, written by `tracing-subscriber`.
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    #[default]
+    Pretty,
+    Json,
+}
+
+impl FromStr for LogFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "pretty" => Ok(LogFormat::Pretty),
+            "json" => Ok(LogFormat::Json),
+            _ => Err(format!("expected \"pretty\" or \"json\", got {:?}", s)),
+        }
+    }
+}
+
+/// Sets up the logger from the `[logging]` config.
+///
+/// The `RUST_LOG` environment variable, if set, takes precedence over the configured `level`, so a deploy can turn up
+/// logging without editing the config. The JSON format also picks up lines logged with `log`, so every line comes out
+/// as JSON. If a logger has already been set up, e.g. by the hosting platform, it is kept and a warning is logged
+/// through it.
+///
+/// # Arguments
+/// * `logging` - The logging settings from the config.
+pub fn init(logging: &LoggingConfig) {
+    let filter = env::var("RUST_LOG")
+        .ok()
+        .filter(|filter| !filter.trim().is_empty())
+        .unwrap_or_else(|| logging.level.clone());
+    let result = match logging.format {
+        LogFormat::Pretty => pretty_env_logger::formatted_builder()
+            .parse_filters(&filter)
+            .try_init()
+            .map_err(|e| e.to_string()),
+        LogFormat::Json => tracing_subscriber::fmt()
+            .json()
+            .with_env_filter(EnvFilter::new(&filter))
+            .try_init()
+            .map_err(|e| e.to_string()),
+    };
+    if let Err(e) = result {
+        warn!("A logger is already set up, ignoring the [logging] config: {}", e);
+    }
+}
@@ -0,0 +1,142 @@
+use std::collections::hash_map::Entry;
+use std::collections::HashMap;
+use std::time::Duration;
+use teloxide::types::{ChatId, MessageId, PhotoSize};
+use tokio::sync::Mutex;
+use tokio::time::Instant;
+
+/// How long to wait after the first photo of an album arrives before queueing the album, so the rest can arrive.
+pub const MEDIA_GROUP_WAIT: Duration = Duration::from_millis(1500);
+
+/// How long an album that is never processed is kept, such as one sent in reply to a request that expired.
+const ALBUM_TTL: Duration = Duration::from_secs(10 * 60);
+
+/// The photos of an album collected so far, and when its first photo arrived.
+struct Album {
+    photos: Vec<(MessageId, PhotoSize)>,
+    started: Instant,
+}
+
+/// A MediaGroups struct that collects the photos of albums sent in reply to a `/degenme` prompt.
+///
+/// Telegram delivers every photo of an album as a separate message sharing a `media_group_id`, so the photos are
+/// gathered here as they arrive. Only the first message of the album is queued, after `MEDIA_GROUP_WAIT`, and the
+/// processor takes the whole album when it gets to that message. At most `max_photos` of an album are processed.
+/// Albums that are never taken are forgotten after ten minutes. Albums are only kept in memory, so they are forgotten
+/// when the bot restarts.
+pub struct MediaGroups {
+    albums: Mutex<HashMap<(ChatId, String), Album>>,
+    max_photos: usize,
+}
+
+impl MediaGroups {
+    /// Creates a new `MediaGroups` instance that processes up to `max_photos` photos of an album.
+    ///
+    /// # Arguments
+    /// * `max_photos` - The number of photos of an album that are processed, at least one.
+    ///
+    /// # Returns
+    /// A new `MediaGroups` instance.
+    pub fn new(max_photos: usize) -> Self {
+        MediaGroups {
+            albums: Mutex::new(HashMap::new()),
+            max_photos: max_photos.max(1),
+        }
+    }
+
+    /// Returns the number of photos of an album that are processed.
+    pub fn max_photos(&self) -> usize {
+        self.max_photos
+    }
+
+    /// Adds a photo to its album, forgetting any albums that have expired.
+    ///
+    /// # Arguments
+    /// * `chat_id` - The chat the album was sent in.
+    /// * `media_group_id` - The album's `media_group_id`.
+    /// * `message_id` - The ID of the message the photo came in.
+    /// * `photo` - The photo, in the size that will be downloaded.
+    ///
+    /// # Returns
+    /// `true` if this is the first photo of the album, in which case the caller queues the album, `false` otherwise.
+    pub async fn add(&self, chat_id: ChatId, media_group_id: &str, message_id: MessageId, photo: PhotoSize) -> bool {
+        let mut albums = self.albums.lock().await;
+        albums.retain(|_, album| album.started.elapsed() < ALBUM_TTL);
+        match albums.entry((chat_id, media_group_id.to_string())) {
+            Entry::Occupied(mut album) => {
+                album.get_mut().photos.push((message_id, photo));
+                false
+            }
+            Entry::Vacant(album) => {
+                album.insert(Album { photos: vec![(message_id, photo)], started: Instant::now() });
+                true
+            }
+        }
+    }
+
+    /// Takes the photos of an album collected so far.
+    ///
+    /// # Arguments
+    /// * `chat_id` - The chat the album was sent in.
+    /// * `media_group_id` - The album's `media_group_id`.
+    ///
+    /// # Returns
+    /// The album's photos with the IDs of their messages, in the order they were sent, or an empty list if no photos of
+    /// the album were collected.
+    pub async fn take(&self, chat_id: ChatId, media_group_id: &str) -> Vec<(MessageId, PhotoSize)> {
+        let album = self.albums.lock().await.remove(&(chat_id, media_group_id.to_string()));
+        let mut photos = album.map(|album| album.photos).unwrap_or_default();
+        // Updates can arrive out of order, but message IDs follow the order the photos were sent in
+        photos.sort_by_key(|(message_id, _)| message_id.0);
+        photos
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn photo(file_id: &str) -> PhotoSize {
+        serde_json::from_value(serde_json::json!({
+            "file_id": file_id,
+            "file_unique_id": file_id,
+            "file_size": 1024,
+            "width": 800,
+            "height": 600,
+        })).expect("a valid photo")
+    }
+
+    #[tokio::test]
+    async fn collects_an_album_in_the_order_it_was_sent() {
+        let media_groups = MediaGroups::new(10);
+        let chat_id = ChatId(1);
+        assert!(media_groups.add(chat_id, "album", MessageId(12), photo("second")).await);
+        assert!(!media_groups.add(chat_id, "album", MessageId(11), photo("first")).await);
+        assert!(!media_groups.add(chat_id, "album", MessageId(13), photo("third")).await);
+        // Another album in the same chat is collected separately
+        assert!(media_groups.add(chat_id, "other", MessageId(20), photo("other")).await);
+
+        let photos = media_groups.take(chat_id, "album").await;
+        let ids: Vec<(i32, &str)> = photos.iter().map(|(id, photo)| (id.0, photo.file.id.as_str())).collect();
+        assert_eq!(ids, [(11, "first"), (12, "second"), (13, "third")]);
+
+        // Taking an album forgets it, so a late photo starts a new one
+        assert!(media_groups.take(chat_id, "album").await.is_empty());
+        assert!(media_groups.add(chat_id, "album", MessageId(14), photo("late")).await);
+        assert_eq!(media_groups.take(chat_id, "other").await.len(), 1);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn forgets_albums_that_are_never_taken() {
+        let media_groups = MediaGroups::new(0);
+        assert_eq!(media_groups.max_photos(), 1);
+        assert!(media_groups.add(ChatId(1), "album", MessageId(1), photo("old")).await);
+
+        tokio::time::advance(ALBUM_TTL).await;
+        // Adding any photo clears out expired albums, so this starts the album again
+        assert!(media_groups.add(ChatId(1), "album", MessageId(2), photo("new")).await);
+        let photos = media_groups.take(ChatId(1), "album").await;
+        assert_eq!(photos.len(), 1);
+        assert_eq!(photos[0].1.file.id, "new");
+    }
+}
@@ -0,0 +1,814 @@
+use std::collections::HashMap;
+use std::fmt::Display;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use serde::Deserialize;
+use teloxide::types::{ChatId, Message, UserId};
+use log::{info, warn};
+use thiserror::Error;
+
+use crate::utils::url_download::UrlDownloadError;
+
+/// Every user-facing message the bot sends, in one language.
+///
+/// A language is loaded from a TOML file with one key per message, such as `messages/de.toml`. Placeholders in braces,
+/// such as `{username}`, are filled in when the message is sent; the placeholders each message supports are listed in
+/// `messages/en.toml`. Any key missing from the file falls back to the built-in English message, so a translation can
+/// be partial.
+#[derive(Debug, Deserialize)]
+#[serde(default)]
+pub struct Messages {
+    welcome: String,
+    prompt: String,
+    prompt_replaced: String,
+    reply_reminder: String,
+    rate_limited: String,
+    rate_limited_for: String,
+    rate_limit_warning: String,
+    daily_limit: String,
+    paused: String,
+    swamped: String,
+    queue_position: String,
+    too_many_styles: String,
+    unknown_style: String,
+    again_usage: String,
+    processing: String,
+    result_caption: String,
+    result_private: String,
+    send_failed: String,
+    replied_to_result: String,
+    source_expired: String,
+    request_expired: String,
+    no_photo: String,
+    unsupported_sticker: String,
+    file_too_large: String,
+    too_large_to_fetch: String,
+    download_failed: String,
+    not_an_image: String,
+    link_download_failed: String,
+    link_unsupported_scheme: String,
+    link_blocked_address: String,
+    link_too_many_redirects: String,
+    link_not_an_image: String,
+    link_too_large: String,
+    unsupported_format: String,
+    decode_failed: String,
+    corrupted: String,
+    too_small: String,
+    took_too_long: String,
+    album_truncated: String,
+    overlay_failed: String,
+    expired_one: String,
+    expired_many: String,
+    confirm_prompt: String,
+    confirm_yes: String,
+    confirm_no: String,
+    confirm_expired: String,
+    confirm_not_yours: String,
+    confirm_accepted: String,
+    confirm_declined: String,
+    random_no_samples: String,
+    random_failed: String,
+    random_caption: String,
+    discord_usage: String,
+    inline_caption: String,
+    dm_nudge: String,
+    queue_status: String,
+    queue_status_one: String,
+    paused_now: String,
+    resumed_now: String,
+    already_paused: String,
+    not_paused: String,
+    ban_usage: String,
+    banned_now: String,
+    unbanned_now: String,
+    already_banned: String,
+    not_banned: String,
+    cannot_ban_admin: String,
+    setlimit_usage: String,
+    limit_set: String,
+    config_reloaded: String,
+    reload_failed: String,
+    preview_usage: String,
+    preview_asset: String,
+    feedback_disabled: String,
+    feedback_missing: String,
+    feedback_rate_limited: String,
+    feedback_sent: String,
+    history_unavailable: String,
+    history_empty: String,
+    history_failed: String,
+}
+
+impl Default for Messages {
+    fn default() -> Self {
+        Messages {
+            welcome: "Welcome to the Degen POV bot! Use /degenme to create an overlay in any channel, group, or DM I am in!".to_string(),
+            prompt: "Hey, {username}! Please reply within {minutes} minutes to this message with an image to see the Degen Point of View!".to_string(),
+            prompt_replaced: "Previous request cancelled. Hey, {username}! Please reply within {minutes} minutes to this message with an image to see the Degen Point of View!".to_string(),
+            reply_reminder: "{username}, still waiting for your photo, {seconds} seconds left!".to_string(),
+            rate_limited: "You're sending commands too quickly. Please wait a moment before trying again.".to_string(),
+            rate_limited_for: "You're sending commands too quickly. Try again in {seconds}s.".to_string(),
+            rate_limit_warning: "You're going fast, slow down.".to_string(),
+            daily_limit: "You've hit your daily limit, try again tomorrow.".to_string(),
+            paused: "The bot is temporarily paused".to_string(),
+            swamped: "I'm swamped, try again in a bit.".to_string(),
+            queue_position: "You're #{position} in line. Please wait...".to_string(),
+            too_many_styles: "You can stack at most {max} overlays at once.".to_string(),
+            unknown_style: "There's no \"{style}\" overlay. Available overlays: {available}".to_string(),
+            again_usage: "Reply to one of my results with /again <style> to try another overlay.".to_string(),
+            processing: "Making {username} a degen... Please wait...".to_string(),
+            result_caption: "Here you go {username}, you degen.".to_string(),
+            result_private: "I'm not allowed to post in that chat, so here's your degen privately.".to_string(),
+            send_failed: "Failed to send your image. Please try again.".to_string(),
+            replied_to_result: "Reply to the /degenme prompt, not the result.".to_string(),
+            source_expired: "I don't have that image anymore. Please send the photo again with /degenme.".to_string(),
+            request_expired: "Your overlay request has expired. Please use the /degenme command again.".to_string(),
+            no_photo: "Please reply with an image or an image link to degen.".to_string(),
+            unsupported_sticker: "Animated and video stickers aren't supported, please send a photo or a static sticker.".to_string(),
+            file_too_large: "Your image is too large, please send one under {megabytes} MB.".to_string(),
+            too_large_to_fetch: "That image is too large for me to fetch, Telegram only lets bots download files up to {megabytes} MB. Please send a smaller one.".to_string(),
+            download_failed: "Failed to download your image. Please try again.".to_string(),
+            not_an_image: "Telegram sent back something that isn't an image. Please try sending your photo again.".to_string(),
+            link_download_failed: "Failed to download the image from your link. Please try again.".to_string(),
+            link_unsupported_scheme: "Couldn't use your link, only http and https links are supported.".to_string(),
+            link_blocked_address: "Couldn't use your link, links to private or local addresses aren't allowed.".to_string(),
+            link_too_many_redirects: "Couldn't use your link, it redirects too many times.".to_string(),
+            link_not_an_image: "Your link doesn't point to an image. Please send a link to a JPG or PNG.".to_string(),
+            link_too_large: "The image at your link is too large, please send one under {megabytes} MB.".to_string(),
+            unsupported_format: "{format} isn't supported, please send a JPG or PNG.".to_string(),
+            decode_failed: "Failed to decode your image. Please try again.".to_string(),
+            corrupted: "Your image seems to be corrupted or incomplete. Please send it again.".to_string(),
+            too_small: "That image is too small to degenify.".to_string(),
+            took_too_long: "That image took too long to process. Please try a smaller or simpler one.".to_string(),
+            album_truncated: "Only the first {max} photos of an album are degened.".to_string(),
+            overlay_failed: "Failed to process your image. Please try again later.".to_string(),
+            expired_one: "{username}, you degen, you forgot to send me a picture! Please run /degenme again to send an image.".to_string(),
+            expired_many: "{usernames}, you degens, you forgot to send me pictures! Please run /degenme again to send an image.".to_string(),
+            confirm_prompt: "This is a big image, process it?".to_string(),
+            confirm_yes: "Yes".to_string(),
+            confirm_no: "No".to_string(),
+            confirm_expired: "This request has expired. Please use /degenme again.".to_string(),
+            confirm_not_yours: "Only the person who sent the image can answer this.".to_string(),
+            confirm_accepted: "Processing your image...".to_string(),
+            confirm_declined: "Okay, skipping that one. You can reply with a smaller image instead.".to_string(),
+            random_no_samples: "No sample photos are configured for /random, sorry!".to_string(),
+            random_failed: "Failed to make a random degen. Please try again later.".to_string(),
+            random_caption: "Here's a random degen. Use /degenme to make your own!".to_string(),
+            discord_usage: "Attach an image to !degenme to degen it, e.g. !degenme hands".to_string(),
+            inline_caption: "Degen Point of View".to_string(),
+            dm_nudge: "Try /degenme to get started, or /start to see what I can do.".to_string(),
+            queue_status: "📷 {queued} images queued, {awaiting} awaiting upload.".to_string(),
+            queue_status_one: "📷 1 image queued, {awaiting} awaiting upload.".to_string(),
+            paused_now: "Processing paused. Use /resume to start again.".to_string(),
+            resumed_now: "Processing resumed.".to_string(),
+            already_paused: "Processing is already paused.".to_string(),
+            not_paused: "Processing isn't paused.".to_string(),
+            ban_usage: "Use /{command} <user id>, or reply to one of the user's messages with /{command}.".to_string(),
+            banned_now: "User {user_id} is banned.".to_string(),
+            unbanned_now: "User {user_id} is no longer banned.".to_string(),
+            already_banned: "User {user_id} is already banned.".to_string(),
+            not_banned: "User {user_id} isn't banned.".to_string(),
+            cannot_ban_admin: "Admins can't be banned.".to_string(),
+            setlimit_usage: "Use /setlimit <max> <window_secs>, e.g. /setlimit 5 60 for 5 overlays a minute, with up to {max_requests} requests and windows from 1 to {max_window} seconds. The limit is {current_max} every {current_window} seconds.".to_string(),
+            limit_set: "Rate limit set to {max} overlays every {window} seconds.".to_string(),
+            config_reloaded: "Config reloaded. The limits, time to reply, queue depth, confirmation size, DM nudge, reply reminder, overlays, styles, tints, captions and messages are updated. The tokens, overlay commands, image workers, archive and everything else need a restart.".to_string(),
+            reload_failed: "Couldn't reload the config, keeping the current settings: {error}".to_string(),
+            preview_usage: "Use /preview <width>x<height> [style], e.g. /preview 1080x1920 hands, with sizes from 1 to {max} pixels.".to_string(),
+            preview_asset: "A {width}x{height} image (aspect ratio {ratio}) uses the {asset} overlays: {overlays}".to_string(),
+            feedback_disabled: "Feedback isn't set up for this bot, sorry!".to_string(),
+            feedback_missing: "Please include your feedback, e.g. /feedback the hands are upside down".to_string(),
+            feedback_rate_limited: "You've sent a lot of feedback recently. Please wait a while before sending more.".to_string(),
+            feedback_sent: "Thanks! Your feedback has been sent to the team.".to_string(),
+            history_unavailable: "History isn't available, this bot doesn't keep your results.".to_string(),
+            history_empty: "I don't have any of your results from this chat yet.".to_string(),
+            history_failed: "Failed to fetch your results. Please try again later.".to_string(),
+        }
+    }
+}
+
+/// The placeholders a chat's own processing message, from `[telegram.chat_messages]`, can use.
+pub const PROCESSING_PLACEHOLDERS: &[&str] = &["username"];
+
+/// Finds the `{name}` placeholders in a message template that aren't in `known`, so typos can be caught at startup
+/// rather than showing up in a message.
+///
+/// # Arguments
+/// * `template` - The message template to check.
+/// * `known` - The names of the placeholders the message supports.
+///
+/// # Returns
+/// The names of the unknown placeholders, in the order they appear.
+pub fn unknown_placeholders(template: &str, known: &[&str]) -> Vec<String> {
+    let mut unknown = Vec::new();
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        rest = &rest[start + 1..];
+        let Some(end) = rest.find('}') else {
+            break;
+        };
+        let name = &rest[..end];
+        if !known.contains(&name) {
+            unknown.push(name.to_string());
+        }
+        rest = &rest[end + 1..];
+    }
+    unknown
+}
+
+/// Fills in the `{name}` placeholders of a message template.
+fn fill(template: &str, values: &[(&str, &dyn Display)]) -> String {
+    values.iter().fold(template.to_string(), |text, (name, value)| {
+        text.replace(&format!("{{{}}}", name), &value.to_string())
+    })
+}
+
+impl Messages {
+    /// The `/start` welcome message.
+    pub fn welcome(&self) -> &str {
+        &self.welcome
+    }
+
+    /// The `/degenme` prompt asking the user to reply with an image within `expiration`, rounded up to whole minutes,
+    /// or the one that also says their previous request was cancelled.
+    pub fn prompt(&self, username: &str, replaced: bool, expiration: Duration) -> String {
+        let template = if replaced { &self.prompt_replaced } else { &self.prompt };
+        let minutes = (expiration.as_secs_f64() / 60.0).ceil().max(1.0) as u64;
+        fill(template, &[("username", &username), ("minutes", &minutes)])
+    }
+
+    /// Reminds a user that their `/degenme` prompt is still waiting for an image, with the seconds left to reply.
+    pub fn reply_reminder(&self, username: &str, left: Duration) -> String {
+        fill(&self.reply_reminder, &[("username", &username), ("seconds", &left.as_secs())])
+    }
+
+    /// Tells a user they are sending commands too quickly.
+    ///
+    /// # Arguments
+    /// * `wait` - How long until the user can try again, from `RateLimiter::time_until_allowed`, if known. It is
+    ///   rounded up to whole seconds.
+    pub fn rate_limited(&self, wait: Option<Duration>) -> String {
+        match wait {
+            Some(wait) => fill(&self.rate_limited_for, &[("seconds", &(wait.as_secs_f64().ceil() as u64).max(1))]),
+            None => self.rate_limited.clone(),
+        }
+    }
+
+    /// Tells a user whose request is still processed that they are about to hit the rate limit.
+    pub fn rate_limit_warning(&self) -> &str {
+        &self.rate_limit_warning
+    }
+
+    /// Tells a user they have used up their daily quota.
+    pub fn daily_limit(&self) -> &str {
+        &self.daily_limit
+    }
+
+    /// Tells a user that an admin has paused processing.
+    pub fn paused(&self) -> &str {
+        &self.paused
+    }
+
+    /// Tells a user the queue is too deep to take their request.
+    pub fn swamped(&self) -> &str {
+        &self.swamped
+    }
+
+    /// Tells a user their position in the queue.
+    pub fn queue_position(&self, position: usize) -> String {
+        fill(&self.queue_position, &[("position", &position)])
+    }
+
+    /// Tells a user they asked for more stacked overlays than allowed.
+    pub fn too_many_styles(&self, max: usize) -> String {
+        fill(&self.too_many_styles, &[("max", &max)])
+    }
+
+    /// Tells a user an overlay style they asked for doesn't exist, listing the ones that do.
+    pub fn unknown_style(&self, style: &str, available: &[String]) -> String {
+        fill(&self.unknown_style, &[("style", &style), ("available", &available.join(", "))])
+    }
+
+    /// Explains how to use `/again`.
+    pub fn again_usage(&self) -> &str {
+        &self.again_usage
+    }
+
+    /// The message shown while a user's image is being processed.
+    pub fn processing(&self, username: &str) -> String {
+        fill(&self.processing, &[("username", &username)])
+    }
+
+    /// The caption of a result.
+    pub fn result_caption(&self, username: &str) -> String {
+        fill(&self.result_caption, &[("username", &username)])
+    }
+
+    /// The caption of a result sent privately because the chat refused it.
+    pub fn result_private(&self) -> &str {
+        &self.result_private
+    }
+
+    pub fn send_failed(&self) -> &str {
+        &self.send_failed
+    }
+
+    pub fn replied_to_result(&self) -> &str {
+        &self.replied_to_result
+    }
+
+    pub fn source_expired(&self) -> &str {
+        &self.source_expired
+    }
+
+    pub fn request_expired(&self) -> &str {
+        &self.request_expired
+    }
+
+    pub fn no_photo(&self) -> &str {
+        &self.no_photo
+    }
+
+    pub fn unsupported_sticker(&self) -> &str {
+        &self.unsupported_sticker
+    }
+
+    /// Tells a user their photo is over the size limit, given in bytes and shown in megabytes.
+    pub fn file_too_large(&self, max_bytes: u32) -> String {
+        fill(&self.file_too_large, &[("megabytes", &format!("{:.1}", max_bytes as f64 / (1024.0 * 1024.0)))])
+    }
+
+    /// Tells a user their image is over the Bot API's download limit, in megabytes.
+    pub fn too_large_to_fetch(&self, max_bytes: u32) -> String {
+        fill(&self.too_large_to_fetch, &[("megabytes", &format!("{:.0}", max_bytes as f64 / (1024.0 * 1024.0)))])
+    }
+
+    pub fn download_failed(&self) -> &str {
+        &self.download_failed
+    }
+
+    /// Tells a user Telegram didn't return an image when their photo was downloaded.
+    pub fn not_an_image(&self) -> &str {
+        &self.not_an_image
+    }
+
+    pub fn link_download_failed(&self) -> &str {
+        &self.link_download_failed
+    }
+
+    /// Tells a user why their image link couldn't be used, with a message of its own for each reason.
+    ///
+    /// Links that couldn't be resolved or fetched get `link_download_failed`, since trying again may help.
+    pub fn link_rejected(&self, error: &UrlDownloadError) -> String {
+        match error {
+            UrlDownloadError::UnsupportedScheme => self.link_unsupported_scheme.clone(),
+            UrlDownloadError::BlockedAddress => self.link_blocked_address.clone(),
+            UrlDownloadError::TooManyRedirects => self.link_too_many_redirects.clone(),
+            UrlDownloadError::NotAnImage(_) => self.link_not_an_image.clone(),
+            UrlDownloadError::TooLarge(max_bytes) => {
+                fill(&self.link_too_large, &[("megabytes", &format!("{:.1}", *max_bytes as f64 / (1024.0 * 1024.0)))])
+            }
+            UrlDownloadError::Resolve(_) | UrlDownloadError::Request(_) => self.link_download_failed.clone(),
+        }
+    }
+
+    /// Tells a user the format of their image, such as `HEIC`, isn't supported.
+    pub fn unsupported_format(&self, format: &str) -> String {
+        fill(&self.unsupported_format, &[("format", &format)])
+    }
+
+    pub fn decode_failed(&self) -> &str {
+        &self.decode_failed
+    }
+
+    /// Tells a user their image seems to be truncated.
+    pub fn corrupted(&self) -> &str {
+        &self.corrupted
+    }
+
+    /// Tells a user their image is below the minimum width or height.
+    pub fn too_small(&self) -> &str {
+        &self.too_small
+    }
+
+    /// Tells a user their image took longer than the maximum processing time to render.
+    pub fn took_too_long(&self) -> &str {
+        &self.took_too_long
+    }
+
+    /// Tells a user only the first `max` photos of their album are processed.
+    pub fn album_truncated(&self, max: usize) -> String {
+        fill(&self.album_truncated, &[("max", &max)])
+    }
+
+    pub fn overlay_failed(&self) -> &str {
+        &self.overlay_failed
+    }
+
+    /// Tells the users whose `/degenme` requests expired that they forgot to send an image.
+    pub fn expired(&self, usernames: &[String]) -> String {
+        match usernames {
+            [username] => fill(&self.expired_one, &[("username", username)]),
+            usernames => fill(&self.expired_many, &[("usernames", &usernames.join(", "))]),
+        }
+    }
+
+    /// Asks a user to confirm processing a large image.
+    pub fn confirm_prompt(&self) -> &str {
+        &self.confirm_prompt
+    }
+
+    pub fn confirm_yes(&self) -> &str {
+        &self.confirm_yes
+    }
+
+    pub fn confirm_no(&self) -> &str {
+        &self.confirm_no
+    }
+
+    pub fn confirm_expired(&self) -> &str {
+        &self.confirm_expired
+    }
+
+    pub fn confirm_not_yours(&self) -> &str {
+        &self.confirm_not_yours
+    }
+
+    pub fn confirm_accepted(&self) -> &str {
+        &self.confirm_accepted
+    }
+
+    pub fn confirm_declined(&self) -> &str {
+        &self.confirm_declined
+    }
+
+    pub fn random_no_samples(&self) -> &str {
+        &self.random_no_samples
+    }
+
+    pub fn random_failed(&self) -> &str {
+        &self.random_failed
+    }
+
+    /// The caption of a `/random` result.
+    pub fn random_caption(&self) -> &str {
+        &self.random_caption
+    }
+
+    /// Tells a Discord user to attach an image to `!degenme`.
+    pub fn discord_usage(&self) -> &str {
+        &self.discord_usage
+    }
+
+    /// The caption of the sample overlay offered in inline mode.
+    pub fn inline_caption(&self) -> &str {
+        &self.inline_caption
+    }
+
+    /// Points a user who sent text that isn't a command in a private chat to `/degenme`.
+    pub fn dm_nudge(&self) -> &str {
+        &self.dm_nudge
+    }
+
+    /// The `/queue` reply, with the number of queued images and of prompts awaiting an image.
+    pub fn queue_status(&self, queued: usize, awaiting: usize) -> String {
+        let template = if queued == 1 { &self.queue_status_one } else { &self.queue_status };
+        fill(template, &[("queued", &queued), ("awaiting", &awaiting)])
+    }
+
+    /// The reply to `/pause` or `/resume`, depending on whether it changed anything.
+    pub fn pause_changed(&self, paused: bool, changed: bool) -> &str {
+        match (paused, changed) {
+            (true, true) => &self.paused_now,
+            (false, true) => &self.resumed_now,
+            (true, false) => &self.already_paused,
+            (false, false) => &self.not_paused,
+        }
+    }
+
+    /// Tells an admin how to use `/ban` or `/unban`, named by `command`.
+    pub fn ban_usage(&self, command: &str) -> String {
+        fill(&self.ban_usage, &[("command", &command)])
+    }
+
+    /// The reply to `/ban` or `/unban`, depending on whether it changed anything.
+    pub fn ban_changed(&self, user_id: UserId, banned: bool, changed: bool) -> String {
+        let template = match (banned, changed) {
+            (true, true) => &self.banned_now,
+            (false, true) => &self.unbanned_now,
+            (true, false) => &self.already_banned,
+            (false, false) => &self.not_banned,
+        };
+        fill(template, &[("user_id", &user_id.0)])
+    }
+
+    /// Tells an admin that other admins can't be banned.
+    pub fn cannot_ban_admin(&self) -> &str {
+        &self.cannot_ban_admin
+    }
+
+    /// Tells an admin how to use `/setlimit`, with the largest values it accepts and the current limit.
+    pub fn setlimit_usage(&self, max_requests: u32, max_window: u64, current_max: u32, current_window: u64) -> String {
+        fill(&self.setlimit_usage, &[
+            ("max_requests", &max_requests),
+            ("max_window", &max_window),
+            ("current_max", &current_max),
+            ("current_window", &current_window),
+        ])
+    }
+
+    /// Confirms to an admin that `/setlimit` changed the rate limit.
+    pub fn limit_set(&self, max: u32, window: u64) -> String {
+        fill(&self.limit_set, &[("max", &max), ("window", &window)])
+    }
+
+    /// Confirms to an admin that `/reload` applied the config file.
+    pub fn config_reloaded(&self) -> &str {
+        &self.config_reloaded
+    }
+
+    /// Tells an admin that `/reload` failed and the current settings were kept, with the reason.
+    pub fn reload_failed(&self, error: &str) -> String {
+        fill(&self.reload_failed, &[("error", &error)])
+    }
+
+    /// Tells an admin how to use `/preview`, with the largest width or height it accepts.
+    pub fn preview_usage(&self, max: u32) -> String {
+        fill(&self.preview_usage, &[("max", &max)])
+    }
+
+    /// The `/preview` reply, naming the asset an image of the given size uses and the overlays it picks from.
+    pub fn preview_asset(&self, width: u32, height: u32, ratio: f32, asset: &str, overlays: &[String]) -> String {
+        let ratio = format!("{:.2}", ratio);
+        fill(
+            &self.preview_asset,
+            &[("width", &width), ("height", &height), ("ratio", &ratio), ("asset", &asset), ("overlays", &overlays.join(", "))],
+        )
+    }
+
+    pub fn feedback_disabled(&self) -> &str {
+        &self.feedback_disabled
+    }
+
+    pub fn feedback_missing(&self) -> &str {
+        &self.feedback_missing
+    }
+
+    pub fn feedback_rate_limited(&self) -> &str {
+        &self.feedback_rate_limited
+    }
+
+    pub fn feedback_sent(&self) -> &str {
+        &self.feedback_sent
+    }
+
+    /// Tells a user `/myimages` can't work because archiving is disabled.
+    pub fn history_unavailable(&self) -> &str {
+        &self.history_unavailable
+    }
+
+    pub fn history_empty(&self) -> &str {
+        &self.history_empty
+    }
+
+    pub fn history_failed(&self) -> &str {
+        &self.history_failed
+    }
+}
+
+/// The messages for every configured language, and the rules for picking one.
+///
+/// Each `<language>.toml` file in the messages directory is loaded at startup as the language named by its file name,
+/// e.g. `messages/pt-br.toml` is `pt-br`. If `use_user_language` is set, a user gets the language matching their
+/// Telegram `language_code`, trying the whole code and then just the language (`pt-br`, then `pt`); otherwise, and for
+/// messages that aren't meant for one user, `default_language` is used. If that isn't loaded either, the built-in
+/// English messages are used.
+///
+/// Chats with a processing message of their own, from `[telegram.chat_messages]`, get it in every language instead of
+/// the translated one, since it is written in the community's own voice.
+pub struct Localization {
+    languages: HashMap<String, Messages>,
+    default_language: String,
+    use_user_language: bool,
+    english: Messages,
+    chat_processing: HashMap<ChatId, String>,
+}
+
+impl Localization {
+    /// Loads every language in `dir`.
+    ///
+    /// A missing directory, or a file that can't be read or parsed, is logged and skipped, so the bot still starts with
+    /// the built-in English messages.
+    ///
+    /// # Arguments
+    /// * `dir` - The directory holding the `<language>.toml` files.
+    /// * `default_language` - The language used when a user's own language isn't available.
+    /// * `use_user_language` - Whether users get messages in their Telegram language.
+    ///
+    /// # Returns
+    /// A new `Localization` instance with the languages that could be loaded.
+    pub fn load(dir: &Path, default_language: &str, use_user_language: bool) -> Self {
+        let languages = match read_languages(dir) {
+            Ok((languages, problems)) => {
+                for problem in problems {
+                    warn!("{}, skipping that language", problem);
+                }
+                languages
+            }
+            Err(e) => {
+                warn!("Could not read messages directory {:?}, using the built-in English messages: {}", dir, e);
+                HashMap::new()
+            }
+        };
+        Localization::from_languages(dir, languages, default_language, use_user_language)
+    }
+
+    /// Loads every language in `dir` like `load`, but fails on a file that can't be read or parsed instead of skipping
+    /// it, so a config reload can't quietly drop a language. A missing directory still means the built-in English
+    /// messages, as at startup.
+    ///
+    /// # Arguments
+    /// * `dir` - The directory holding the `<language>.toml` files.
+    /// * `default_language` - The language used when a user's own language isn't available.
+    /// * `use_user_language` - Whether users get messages in their Telegram language.
+    ///
+    /// # Returns
+    /// A new `Localization` instance with every language in `dir`, or the `MessagesError` for the first file that
+    /// couldn't be loaded.
+    pub fn try_load(dir: &Path, default_language: &str, use_user_language: bool) -> Result<Self, MessagesError> {
+        let languages = match read_languages(dir) {
+            Ok((languages, problems)) => match problems.into_iter().next() {
+                Some(problem) => return Err(problem),
+                None => languages,
+            },
+            Err(e) if e.kind() == io::ErrorKind::NotFound => HashMap::new(),
+            Err(e) => return Err(MessagesError { path: dir.to_path_buf(), reason: e.to_string() }),
+        };
+        Ok(Localization::from_languages(dir, languages, default_language, use_user_language))
+    }
+
+    /// Creates a `Localization` from the languages loaded from `dir`, logging which ones there are.
+    fn from_languages(dir: &Path, languages: HashMap<String, Messages>, default_language: &str, use_user_language: bool) -> Self {
+        let default_language = default_language.to_lowercase();
+        if !languages.contains_key(&default_language) && default_language != "en" {
+            warn!("Default language {:?} isn't in {:?}, falling back to English", default_language, dir);
+        }
+        let mut loaded: Vec<&String> = languages.keys().collect();
+        loaded.sort();
+        info!("Loaded messages for {:?}, defaulting to {:?}", loaded, default_language);
+
+        Localization { languages, default_language, use_user_language, english: Messages::default(), chat_processing: HashMap::new() }
+    }
+
+    /// Sets the processing message templates of chats that have their own.
+    ///
+    /// # Arguments
+    /// * `templates` - The processing message template for each chat that has one, using the placeholders in
+    ///   `PROCESSING_PLACEHOLDERS`.
+    ///
+    /// # Returns
+    /// The `Localization` instance, for chaining.
+    pub fn with_chat_processing(mut self, templates: HashMap<i64, String>) -> Self {
+        self.chat_processing = templates.into_iter().map(|(chat_id, template)| (ChatId(chat_id), template)).collect();
+        self
+    }
+
+    /// The message shown while a user's image is being processed, using the chat's own template if it has one.
+    ///
+    /// # Arguments
+    /// * `msg` - The message with the image being processed.
+    /// * `username` - The name the user is addressed by.
+    ///
+    /// # Returns
+    /// The processing message, in the user's language unless the chat has a template of its own.
+    pub fn processing(&self, msg: &Message, username: &str) -> String {
+        match self.chat_processing.get(&msg.chat.id) {
+            Some(template) => fill(template, &[("username", &username)]),
+            None => self.for_message(msg).processing(username),
+        }
+    }
+
+    /// Returns the messages for a Telegram language code, such as `"pt-br"`.
+    ///
+    /// # Arguments
+    /// * `language_code` - The user's language code, if known.
+    ///
+    /// # Returns
+    /// The messages in the user's language if it is loaded and `use_user_language` is set, or the default ones.
+    pub fn get(&self, language_code: Option<&str>) -> &Messages {
+        let user_language = language_code
+            .filter(|_| self.use_user_language)
+            .map(str::to_lowercase)
+            .and_then(|code| {
+                let primary = code.split('-').next().unwrap_or_default().to_string();
+                self.languages.get(&code).or_else(|| self.languages.get(&primary))
+            });
+        user_language.unwrap_or_else(|| self.default_messages())
+    }
+
+    /// Returns the messages for the sender of a Telegram message.
+    pub fn for_message(&self, msg: &Message) -> &Messages {
+        self.get(msg.from().and_then(|user| user.language_code.as_deref()))
+    }
+
+    /// Returns the messages in the default language, for messages that aren't meant for one user.
+    pub fn default_messages(&self) -> &Messages {
+        self.languages.get(&self.default_language).unwrap_or(&self.english)
+    }
+}
+
+/// A messages file that `Localization::try_load` couldn't read or parse.
+#[derive(Debug, Error)]
+#[error("Could not load messages from {}: {reason}", .path.display())]
+pub struct MessagesError {
+    pub path: PathBuf,
+    pub reason: String,
+}
+
+/// Reads every `<language>.toml` file in `dir`.
+///
+/// # Returns
+/// The messages for each language that loaded and the problem with each file that didn't, or the error reading `dir`.
+fn read_languages(dir: &Path) -> io::Result<(HashMap<String, Messages>, Vec<MessagesError>)> {
+    let mut languages = HashMap::new();
+    let mut problems = Vec::new();
+    for path in fs::read_dir(dir)?.filter_map(|entry| entry.ok().map(|entry| entry.path())) {
+        if !path.extension().is_some_and(|ext| ext.eq_ignore_ascii_case("toml")) {
+            continue;
+        }
+        let Some(language) = path.file_stem().and_then(|stem| stem.to_str()).map(str::to_lowercase) else {
+            continue;
+        };
+        let parsed = fs::read_to_string(&path)
+            .map_err(|e| e.to_string())
+            .and_then(|content| toml::from_str::<Messages>(&content).map_err(|e| e.to_string()));
+        match parsed {
+            Ok(messages) => {
+                languages.insert(language, messages);
+            }
+            Err(reason) => problems.push(MessagesError { path, reason }),
+        }
+    }
+    Ok((languages, problems))
+}
+
+impl Default for Localization {
+    /// A `Localization` with only the built-in English messages.
+    fn default() -> Self {
+        Localization {
+            languages: HashMap::new(),
+            default_language: "en".to_string(),
+            use_user_language: false,
+            english: Messages::default(),
+            chat_processing: HashMap::new(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::sender::mock_message;
+
+    #[test]
+    fn chats_with_a_processing_template_use_it_and_others_fall_back() {
+        let localization = Localization::default()
+            .with_chat_processing(HashMap::from([(-100, "Hang on {username}, the ACME hands are on their way".to_string())]));
+
+        assert_eq!(localization.processing(&mock_message(-100, 20, ""), "@degen"), "Hang on @degen, the ACME hands are on their way");
+        assert_eq!(localization.processing(&mock_message(-200, 20, ""), "@degen"), Messages::default().processing("@degen"));
+    }
+
+    #[test]
+    fn try_load_fails_on_a_file_that_load_skips() {
+        let dir = std::env::temp_dir().join(format!("degenbot-messages-{}-broken", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("de.toml"), "welcome = \"Hallo\"\n").unwrap();
+        fs::write(dir.join("fr.toml"), "welcome = \n").unwrap();
+
+        let loaded = Localization::load(&dir, "en", true);
+        assert!(loaded.languages.contains_key("de"));
+        assert!(!loaded.languages.contains_key("fr"));
+        assert!(matches!(Localization::try_load(&dir, "en", true), Err(MessagesError { path, .. }) if path == dir.join("fr.toml")));
+    }
+
+    #[test]
+    fn try_load_without_a_directory_uses_english() {
+        let dir = std::env::temp_dir().join(format!("degenbot-messages-{}-missing", std::process::id()));
+
+        let loaded = Localization::try_load(&dir, "en", true).unwrap();
+        assert!(loaded.languages.is_empty());
+    }
+
+    #[test]
+    fn link_errors_are_explained_in_the_users_language() {
+        let messages: Messages = toml::from_str("link_blocked_address = \"Dieser Link zeigt auf eine private Adresse.\"\n").unwrap();
+
+        assert_eq!(messages.link_rejected(&UrlDownloadError::BlockedAddress), "Dieser Link zeigt auf eine private Adresse.");
+        assert_eq!(messages.link_rejected(&UrlDownloadError::TooLarge(5 * 1024 * 1024)), "The image at your link is too large, please send one under 5.0 MB.");
+        assert_eq!(messages.link_rejected(&UrlDownloadError::NotAnImage("text/html".to_string())), messages.link_not_an_image);
+    }
+
+}
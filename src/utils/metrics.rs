@@ -0,0 +1,135 @@
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// The upper bounds, in bytes, of the buckets for input and output image sizes.
+const BYTE_BUCKETS: &[u64] = &[64 * 1024, 256 * 1024, 1024 * 1024, 4 * 1024 * 1024, 16 * 1024 * 1024];
+
+/// The upper bounds, in pixels, of the buckets for decoded image widths and heights.
+const DIMENSION_BUCKETS: &[u64] = &[256, 512, 1024, 2048, 4096, 8192];
+
+/// A Histogram struct that counts observations into fixed buckets, like a Prometheus histogram.
+///
+/// Every field is an atomic, so observing a value is a handful of relaxed increments and never blocks. Each bucket
+/// counts the observations up to its bound, and values above the last bound are only counted in the total.
+struct Histogram {
+    bounds: &'static [u64],
+    buckets: Vec<AtomicU64>,
+    sum: AtomicU64,
+    count: AtomicU64,
+}
+
+impl Histogram {
+    /// Creates an empty histogram with the given bucket bounds, in ascending order.
+    fn new(bounds: &'static [u64]) -> Self {
+        Histogram {
+            bounds,
+            buckets: bounds.iter().map(|_| AtomicU64::new(0)).collect(),
+            sum: AtomicU64::new(0),
+            count: AtomicU64::new(0),
+        }
+    }
+
+    /// Records one observation.
+    fn observe(&self, value: u64) {
+        if let Some(index) = self.bounds.iter().position(|bound| value <= *bound) {
+            self.buckets[index].fetch_add(1, Ordering::Relaxed);
+        }
+        self.sum.fetch_add(value, Ordering::Relaxed);
+        self.count.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Appends the histogram to `out` in the Prometheus text format, with cumulative buckets.
+    fn write_to(&self, out: &mut String, name: &str, help: &str) {
+        let _ = writeln!(out, "# HELP {} {}", name, help);
+        let _ = writeln!(out, "# TYPE {} histogram", name);
+        let mut cumulative = 0;
+        for (bound, bucket) in self.bounds.iter().zip(&self.buckets) {
+            cumulative += bucket.load(Ordering::Relaxed);
+            let _ = writeln!(out, "{}_bucket{{le=\"{}\"}} {}", name, bound, cumulative);
+        }
+        let count = self.count.load(Ordering::Relaxed);
+        let _ = writeln!(out, "{}_bucket{{le=\"+Inf\"}} {}", name, count);
+        let _ = writeln!(out, "{}_sum {}", name, self.sum.load(Ordering::Relaxed));
+        let _ = writeln!(out, "{}_count {}", name, count);
+    }
+}
+
+/// An OverlayMetrics struct that records the sizes of the images the bot renders, for capacity planning.
+///
+/// For every overlay rendered from a Telegram request, it records the size in bytes of the downloaded image, the
+/// width and height it decoded to, before any downscaling, and the size in bytes of the encoded result. The values are
+/// kept in histograms served at `/metrics` in the Prometheus text format. Results served from the result cache aren't
+/// rendered, so they aren't recorded. It also counts how often each background task, such as the queue processor,
+/// stopped unexpectedly. The metrics are only kept in memory, so they start over when the bot restarts.
+pub struct OverlayMetrics {
+    input_bytes: Histogram,
+    decoded_width: Histogram,
+    decoded_height: Histogram,
+    output_bytes: Histogram,
+    task_exits: Mutex<BTreeMap<&'static str, u64>>,
+}
+
+impl OverlayMetrics {
+    /// Creates a new `OverlayMetrics` instance with nothing recorded.
+    ///
+    /// # Returns
+    /// A new `OverlayMetrics` instance.
+    pub fn new() -> Self {
+        OverlayMetrics {
+            input_bytes: Histogram::new(BYTE_BUCKETS),
+            decoded_width: Histogram::new(DIMENSION_BUCKETS),
+            decoded_height: Histogram::new(DIMENSION_BUCKETS),
+            output_bytes: Histogram::new(BYTE_BUCKETS),
+            task_exits: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    /// Records the sizes of one rendered overlay.
+    ///
+    /// # Arguments
+    /// * `input_bytes` - The size of the downloaded image, in bytes.
+    /// * `width` - The width of the decoded image, in pixels.
+    /// * `height` - The height of the decoded image, in pixels.
+    /// * `output_bytes` - The size of the encoded result, in bytes.
+    pub fn record(&self, input_bytes: usize, width: u32, height: u32, output_bytes: usize) {
+        self.input_bytes.observe(input_bytes as u64);
+        self.decoded_width.observe(u64::from(width));
+        self.decoded_height.observe(u64::from(height));
+        self.output_bytes.observe(output_bytes as u64);
+    }
+
+    /// Counts one unexpected exit of a background task.
+    ///
+    /// # Arguments
+    /// * `task` - The name of the task, e.g. `"queue"`.
+    pub fn record_task_exit(&self, task: &'static str) {
+        let mut task_exits = self.task_exits.lock().unwrap_or_else(|e| e.into_inner());
+        *task_exits.entry(task).or_insert(0) += 1;
+    }
+
+    /// Renders every histogram and counter in the Prometheus text format.
+    ///
+    /// # Returns
+    /// The body of the `/metrics` response.
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        self.input_bytes.write_to(&mut out, "degenbot_input_image_bytes", "Size of the downloaded images overlays were rendered onto.");
+        self.decoded_width.write_to(&mut out, "degenbot_decoded_image_width_pixels", "Width of the decoded images, before downscaling.");
+        self.decoded_height.write_to(&mut out, "degenbot_decoded_image_height_pixels", "Height of the decoded images, before downscaling.");
+        self.output_bytes.write_to(&mut out, "degenbot_output_image_bytes", "Size of the encoded overlay results.");
+        let _ = writeln!(out, "# HELP degenbot_task_exits_total Unexpected exits of background tasks.");
+        let _ = writeln!(out, "# TYPE degenbot_task_exits_total counter");
+        for (task, exits) in self.task_exits.lock().unwrap_or_else(|e| e.into_inner()).iter() {
+            let _ = writeln!(out, "degenbot_task_exits_total{{task=\"{}\"}} {}", task, exits);
+        }
+        out
+    }
+}
+
+impl Default for OverlayMetrics {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -2,3 +2,26 @@
 pub mod queue;
 pub mod rate_limiter;
 pub mod image_utils;
+pub mod overlay_assets;
+pub mod chat_overlays;
+pub mod daily_quota;
+pub mod telegram;
+pub mod worker_pool;
+pub mod url_download;
+pub mod recent_results;
+pub mod pause;
+pub mod sender;
+pub mod restricted_chats;
+pub mod source_cache;
+pub mod result_cache;
+pub mod download_cache;
+pub mod archive;
+pub mod audit;
+pub mod messages;
+pub mod seen_messages;
+pub mod media_groups;
+pub mod metrics;
+pub mod banned_users;
+pub mod watchdog;
+pub mod logging;
+pub mod live_config;
@@ -0,0 +1,666 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use log::{info, warn, error};
+use opencv::imgcodecs;
+use opencv::prelude::*;
+use rand::seq::SliceRandom;
+use serde::Deserialize;
+use thiserror::Error;
+
+use crate::config::ProcessingConfig;
+use crate::utils::image_utils::{apply_watermark, tint_overlay, unpremultiply_alpha, CompositeMode, ImageQualityConfig, OutputFormat, WatermarkCorner, DEFAULT_FILE_NAME_TEMPLATE};
+
+/// The directory the default overlays are loaded from.
+pub const OVERLAY_DIR: &str = "img";
+
+/// The asset used for portrait images, always loaded.
+const PORTRAIT_ASSET: &str = "portrait";
+/// The asset used for landscape images, always loaded, and used for any bucket whose asset has no overlays.
+const LANDSCAPE_ASSET: &str = "landscape";
+
+/// The largest width or height of an image before it is downscaled, used if `with_max_dimension` is never called.
+const DEFAULT_MAX_DIMENSION: i32 = 2048;
+
+/// The smallest width or height of an image that is overlaid, used if `with_min_dimension` is never called.
+const DEFAULT_MIN_DIMENSION: i32 = 64;
+
+/// An aspect ratio bucket, selecting which overlay asset is used for images of a given shape.
+///
+/// The aspect ratio is the image's height divided by its width, so landscape images are below `1.0` and portrait
+/// images above it. An image uses the first bucket whose `ratio_max` is at least its aspect ratio; the last bucket
+/// leaves `ratio_max` out and catches everything else. The asset names the subdirectory of the image directory the
+/// overlays are read from, such as `square` for `img/square`.
+#[derive(Clone, Debug, Deserialize)]
+pub struct AspectBucket {
+    #[serde(default)]
+    pub ratio_max: Option<f32>,
+    pub asset: String,
+}
+
+/// Returns the default aspect ratio buckets, matching the original split: images up to 5% taller than they are wide
+/// use the landscape overlays, and taller images the portrait overlays.
+pub fn default_aspect_buckets() -> Vec<AspectBucket> {
+    vec![
+        AspectBucket { ratio_max: Some(1.05), asset: LANDSCAPE_ASSET.to_string() },
+        AspectBucket { ratio_max: None, asset: PORTRAIT_ASSET.to_string() },
+    ]
+}
+
+/// An error found when checking the overlays at startup.
+///
+/// - `Invalid`: Some overlays are missing or couldn't be decoded; holds their paths.
+/// - `MissingDirectory`: A chat's overlay directory from `[telegram.chat_overlays]` doesn't exist; holds its path.
+#[derive(Debug, Error)]
+pub enum OverlayAssetsError {
+    #[error("Missing or unreadable overlay images: {}", .0.iter().map(|path| path.display().to_string()).collect::<Vec<_>>().join(", "))]
+    Invalid(Vec<PathBuf>),
+    #[error("Overlay directory {} doesn't exist", .0.display())]
+    MissingDirectory(PathBuf),
+}
+
+/// The set of overlay images the bot can choose from.
+///
+/// The listing is built once at startup by scanning the image directory's subdirectory for each asset, such as
+/// `portrait` and `landscape`, so picking an overlay for a request never touches the filesystem. If an asset's directory
+/// is missing or contains no PNG files, the single default overlay for that asset, e.g. `img/hands_portrait.png`, is used
+/// instead. Which asset an image uses is decided by its aspect ratio, using the buckets set with `with_aspect_buckets`.
+///
+/// Every overlay is also decoded once at startup and kept in memory, so requests don't have to read and decode the same
+/// PNG over and over. The decoded images sit behind a mutex since `Mat` can't be shared between threads directly.
+/// Overlays are expected to use straight alpha; if they were exported with premultiplied alpha, `with_premultiplied_alpha`
+/// converts them once when they are decoded. Styles can also be given a hue shift with `with_style_tints`, which is
+/// likewise applied once to the decoded overlays rather than on every request.
+///
+/// An optional watermark logo is kept alongside the overlays and applied to every output, together with the settings
+/// for how overlays are composited, how opaque they are, and how large an image may be before it is downscaled.
+pub struct OverlayAssets {
+    img_dir: PathBuf,
+    sets: BTreeMap<String, Vec<PathBuf>>,
+    buckets: Vec<AspectBucket>,
+    decoded: Mutex<HashMap<PathBuf, Mat>>,
+    watermark: Option<Watermark>,
+    composite_mode: CompositeMode,
+    crop_to_circle: bool,
+    max_dimension: i32,
+    min_dimension: i32,
+    normalize_width: Option<i32>,
+    opacity: f32,
+    bottom_padding: u32,
+    premultiplied_alpha: bool,
+    style_tints: HashMap<String, f32>,
+    output_format: OutputFormat,
+    quality: ImageQualityConfig,
+    file_name_template: String,
+}
+
+/// A decoded watermark logo and the settings used to apply it.
+struct Watermark {
+    logo: Mutex<Mat>,
+    corner: WatermarkCorner,
+    opacity: f32,
+    max_width_ratio: f32,
+}
+
+impl OverlayAssets {
+    /// Scans `img_dir/portrait` and `img_dir/landscape` for PNG files, decodes them, and caches the result.
+    ///
+    /// # Arguments
+    /// * `img_dir` - The directory containing the `portrait` and `landscape` overlay directories.
+    ///
+    /// # Returns
+    /// A new `OverlayAssets` instance.
+    pub fn load(img_dir: &Path) -> Self {
+        let portrait = scan_pngs(&img_dir.join(PORTRAIT_ASSET), &default_overlay(img_dir, PORTRAIT_ASSET));
+        let landscape = scan_pngs(&img_dir.join(LANDSCAPE_ASSET), &default_overlay(img_dir, LANDSCAPE_ASSET));
+        info!("Loaded {} portrait and {} landscape overlays", portrait.len(), landscape.len());
+
+        let mut decoded = HashMap::new();
+        for path in portrait.iter().chain(landscape.iter()) {
+            match read_overlay(path) {
+                Ok(mat) => {
+                    decoded.insert(path.clone(), mat);
+                }
+                Err(e) => error!("Failed to decode overlay {:?}: {}", path, e),
+            }
+        }
+
+        let mut sets = BTreeMap::new();
+        sets.insert(PORTRAIT_ASSET.to_string(), portrait);
+        sets.insert(LANDSCAPE_ASSET.to_string(), landscape);
+
+        OverlayAssets {
+            img_dir: img_dir.to_path_buf(),
+            sets,
+            buckets: default_aspect_buckets(),
+            decoded: Mutex::new(decoded),
+            watermark: None,
+            composite_mode: CompositeMode::Rectangle,
+            crop_to_circle: false,
+            max_dimension: DEFAULT_MAX_DIMENSION,
+            min_dimension: DEFAULT_MIN_DIMENSION,
+            normalize_width: None,
+            opacity: 1.0,
+            bottom_padding: 0,
+            premultiplied_alpha: false,
+            style_tints: HashMap::new(),
+            output_format: OutputFormat::Png,
+            quality: ImageQualityConfig::default(),
+            file_name_template: DEFAULT_FILE_NAME_TEMPLATE.to_string(),
+        }
+    }
+
+    /// Loads the overlays from `img_dir` and applies the `[processing]` settings from the config to them.
+    ///
+    /// This is how both the bot and `degen-render` load their overlays, so a preview renders exactly like the bot.
+    ///
+    /// # Arguments
+    /// * `img_dir` - The directory containing the overlay directories.
+    /// * `processing` - The processing settings from the config.
+    ///
+    /// # Returns
+    /// A new `OverlayAssets` instance with the settings applied.
+    pub fn from_config(img_dir: &Path, processing: &ProcessingConfig) -> Self {
+        let overlay_assets = OverlayAssets::load(img_dir)
+            .with_composite(processing.composite_mode, processing.crop_to_circle)
+            .with_max_dimension(processing.max_dimension)
+            .with_min_dimension(processing.min_dimension)
+            .with_normalize_width(processing.normalize_width)
+            .with_opacity(processing.overlay_opacity)
+            .with_bottom_padding(processing.overlay_bottom_padding_px)
+            .with_premultiplied_alpha(processing.premultiplied_alpha)
+            .with_style_tints(&processing.style_tints)
+            .with_output_format(processing.output_format, processing.quality)
+            .with_file_name_template(&processing.output_filename_template)
+            .with_aspect_buckets(processing.aspect_buckets.clone());
+        match &processing.watermark_path {
+            Some(watermark_path) => overlay_assets.with_watermark(
+                Path::new(watermark_path),
+                processing.watermark_corner,
+                processing.watermark_opacity,
+                processing.watermark_max_width,
+            ),
+            None => overlay_assets,
+        }
+    }
+
+    /// Sets the aspect ratio buckets that decide which asset an image uses, loading any asset not loaded yet.
+    ///
+    /// An asset other than `portrait` and `landscape` whose directory has no PNG files and whose default overlay doesn't
+    /// exist is logged, and images in its bucket use the landscape overlays instead.
+    ///
+    /// # Arguments
+    /// * `buckets` - The buckets, ordered by `ratio_max`, with a last bucket that leaves it out.
+    ///
+    /// # Returns
+    /// The `OverlayAssets` instance with the buckets applied.
+    pub fn with_aspect_buckets(mut self, buckets: Vec<AspectBucket>) -> Self {
+        for bucket in &buckets {
+            if self.sets.contains_key(&bucket.asset) {
+                continue;
+            }
+
+            let default = default_overlay(&self.img_dir, &bucket.asset);
+            let paths = scan_pngs(&self.img_dir.join(&bucket.asset), &default);
+            if paths == [default.clone()] && !default.exists() {
+                warn!("No overlays found for asset {}, using the landscape overlays instead", bucket.asset);
+                continue;
+            }
+
+            info!("Loaded {} {} overlays", paths.len(), bucket.asset);
+            // The asset is added first so its suffix is stripped from the style names the overlays are tinted by
+            self.sets.insert(bucket.asset.clone(), paths.clone());
+            for path in &paths {
+                if let Err(e) = self.decoded(path) {
+                    error!("Failed to decode overlay {:?}: {}", path, e);
+                }
+            }
+        }
+        self.buckets = buckets;
+        self
+    }
+
+    /// Returns the asset used for images with the given aspect ratio, according to the aspect ratio buckets.
+    ///
+    /// # Arguments
+    /// * `aspect_ratio` - The image's height divided by its width.
+    ///
+    /// # Returns
+    /// The name of the asset, such as `portrait`.
+    pub fn asset_for(&self, aspect_ratio: f32) -> &str {
+        self.buckets.iter()
+            .find(|bucket| bucket.ratio_max.is_none_or(|ratio_max| aspect_ratio <= ratio_max))
+            .or(self.buckets.last())
+            .map_or(LANDSCAPE_ASSET, |bucket| bucket.asset.as_str())
+    }
+
+    /// Sets how overlays are composited onto images.
+    ///
+    /// # Arguments
+    /// * `composite_mode` - Where on the image the overlay is applied.
+    /// * `crop_to_circle` - Whether the output is cropped to a circle with transparent corners.
+    ///
+    /// # Returns
+    /// The `OverlayAssets` instance with the composite settings applied.
+    pub fn with_composite(mut self, composite_mode: CompositeMode, crop_to_circle: bool) -> Self {
+        self.composite_mode = composite_mode;
+        self.crop_to_circle = crop_to_circle;
+        self
+    }
+
+    /// Returns where on the image overlays are applied.
+    pub fn composite_mode(&self) -> CompositeMode {
+        self.composite_mode
+    }
+
+    /// Returns whether outputs are cropped to a circle.
+    pub fn crop_to_circle(&self) -> bool {
+        self.crop_to_circle
+    }
+
+    /// Sets the opacity overlays are composited at, multiplying each overlay's own alpha.
+    ///
+    /// # Arguments
+    /// * `opacity` - The opacity of the overlays, from `0.0` (invisible) to `1.0` (as drawn), clamped to that range.
+    ///
+    /// # Returns
+    /// The `OverlayAssets` instance with the opacity applied.
+    pub fn with_opacity(mut self, opacity: f32) -> Self {
+        self.opacity = opacity.clamp(0.0, 1.0);
+        self
+    }
+
+    /// Returns the opacity overlays are composited at.
+    pub fn opacity(&self) -> f32 {
+        self.opacity
+    }
+
+    /// Sets how far overlays are raised off the bottom of the image.
+    ///
+    /// # Arguments
+    /// * `bottom_padding` - The padding below the overlay, in pixels of the downscaled image.
+    ///
+    /// # Returns
+    /// The `OverlayAssets` instance with the padding applied.
+    pub fn with_bottom_padding(mut self, bottom_padding: u32) -> Self {
+        self.bottom_padding = bottom_padding;
+        self
+    }
+
+    /// Returns how many pixels overlays are raised off the bottom of the image.
+    pub fn bottom_padding(&self) -> u32 {
+        self.bottom_padding
+    }
+
+    /// Sets the format results are encoded in.
+    ///
+    /// The encoder is checked here, at startup, so an OpenCV build without WebP support falls back to PNG with a
+    /// warning instead of failing every request.
+    ///
+    /// # Arguments
+    /// * `output_format` - The format results are encoded in.
+    /// * `quality` - The encoder settings, of which only the ones for the output format are used.
+    ///
+    /// # Returns
+    /// The `OverlayAssets` instance with the output format applied.
+    pub fn with_output_format(mut self, output_format: OutputFormat, quality: ImageQualityConfig) -> Self {
+        self.output_format = if output_format == OutputFormat::Png || output_format.is_supported() {
+            output_format
+        } else {
+            warn!("This OpenCV build can't encode {:?}, falling back to PNG", output_format);
+            OutputFormat::Png
+        };
+        self.quality = quality;
+        info!("Encoding results as {:?}", self.output_format);
+        self
+    }
+
+    /// Sets the template results' file names are rendered from, see `OutputFormat::file_name`.
+    ///
+    /// # Arguments
+    /// * `template` - The file name template, e.g. `{username}_{timestamp}.{ext}`.
+    ///
+    /// # Returns
+    /// The `OverlayAssets` instance with the template applied.
+    pub fn with_file_name_template(mut self, template: &str) -> Self {
+        self.file_name_template = template.to_string();
+        self
+    }
+
+    /// Returns the file name a result is sent with, rendered from the file name template in the output format.
+    ///
+    /// # Arguments
+    /// * `username` - The username of the user the result is for, if they have one.
+    pub fn output_file_name(&self, username: Option<&str>) -> String {
+        self.output_format.file_name(&self.file_name_template, username)
+    }
+
+    /// Returns the directory the overlays were loaded from.
+    pub fn img_dir(&self) -> &Path {
+        &self.img_dir
+    }
+
+    /// Returns the format results are encoded in.
+    pub fn output_format(&self) -> OutputFormat {
+        self.output_format
+    }
+
+    /// Returns the encoder settings results are encoded with.
+    pub fn quality(&self) -> &ImageQualityConfig {
+        &self.quality
+    }
+
+    /// Marks the overlays as exported with premultiplied alpha, converting them to the straight alpha the blend expects.
+    ///
+    /// Overlays that fail to convert are logged and left as they are.
+    ///
+    /// # Arguments
+    /// * `premultiplied_alpha` - Whether the overlay PNGs use premultiplied alpha.
+    ///
+    /// # Returns
+    /// The `OverlayAssets` instance with the overlays converted if needed.
+    pub fn with_premultiplied_alpha(mut self, premultiplied_alpha: bool) -> Self {
+        self.premultiplied_alpha = premultiplied_alpha;
+        if premultiplied_alpha {
+            for (path, mat) in self.decoded.get_mut().unwrap().iter_mut() {
+                if let Err(e) = unpremultiply_alpha(mat) {
+                    error!("Failed to convert overlay {:?} from premultiplied alpha: {}", path, e);
+                }
+            }
+            info!("Converted overlays from premultiplied alpha");
+        }
+        self
+    }
+
+    /// Shifts the hue of the overlays for each style in `style_tints`, so a style can be recoloured without editing its
+    /// PNGs.
+    ///
+    /// Style names are matched without regard to case, like `pick_style`. Overlays that fail to tint are logged and
+    /// left as they are.
+    ///
+    /// # Arguments
+    /// * `style_tints` - The hue shift in degrees for each style name.
+    ///
+    /// # Returns
+    /// The `OverlayAssets` instance with the overlays tinted.
+    pub fn with_style_tints(mut self, style_tints: &HashMap<String, f32>) -> Self {
+        self.style_tints = style_tints.iter()
+            .filter(|(_, hue_shift)| **hue_shift != 0.0)
+            .map(|(style, hue_shift)| (style.to_lowercase(), *hue_shift))
+            .collect();
+        if self.style_tints.is_empty() {
+            return self;
+        }
+
+        let tinted: Vec<(PathBuf, f32)> = self.sets.values()
+            .flatten()
+            .filter_map(|path| self.style_tint(path).map(|hue_shift| (path.clone(), hue_shift)))
+            .collect();
+        let decoded = self.decoded.get_mut().unwrap();
+        for (path, hue_shift) in tinted {
+            let Some(mat) = decoded.get_mut(&path) else {
+                continue;
+            };
+            match tint_overlay(mat, hue_shift) {
+                Ok(tinted) => *mat = tinted,
+                Err(e) => error!("Failed to tint overlay {:?}: {}", path, e),
+            }
+        }
+        info!("Tinted overlays for {} styles", self.style_tints.len());
+        self
+    }
+
+    /// Sets the largest width or height an image may have before it is downscaled for compositing.
+    ///
+    /// # Arguments
+    /// * `max_dimension` - The largest width or height allowed, in pixels.
+    ///
+    /// # Returns
+    /// The `OverlayAssets` instance with the limit applied.
+    pub fn with_max_dimension(mut self, max_dimension: u32) -> Self {
+        self.max_dimension = i32::try_from(max_dimension).unwrap_or(i32::MAX);
+        self
+    }
+
+    /// Returns the largest width or height an image may have before it is downscaled.
+    pub fn max_dimension(&self) -> i32 {
+        self.max_dimension
+    }
+
+    /// Sets the smallest width or height an image may have to be overlaid, since tiny images give useless results.
+    ///
+    /// # Arguments
+    /// * `min_dimension` - The smallest width or height allowed, in pixels, or `0` to accept any size.
+    ///
+    /// # Returns
+    /// The `OverlayAssets` instance with the limit applied.
+    pub fn with_min_dimension(mut self, min_dimension: u32) -> Self {
+        self.min_dimension = i32::try_from(min_dimension).unwrap_or(i32::MAX);
+        self
+    }
+
+    /// Returns the smallest width or height an image may have to be overlaid.
+    pub fn min_dimension(&self) -> i32 {
+        self.min_dimension
+    }
+
+    /// Sets the width every image is resized to before compositing, so results come out the same size.
+    ///
+    /// # Arguments
+    /// * `normalize_width` - The width to resize images to, in pixels, or `None` to keep their own width.
+    ///
+    /// # Returns
+    /// The `OverlayAssets` instance with the width applied.
+    pub fn with_normalize_width(mut self, normalize_width: Option<u32>) -> Self {
+        self.normalize_width = normalize_width.map(|width| i32::try_from(width).unwrap_or(i32::MAX));
+        self
+    }
+
+    /// Returns the width every image is resized to before compositing, if any.
+    pub fn normalize_width(&self) -> Option<i32> {
+        self.normalize_width
+    }
+
+    /// Loads the watermark logo that `apply_watermark` blends into every output.
+    ///
+    /// If the logo can't be read, the error is logged and outputs are left without a watermark.
+    ///
+    /// # Arguments
+    /// * `path` - The path of the logo image.
+    /// * `corner` - The corner of the output to place the logo in.
+    /// * `opacity` - The opacity of the logo, from `0.0` to `1.0`.
+    /// * `max_width_ratio` - The maximum width of the logo as a fraction of the output width.
+    ///
+    /// # Returns
+    /// The `OverlayAssets` instance with the watermark loaded.
+    pub fn with_watermark(mut self, path: &Path, corner: WatermarkCorner, opacity: f32, max_width_ratio: f32) -> Self {
+        match read_overlay(path) {
+            Ok(logo) => {
+                info!("Loaded watermark {:?}", path);
+                self.watermark = Some(Watermark { logo: Mutex::new(logo), corner, opacity, max_width_ratio });
+            }
+            Err(e) => error!("Failed to decode watermark {:?}, outputs will not be watermarked: {}", path, e),
+        }
+        self
+    }
+
+    /// Returns the overlays a random overlay is picked from for the given asset.
+    ///
+    /// # Arguments
+    /// * `asset` - The asset to list the overlays of, from `asset_for`.
+    ///
+    /// # Returns
+    /// The paths of the asset's overlays, or of the landscape overlays if the asset has none.
+    pub fn overlays(&self, asset: &str) -> &[PathBuf] {
+        self.set(asset)
+    }
+
+    /// Picks a random overlay for the given asset.
+    ///
+    /// # Arguments
+    /// * `asset` - The asset for the image being processed, from `asset_for`.
+    ///
+    /// # Returns
+    /// The path of the chosen overlay image.
+    pub fn pick(&self, asset: &str) -> &Path {
+        self.set(asset)
+            .choose(&mut rand::thread_rng())
+            .expect("overlay list always contains at least the default overlay")
+    }
+
+    /// Picks the overlay for a named style, such as `hands`, preferring one for the given asset.
+    ///
+    /// A style's name is the overlay's file name without the extension or an asset suffix such as `_portrait`, so
+    /// `img/hands_portrait.png` and `img/landscape/hands.png` are both the `hands` style. If the style only exists for
+    /// other assets, one of those overlays is used instead.
+    ///
+    /// # Arguments
+    /// * `style` - The name of the style, ignoring case.
+    /// * `asset` - The asset for the image being processed, from `asset_for`.
+    ///
+    /// # Returns
+    /// The path of the overlay for the style, or `None` if there is no overlay with that name.
+    pub fn pick_style(&self, style: &str, asset: &str) -> Option<&Path> {
+        self.set(asset).iter()
+            .chain(self.sets.values().flatten())
+            .find(|path| self.style_name(path).eq_ignore_ascii_case(style))
+            .map(PathBuf::as_path)
+    }
+
+    /// Returns the names of every overlay style, sorted and without duplicates.
+    pub fn styles(&self) -> Vec<String> {
+        let mut styles: Vec<String> = self.sets.values()
+            .flatten()
+            .map(|path| self.style_name(path).to_lowercase())
+            .collect();
+        styles.sort();
+        styles.dedup();
+        styles
+    }
+
+    /// Returns a copy of the decoded overlay at `path`.
+    ///
+    /// Overlays are normally decoded at startup; if one failed to decode then, it is read from disk again here and
+    /// cached on success.
+    ///
+    /// # Arguments
+    /// * `path` - The path of the overlay, as returned by `pick`.
+    ///
+    /// # Returns
+    /// The decoded overlay image, or an error if it could not be copied or read.
+    pub fn decoded(&self, path: &Path) -> Result<Mat, opencv::Error> {
+        let mut decoded = self.decoded.lock().unwrap();
+        if let Some(mat) = decoded.get(path) {
+            return mat.try_clone();
+        }
+
+        let mut mat = read_overlay(path)?;
+        if self.premultiplied_alpha {
+            unpremultiply_alpha(&mut mat)?;
+        }
+        if let Some(hue_shift) = self.style_tint(path) {
+            mat = tint_overlay(&mat, hue_shift)?;
+        }
+        decoded.insert(path.to_path_buf(), mat.try_clone()?);
+        Ok(mat)
+    }
+
+    /// Checks that every overlay that can be picked was decoded into a non-empty image.
+    ///
+    /// Overlays that fail to decode are only logged while loading, so this is called once everything is loaded to
+    /// fail fast instead of failing every request that picks a broken overlay.
+    ///
+    /// # Returns
+    /// `Ok(())` if every overlay is usable, or `OverlayAssetsError::Invalid` listing the ones that aren't.
+    pub fn check(&self) -> Result<(), OverlayAssetsError> {
+        let decoded = self.decoded.lock().unwrap();
+        let mut invalid: Vec<PathBuf> = self.sets.values()
+            .flatten()
+            .filter(|path| decoded.get(*path).is_none_or(|mat| mat.empty()))
+            .cloned()
+            .collect();
+        if invalid.is_empty() {
+            return Ok(());
+        }
+
+        invalid.sort();
+        invalid.dedup();
+        Err(OverlayAssetsError::Invalid(invalid))
+    }
+
+    /// Returns the overlays for an asset, or the landscape overlays if the asset has none.
+    fn set(&self, asset: &str) -> &[PathBuf] {
+        self.sets.get(asset)
+            .or_else(|| self.sets.get(LANDSCAPE_ASSET))
+            .expect("the landscape overlays are always loaded")
+    }
+
+    /// Returns the style name of an overlay: its file name without the extension or an asset suffix such as `_portrait`.
+    fn style_name<'p>(&self, path: &'p Path) -> &'p str {
+        let stem = path.file_stem().and_then(|stem| stem.to_str()).unwrap_or_default();
+        self.sets.keys()
+            .find_map(|asset| stem.strip_suffix(asset.as_str()).and_then(|stem| stem.strip_suffix('_')))
+            .unwrap_or(stem)
+    }
+
+    /// Returns the hue shift configured for an overlay's style, if any.
+    fn style_tint(&self, path: &Path) -> Option<f32> {
+        self.style_tints.get(&self.style_name(path).to_lowercase()).copied()
+    }
+
+    /// Applies the configured watermark to `image`, or returns it unchanged if no watermark is configured.
+    ///
+    /// # Arguments
+    /// * `image` - The image to watermark.
+    ///
+    /// # Returns
+    /// The watermarked image, or an error if the operation fails.
+    pub fn apply_watermark(&self, image: Mat) -> Result<Mat, opencv::Error> {
+        match &self.watermark {
+            Some(watermark) => {
+                let logo = watermark.logo.lock().unwrap();
+                apply_watermark(&image, &logo, watermark.corner, watermark.opacity, watermark.max_width_ratio)
+            }
+            None => Ok(image),
+        }
+    }
+}
+
+/// Returns the overlay used for an asset when its directory has no PNG files, e.g. `img/hands_portrait.png`.
+fn default_overlay(img_dir: &Path, asset: &str) -> PathBuf {
+    img_dir.join(format!("hands_{}.png", asset))
+}
+
+/// Reads and decodes an overlay image from disk, keeping its alpha channel.
+fn read_overlay(path: &Path) -> Result<Mat, opencv::Error> {
+    let path = path.to_str()
+        .ok_or_else(|| opencv::Error::new(opencv::core::StsBadArg, format!("Invalid overlay path: {:?}", path)))?;
+    let mat = imgcodecs::imread(path, imgcodecs::IMREAD_UNCHANGED)?;
+    if mat.empty() {
+        return Err(opencv::Error::new(opencv::core::StsObjectNotFound, format!("Could not read overlay: {}", path)));
+    }
+    Ok(mat)
+}
+
+/// Lists the PNG files in `dir`, sorted by name, falling back to `default` if there are none.
+fn scan_pngs(dir: &Path, default: &Path) -> Vec<PathBuf> {
+    let mut paths: Vec<PathBuf> = match fs::read_dir(dir) {
+        Ok(entries) => entries
+            .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+            .filter(|path| path.extension().is_some_and(|ext| ext.eq_ignore_ascii_case("png")))
+            .collect(),
+        Err(e) => {
+            warn!("Could not read overlay directory {:?}: {}", dir, e);
+            Vec::new()
+        }
+    };
+
+    if paths.is_empty() {
+        info!("No overlays found in {:?}, using {:?}", dir, default);
+        paths.push(default.to_path_buf());
+    }
+
+    paths.sort();
+    paths
+}
@@ -0,0 +1,67 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use tokio::sync::Notify;
+
+/// A PauseSwitch struct that lets admins pause and resume overlay processing without redeploying.
+///
+/// The switch is shared between the message handler, which stops accepting new overlay requests while paused, and the
+/// queue processor, which holds on to queued images until processing is resumed. A `Notify` wakes the queue processor
+/// waiting in `wait_until_resumed` when the switch is flipped back.
+pub struct PauseSwitch {
+    paused: AtomicBool,
+    resumed: Notify,
+}
+
+impl PauseSwitch {
+    /// Creates a new `PauseSwitch` that starts out resumed.
+    ///
+    /// # Returns
+    /// A new `PauseSwitch` instance.
+    pub fn new() -> Self {
+        PauseSwitch {
+            paused: AtomicBool::new(false),
+            resumed: Notify::new(),
+        }
+    }
+
+    /// Returns whether processing is currently paused.
+    pub fn is_paused(&self) -> bool {
+        self.paused.load(Ordering::SeqCst)
+    }
+
+    /// Pauses or resumes processing.
+    ///
+    /// # Arguments
+    /// * `paused` - `true` to pause processing, `false` to resume it.
+    ///
+    /// # Returns
+    /// `true` if the switch was flipped, `false` if it was already in the requested state.
+    pub fn set_paused(&self, paused: bool) -> bool {
+        let changed = self.paused.swap(paused, Ordering::SeqCst) != paused;
+        if changed && !paused {
+            self.resumed.notify_waiters();
+        }
+        changed
+    }
+
+    /// Waits until processing is resumed, returning right away if it isn't paused.
+    pub async fn wait_until_resumed(&self) {
+        loop {
+            // Register interest before checking, so a resume between the check and the await isn't missed
+            let notified = self.resumed.notified();
+            tokio::pin!(notified);
+            notified.as_mut().enable();
+
+            if !self.is_paused() {
+                return;
+            }
+
+            notified.await;
+        }
+    }
+}
+
+impl Default for PauseSwitch {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -2,7 +2,8 @@
 
 use std::collections::VecDeque;
 use std::sync::Arc;
-use tokio::sync::Mutex;
+use std::sync::atomic::{AtomicBool, Ordering};
+use tokio::sync::{Mutex, Notify};
 use teloxide::types::{ChatId, UserId};
 
 /// A queue item that contains a chat ID, user ID, and some data of type `T`.
@@ -18,10 +19,13 @@ pub struct QueueItem<T> {
 
 /// A queue that stores items of type `T`.
 ///
-/// The `Queue` struct is a thread-safe queue that stores items of type `QueueItem<T>`. It provides methods to enqueue, dequeue, and check if the queue is empty.
+/// The `Queue` struct is a thread-safe queue that stores items of type `QueueItem<T>`. It provides methods to enqueue, dequeue, inspect the front item, and check the length of the queue.
 /// The queue is implemented using an `Arc<Mutex<VecDeque<QueueItem<T>>>>`, which allows for concurrent access and modification of the queue.
+/// A `Notify` wakes consumers waiting in `dequeue` when an item is enqueued or the queue is closed.
 pub struct Queue<T> {
     items: Arc<Mutex<VecDeque<QueueItem<T>>>>,
+    notify: Notify,
+    closed: AtomicBool,
 }
 
 /// Implements a thread-safe queue that stores items of type `QueueItem<T>`.
@@ -51,21 +55,114 @@ impl<T> Queue<T> {
     pub fn new() -> Self {
         Queue {
             items: Arc::new(Mutex::new(VecDeque::new())),
+            notify: Notify::new(),
+            closed: AtomicBool::new(false),
         }
     }
 
     pub async fn enqueue(&self, item: QueueItem<T>) {
         let mut queue = self.items.lock().await;
         queue.push_back(item);
+        self.notify.notify_one();
     }
 
+    /// Removes and returns the item at the front of the queue, waiting until one is available.
+    ///
+    /// Returns `None` only once the queue has been closed with `close` and every remaining item has been dequeued.
     pub async fn dequeue(&self) -> Option<QueueItem<T>> {
+        loop {
+            // Register interest before checking, so an enqueue or close between the check and the await isn't missed
+            let notified = self.notify.notified();
+            tokio::pin!(notified);
+            notified.as_mut().enable();
+
+            if let Some(item) = self.try_dequeue().await {
+                return Some(item);
+            }
+            if self.closed.load(Ordering::Acquire) {
+                return None;
+            }
+
+            notified.await;
+        }
+    }
+
+    /// Removes and returns the item at the front of the queue, or `None` right away if the queue is empty.
+    pub async fn try_dequeue(&self) -> Option<QueueItem<T>> {
         let mut queue = self.items.lock().await;
         queue.pop_front()
     }
 
+    /// Closes the queue, waking every consumer waiting in `dequeue`.
+    ///
+    /// Items already in the queue can still be dequeued; after that, `dequeue` returns `None`.
+    pub fn close(&self) {
+        self.closed.store(true, Ordering::Release);
+        self.notify.notify_waiters();
+    }
+
+    /// Returns whether the queue has been closed with `close`.
+    pub fn is_closed(&self) -> bool {
+        self.closed.load(Ordering::Acquire)
+    }
+
+    /// Returns the number of items currently waiting in the queue.
+    pub async fn len(&self) -> usize {
+        let queue = self.items.lock().await;
+        queue.len()
+    }
+
+    /// Returns the chat ID and user ID of the item at the front of the queue without removing it.
+    ///
+    /// Only the metadata is exposed, since the queued data (usually a `Message`) isn't cheap to clone.
+    pub async fn front_metadata(&self) -> Option<(ChatId, UserId)> {
+        let queue = self.items.lock().await;
+        queue.front().map(|item| (item._chat_id, item._user_id))
+    }
+
     pub async fn is_empty(&self) -> bool {
         let queue = self.items.lock().await;
         queue.is_empty()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn item(chat_id: i64, user_id: u64) -> QueueItem<&'static str> {
+        QueueItem { _chat_id: ChatId(chat_id), _user_id: UserId(user_id), data: "photo" }
+    }
+
+    #[tokio::test]
+    async fn len_and_front_metadata_follow_the_queue() {
+        let queue = Queue::new();
+        assert_eq!(queue.len().await, 0);
+        assert_eq!(queue.front_metadata().await, None);
+
+        queue.enqueue(item(1, 10)).await;
+        queue.enqueue(item(2, 20)).await;
+        assert_eq!(queue.len().await, 2);
+        assert_eq!(queue.front_metadata().await, Some((ChatId(1), UserId(10))));
+        // Peeking doesn't remove the item
+        assert_eq!(queue.len().await, 2);
+
+        queue.try_dequeue().await.unwrap();
+        assert_eq!(queue.len().await, 1);
+        assert_eq!(queue.front_metadata().await, Some((ChatId(2), UserId(20))));
+
+        queue.try_dequeue().await.unwrap();
+        assert!(queue.is_empty().await);
+        assert_eq!(queue.front_metadata().await, None);
+    }
+
+    #[tokio::test]
+    async fn dequeue_drains_a_closed_queue_before_returning_none() {
+        let queue = Queue::new();
+        queue.enqueue(item(1, 10)).await;
+        queue.close();
+
+        assert!(queue.dequeue().await.is_some());
+        assert!(queue.dequeue().await.is_none());
+    }
+}
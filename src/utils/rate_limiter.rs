@@ -1,17 +1,60 @@
 use std::collections::HashMap;
-use std::sync::Arc;
+use std::io;
+use std::path::Path;
+use std::sync::{Arc, Mutex as StdMutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+use log::{info, warn};
 use tokio::sync::Mutex;
 use tokio::time::{Duration, Instant};
 
-/// A RateLimiter struct that tracks the number of requests made within a given time window for a set of keys.
-/// 
-/// The RateLimiter maintains a HashMap that tracks the last reset time and the current count of requests for each key.
-/// When `check_rate_limit` is called, it checks if the number of requests for the given key has exceeded the `max_requests` limit within the `time_window`.
-/// If the limit has been exceeded, it returns `false`, otherwise it updates the count and returns `true`.
-pub struct RateLimiter {
-    limits: Arc<Mutex<HashMap<String, (Instant, u32)>>>,
+/// The strategy a `RateLimiter` uses to decide whether a request is allowed.
+///
+/// - `FixedWindow`: Counts requests per key and resets the count once `time_window` has elapsed since the last reset.
+///   This allows bursts of up to `2 * max_requests` across a window boundary.
+/// - `TokenBucket`: Each key holds a bucket of up to `max_requests` tokens that refills continuously at `refill_rate`
+///   tokens per second. Every request consumes one token, so there is no window boundary to burst across.
+#[derive(Clone, Copy, Debug)]
+pub enum RateLimitStrategy {
+    FixedWindow,
+    TokenBucket { refill_rate: f64 },
+}
+
+/// How many requests a key may have left after a request before `check` warns it, i.e. the last two are warned about.
+const WARN_REMAINING: f64 = 1.0;
+
+/// The smallest limit `check` warns about, since with fewer requests allowed nearly every request would be warned.
+const MIN_REQUESTS_TO_WARN: u32 = 3;
+
+/// The outcome of checking a request against a `RateLimiter`.
+///
+/// - `Allowed`: The request is allowed and recorded.
+/// - `Warn`: The request is allowed and recorded, but the key is close to its limit, with at most one request left.
+/// - `Rejected`: The key has hit its limit, so the request is not allowed.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RateLimitStatus {
+    Allowed,
+    Warn,
+    Rejected,
+}
+
+/// The limit a `RateLimiter` enforces, which can be changed while the bot is running with `set_limit`.
+#[derive(Clone, Copy, Debug)]
+struct Settings {
     max_requests: u32,
     time_window: Duration,
+    strategy: RateLimitStrategy,
+}
+
+/// A RateLimiter struct that tracks the number of requests made within a given time window for a set of keys.
+///
+/// The RateLimiter maintains a HashMap that tracks, for each key, the last update time and either the current count of
+/// requests (fixed window) or the number of tokens left in the bucket (token bucket).
+/// When `check_rate_limit` is called, it checks the key against the configured `RateLimitStrategy`.
+/// If the limit has been exceeded, it returns `false`, otherwise it records the request and returns `true`.
+/// The limit itself sits behind a lock of its own, so admins can change it at runtime with `set_limit`.
+pub struct RateLimiter {
+    limits: Arc<Mutex<HashMap<String, (Instant, f64)>>>,
+    settings: StdMutex<Settings>,
 }
 
 /// Checks the rate limit for the given key and updates the count if the limit has not been exceeded.
@@ -24,7 +67,7 @@ pub struct RateLimiter {
 /// # Returns
 /// `true` if the rate limit has not been exceeded, `false` otherwise.
 impl RateLimiter {
-    /// Creates a new `RateLimiter` instance with the specified maximum number of requests and time window.
+    /// Creates a new fixed-window `RateLimiter` instance with the specified maximum number of requests and time window.
     ///
     /// The `RateLimiter` maintains a HashMap that tracks the last reset time and the current count of requests for each key.
     /// When `check_rate_limit` is called, it checks if the number of requests for the given key has exceeded the `max_requests` limit within the `time_window`.
@@ -39,14 +82,68 @@ impl RateLimiter {
     pub fn new(max_requests: u32, time_window: Duration) -> Self {
         RateLimiter {
             limits: Arc::new(Mutex::new(HashMap::new())),
-            max_requests,
-            time_window,
+            settings: StdMutex::new(Settings { max_requests, time_window, strategy: RateLimitStrategy::FixedWindow }),
+        }
+    }
+
+    /// Creates a new token-bucket `RateLimiter` instance.
+    ///
+    /// Each key starts with a full bucket of `burst` tokens, which refills at `rate` tokens per second up to `burst`.
+    /// Unlike the fixed window, a key can never exceed `burst` requests in quick succession, regardless of timing.
+    ///
+    /// # Arguments
+    /// * `rate` - The number of tokens added back to each bucket per second.
+    /// * `burst` - The maximum number of tokens a bucket can hold.
+    ///
+    /// # Returns
+    /// A new `RateLimiter` instance.
+    pub fn new_token_bucket(rate: f64, burst: u32) -> Self {
+        RateLimiter {
+            limits: Arc::new(Mutex::new(HashMap::new())),
+            settings: StdMutex::new(Settings {
+                max_requests: burst,
+                time_window: Duration::from_secs_f64(burst as f64 / rate),
+                strategy: RateLimitStrategy::TokenBucket { refill_rate: rate },
+            }),
+        }
+    }
+
+    /// Returns the current limit, as the number of requests allowed and the time window they are allowed in.
+    pub fn limit(&self) -> (u32, Duration) {
+        let settings = self.settings();
+        (settings.max_requests, settings.time_window)
+    }
+
+    /// Changes the limit, taking effect from the next request.
+    ///
+    /// A fixed-window limiter allows `max_requests` per `time_window`. A token bucket holds up to `max_requests` tokens
+    /// and refills at `max_requests` per `time_window`, so it allows the same rate with bursts of up to `max_requests`.
+    /// Requests already recorded are kept, so nobody's count starts over; buckets holding more tokens than the new
+    /// limit are trimmed to it on their next request.
+    ///
+    /// # Arguments
+    /// * `max_requests` - The number of requests allowed, at least one.
+    /// * `time_window` - The time window the requests are allowed in, longer than zero.
+    pub fn set_limit(&self, max_requests: u32, time_window: Duration) {
+        let mut settings = self.settings.lock().unwrap_or_else(|e| e.into_inner());
+        settings.max_requests = max_requests;
+        settings.time_window = time_window;
+        if let RateLimitStrategy::TokenBucket { refill_rate } = &mut settings.strategy {
+            *refill_rate = max_requests as f64 / time_window.as_secs_f64();
         }
+        info!("Rate limit set to {} requests per {:?}", max_requests, time_window);
     }
 
-    /// Checks the rate limit for the given key and updates the count if the limit has not been exceeded.
+    /// Returns a copy of the current settings, so the lock isn't held while they are used.
+    fn settings(&self) -> Settings {
+        *self.settings.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Checks the rate limit for the given key and records the request if the limit has not been exceeded.
     ///
-    /// This method acquires a lock on the `limits` HashMap, checks if the given key exists, and updates the last reset time and count accordingly. If the count exceeds the `max_requests` limit within the `time_window`, it returns `false`. Otherwise, it updates the count and returns `true`.
+    /// This method acquires a lock on the `limits` HashMap and applies the configured `RateLimitStrategy` to the given key.
+    /// For the fixed window, if the count exceeds the `max_requests` limit within the `time_window`, it returns `false`.
+    /// For the token bucket, the bucket is refilled for the elapsed time and a token is consumed if one is available.
     ///
     /// # Arguments
     /// * `key` - The key to check the rate limit for.
@@ -54,22 +151,265 @@ pub fn new(max_requests: u32, time_window: Duration) -> Self {
     /// # Returns
     /// `true` if the rate limit has not been exceeded, `false` otherwise.
     pub async fn check_rate_limit(&self, key: &str) -> bool {
+        self.check(key).await != RateLimitStatus::Rejected
+    }
+
+    /// Checks the rate limit for the given key like `check_rate_limit`, also telling whether the key is close to it.
+    ///
+    /// A request that leaves the key with at most one request before it is limited is allowed with
+    /// `RateLimitStatus::Warn`, so the user can be told to slow down before they are turned away, e.g. on the 4th and
+    /// 5th of 5 requests a minute. Limits of fewer than 3 requests never warn.
+    ///
+    /// # Arguments
+    /// * `key` - The key to check the rate limit for.
+    ///
+    /// # Returns
+    /// Whether the request is allowed, allowed with a warning, or rejected.
+    pub async fn check(&self, key: &str) -> RateLimitStatus {
+        let settings = self.settings();
         let mut limits = self.limits.lock().await;
         let now = Instant::now();
 
-        if let Some((last_reset, count)) = limits.get_mut(key) {
-            if now.duration_since(*last_reset) > self.time_window {
-                *last_reset = now;
-                *count = 1;
-            } else if *count >= self.max_requests {
-                return false;
-            } else {
-                *count += 1;
+        // How many more requests the key has before it is limited, once this one is recorded
+        let remaining = match settings.strategy {
+            RateLimitStrategy::FixedWindow => {
+                let (last_reset, count) = limits.entry(key.to_string()).or_insert((now, 0.0));
+                if now.duration_since(*last_reset) > settings.time_window {
+                    *last_reset = now;
+                    *count = 0.0;
+                } else if *count >= settings.max_requests as f64 {
+                    return RateLimitStatus::Rejected;
+                }
+                *count += 1.0;
+                settings.max_requests as f64 - *count
             }
+            RateLimitStrategy::TokenBucket { refill_rate } => {
+                let capacity = settings.max_requests as f64;
+                let (last_refill, tokens) = limits.entry(key.to_string()).or_insert((now, capacity));
+                let refilled = now.duration_since(*last_refill).as_secs_f64() * refill_rate;
+                *tokens = (*tokens + refilled).min(capacity);
+                *last_refill = now;
+
+                if *tokens < 1.0 {
+                    return RateLimitStatus::Rejected;
+                }
+                *tokens -= 1.0;
+                tokens.floor()
+            }
+        };
+
+        if settings.max_requests >= MIN_REQUESTS_TO_WARN && remaining <= WARN_REMAINING {
+            RateLimitStatus::Warn
         } else {
-            limits.insert(key.to_string(), (now, 1));
+            RateLimitStatus::Allowed
+        }
+    }
+
+    /// Works out how long the given key has to wait before its next request is allowed.
+    ///
+    /// Unlike `check_rate_limit`, this doesn't record a request, so it can be used to tell a rate-limited user how long
+    /// to wait. For the fixed window the wait runs until `last_reset + time_window`; for the token bucket it is the time
+    /// the bucket needs to refill to one token.
+    ///
+    /// # Arguments
+    /// * `key` - The key to check.
+    ///
+    /// # Returns
+    /// `Some` with the remaining wait if the key is currently rate limited, `None` if a request would be allowed now.
+    pub async fn time_until_allowed(&self, key: &str) -> Option<Duration> {
+        let settings = self.settings();
+        let limits = self.limits.lock().await;
+        let (last_update, value) = *limits.get(key)?;
+        let elapsed = Instant::now().duration_since(last_update);
+
+        match settings.strategy {
+            RateLimitStrategy::FixedWindow => {
+                if elapsed > settings.time_window || value < settings.max_requests as f64 {
+                    return None;
+                }
+                Some(settings.time_window - elapsed)
+            }
+            RateLimitStrategy::TokenBucket { refill_rate } => {
+                let tokens = (value + elapsed.as_secs_f64() * refill_rate).min(settings.max_requests as f64);
+                if tokens >= 1.0 {
+                    return None;
+                }
+                Some(Duration::from_secs_f64((1.0 - tokens) / refill_rate))
+            }
+        }
+    }
+
+    /// Saves the limiter's state to a file, so it can be restored with `restore` after a restart.
+    ///
+    /// `Instant`s can't be serialized, so each key's last update time is stored as wall-clock seconds since the Unix
+    /// epoch. The file is JSON mapping each key to `[last_update_secs, count_or_tokens]`. It is written to a temporary
+    /// file first and renamed into place, so a crash mid-write never leaves a truncated state file behind.
+    ///
+    /// # Arguments
+    /// * `path` - The file to save the state to.
+    ///
+    /// # Returns
+    /// `Ok(())` if the state was saved, or the I/O error that stopped it.
+    pub async fn save(&self, path: &Path) -> io::Result<()> {
+        let now = Instant::now();
+        let wall_now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+        let saved: HashMap<String, (f64, f64)> = self.limits.lock().await
+            .iter()
+            .map(|(key, (last_update, value))| {
+                let last_update = wall_now.saturating_sub(now.duration_since(*last_update));
+                (key.clone(), (last_update.as_secs_f64(), *value))
+            })
+            .collect();
+
+        let json = serde_json::to_vec(&saved).map_err(io::Error::other)?;
+        let temp_path = path.with_extension("tmp");
+        tokio::fs::write(&temp_path, json).await?;
+        tokio::fs::rename(&temp_path, path).await
+    }
+
+    /// Restores the limiter's state from a file written by `save`.
+    ///
+    /// The saved wall-clock times are converted back to `Instant`s relative to now. Entries whose window or bucket
+    /// has fully elapsed since they were saved are dropped, since they no longer limit anything. A missing or corrupt
+    /// state file is logged and the limiter starts fresh, so persistence can never stop the bot from starting.
+    ///
+    /// # Arguments
+    /// * `path` - The file to restore the state from.
+    pub async fn restore(&self, path: &Path) {
+        let json = match tokio::fs::read(path).await {
+            Ok(json) => json,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                info!("No rate limiter state at {:?}, starting fresh", path);
+                return;
+            }
+            Err(e) => {
+                warn!("Failed to read rate limiter state from {:?}, starting fresh: {}", path, e);
+                return;
+            }
+        };
+        let saved: HashMap<String, (f64, f64)> = match serde_json::from_slice(&json) {
+            Ok(saved) => saved,
+            Err(e) => {
+                warn!("Rate limiter state at {:?} is corrupt, starting fresh: {}", path, e);
+                return;
+            }
+        };
+
+        let time_window = self.settings().time_window;
+        let now = Instant::now();
+        let wall_now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+        let mut limits = self.limits.lock().await;
+        for (key, (last_update_secs, value)) in saved {
+            if !last_update_secs.is_finite() || !value.is_finite() || last_update_secs < 0.0 {
+                continue;
+            }
+            // A time in the future, e.g. after the clock was set back, is treated as just now
+            let age = wall_now.saturating_sub(Duration::from_secs_f64(last_update_secs));
+            if age > time_window {
+                continue;
+            }
+            if let Some(last_update) = now.checked_sub(age) {
+                limits.insert(key, (last_update, value));
+            }
+        }
+        info!("Restored rate limiter state for {} keys from {:?}", limits.len(), path);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Sends `count` requests for `key` and returns how many were allowed.
+    async fn allowed(limiter: &RateLimiter, key: &str, count: u32) -> u32 {
+        let mut allowed = 0;
+        for _ in 0..count {
+            if limiter.check_rate_limit(key).await {
+                allowed += 1;
+            }
         }
+        allowed
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn fixed_window_allows_a_double_burst_across_the_boundary() {
+        let limiter = RateLimiter::new(5, Duration::from_secs(60));
+        assert_eq!(allowed(&limiter, "user", 6).await, 5);
+        assert!(limiter.time_until_allowed("user").await.is_some());
+
+        // Just after the window ends the count resets, so 5 more go through straight away
+        tokio::time::advance(Duration::from_secs(61)).await;
+        assert_eq!(limiter.time_until_allowed("user").await, None);
+        assert_eq!(allowed(&limiter, "user", 6).await, 5);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn token_bucket_caps_bursts_across_the_boundary() {
+        let limiter = RateLimiter::new_token_bucket(5.0 / 60.0, 5);
+        tokio::time::advance(Duration::from_secs(59)).await;
+        assert_eq!(allowed(&limiter, "user", 6).await, 5);
+
+        // Where a fixed window would have reset, the bucket has only refilled for the time that passed
+        tokio::time::advance(Duration::from_secs(2)).await;
+        assert_eq!(allowed(&limiter, "user", 5).await, 0);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn token_bucket_reports_the_wait_for_the_next_token() {
+        let limiter = RateLimiter::new_token_bucket(5.0 / 60.0, 5);
+        assert_eq!(allowed(&limiter, "user", 5).await, 5);
+
+        let wait = limiter.time_until_allowed("user").await.expect("the bucket is empty");
+        assert!(wait > Duration::from_millis(11_900) && wait <= Duration::from_millis(12_001), "waited {:?}", wait);
+
+        tokio::time::advance(wait + Duration::from_millis(1)).await;
+        assert_eq!(limiter.time_until_allowed("user").await, None);
+        assert!(limiter.check_rate_limit("user").await);
+        assert!(!limiter.check_rate_limit("user").await);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn keys_are_limited_separately() {
+        let limiter = RateLimiter::new_token_bucket(5.0 / 60.0, 5);
+        assert_eq!(allowed(&limiter, "first", 5).await, 5);
+        assert!(!limiter.check_rate_limit("first").await);
+        assert!(limiter.check_rate_limit("second").await);
+        assert_eq!(limiter.time_until_allowed("second").await, None);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn set_limit_changes_what_a_fixed_window_allows() {
+        let limiter = RateLimiter::new(5, Duration::from_secs(60));
+        assert_eq!(allowed(&limiter, "user", 2).await, 2);
+
+        // Lowering the limit keeps the requests already counted, so only one more fits in the window
+        limiter.set_limit(3, Duration::from_secs(60));
+        assert_eq!(limiter.limit(), (3, Duration::from_secs(60)));
+        assert_eq!(allowed(&limiter, "user", 5).await, 1);
+
+        // Raising it lets the same user through again straight away
+        limiter.set_limit(10, Duration::from_secs(60));
+        assert_eq!(allowed(&limiter, "user", 10).await, 7);
+
+        // A shorter window resets the count sooner
+        limiter.set_limit(10, Duration::from_secs(10));
+        tokio::time::advance(Duration::from_secs(11)).await;
+        assert_eq!(allowed(&limiter, "user", 11).await, 10);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn set_limit_changes_the_token_bucket_capacity_and_rate() {
+        let limiter = RateLimiter::new_token_bucket(5.0 / 60.0, 5);
+        assert_eq!(allowed(&limiter, "user", 1).await, 1);
+
+        // The 4 tokens left are trimmed to the new capacity of 2
+        limiter.set_limit(2, Duration::from_secs(60));
+        assert_eq!(allowed(&limiter, "user", 5).await, 2);
 
-        true
+        // At 2 per minute a token takes 30 seconds to come back, not the 12 it took before
+        tokio::time::advance(Duration::from_secs(13)).await;
+        assert!(!limiter.check_rate_limit("user").await);
+        tokio::time::advance(Duration::from_secs(18)).await;
+        assert!(limiter.check_rate_limit("user").await);
     }
 }
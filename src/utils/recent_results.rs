@@ -0,0 +1,61 @@
+use std::collections::VecDeque;
+use std::sync::Arc;
+use teloxide::types::{ChatId, MessageId};
+use tokio::sync::Mutex;
+
+/// A RecentResults struct that remembers the most recent overlay results the bot has sent.
+///
+/// Users sometimes reply to the bot's result instead of the `/degenme` prompt. Remembering the result messages lets
+/// the bot recognise those replies and point the user at the prompt instead of ignoring them.
+///
+/// Only the last `capacity` results are kept; the oldest is forgotten when a new one is recorded. Results are only kept
+/// in memory, so they are forgotten when the bot restarts.
+pub struct RecentResults {
+    messages: Arc<Mutex<VecDeque<(ChatId, MessageId)>>>,
+    capacity: usize,
+}
+
+impl RecentResults {
+    /// Creates a new `RecentResults` instance that remembers up to `capacity` results.
+    ///
+    /// # Arguments
+    /// * `capacity` - The number of results to remember.
+    ///
+    /// # Returns
+    /// A new `RecentResults` instance.
+    pub fn new(capacity: usize) -> Self {
+        RecentResults {
+            messages: Arc::new(Mutex::new(VecDeque::with_capacity(capacity))),
+            capacity,
+        }
+    }
+
+    /// Records a result message sent by the bot, forgetting the oldest one if the set is full.
+    ///
+    /// # Arguments
+    /// * `chat_id` - The chat the result was sent in.
+    /// * `message_id` - The ID of the result message.
+    pub async fn record(&self, chat_id: ChatId, message_id: MessageId) {
+        if self.capacity == 0 {
+            return;
+        }
+
+        let mut messages = self.messages.lock().await;
+        if messages.len() >= self.capacity {
+            messages.pop_front();
+        }
+        messages.push_back((chat_id, message_id));
+    }
+
+    /// Checks whether a message is one of the recently sent results.
+    ///
+    /// # Arguments
+    /// * `chat_id` - The chat the message is in.
+    /// * `message_id` - The ID of the message.
+    ///
+    /// # Returns
+    /// `true` if the message is a remembered result, `false` otherwise.
+    pub async fn contains(&self, chat_id: ChatId, message_id: MessageId) -> bool {
+        self.messages.lock().await.contains(&(chat_id, message_id))
+    }
+}
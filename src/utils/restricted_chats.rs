@@ -0,0 +1,82 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+use teloxide::types::ChatId;
+use tokio::sync::Mutex;
+use tokio::time::Instant;
+
+/// The number of permission failures in a row after which a chat is suppressed.
+const SUPPRESS_AFTER_FAILURES: u32 = 2;
+
+/// How long a chat stays suppressed, after which the bot tries again in case it has been given the rights it needs.
+const SUPPRESSION_PERIOD: Duration = Duration::from_secs(60 * 60);
+
+/// A RestrictedChats struct that remembers chats where the bot isn't allowed to post.
+///
+/// When the bot is restricted in a group, e.g. it can't send media, or a user has blocked it, every overlay request in
+/// that chat ends with Telegram rejecting the result. After `SUPPRESS_AFTER_FAILURES` such failures in a row the chat is
+/// suppressed for `SUPPRESSION_PERIOD`, and overlay requests there are ignored instead of being processed for nothing.
+/// A successful send clears the chat's failures.
+///
+/// Chats are only kept in memory, so they are forgotten when the bot restarts.
+pub struct RestrictedChats {
+    failures: Arc<Mutex<HashMap<ChatId, (u32, Instant)>>>,
+}
+
+impl RestrictedChats {
+    /// Creates a new `RestrictedChats` instance with no restricted chats.
+    ///
+    /// # Returns
+    /// A new `RestrictedChats` instance.
+    pub fn new() -> Self {
+        RestrictedChats {
+            failures: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// Records that the bot wasn't allowed to post in a chat.
+    ///
+    /// # Arguments
+    /// * `chat_id` - The chat the bot failed to post in.
+    ///
+    /// # Returns
+    /// `true` if the chat is now suppressed, `false` otherwise.
+    pub async fn record_failure(&self, chat_id: ChatId) -> bool {
+        let mut failures = self.failures.lock().await;
+        let (count, last_failure) = failures.entry(chat_id).or_insert((0, Instant::now()));
+        // A failure after the suppression has lapsed starts counting again
+        if last_failure.elapsed() > SUPPRESSION_PERIOD {
+            *count = 0;
+        }
+        *count += 1;
+        *last_failure = Instant::now();
+        *count >= SUPPRESS_AFTER_FAILURES
+    }
+
+    /// Records that the bot posted in a chat, clearing its failures.
+    ///
+    /// # Arguments
+    /// * `chat_id` - The chat the bot posted in.
+    pub async fn record_success(&self, chat_id: ChatId) {
+        self.failures.lock().await.remove(&chat_id);
+    }
+
+    /// Checks whether overlay requests in a chat should be ignored.
+    ///
+    /// # Arguments
+    /// * `chat_id` - The chat to check.
+    ///
+    /// # Returns
+    /// `true` if the chat is suppressed, `false` otherwise.
+    pub async fn is_suppressed(&self, chat_id: ChatId) -> bool {
+        self.failures.lock().await
+            .get(&chat_id)
+            .is_some_and(|(count, last_failure)| *count >= SUPPRESS_AFTER_FAILURES && last_failure.elapsed() <= SUPPRESSION_PERIOD)
+    }
+}
+
+impl Default for RestrictedChats {
+    fn default() -> Self {
+        Self::new()
+    }
+}
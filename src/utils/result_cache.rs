@@ -0,0 +1,298 @@
+use std::collections::{HashMap, VecDeque};
+use std::future::Future;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+
+/// The key a result is cached under: the directory the overlays come from, since chats can have their own, the
+/// `LiveAssets` generation they were loaded in, so results rendered before a reload aren't served after it, the
+/// Telegram file's unique ID and the requested overlay styles, in order.
+pub type ResultKey = (PathBuf, u64, String, Vec<String>);
+
+/// A result kept by `ResultCache`, together with the source image it was rendered from, so replying to a result
+/// served from the cache with `/again` still works.
+#[derive(Clone)]
+pub struct CachedResult {
+    pub result: Arc<Vec<u8>>,
+    pub source: Arc<Vec<u8>>,
+}
+
+impl CachedResult {
+    /// Returns the number of bytes the result and its source take up.
+    fn size(&self) -> usize {
+        self.result.len() + self.source.len()
+    }
+}
+
+/// The cached results, with their keys from least to most recently used, and the renders currently in progress.
+struct Entries {
+    results: HashMap<ResultKey, CachedResult>,
+    order: VecDeque<ResultKey>,
+    bytes: usize,
+    in_flight: HashMap<ResultKey, Arc<tokio::sync::Mutex<()>>>,
+}
+
+impl Entries {
+    /// Looks up a result and marks it as the most recently used.
+    fn touch(&mut self, key: &ResultKey) -> Option<CachedResult> {
+        let hit = self.results.get(key)?.clone();
+        if let Some(position) = self.order.iter().position(|cached| cached == key) {
+            let key = self.order.remove(position).expect("position is in bounds");
+            self.order.push_back(key);
+        }
+        Some(hit)
+    }
+
+    /// Caches a result, evicting the least recently used ones until it fits within both limits.
+    fn insert(&mut self, key: ResultKey, result: CachedResult, max_entries: usize, max_bytes: usize) {
+        let size = result.size();
+        if size > max_bytes {
+            return;
+        }
+
+        if let Some(replaced) = self.results.remove(&key) {
+            self.bytes -= replaced.size();
+            self.order.retain(|cached| cached != &key);
+        }
+        while self.results.len() >= max_entries || self.bytes + size > max_bytes {
+            let Some(oldest) = self.order.pop_front() else {
+                break;
+            };
+            if let Some(evicted) = self.results.remove(&oldest) {
+                self.bytes -= evicted.size();
+            }
+        }
+
+        self.bytes += size;
+        self.results.insert(key.clone(), result);
+        self.order.push_back(key);
+    }
+}
+
+/// A call's hold on the render of a key in `in_flight`, which removes the key's entry once the last call holding it is
+/// dropped.
+///
+/// Cleaning up on drop rather than at the end of `get_or_try_insert_with` also covers calls that are cancelled, such as
+/// a request whose task is aborted while it renders, which would otherwise leave the entry behind for good.
+struct Flight<'a> {
+    entries: &'a Mutex<Entries>,
+    key: ResultKey,
+    rendering: Option<Arc<tokio::sync::Mutex<()>>>,
+}
+
+impl Drop for Flight<'_> {
+    fn drop(&mut self) {
+        let mut entries = self.entries.lock().unwrap();
+        let Some(rendering) = self.rendering.take() else {
+            return;
+        };
+        // The map holds one reference and this call another, so anyone else still waiting removes it when they're done
+        let current = entries.in_flight.get(&self.key).is_some_and(|current| Arc::ptr_eq(current, &rendering));
+        if current && Arc::strong_count(&rendering) <= 2 {
+            entries.in_flight.remove(&self.key);
+        }
+        // Dropped while the entries are still locked, so two calls finishing at once can't both see the other's reference
+        drop(rendering);
+    }
+}
+
+/// A ResultCache struct that keeps recently rendered results, so the same photo with the same overlays isn't rendered
+/// twice.
+///
+/// When several users reply with the same Telegram photo, its file's unique ID is the same each time, so the result
+/// for a given set of styles can be sent again straight from the cache without downloading or rendering anything.
+/// Requests without styles pick a random overlay, so they are never cached.
+///
+/// The cache is bounded both by the number of results and by the bytes they take up, including their source images,
+/// and evicts the least recently used results first. Concurrent requests for the same key are coalesced: while one of
+/// them renders the result, the others wait for it and are then served from the cache instead of rendering it again.
+/// The entries are behind a blocking `Mutex`, which is never held across an `.await`, so a cancelled call can clean up
+/// after itself when it is dropped. Results are only kept in memory, so they are forgotten when the bot restarts.
+pub struct ResultCache {
+    entries: Mutex<Entries>,
+    max_entries: usize,
+    max_bytes: usize,
+}
+
+impl ResultCache {
+    /// Creates a new `ResultCache` instance that keeps up to `max_entries` results taking up at most `max_bytes`.
+    ///
+    /// # Arguments
+    /// * `max_entries` - The number of results to keep, or `0` to disable the cache.
+    /// * `max_bytes` - The combined size of the results and their sources, or `0` to disable the cache.
+    ///
+    /// # Returns
+    /// A new `ResultCache` instance.
+    pub fn new(max_entries: usize, max_bytes: usize) -> Self {
+        ResultCache {
+            entries: Mutex::new(Entries {
+                results: HashMap::new(),
+                order: VecDeque::new(),
+                bytes: 0,
+                in_flight: HashMap::new(),
+            }),
+            max_entries,
+            max_bytes,
+        }
+    }
+
+    /// Returns the cached result for `key`, or renders and caches it with `render`.
+    ///
+    /// Only one call renders a given key at a time. Calls for a key that is already being rendered wait for that render
+    /// and return its result from the cache. If the render fails or the rendering call is cancelled, nothing is cached and
+    /// the next waiting call renders the key itself. If the cache is disabled, `render` is always called.
+    ///
+    /// # Arguments
+    /// * `key` - The key the result is cached under.
+    /// * `render` - Renders the result if it isn't cached.
+    ///
+    /// # Returns
+    /// The cached or newly rendered result, or the error returned by `render`.
+    pub async fn get_or_try_insert_with<F, Fut, E>(&self, key: ResultKey, render: F) -> Result<CachedResult, E>
+    where
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = Result<CachedResult, E>>,
+    {
+        if self.max_entries == 0 || self.max_bytes == 0 {
+            return render().await;
+        }
+
+        let flight = {
+            let mut entries = self.entries.lock().unwrap();
+            if let Some(hit) = entries.touch(&key) {
+                return Ok(hit);
+            }
+            let rendering = Arc::clone(entries.in_flight.entry(key.clone()).or_default());
+            Flight { entries: &self.entries, key, rendering: Some(rendering) }
+        };
+
+        let _rendering = flight.rendering.as_ref().expect("the flight is only emptied when dropped").lock().await;
+        // Whoever rendered this key while we waited has cached the result by now, unless their render failed
+        let hit = self.entries.lock().unwrap().touch(&flight.key);
+        if let Some(hit) = hit {
+            return Ok(hit);
+        }
+        let result = render().await;
+        if let Ok(rendered) = &result {
+            self.entries.lock().unwrap().insert(flight.key.clone(), rendered.clone(), self.max_entries, self.max_bytes);
+        }
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::Duration;
+
+    fn key(file: &str) -> ResultKey {
+        (PathBuf::from("img"), 0, file.to_string(), vec!["hat".to_string()])
+    }
+
+    fn result(bytes: usize) -> CachedResult {
+        CachedResult { result: Arc::new(vec![1; bytes]), source: Arc::new(Vec::new()) }
+    }
+
+    /// Renders a result of `bytes` bytes after a short delay, counting how often it is called.
+    async fn render(renders: &AtomicUsize, bytes: usize) -> Result<CachedResult, ()> {
+        renders.fetch_add(1, Ordering::SeqCst);
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        Ok(result(bytes))
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn concurrent_misses_render_once() {
+        let cache = ResultCache::new(8, 1024);
+        let renders = AtomicUsize::new(0);
+
+        let (first, second) = tokio::join!(
+            cache.get_or_try_insert_with(key("photo"), || render(&renders, 10)),
+            cache.get_or_try_insert_with(key("photo"), || render(&renders, 10)),
+        );
+        assert_eq!(renders.load(Ordering::SeqCst), 1);
+        assert!(Arc::ptr_eq(&first.unwrap().result, &second.unwrap().result));
+        assert!(cache.entries.lock().unwrap().in_flight.is_empty());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn a_cancelled_render_leaves_nothing_in_flight() {
+        let cache = ResultCache::new(8, 1024);
+        let renders = AtomicUsize::new(0);
+
+        // The render takes 50ms, so the timeout drops the call while it is still rendering
+        let cancelled = tokio::time::timeout(Duration::from_millis(10), cache.get_or_try_insert_with(key("photo"), || render(&renders, 10))).await;
+        assert!(cancelled.is_err());
+        assert!(cache.entries.lock().unwrap().in_flight.is_empty());
+
+        // Nothing was cached, so the next call renders the key itself
+        cache.get_or_try_insert_with(key("photo"), || render(&renders, 10)).await.unwrap();
+        assert_eq!(renders.load(Ordering::SeqCst), 2);
+        assert!(cache.entries.lock().unwrap().in_flight.is_empty());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn the_next_waiter_renders_after_a_failure() {
+        let cache = ResultCache::new(8, 1024);
+        let renders = AtomicUsize::new(0);
+        let failing = || async {
+            renders.fetch_add(1, Ordering::SeqCst);
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            Err(())
+        };
+
+        let (first, second) = tokio::join!(
+            cache.get_or_try_insert_with(key("photo"), failing),
+            cache.get_or_try_insert_with(key("photo"), || render(&renders, 10)),
+        );
+        assert!(first.is_err());
+        assert!(second.is_ok());
+        assert_eq!(renders.load(Ordering::SeqCst), 2);
+
+        // The second render was cached
+        let third = cache.get_or_try_insert_with(key("photo"), || render(&renders, 10)).await;
+        assert!(third.is_ok());
+        assert_eq!(renders.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn evicts_the_least_recently_used_result() {
+        let cache = ResultCache::new(2, 1024);
+        let renders = AtomicUsize::new(0);
+        for file in ["first", "second", "first", "third"] {
+            cache.get_or_try_insert_with(key(file), || render(&renders, 10)).await.unwrap();
+        }
+        assert_eq!(renders.load(Ordering::SeqCst), 3);
+
+        // "first" was used more recently than "second", so "second" made room for "third"
+        let entries = cache.entries.lock().unwrap();
+        assert!(entries.results.contains_key(&key("first")));
+        assert!(!entries.results.contains_key(&key("second")));
+        assert!(entries.results.contains_key(&key("third")));
+        assert_eq!(entries.bytes, 20);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn keeps_within_the_byte_limit() {
+        let cache = ResultCache::new(8, 100);
+        let renders = AtomicUsize::new(0);
+        cache.get_or_try_insert_with(key("first"), || render(&renders, 60)).await.unwrap();
+        cache.get_or_try_insert_with(key("second"), || render(&renders, 60)).await.unwrap();
+        // Larger than the whole cache, so it is returned but never cached
+        cache.get_or_try_insert_with(key("huge"), || render(&renders, 200)).await.unwrap();
+
+        let entries = cache.entries.lock().unwrap();
+        assert_eq!(entries.results.len(), 1);
+        assert!(entries.results.contains_key(&key("second")));
+        assert_eq!(entries.bytes, 60);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn a_disabled_cache_always_renders() {
+        let cache = ResultCache::new(0, 1024);
+        let renders = AtomicUsize::new(0);
+        for _ in 0..2 {
+            cache.get_or_try_insert_with(key("photo"), || render(&renders, 10)).await.unwrap();
+        }
+        assert_eq!(renders.load(Ordering::SeqCst), 2);
+    }
+}
@@ -0,0 +1,143 @@
+use std::collections::{HashSet, VecDeque};
+use std::time::Duration;
+use teloxide::types::{ChatId, MessageId};
+use tokio::sync::Mutex;
+use tokio::time::Instant;
+
+/// The messages remembered by `SeenMessages`, oldest first, with a set for quick lookups.
+struct Seen {
+    order: VecDeque<(ChatId, MessageId, Instant)>,
+    keys: HashSet<(ChatId, MessageId)>,
+}
+
+/// A SeenMessages struct that remembers the messages the bot has recently handled, so redelivered ones are ignored.
+///
+/// Telegram can deliver the same update more than once, for example when a webhook response times out, which would
+/// otherwise overlay the same photo twice. A message is identified by its chat and message ID, which stay the same
+/// when it is redelivered.
+///
+/// Only the last `capacity` messages are kept, and each one is forgotten `ttl` after it was first seen, since
+/// redeliveries arrive shortly after the original. Messages are only kept in memory, so they are forgotten when the bot
+/// restarts.
+pub struct SeenMessages {
+    seen: Mutex<Seen>,
+    capacity: usize,
+    ttl: Duration,
+}
+
+impl SeenMessages {
+    /// Creates a new `SeenMessages` instance that remembers up to `capacity` messages for `ttl` each.
+    ///
+    /// # Arguments
+    /// * `capacity` - The number of messages to remember.
+    /// * `ttl` - How long each message is remembered.
+    ///
+    /// # Returns
+    /// A new `SeenMessages` instance.
+    pub fn new(capacity: usize, ttl: Duration) -> Self {
+        SeenMessages {
+            seen: Mutex::new(Seen { order: VecDeque::with_capacity(capacity), keys: HashSet::with_capacity(capacity) }),
+            capacity,
+            ttl,
+        }
+    }
+
+    /// Records a message as seen, forgetting expired messages, and the oldest one if the set is full and the message is
+    /// new.
+    ///
+    /// A message that is already remembered is never forgotten to make room for itself, so a redelivery is recognized
+    /// however full the set is.
+    ///
+    /// # Arguments
+    /// * `chat_id` - The chat the message is in.
+    /// * `message_id` - The ID of the message.
+    ///
+    /// # Returns
+    /// `true` if the message is new, or `false` if it was already seen within the last `ttl` and should be ignored.
+    pub async fn first_seen(&self, chat_id: ChatId, message_id: MessageId) -> bool {
+        if self.capacity == 0 {
+            return true;
+        }
+
+        let mut seen = self.seen.lock().await;
+        let now = Instant::now();
+        while let Some(&(old_chat_id, old_message_id, seen_at)) = seen.order.front() {
+            if now.duration_since(seen_at) <= self.ttl {
+                break;
+            }
+            seen.order.pop_front();
+            seen.keys.remove(&(old_chat_id, old_message_id));
+        }
+        if seen.keys.contains(&(chat_id, message_id)) {
+            return false;
+        }
+
+        if seen.order.len() >= self.capacity {
+            if let Some((old_chat_id, old_message_id, _)) = seen.order.pop_front() {
+                seen.keys.remove(&(old_chat_id, old_message_id));
+            }
+        }
+        seen.keys.insert((chat_id, message_id));
+        seen.order.push_back((chat_id, message_id, now));
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test(start_paused = true)]
+    async fn a_redelivered_message_is_only_handled_once() {
+        let seen = SeenMessages::new(10, Duration::from_secs(60));
+
+        assert!(seen.first_seen(ChatId(1), MessageId(5)).await);
+        assert!(!seen.first_seen(ChatId(1), MessageId(5)).await);
+        // Message IDs are only unique within a chat
+        assert!(seen.first_seen(ChatId(2), MessageId(5)).await);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn messages_are_forgotten_after_the_ttl() {
+        let seen = SeenMessages::new(10, Duration::from_secs(60));
+        assert!(seen.first_seen(ChatId(1), MessageId(5)).await);
+
+        tokio::time::advance(Duration::from_secs(30)).await;
+        assert!(!seen.first_seen(ChatId(1), MessageId(5)).await);
+        tokio::time::advance(Duration::from_secs(31)).await;
+        assert!(seen.first_seen(ChatId(1), MessageId(5)).await);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn the_oldest_message_is_forgotten_when_full() {
+        let seen = SeenMessages::new(2, Duration::from_secs(60));
+        for id in 1..=3 {
+            assert!(seen.first_seen(ChatId(1), MessageId(id)).await);
+        }
+
+        assert!(!seen.first_seen(ChatId(1), MessageId(3)).await);
+        assert!(seen.first_seen(ChatId(1), MessageId(1)).await);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn a_redelivery_is_recognized_when_full() {
+        let seen = SeenMessages::new(2, Duration::from_secs(60));
+        assert!(seen.first_seen(ChatId(1), MessageId(1)).await);
+        assert!(seen.first_seen(ChatId(1), MessageId(2)).await);
+
+        assert!(!seen.first_seen(ChatId(1), MessageId(2)).await);
+        assert!(!seen.first_seen(ChatId(1), MessageId(1)).await);
+
+        let single = SeenMessages::new(1, Duration::from_secs(60));
+        assert!(single.first_seen(ChatId(1), MessageId(1)).await);
+        assert!(!single.first_seen(ChatId(1), MessageId(1)).await);
+    }
+
+    #[tokio::test]
+    async fn a_zero_capacity_remembers_nothing() {
+        let seen = SeenMessages::new(0, Duration::from_secs(60));
+
+        assert!(seen.first_seen(ChatId(1), MessageId(5)).await);
+        assert!(seen.first_seen(ChatId(1), MessageId(5)).await);
+    }
+}
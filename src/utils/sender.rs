@@ -0,0 +1,266 @@
+use std::future::Future;
+use std::io;
+use std::sync::{Arc, Mutex};
+use teloxide::{ApiError, RequestError};
+use teloxide::prelude::*;
+use std::sync::atomic::{AtomicI32, AtomicUsize, Ordering};
+use teloxide::requests::JsonRequest;
+use teloxide::types::{InputFile, InputMedia, InputMediaPhoto, MessageId};
+
+use crate::utils::telegram::SetMessageReaction;
+
+/// The subset of the Telegram API used by the overlay pipeline.
+///
+/// The overlay handler and processor are generic over this trait instead of using `Bot` directly, so their logic can
+/// be driven by `MockSender` without a network connection. The `Bot` implementation relies on the request timeout set on
+/// the bot's client by `bot_with_timeout`, so callers don't need to wrap the requests themselves.
+pub trait MessageSender: Clone + Send + Sync + 'static {
+    /// Sends a text message and returns the ID of the sent message.
+    ///
+    /// Like every sending method, it takes the forum topic to post in, from `topic_thread_id`, or `None` for chats
+    /// without topics and for the "General" topic.
+    fn send_message(&self, chat_id: ChatId, thread_id: Option<i32>, text: String) -> impl Future<Output = ResponseResult<MessageId>> + Send;
+
+    /// Sends a photo with a caption and returns the ID of the sent message.
+    fn send_photo(&self, chat_id: ChatId, thread_id: Option<i32>, photo: Vec<u8>, file_name: &str, caption: String) -> impl Future<Output = ResponseResult<MessageId>> + Send;
+
+    /// Sends a photo with a caption as a reply to another message and returns the ID of the sent message.
+    fn reply_photo(&self, chat_id: ChatId, thread_id: Option<i32>, reply_to: MessageId, photo: Vec<u8>, file_name: &str, caption: String) -> impl Future<Output = ResponseResult<MessageId>> + Send;
+
+    /// Sends photos as one album, optionally as a reply to another message, and returns the IDs of the sent messages.
+    ///
+    /// The caption goes on the first photo, which Telegram shows as the album's caption. Telegram takes 2 to 10 photos
+    /// per album.
+    fn send_media_group(&self, chat_id: ChatId, thread_id: Option<i32>, reply_to: Option<MessageId>, photos: Vec<Vec<u8>>, file_name: &str, caption: String) -> impl Future<Output = ResponseResult<Vec<MessageId>>> + Send;
+
+    /// Looks up a file sent to the bot and returns the URL it can be downloaded from.
+    fn file_url(&self, file_id: &str) -> impl Future<Output = ResponseResult<String>> + Send;
+
+    /// Deletes a message.
+    fn delete_message(&self, chat_id: ChatId, message_id: MessageId) -> impl Future<Output = ResponseResult<()>> + Send;
+
+    /// Sets the bot's reaction to a message to a single emoji.
+    fn set_reaction(&self, chat_id: ChatId, message_id: MessageId, emoji: &str) -> impl Future<Output = ResponseResult<()>> + Send;
+
+    /// Looks up a member of a chat and returns their username, or `None` if they don't have one.
+    fn member_username(&self, chat_id: ChatId, user_id: UserId) -> impl Future<Output = ResponseResult<Option<String>>> + Send;
+}
+
+impl MessageSender for Bot {
+    async fn send_message(&self, chat_id: ChatId, thread_id: Option<i32>, text: String) -> ResponseResult<MessageId> {
+        let mut request = Requester::send_message(self, chat_id, text);
+        if let Some(thread_id) = thread_id {
+            request = request.message_thread_id(thread_id);
+        }
+        let sent = request.await?;
+        Ok(sent.id)
+    }
+
+    async fn send_photo(&self, chat_id: ChatId, thread_id: Option<i32>, photo: Vec<u8>, file_name: &str, caption: String) -> ResponseResult<MessageId> {
+        let photo = InputFile::memory(photo).file_name(file_name.to_string());
+        let mut request = Requester::send_photo(self, chat_id, photo).caption(caption);
+        if let Some(thread_id) = thread_id {
+            request = request.message_thread_id(thread_id);
+        }
+        let sent = request.await?;
+        Ok(sent.id)
+    }
+
+    async fn reply_photo(&self, chat_id: ChatId, thread_id: Option<i32>, reply_to: MessageId, photo: Vec<u8>, file_name: &str, caption: String) -> ResponseResult<MessageId> {
+        let photo = InputFile::memory(photo).file_name(file_name.to_string());
+        let mut request = Requester::send_photo(self, chat_id, photo).caption(caption).reply_to_message_id(reply_to);
+        if let Some(thread_id) = thread_id {
+            request = request.message_thread_id(thread_id);
+        }
+        let sent = request.await?;
+        Ok(sent.id)
+    }
+
+    async fn send_media_group(&self, chat_id: ChatId, thread_id: Option<i32>, reply_to: Option<MessageId>, photos: Vec<Vec<u8>>, file_name: &str, caption: String) -> ResponseResult<Vec<MessageId>> {
+        let media = photos.into_iter().enumerate().map(|(index, photo)| {
+            let photo = InputMediaPhoto::new(InputFile::memory(photo).file_name(file_name.to_string()));
+            InputMedia::Photo(if index == 0 { photo.caption(caption.clone()) } else { photo })
+        });
+        let mut request = Requester::send_media_group(self, chat_id, media);
+        if let Some(reply_to) = reply_to {
+            request = request.reply_to_message_id(reply_to);
+        }
+        if let Some(thread_id) = thread_id {
+            request = request.message_thread_id(thread_id);
+        }
+        let sent = request.await?;
+        Ok(sent.iter().map(|message| message.id).collect())
+    }
+
+    async fn file_url(&self, file_id: &str) -> ResponseResult<String> {
+        let file = self.get_file(file_id).await?;
+        Ok(format!("https://api.telegram.org/file/bot{}/{}", self.token(), file.path))
+    }
+
+    async fn delete_message(&self, chat_id: ChatId, message_id: MessageId) -> ResponseResult<()> {
+        Requester::delete_message(self, chat_id, message_id).await?;
+        Ok(())
+    }
+
+    async fn set_reaction(&self, chat_id: ChatId, message_id: MessageId, emoji: &str) -> ResponseResult<()> {
+        JsonRequest::new(self.clone(), SetMessageReaction::new(chat_id, message_id, emoji)).await?;
+        Ok(())
+    }
+
+    async fn member_username(&self, chat_id: ChatId, user_id: UserId) -> ResponseResult<Option<String>> {
+        let member = self.get_chat_member(chat_id, user_id).await?;
+        Ok(member.user.username)
+    }
+}
+
+/// A call recorded by `MockSender`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum SentCall {
+    Message { chat_id: ChatId, thread_id: Option<i32>, text: String },
+    Photo { chat_id: ChatId, thread_id: Option<i32>, reply_to: Option<MessageId>, file_name: String, caption: String, size: usize },
+    MediaGroup { chat_id: ChatId, thread_id: Option<i32>, reply_to: Option<MessageId>, file_name: String, caption: String, sizes: Vec<usize> },
+    FileUrl { file_id: String },
+    DeleteMessage { chat_id: ChatId, message_id: MessageId },
+    Reaction { chat_id: ChatId, message_id: MessageId, emoji: String },
+}
+
+/// A `MessageSender` that records every call instead of talking to Telegram.
+///
+/// Sent messages get increasing IDs starting at 1, and every photo of an album gets one of its own. Every chat member's
+/// username is `user<id>`. `file_url` returns
+/// the URLs set with `with_file_url` or `with_file_urls` in turn, repeating the last one, or fails with a not found
+/// error if there are none, so a test can hand out an expired file path followed by a fresh one. Deletes succeed unless
+/// `with_failing_deletes` is set. Clones share the same call log,
+/// so a clone can be handed to the code under test and the original inspected afterwards.
+#[derive(Clone, Default)]
+pub struct MockSender {
+    calls: Arc<Mutex<Vec<SentCall>>>,
+    last_id: Arc<AtomicI32>,
+    file_urls: Vec<String>,
+    file_url_calls: Arc<AtomicUsize>,
+    failing_deletes: bool,
+}
+
+impl MockSender {
+    /// Creates a new `MockSender` with an empty call log.
+    ///
+    /// # Returns
+    /// A new `MockSender` instance.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets the URL returned by `file_url`, e.g. the address of a local test server.
+    ///
+    /// # Arguments
+    /// * `file_url` - The URL returned for every file.
+    ///
+    /// # Returns
+    /// The `MockSender` instance with the file URL set.
+    pub fn with_file_url(self, file_url: impl Into<String>) -> Self {
+        self.with_file_urls([file_url])
+    }
+
+    /// Sets the URLs returned by `file_url`, one per call in order, with the last one returned for every later call.
+    ///
+    /// # Arguments
+    /// * `file_urls` - The URLs to return.
+    ///
+    /// # Returns
+    /// The `MockSender` instance with the file URLs set.
+    pub fn with_file_urls(mut self, file_urls: impl IntoIterator<Item = impl Into<String>>) -> Self {
+        self.file_urls = file_urls.into_iter().map(Into::into).collect();
+        self
+    }
+
+    /// Makes every `delete_message` call fail as if the message were already gone, after recording it.
+    ///
+    /// # Returns
+    /// The `MockSender` instance with failing deletes.
+    pub fn with_failing_deletes(mut self) -> Self {
+        self.failing_deletes = true;
+        self
+    }
+
+    /// Returns a copy of every call recorded so far, in order.
+    pub fn calls(&self) -> Vec<SentCall> {
+        self.calls.lock().unwrap().clone()
+    }
+
+    /// Records a call and returns the next message ID, used as the ID of a sent message.
+    fn record(&self, call: SentCall) -> i32 {
+        self.calls.lock().unwrap().push(call);
+        self.last_id.fetch_add(1, Ordering::SeqCst) + 1
+    }
+}
+
+/// Builds a message from a user in a private chat, as Telegram would deliver it, for tests that drive a handler with
+/// `MockSender`.
+///
+/// # Arguments
+/// * `chat_id` - The chat the message was sent in.
+/// * `user_id` - The user who sent it.
+/// * `text` - The text of the message, e.g. `"/degenme hat"`.
+///
+/// # Returns
+/// The message.
+#[cfg(test)]
+pub fn mock_message(chat_id: i64, user_id: u64, text: &str) -> Message {
+    serde_json::from_value(serde_json::json!({
+        "message_id": 1,
+        "date": 1_700_000_000,
+        "chat": { "id": chat_id, "type": "private", "first_name": "Degen" },
+        "from": { "id": user_id, "is_bot": false, "first_name": "Degen", "username": "degen" },
+        "text": text,
+    })).expect("a valid message")
+}
+
+impl MessageSender for MockSender {
+    async fn send_message(&self, chat_id: ChatId, thread_id: Option<i32>, text: String) -> ResponseResult<MessageId> {
+        Ok(MessageId(self.record(SentCall::Message { chat_id, thread_id, text })))
+    }
+
+    async fn send_photo(&self, chat_id: ChatId, thread_id: Option<i32>, photo: Vec<u8>, file_name: &str, caption: String) -> ResponseResult<MessageId> {
+        let call = SentCall::Photo { chat_id, thread_id, reply_to: None, file_name: file_name.to_string(), caption, size: photo.len() };
+        Ok(MessageId(self.record(call)))
+    }
+
+    async fn reply_photo(&self, chat_id: ChatId, thread_id: Option<i32>, reply_to: MessageId, photo: Vec<u8>, file_name: &str, caption: String) -> ResponseResult<MessageId> {
+        let call = SentCall::Photo { chat_id, thread_id, reply_to: Some(reply_to), file_name: file_name.to_string(), caption, size: photo.len() };
+        Ok(MessageId(self.record(call)))
+    }
+
+    async fn send_media_group(&self, chat_id: ChatId, thread_id: Option<i32>, reply_to: Option<MessageId>, photos: Vec<Vec<u8>>, file_name: &str, caption: String) -> ResponseResult<Vec<MessageId>> {
+        let sizes: Vec<usize> = photos.iter().map(Vec::len).collect();
+        let count = sizes.len() as i32;
+        let first_id = self.record(SentCall::MediaGroup { chat_id, thread_id, reply_to, file_name: file_name.to_string(), caption, sizes });
+        // The first photo took the call's ID, the rest get the IDs after it
+        self.last_id.fetch_add((count - 1).max(0), Ordering::SeqCst);
+        Ok((0..count).map(|offset| MessageId(first_id + offset)).collect())
+    }
+
+    async fn file_url(&self, file_id: &str) -> ResponseResult<String> {
+        self.record(SentCall::FileUrl { file_id: file_id.to_string() });
+        let call = self.file_url_calls.fetch_add(1, Ordering::SeqCst);
+        self.file_urls.get(call).or(self.file_urls.last())
+            .cloned()
+            .ok_or_else(|| RequestError::Io(io::Error::new(io::ErrorKind::NotFound, "MockSender has no file URL").into()))
+    }
+
+    async fn delete_message(&self, chat_id: ChatId, message_id: MessageId) -> ResponseResult<()> {
+        self.record(SentCall::DeleteMessage { chat_id, message_id });
+        if self.failing_deletes {
+            return Err(RequestError::Api(ApiError::MessageToDeleteNotFound));
+        }
+        Ok(())
+    }
+
+    async fn set_reaction(&self, chat_id: ChatId, message_id: MessageId, emoji: &str) -> ResponseResult<()> {
+        self.record(SentCall::Reaction { chat_id, message_id, emoji: emoji.to_string() });
+        Ok(())
+    }
+
+    async fn member_username(&self, _chat_id: ChatId, user_id: UserId) -> ResponseResult<Option<String>> {
+        Ok(Some(format!("user{}", user_id)))
+    }
+}
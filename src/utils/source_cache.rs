@@ -0,0 +1,75 @@
+use std::collections::VecDeque;
+use std::sync::Arc;
+use std::time::Duration;
+use teloxide::types::{ChatId, MessageId};
+use tokio::sync::Mutex;
+use tokio::time::Instant;
+
+/// A SourceCache struct that keeps the source images of recent overlay results for a short time.
+///
+/// Replying to a result with `/again <style>` re-renders the original image with another overlay. Keeping the source
+/// bytes, keyed by the result message, means the user doesn't have to upload the image again, and works the same for
+/// photos and image links.
+///
+/// Only the last `capacity` sources are kept, and each one is forgotten `ttl` after it was cached, since source images
+/// can be several megabytes. Sources are only kept in memory, so they are forgotten when the bot restarts.
+pub struct SourceCache {
+    entries: Arc<Mutex<VecDeque<(ChatId, MessageId, Instant, Arc<Vec<u8>>)>>>,
+    capacity: usize,
+    ttl: Duration,
+}
+
+impl SourceCache {
+    /// Creates a new `SourceCache` instance that keeps up to `capacity` sources for `ttl` each.
+    ///
+    /// # Arguments
+    /// * `capacity` - The number of sources to keep.
+    /// * `ttl` - How long each source is kept.
+    ///
+    /// # Returns
+    /// A new `SourceCache` instance.
+    pub fn new(capacity: usize, ttl: Duration) -> Self {
+        SourceCache {
+            entries: Arc::new(Mutex::new(VecDeque::with_capacity(capacity))),
+            capacity,
+            ttl,
+        }
+    }
+
+    /// Caches the source image of a result, forgetting the oldest one if the cache is full.
+    ///
+    /// # Arguments
+    /// * `chat_id` - The chat the result was sent in.
+    /// * `message_id` - The ID of the result message.
+    /// * `source` - The raw bytes of the image the overlay was applied to.
+    pub async fn insert(&self, chat_id: ChatId, message_id: MessageId, source: Arc<Vec<u8>>) {
+        if self.capacity == 0 {
+            return;
+        }
+
+        let mut entries = self.entries.lock().await;
+        if entries.len() >= self.capacity {
+            entries.pop_front();
+        }
+        entries.push_back((chat_id, message_id, Instant::now(), source));
+    }
+
+    /// Looks up the source image of a result, forgetting any sources that have expired.
+    ///
+    /// # Arguments
+    /// * `chat_id` - The chat the result is in.
+    /// * `message_id` - The ID of the result message.
+    ///
+    /// # Returns
+    /// The raw bytes of the source image, or `None` if it was never cached or has expired.
+    pub async fn get(&self, chat_id: ChatId, message_id: MessageId) -> Option<Arc<Vec<u8>>> {
+        let mut entries = self.entries.lock().await;
+        // Entries are in insertion order, so the expired ones are at the front
+        while entries.front().is_some_and(|(_, _, cached_at, _)| cached_at.elapsed() > self.ttl) {
+            entries.pop_front();
+        }
+        entries.iter()
+            .find(|(chat, message, _, _)| *chat == chat_id && *message == message_id)
+            .map(|(_, _, _, source)| Arc::clone(source))
+    }
+}
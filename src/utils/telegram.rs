@@ -0,0 +1,210 @@
+use serde::Serialize;
+use teloxide::{ApiError, RequestError};
+use teloxide::prelude::*;
+use teloxide::requests::Payload;
+use teloxide::types::{MessageId, PhotoSize, True};
+use tokio::time::Duration;
+
+/// Creates a bot whose requests give up after `request_timeout`.
+///
+/// The timeout is set on the bot's HTTP client, so it covers every request made with the bot and its clones. That
+/// includes the dispatcher's `getUpdates` long poll, which waits up to 10 seconds for updates, so `request_timeout` must
+/// be longer than that. A timed out request fails with a `RequestError::Network` like any other failed request.
+///
+/// # Arguments
+/// * `token` - The bot's token.
+/// * `request_timeout` - How long to wait for a Telegram request before giving up.
+///
+/// # Returns
+/// A new `Bot` using a client with the given timeout.
+pub fn bot_with_timeout(token: &str, request_timeout: Duration) -> Bot {
+    let client = teloxide::net::default_reqwest_settings()
+        .timeout(request_timeout)
+        .build()
+        .expect("failed to build the Telegram HTTP client");
+    Bot::with_client(token, client)
+}
+
+/// Returns the forum topic a message was sent in, so replies to it can be posted in the same topic.
+///
+/// Only messages in forum supergroups belong to a topic. Elsewhere, including the "General" topic of a forum, this is
+/// `None` and replies are sent to the chat as usual, since a thread ID that isn't a topic would be rejected by Telegram.
+///
+/// # Arguments
+/// * `msg` - The message being replied to.
+///
+/// # Returns
+/// The ID of the message's topic, or `None` if it isn't in one.
+pub fn topic_thread_id(msg: &Message) -> Option<i32> {
+    msg.thread_id.filter(|_| msg.is_topic_message)
+}
+
+/// Returns the highest-resolution variant of a message's photo, the one with the most pixels.
+///
+/// Telegram sends every photo in several sizes. They usually arrive smallest first, but that isn't guaranteed, so the
+/// variants are compared by `width * height` instead of taking the last one.
+///
+/// # Arguments
+/// * `msg` - The message with the photo.
+///
+/// # Returns
+/// The largest variant of the photo, or `None` if the message has no photo.
+pub fn largest_photo(msg: &Message) -> Option<&PhotoSize> {
+    msg.photo()?.iter().max_by_key(|photo| u64::from(photo.width) * u64::from(photo.height))
+}
+
+/// The reaction the bot leaves on a user's message once its result has been sent, if `react_on_success` is set.
+pub const SUCCESS_REACTION: &str = "🔥";
+
+/// The payload of the Bot API's `setMessageReaction` method, which this version of teloxide doesn't have yet.
+///
+/// It is sent with `teloxide::requests::JsonRequest`, like teloxide's own payloads.
+#[derive(Clone, Serialize)]
+pub struct SetMessageReaction {
+    chat_id: i64,
+    message_id: i32,
+    reaction: Vec<ReactionType>,
+}
+
+/// An emoji reaction, as the Bot API's `ReactionTypeEmoji`.
+#[derive(Clone, Serialize)]
+struct ReactionType {
+    #[serde(rename = "type")]
+    kind: &'static str,
+    emoji: String,
+}
+
+impl SetMessageReaction {
+    /// Creates the payload that sets the bot's reaction to a message to a single emoji.
+    ///
+    /// # Arguments
+    /// * `chat_id` - The chat the message is in.
+    /// * `message_id` - The message to react to.
+    /// * `emoji` - The emoji to react with, which must be one Telegram allows as a reaction.
+    ///
+    /// # Returns
+    /// A new `SetMessageReaction` payload.
+    pub fn new(chat_id: ChatId, message_id: MessageId, emoji: &str) -> Self {
+        SetMessageReaction {
+            chat_id: chat_id.0,
+            message_id: message_id.0,
+            reaction: vec![ReactionType { kind: "emoji", emoji: emoji.to_string() }],
+        }
+    }
+}
+
+impl Payload for SetMessageReaction {
+    type Output = True;
+
+    const NAME: &'static str = "SetMessageReaction";
+}
+
+/// Checks whether a Telegram request failed because reactions can't be used in the chat.
+///
+/// Chats can turn reactions off or limit them to a few emoji, which Telegram reports as "REACTION_INVALID" or
+/// "REACTIONS_UNAVAILABLE". teloxide doesn't know either, so they are matched on their text.
+///
+/// # Arguments
+/// * `error` - The error returned by the request.
+///
+/// # Returns
+/// `true` if the chat doesn't allow the reaction, `false` otherwise.
+pub fn is_reaction_unavailable_error(error: &RequestError) -> bool {
+    matches!(error, RequestError::Api(ApiError::Unknown(message)) if message.to_uppercase().contains("REACTION"))
+}
+
+/// The largest file the Bot API lets bots download, whatever `max_file_size_bytes` is set to.
+pub const TELEGRAM_MAX_DOWNLOAD_BYTES: u32 = 20 * 1024 * 1024;
+
+/// Checks whether a Telegram request failed because the file is over the Bot API's download limit.
+///
+/// Telegram answers `getFile` for such a file with "file is too big", which teloxide doesn't know, so it is matched on
+/// its text.
+///
+/// # Arguments
+/// * `error` - The error returned by the request.
+///
+/// # Returns
+/// `true` if the file is too large for the bot to download, `false` otherwise.
+pub fn is_file_too_big_error(error: &RequestError) -> bool {
+    matches!(error, RequestError::Api(ApiError::Unknown(message)) if message.to_lowercase().contains("file is too big"))
+}
+
+/// Checks whether downloading a Telegram file failed because its file path has expired.
+///
+/// The path `getFile` returns is only guaranteed to work for an hour, after which the file server answers with 404 Not
+/// Found. Photos that waited in a deep queue can outlive their path, and calling `getFile` again gives a fresh one.
+///
+/// # Arguments
+/// * `error` - The error returned by the download.
+///
+/// # Returns
+/// `true` if the file path is likely to have expired, `false` otherwise.
+pub fn is_expired_file_path(error: &reqwest::Error) -> bool {
+    error.status() == Some(reqwest::StatusCode::NOT_FOUND)
+}
+
+/// Checks whether a Telegram request failed because the bot isn't allowed to post in the chat.
+///
+/// This covers the bot being blocked by the user, removed from the group, or restricted so it can't send messages or
+/// media. Telegram reports some of the media restrictions with messages teloxide doesn't know, such as
+/// "not enough rights to send photos to the chat", so those are matched on their text.
+///
+/// # Arguments
+/// * `error` - The error returned by the request.
+///
+/// # Returns
+/// `true` if retrying in the same chat would fail the same way, `false` otherwise.
+pub fn is_permission_error(error: &RequestError) -> bool {
+    match error {
+        RequestError::Api(
+            ApiError::BotBlocked
+            | ApiError::BotKicked
+            | ApiError::BotKickedFromSupergroup
+            | ApiError::NotEnoughRightsToPostMessages
+            | ApiError::CantInitiateConversation
+            | ApiError::UserDeactivated,
+        ) => true,
+        RequestError::Api(ApiError::Unknown(message)) => {
+            let message = message.to_lowercase();
+            message.contains("not enough rights") || message.contains("have no rights")
+        }
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn photo_message(sizes: &[(&str, u32, u32)]) -> Message {
+        let photo: Vec<_> = sizes.iter().map(|(file_id, width, height)| serde_json::json!({
+            "file_id": file_id, "file_unique_id": file_id, "file_size": width * height, "width": width, "height": height,
+        })).collect();
+        serde_json::from_value(serde_json::json!({
+            "message_id": 1,
+            "date": 1_700_000_000,
+            "chat": { "id": 10, "type": "private", "first_name": "Degen" },
+            "from": { "id": 20, "is_bot": false, "first_name": "Degen" },
+            "photo": photo,
+        })).expect("a valid message")
+    }
+
+    #[test]
+    fn largest_photo_picks_the_most_pixels_whatever_the_order() {
+        let msg = photo_message(&[("medium", 320, 240), ("large", 1280, 960), ("small", 90, 67), ("wide", 1000, 100)]);
+        assert_eq!(largest_photo(&msg).map(|photo| photo.file.id.as_str()), Some("large"));
+    }
+
+    #[test]
+    fn largest_photo_is_none_without_a_photo() {
+        let msg: Message = serde_json::from_value(serde_json::json!({
+            "message_id": 1,
+            "date": 1_700_000_000,
+            "chat": { "id": 10, "type": "private", "first_name": "Degen" },
+            "from": { "id": 20, "is_bot": false, "first_name": "Degen" },
+            "text": "no photo here",
+        })).expect("a valid message");
+        assert!(largest_photo(&msg).is_none());
+    }
+}
@@ -0,0 +1,317 @@
+use std::error::Error as StdError;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use reqwest::dns::{Addrs, Name, Resolve, Resolving};
+use reqwest::header::{CONTENT_TYPE, LOCATION};
+use thiserror::Error;
+use tokio::net::lookup_host;
+use url::Url;
+use log::{info, warn};
+
+/// The maximum number of redirects followed when downloading an image from a URL.
+const MAX_REDIRECTS: usize = 3;
+
+/// An error that occurred while downloading an image from a user-supplied URL.
+///
+/// The variants are:
+/// - `UnsupportedScheme`: The URL isn't an `http` or `https` URL.
+/// - `BlockedAddress`: The URL's host resolves to a private, loopback or otherwise internal address.
+/// - `Resolve`: The URL's host couldn't be resolved.
+/// - `TooManyRedirects`: The URL redirected more than `MAX_REDIRECTS` times.
+/// - `Request`: The request failed or returned an error status.
+/// - `NotAnImage`: The response's content type isn't an image type; holds the content type.
+/// - `TooLarge`: The image is larger than the maximum download size; holds the limit in bytes.
+#[derive(Debug, Error)]
+pub enum UrlDownloadError {
+    #[error("Only http and https links are supported")]
+    UnsupportedScheme,
+    #[error("Links to private or local addresses are not allowed")]
+    BlockedAddress,
+    #[error("Could not resolve host: {0}")]
+    Resolve(io::Error),
+    #[error("Too many redirects")]
+    TooManyRedirects,
+    #[error("Request failed: {0}")]
+    Request(#[from] reqwest::Error),
+    #[error("Content type {0:?} is not an image")]
+    NotAnImage(String),
+    #[error("Image is larger than {0} bytes")]
+    TooLarge(u64),
+}
+
+/// A resolver for the HTTP client that leaves out private, loopback and otherwise internal addresses.
+///
+/// `check_url` resolves a link's host before it is requested, but the client resolves it again when it connects, so a
+/// host under an attacker's control could answer with a public address for the check and an internal one for the
+/// request. Resolving through `PublicResolver` drops the internal addresses when the connection is made, for every
+/// redirect hop too, and fails if none are left. Hosts written as IP addresses aren't resolved by the client, so those
+/// are only checked by `check_url`, which is enough since they can't change.
+pub struct PublicResolver;
+
+impl Resolve for PublicResolver {
+    fn resolve(&self, name: Name) -> Resolving {
+        Box::pin(resolve_public(name))
+    }
+}
+
+/// Resolves a host for `PublicResolver`, keeping only its public addresses.
+async fn resolve_public(name: Name) -> Result<Addrs, Box<dyn StdError + Send + Sync>> {
+    // The client fills in the port from the URL
+    let addresses: Vec<SocketAddr> = lookup_host((name.as_str(), 0)).await?
+        .filter(|address| !is_blocked_ip(address.ip()))
+        .collect();
+    if addresses.is_empty() {
+        warn!("Refusing to connect to {}, it only resolves to internal addresses", name.as_str());
+        return Err(Box::new(UrlDownloadError::BlockedAddress));
+    }
+    Ok(Box::new(addresses.into_iter()))
+}
+
+/// Finds the first `http` or `https` URL in a message's text.
+///
+/// # Arguments
+/// * `text` - The message text, e.g. the arguments of `/degenme <url>` or a reply to the overlay prompt.
+///
+/// # Returns
+/// The first word of `text` that parses as an `http` or `https` URL, or `None` if there is none.
+pub fn find_image_url(text: &str) -> Option<Url> {
+    text.split_whitespace()
+        .filter_map(|word| Url::parse(word).ok())
+        .find(|url| matches!(url.scheme(), "http" | "https"))
+}
+
+/// Downloads an image from a user-supplied URL.
+///
+/// Every URL, including each redirect target, is checked before it is requested: only `http` and `https` are allowed,
+/// and hosts that resolve to private, loopback or otherwise internal addresses are rejected to guard against SSRF.
+/// Redirects are followed here rather than by the client so each hop can be checked, which means the client passed in
+/// must not follow redirects itself. The client must also resolve hosts with `PublicResolver`, so a host can't pass the
+/// check and then resolve to an internal address when it is requested. The response must have an `image/*` content type and may not be larger than
+/// `max_bytes`.
+///
+/// # Arguments
+/// * `http_client` - The shared HTTP client, configured not to follow redirects and to resolve with `PublicResolver`.
+/// * `url` - The URL to download the image from.
+/// * `max_bytes` - The largest image, in bytes, that will be downloaded, from `processing.max_url_download_bytes`.
+///
+/// # Returns
+/// The raw bytes of the image, or a `UrlDownloadError` describing why it couldn't be downloaded.
+pub async fn download_image_url(http_client: &reqwest::Client, url: Url, max_bytes: u64) -> Result<Vec<u8>, UrlDownloadError> {
+    let mut url = url;
+    let mut redirects = 0;
+    let mut response = loop {
+        check_url(&url).await?;
+        info!("Downloading image from URL: {}", url);
+        let response = http_client.get(url.clone()).send().await.map_err(|e| {
+            if is_blocked_by_resolver(&e) { UrlDownloadError::BlockedAddress } else { UrlDownloadError::Request(e) }
+        })?;
+        if !response.status().is_redirection() {
+            break response.error_for_status()?;
+        }
+
+        redirects += 1;
+        if redirects > MAX_REDIRECTS {
+            return Err(UrlDownloadError::TooManyRedirects);
+        }
+        let Some(location) = response.headers().get(LOCATION)
+            .and_then(|location| location.to_str().ok())
+            .and_then(|location| url.join(location).ok()) else {
+            break response;
+        };
+        url = location;
+    };
+
+    let content_type = response.headers().get(CONTENT_TYPE)
+        .and_then(|content_type| content_type.to_str().ok())
+        .unwrap_or_default()
+        .to_string();
+    if !content_type.starts_with("image/") {
+        return Err(UrlDownloadError::NotAnImage(content_type));
+    }
+    if response.content_length().is_some_and(|length| length > max_bytes) {
+        return Err(UrlDownloadError::TooLarge(max_bytes));
+    }
+
+    // The content length can be missing or wrong, so the limit is enforced while reading the body too
+    let mut image_data = Vec::new();
+    while let Some(chunk) = response.chunk().await? {
+        if (image_data.len() + chunk.len()) as u64 > max_bytes {
+            return Err(UrlDownloadError::TooLarge(max_bytes));
+        }
+        image_data.extend_from_slice(&chunk);
+    }
+    Ok(image_data)
+}
+
+/// Checks that a URL uses `http` or `https` and that its host only resolves to public addresses.
+async fn check_url(url: &Url) -> Result<(), UrlDownloadError> {
+    if !matches!(url.scheme(), "http" | "https") {
+        return Err(UrlDownloadError::UnsupportedScheme);
+    }
+    let host = url.host_str().ok_or(UrlDownloadError::UnsupportedScheme)?;
+    let port = url.port_or_known_default().ok_or(UrlDownloadError::UnsupportedScheme)?;
+
+    // IPv6 hosts are written in brackets in URLs, which lookup_host doesn't accept
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    let mut addresses = lookup_host((host, port)).await.map_err(UrlDownloadError::Resolve)?.peekable();
+    if addresses.peek().is_none() {
+        return Err(UrlDownloadError::Resolve(io::Error::new(io::ErrorKind::NotFound, "no addresses found")));
+    }
+    if addresses.any(|address| is_blocked_ip(address.ip())) {
+        warn!("Blocked image download from internal address: {}", url);
+        return Err(UrlDownloadError::BlockedAddress);
+    }
+    Ok(())
+}
+
+/// Returns whether a request failed because `PublicResolver` found only internal addresses for its host.
+fn is_blocked_by_resolver(error: &reqwest::Error) -> bool {
+    let mut source = error.source();
+    while let Some(error) = source {
+        if matches!(error.downcast_ref::<UrlDownloadError>(), Some(UrlDownloadError::BlockedAddress)) {
+            return true;
+        }
+        source = error.source();
+    }
+    false
+}
+
+/// Returns whether an address is private, loopback or otherwise not publicly routable.
+fn is_blocked_ip(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => is_blocked_ipv4(ip),
+        IpAddr::V6(ip) => match embedded_ipv4(ip) {
+            Some(ip) => is_blocked_ipv4(ip),
+            None => is_blocked_ipv6(ip),
+        },
+    }
+}
+
+/// Returns the IPv4 address an IPv6 address reaches, for the ranges that carry one: IPv4-mapped `::ffff:a.b.c.d`,
+/// IPv4-compatible `::a.b.c.d`, NAT64 `64:ff9b::a.b.c.d` and 6to4 `2002:aabb:ccdd::`.
+fn embedded_ipv4(ip: Ipv6Addr) -> Option<Ipv4Addr> {
+    let octets = ip.octets();
+    match ip.segments() {
+        [0x0064, 0xff9b, 0, 0, 0, 0, _, _] => Some(Ipv4Addr::new(octets[12], octets[13], octets[14], octets[15])),
+        [0x2002, ..] => Some(Ipv4Addr::new(octets[2], octets[3], octets[4], octets[5])),
+        // Covers both the mapped and the compatible forms
+        _ => ip.to_ipv4(),
+    }
+}
+
+fn is_blocked_ipv4(ip: Ipv4Addr) -> bool {
+    let [a, b, c, _] = ip.octets();
+    ip.is_private()
+        || ip.is_loopback()
+        || ip.is_link_local()
+        || ip.is_unspecified()
+        || ip.is_broadcast()
+        || ip.is_multicast()
+        || ip.is_documentation()
+        || a == 0 // "this" network
+        || (a == 100 && (64..128).contains(&b)) // Carrier-grade NAT
+        || (a == 192 && b == 0 && c == 0) // IETF protocol assignments
+        || (a == 198 && (18..20).contains(&b)) // Benchmarking
+        || a >= 240 // Reserved
+}
+
+fn is_blocked_ipv6(ip: Ipv6Addr) -> bool {
+    let first = ip.segments()[0];
+    ip.is_loopback()
+        || ip.is_unspecified()
+        || ip.is_multicast()
+        || (first & 0xfe00) == 0xfc00 // Unique local
+        || (first & 0xffc0) == 0xfe80 // Link local
+        || (first == 0x2001 && ip.segments()[1] == 0x0db8) // Documentation
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use super::*;
+
+    fn blocked(ip: &str) -> bool {
+        is_blocked_ip(ip.parse().expect("a valid address"))
+    }
+
+    fn resolving_client() -> reqwest::Client {
+        reqwest::Client::builder()
+            .redirect(reqwest::redirect::Policy::none())
+            .dns_resolver(Arc::new(PublicResolver))
+            .build()
+            .expect("a valid client")
+    }
+
+    #[tokio::test]
+    async fn the_resolver_refuses_hosts_with_only_internal_addresses() {
+        let resolved = PublicResolver.resolve("localhost".parse().expect("a valid name")).await;
+        let error = resolved.err().expect("localhost is refused");
+        assert!(matches!(error.downcast_ref::<UrlDownloadError>(), Some(UrlDownloadError::BlockedAddress)));
+    }
+
+    #[tokio::test]
+    async fn a_request_the_resolver_refuses_is_a_blocked_address() {
+        // Straight to the client, as if the name had resolved to a public address when check_url looked it up
+        let error = resolving_client().get("http://localhost:9/image.png").send().await.expect_err("localhost is refused");
+        assert!(is_blocked_by_resolver(&error));
+    }
+
+    #[tokio::test]
+    async fn links_to_internal_addresses_are_blocked() {
+        for url in ["http://127.0.0.1/image.png", "http://[::1]/image.png", "http://localhost/image.png", "http://169.254.169.254/latest/meta-data"] {
+            let downloaded = download_image_url(&resolving_client(), Url::parse(url).unwrap(), 1024).await;
+            assert!(matches!(downloaded, Err(UrlDownloadError::BlockedAddress)), "{} wasn't blocked", url);
+        }
+    }
+
+    #[test]
+    fn blocks_internal_ipv4_addresses() {
+        for ip in ["127.0.0.1", "10.1.2.3", "172.16.0.1", "192.168.1.1", "169.254.169.254", "0.0.0.0", "100.64.0.1", "192.0.0.1", "192.0.0.170", "198.18.0.1", "240.0.0.1", "255.255.255.255"] {
+            assert!(blocked(ip), "{} is allowed", ip);
+        }
+    }
+
+    #[test]
+    fn allows_public_addresses() {
+        for ip in ["1.1.1.1", "8.8.8.8", "192.0.1.1", "149.154.167.220", "2606:4700:4700::1111", "2a00:1450:4001:80b::200e"] {
+            assert!(!blocked(ip), "{} is blocked", ip);
+        }
+    }
+
+    #[test]
+    fn blocks_internal_ipv6_addresses() {
+        for ip in ["::1", "::", "fc00::1", "fd12:3456::1", "fe80::1", "ff02::1", "2001:db8::1"] {
+            assert!(blocked(ip), "{} is allowed", ip);
+        }
+    }
+
+    #[test]
+    fn blocks_ipv4_mapped_addresses_by_their_ipv4_address() {
+        assert!(blocked("::ffff:127.0.0.1"));
+        assert!(blocked("::ffff:169.254.169.254"));
+        assert!(!blocked("::ffff:8.8.8.8"));
+    }
+
+    #[test]
+    fn blocks_ipv4_compatible_addresses_by_their_ipv4_address() {
+        assert!(blocked("::127.0.0.1"));
+        assert!(blocked("::10.0.0.1"));
+        assert!(!blocked("::8.8.8.8"));
+    }
+
+    #[test]
+    fn blocks_nat64_addresses_by_their_ipv4_address() {
+        assert!(blocked("64:ff9b::127.0.0.1"));
+        assert!(blocked("64:ff9b::a9fe:a9fe"));
+        assert!(blocked("64:ff9b::192.0.0.1"));
+        assert!(!blocked("64:ff9b::8.8.8.8"));
+    }
+
+    #[test]
+    fn blocks_6to4_addresses_by_their_ipv4_address() {
+        assert!(blocked("2002:7f00:1::")); // 127.0.0.1
+        assert!(blocked("2002:a9fe:a9fe::1")); // 169.254.169.254
+        assert!(blocked("2002:c0a8:101::")); // 192.168.1.1
+        assert!(!blocked("2002:808:808::1")); // 8.8.8.8
+    }
+}
@@ -0,0 +1,108 @@
+use std::sync::Arc;
+use std::time::Duration;
+use log::{error, info, warn};
+use tokio::task::JoinHandle;
+
+use crate::utils::metrics::OverlayMetrics;
+
+/// How long to wait before restarting a task that stopped, so a task that fails straight away doesn't spin.
+const RESTART_DELAY: Duration = Duration::from_secs(5);
+
+/// Spawns a task again after it stopped, for tasks `watch` may restart.
+pub type Respawn = Box<dyn Fn() -> JoinHandle<()> + Send>;
+
+/// Watches a long-lived background task, logging when it stops unexpectedly and restarting it if it can.
+///
+/// The watchdog waits on the task's `JoinHandle` in a task of its own. A task that returns while `finished` says it is
+/// done, such as the queue processor after the queue has been closed, has stopped as expected and is left alone. Any
+/// other exit, including a panic, is logged, counted in `metrics` under the task's name and, if there is a `respawn`,
+/// followed by a fresh task from it after `RESTART_DELAY`.
+///
+/// # Arguments
+/// * `name` - The task's name, used in the logs and the metrics, e.g. `"cleanup"`.
+/// * `handle` - The `JoinHandle` of the running task.
+/// * `finished` - Whether the task is allowed to have stopped, checked when it returns without panicking.
+/// * `respawn` - Spawns the task again after it stopped unexpectedly, or `None` to leave it stopped.
+/// * `metrics` - The metrics the unexpected exits are counted in.
+///
+/// # Returns
+/// The `JoinHandle` of the watchdog itself, which finishes once the task has stopped for good.
+pub fn watch<F>(name: &'static str, mut handle: JoinHandle<()>, finished: F, respawn: Option<Respawn>, metrics: Arc<OverlayMetrics>) -> JoinHandle<()>
+where
+    F: Fn() -> bool + Send + 'static,
+{
+    tokio::spawn(async move {
+        loop {
+            match handle.await {
+                Ok(()) if finished() => {
+                    info!("The {} task finished", name);
+                    return;
+                }
+                Ok(()) => warn!("The {} task stopped unexpectedly", name),
+                Err(e) if e.is_panic() => error!("The {} task panicked: {}", name, e),
+                Err(e) => warn!("The {} task was cancelled: {}", name, e),
+            }
+            metrics.record_task_exit(name);
+
+            let Some(respawn) = &respawn else {
+                warn!("Not restarting the {} task", name);
+                return;
+            };
+            tokio::time::sleep(RESTART_DELAY).await;
+            info!("Restarting the {} task", name);
+            handle = respawn();
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use super::*;
+
+    fn exits(metrics: &OverlayMetrics, task: &str) -> Option<String> {
+        let prefix = format!("degenbot_task_exits_total{{task=\"{}\"}} ", task);
+        metrics.render().lines().find_map(|line| line.strip_prefix(&prefix).map(str::to_string))
+    }
+
+    #[tokio::test]
+    async fn counts_a_task_that_stops_unexpectedly() {
+        let metrics = Arc::new(OverlayMetrics::new());
+        let watchdog = watch("stopped", tokio::spawn(async {}), || false, None, Arc::clone(&metrics));
+        watchdog.await.unwrap();
+        assert_eq!(exits(&metrics, "stopped").as_deref(), Some("1"));
+    }
+
+    #[tokio::test]
+    async fn counts_a_task_that_panics() {
+        let metrics = Arc::new(OverlayMetrics::new());
+        let watchdog = watch("panicked", tokio::spawn(async { panic!("boom") }), || true, None, Arc::clone(&metrics));
+        watchdog.await.unwrap();
+        assert_eq!(exits(&metrics, "panicked").as_deref(), Some("1"));
+    }
+
+    #[tokio::test]
+    async fn leaves_a_finished_task_alone() {
+        let metrics = Arc::new(OverlayMetrics::new());
+        let watchdog = watch("finished", tokio::spawn(async {}), || true, None, Arc::clone(&metrics));
+        watchdog.await.unwrap();
+        assert_eq!(exits(&metrics, "finished"), None);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn restarts_a_task_that_stops_unexpectedly() {
+        let metrics = Arc::new(OverlayMetrics::new());
+        let respawns = Arc::new(AtomicUsize::new(0));
+        let respawned = Arc::clone(&respawns);
+        let respawn: Respawn = Box::new(move || {
+            respawned.fetch_add(1, Ordering::SeqCst);
+            tokio::spawn(async {})
+        });
+        // The first exit is unexpected and the respawned task's exit is the expected one
+        let finished = Arc::clone(&respawns);
+        let watchdog = watch("restarted", tokio::spawn(async {}), move || finished.load(Ordering::SeqCst) > 0, Some(respawn), Arc::clone(&metrics));
+        watchdog.await.unwrap();
+        assert_eq!(respawns.load(Ordering::SeqCst), 1);
+        assert_eq!(exits(&metrics, "restarted").as_deref(), Some("1"));
+    }
+}
@@ -0,0 +1,190 @@
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use log::{info, warn, error};
+use tokio::sync::oneshot;
+
+/// A unit of work run on one of the pool's threads.
+type Job = Box<dyn FnOnce() + Send + 'static>;
+
+/// A fixed-size pool of OS threads that runs the blocking OpenCV work.
+///
+/// Image decoding and compositing is CPU heavy and blocks the thread it runs on. Running it on a dedicated pool, rather
+/// than with `tokio::task::spawn_blocking`, keeps image work from filling up Tokio's blocking pool and starving other
+/// blocking tasks. Jobs are fed to the threads over a channel and picked up in the order they were submitted.
+pub struct ImageWorkerPool {
+    sender: Mutex<Option<Sender<Job>>>,
+    workers: Mutex<Vec<JoinHandle<()>>>,
+}
+
+impl ImageWorkerPool {
+    /// Starts a pool with `size` worker threads.
+    ///
+    /// # Arguments
+    /// * `size` - The number of worker threads, at least one thread is always started.
+    ///
+    /// # Returns
+    /// The new `ImageWorkerPool`.
+    pub fn new(size: usize) -> Self {
+        let (sender, receiver) = mpsc::channel::<Job>();
+        let receiver = Arc::new(Mutex::new(receiver));
+
+        let workers = (0..size.max(1))
+            .map(|index| {
+                let receiver = Arc::clone(&receiver);
+                thread::Builder::new()
+                    .name(format!("image-worker-{}", index))
+                    .spawn(move || run_worker(receiver))
+                    .expect("Failed to spawn image worker thread")
+            })
+            .collect();
+        info!("Started image worker pool with {} threads", size.max(1));
+
+        ImageWorkerPool {
+            sender: Mutex::new(Some(sender)),
+            workers: Mutex::new(workers),
+        }
+    }
+
+    /// Submits a job to the pool.
+    ///
+    /// # Arguments
+    /// * `job` - The closure to run on a worker thread.
+    ///
+    /// # Returns
+    /// A receiver that resolves to the job's result. If the pool has been shut down, or the job panics, the receiver
+    /// resolves to an error instead.
+    pub fn submit<F, T>(&self, job: F) -> oneshot::Receiver<T>
+    where
+        F: FnOnce() -> T + Send + 'static,
+        T: Send + 'static,
+    {
+        let (result_sender, result_receiver) = oneshot::channel();
+        let job: Job = Box::new(move || {
+            // The caller may have stopped waiting for the result, which is fine
+            let _ = result_sender.send(job());
+        });
+
+        match self.sender.lock().expect("worker pool lock poisoned").as_ref() {
+            Some(sender) => {
+                if sender.send(job).is_err() {
+                    warn!("Image worker pool has no running threads, dropping job");
+                }
+            }
+            None => warn!("Image worker pool is shut down, dropping job"),
+        }
+        result_receiver
+    }
+
+    /// Shuts the pool down.
+    ///
+    /// Jobs that were already submitted are finished first, then the worker threads are joined. Jobs submitted after
+    /// this point are dropped. Calling this more than once has no effect.
+    pub fn shutdown(&self) {
+        // Dropping the sender disconnects the channel, which stops each worker once the queued jobs are done
+        if self.sender.lock().expect("worker pool lock poisoned").take().is_none() {
+            return;
+        }
+
+        let workers = std::mem::take(&mut *self.workers.lock().expect("worker pool lock poisoned"));
+        for worker in workers {
+            if worker.join().is_err() {
+                warn!("Image worker thread panicked");
+            }
+        }
+        info!("Image worker pool shut down");
+    }
+}
+
+impl Drop for ImageWorkerPool {
+    fn drop(&mut self) {
+        self.shutdown();
+    }
+}
+
+/// Runs jobs from the shared channel until it is disconnected.
+fn run_worker(receiver: Arc<Mutex<Receiver<Job>>>) {
+    loop {
+        // Only hold the lock while waiting for a job, not while running it
+        let job = match receiver.lock() {
+            Ok(receiver) => receiver.recv(),
+            Err(_) => return,
+        };
+        match job {
+            // A panicking job drops its result sender, so the caller sees an error, and the thread keeps serving jobs
+            Ok(job) => {
+                if panic::catch_unwind(AssertUnwindSafe(job)).is_err() {
+                    error!("Image worker job panicked");
+                }
+            }
+            Err(_) => return,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn runs_jobs_on_the_worker_threads() {
+        let pool = ImageWorkerPool::new(2);
+        let thread_name = pool.submit(|| thread::current().name().map(str::to_string)).await.unwrap();
+        assert!(thread_name.is_some_and(|name| name.starts_with("image-worker-")));
+        assert_eq!(pool.submit(|| 6 * 7).await.unwrap(), 42);
+    }
+
+    #[tokio::test]
+    async fn a_panicking_job_fails_without_stopping_the_pool() {
+        // A single thread, so the job after the panic can only run if the thread survived it
+        let pool = ImageWorkerPool::new(1);
+        let panicked = pool.submit(|| -> u32 { panic!("job panicked on purpose") });
+        assert!(panicked.await.is_err());
+        assert_eq!(pool.submit(|| 1).await.unwrap(), 1);
+    }
+
+    #[tokio::test]
+    async fn shutdown_finishes_queued_jobs_and_drops_later_ones() {
+        let pool = ImageWorkerPool::new(1);
+        let finished = Arc::new(AtomicUsize::new(0));
+        let receivers: Vec<_> = (0..3)
+            .map(|_| {
+                let finished = Arc::clone(&finished);
+                pool.submit(move || {
+                    thread::sleep(Duration::from_millis(10));
+                    finished.fetch_add(1, Ordering::SeqCst);
+                })
+            })
+            .collect();
+
+        pool.shutdown();
+        assert_eq!(finished.load(Ordering::SeqCst), 3);
+        for receiver in receivers {
+            assert!(receiver.await.is_ok());
+        }
+        assert!(pool.submit(|| ()).await.is_err());
+        // Shutting down again does nothing
+        pool.shutdown();
+    }
+
+    #[tokio::test]
+    async fn jobs_run_in_parallel_across_threads() {
+        let pool = ImageWorkerPool::new(2);
+        let (first_started, wait_for_first) = mpsc::channel();
+        let (second_started, wait_for_second) = mpsc::channel();
+        // Each job waits for the other to start, so they only both finish if they run at the same time
+        let first = pool.submit(move || {
+            first_started.send(()).unwrap();
+            wait_for_second.recv_timeout(Duration::from_secs(5)).is_ok()
+        });
+        let second = pool.submit(move || {
+            second_started.send(()).unwrap();
+            wait_for_first.recv_timeout(Duration::from_secs(5)).is_ok()
+        });
+        assert!(first.await.unwrap());
+        assert!(second.await.unwrap());
+    }
+}